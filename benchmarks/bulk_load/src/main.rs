@@ -0,0 +1,52 @@
+//! Compares filling an empty `Tree` with `Tree::bulk_load` against
+//! inserting the same sorted entries one at a time with `Tree::set`.
+//!
+//! Run with `cargo run --release`.
+extern crate sled;
+
+use std::time::Instant;
+
+const N_KEYS: usize = 1_000_000;
+
+fn key(i: usize) -> Vec<u8> {
+    vec![(i >> 24) as u8, (i >> 16) as u8, (i >> 8) as u8, i as u8]
+}
+
+fn entries() -> Box<Iterator<Item = (Vec<u8>, Vec<u8>)>> {
+    Box::new((0..N_KEYS).map(|i| (key(i), vec![0; 8])))
+}
+
+fn new_tree() -> sled::Tree {
+    let config = sled::ConfigBuilder::new()
+        .temporary(true)
+        .cache_capacity(1_000_000_000)
+        .flush_every_ms(None)
+        .build();
+    sled::Tree::start(config).unwrap()
+}
+
+fn bench<F>(name: &str, f: F)
+    where F: FnOnce(&sled::Tree)
+{
+    let tree = new_tree();
+    let now = Instant::now();
+    f(&tree);
+    let elapsed = now.elapsed();
+    let nanos =
+        elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+
+    assert_eq!(tree.len(), N_KEYS);
+    println!("{:>24}: {:>6} ns/key", name, nanos / N_KEYS as u64);
+}
+
+fn main() {
+    bench("set", |tree| for (k, v) in entries() {
+        tree.set(k, v).unwrap();
+    });
+    bench("bulk_load", |tree| {
+        tree.bulk_load(entries()).unwrap();
+    });
+    bench("bulk_load, 0.75 full", |tree| {
+        tree.bulk_loader().fill_factor(0.75).load(entries()).unwrap();
+    });
+}
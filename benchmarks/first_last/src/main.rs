@@ -0,0 +1,50 @@
+//! Compares `Tree::first` and `Tree::last` against getting the
+//! same entries from a full-range iterator.
+//!
+//! Run with `cargo run --release`.
+extern crate sled;
+
+use std::time::Instant;
+
+const N_KEYS: usize = 1_000_000;
+const N_READS: usize = 1_000_000;
+
+fn bench<F>(name: &str, mut f: F)
+    where F: FnMut() -> Option<(Vec<u8>, sled::IVec)>
+{
+    let now = Instant::now();
+    for _ in 0..N_READS {
+        assert!(f().is_some());
+    }
+    let elapsed = now.elapsed();
+    let nanos =
+        elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+
+    println!("{:>18}: {:>6} ns/op", name, nanos / N_READS as u64);
+}
+
+fn main() {
+    let config = sled::ConfigBuilder::new()
+        .temporary(true)
+        .cache_capacity(1_000_000_000)
+        .flush_every_ms(None)
+        .build();
+    let tree = sled::Tree::start(config).unwrap();
+
+    for i in 0..N_KEYS as u64 {
+        let k = [
+            (i >> 24) as u8,
+            (i >> 16) as u8,
+            (i >> 8) as u8,
+            i as u8,
+        ];
+        tree.set(k.to_vec(), vec![]).unwrap();
+    }
+
+    bench("first", || tree.first().unwrap());
+    bench("iter().next()", || tree.iter().next().map(|r| r.unwrap()));
+    bench("last", || tree.last().unwrap());
+    bench("iter().next_back()", || {
+        tree.iter().next_back().map(|r| r.unwrap())
+    });
+}
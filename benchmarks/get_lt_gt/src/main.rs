@@ -0,0 +1,61 @@
+//! Compares `Tree::get_lt` and `Tree::get_gt` against getting the
+//! same entries from a range iterator that is created for each read.
+//! Only every other key is stored, so half of the reads ask for a
+//! neighbour of a key that is not in the tree.
+//!
+//! Run with `cargo run --release`.
+extern crate sled;
+
+use std::ops::Bound::{Excluded, Unbounded};
+use std::time::Instant;
+
+const N_KEYS: usize = 1_000_000;
+const N_READS: usize = 1_000_000;
+
+fn key(i: usize) -> Vec<u8> {
+    vec![(i >> 24) as u8, (i >> 16) as u8, (i >> 8) as u8, i as u8]
+}
+
+// spreads the reads across the tree, skipping the first and
+// last few keys so that every read has a neighbour.
+fn probe(i: usize) -> Vec<u8> {
+    key(2 + i.wrapping_mul(7919) % (N_KEYS * 2 - 4))
+}
+
+fn bench<F>(name: &str, mut f: F)
+    where F: FnMut(&[u8]) -> Option<(Vec<u8>, sled::IVec)>
+{
+    let now = Instant::now();
+    for i in 0..N_READS {
+        assert!(f(&*probe(i)).is_some());
+    }
+    let elapsed = now.elapsed();
+    let nanos =
+        elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+
+    println!("{:>24}: {:>6} ns/op", name, nanos / N_READS as u64);
+}
+
+fn main() {
+    let config = sled::ConfigBuilder::new()
+        .temporary(true)
+        .cache_capacity(1_000_000_000)
+        .flush_every_ms(None)
+        .build();
+    let tree = sled::Tree::start(config).unwrap();
+
+    for i in 0..N_KEYS {
+        tree.set(key(i * 2), vec![]).unwrap();
+    }
+
+    bench("get_lt", |k| tree.get_lt(k).unwrap());
+    bench("range(..k).next_back()", |k| {
+        tree.range(..k).next_back().map(|r| r.unwrap())
+    });
+    bench("get_gt", |k| tree.get_gt(k).unwrap());
+    bench("range(>k..).next()", |k| {
+        tree.range::<[u8], _>((Excluded(k), Unbounded))
+            .next()
+            .map(|r| r.unwrap())
+    });
+}
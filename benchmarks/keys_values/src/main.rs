@@ -0,0 +1,67 @@
+//! Compares `Iter::keys` and `Iter::values` against mapping over
+//! the full key-value pairs, on a tree with 1kb values.
+//!
+//! Run with `cargo run --release`.
+extern crate sled;
+
+use std::time::Instant;
+
+const N_KEYS: usize = 100_000;
+const N_SCANS: usize = 20;
+const VALUE_LEN: usize = 1024;
+
+fn bench<F>(name: &str, mut f: F)
+    where F: FnMut() -> usize
+{
+    let now = Instant::now();
+    for _ in 0..N_SCANS {
+        assert_eq!(f(), N_KEYS);
+    }
+    let elapsed = now.elapsed();
+    let nanos =
+        elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+
+    println!(
+        "{:>28}: {:>6} ns/item",
+        name,
+        nanos / (N_SCANS * N_KEYS) as u64
+    );
+}
+
+fn main() {
+    let config = sled::ConfigBuilder::new()
+        .temporary(true)
+        .cache_capacity(1_000_000_000)
+        .flush_every_ms(None)
+        .build();
+    let tree = sled::Tree::start(config).unwrap();
+
+    for i in 0..N_KEYS as u64 {
+        let k = [
+            (i >> 24) as u8,
+            (i >> 16) as u8,
+            (i >> 8) as u8,
+            i as u8,
+        ];
+        tree.set(k.to_vec(), vec![0; VALUE_LEN]).unwrap();
+    }
+
+    bench("iter().map(key)", || {
+        tree.iter().map(|r| r.map(|(k, _)| k).unwrap()).count()
+    });
+    bench("iter().keys()", || {
+        tree.iter().keys().map(|r| r.unwrap()).count()
+    });
+    bench("iter().rev().map(key)", || {
+        tree.iter().rev().map(|r| r.map(|(k, _)| k).unwrap()).count()
+    });
+    bench("iter().keys().rev()", || {
+        tree.iter().keys().rev().map(|r| r.unwrap()).count()
+    });
+    bench("iter().map(value)", || {
+        tree.iter().map(|r| r.map(|(_, v)| v).unwrap()).count()
+    });
+    bench("iter().values()", || {
+        tree.iter().values().map(|r| r.unwrap()).count()
+    });
+}
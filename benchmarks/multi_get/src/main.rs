@@ -0,0 +1,85 @@
+//! Compares `Tree::multi_get` against separate calls to `Tree::get`,
+//! for batches of keys that are clustered in a few leaves and for
+//! batches of keys scattered across the whole tree.
+//!
+//! Run with `cargo run --release`.
+extern crate sled;
+
+use std::time::Instant;
+
+const N_KEYS: usize = 1_000_000;
+const N_BATCHES: usize = 5_000;
+const BATCH_LEN: usize = 200;
+
+fn key(i: usize) -> Vec<u8> {
+    let i = i % N_KEYS;
+    vec![(i >> 24) as u8, (i >> 16) as u8, (i >> 8) as u8, i as u8]
+}
+
+fn bench<F>(name: &str, mut f: F)
+    where F: FnMut(usize) -> usize
+{
+    let now = Instant::now();
+    for batch in 0..N_BATCHES {
+        assert_eq!(f(batch), BATCH_LEN);
+    }
+    let elapsed = now.elapsed();
+    let nanos =
+        elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+
+    println!(
+        "{:>24}: {:>6} ns/key",
+        name,
+        nanos / (N_BATCHES * BATCH_LEN) as u64
+    );
+}
+
+fn main() {
+    let config = sled::ConfigBuilder::new()
+        .temporary(true)
+        .cache_capacity(1_000_000_000)
+        .flush_every_ms(None)
+        .build();
+    let tree = sled::Tree::start(config).unwrap();
+
+    for i in 0..N_KEYS {
+        tree.set(key(i), vec![0; 8]).unwrap();
+    }
+
+    // every other key in a short run, in reverse order
+    let clustered = |batch: usize| -> Vec<Vec<u8>> {
+        let start = batch * 7919;
+        (0..BATCH_LEN).rev().map(|i| key(start + i * 2)).collect()
+    };
+    // spread evenly over the whole key space
+    let scattered = |batch: usize| -> Vec<Vec<u8>> {
+        (0..BATCH_LEN)
+            .map(|i| key(batch + i * (N_KEYS / BATCH_LEN)))
+            .collect()
+    };
+
+    bench("clustered get", |batch| {
+        clustered(batch)
+            .iter()
+            .map(|k| tree.get(k).unwrap().unwrap())
+            .count()
+    });
+    bench("clustered multi_get", |batch| {
+        tree.multi_get(clustered(batch))
+            .into_iter()
+            .map(|r| r.unwrap().unwrap())
+            .count()
+    });
+    bench("scattered get", |batch| {
+        scattered(batch)
+            .iter()
+            .map(|k| tree.get(k).unwrap().unwrap())
+            .count()
+    });
+    bench("scattered multi_get", |batch| {
+        tree.multi_get(scattered(batch))
+            .into_iter()
+            .map(|r| r.unwrap().unwrap())
+            .count()
+    });
+}
@@ -0,0 +1,95 @@
+//! Measures point reads and scans of small values, which are returned
+//! as `IVec`s that hold up to 22 bytes inline, against copying each
+//! value out into a `Vec<u8>` as reads used to. Allocations are
+//! counted with a wrapper around the system allocator.
+//!
+//! Run with `cargo run --release`.
+extern crate sled;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+const N_KEYS: usize = 100_000;
+const N_READS: usize = 1_000_000;
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn key(i: usize) -> Vec<u8> {
+    // spread the reads over the whole key space
+    let i = i.wrapping_mul(7919) % N_KEYS;
+    vec![(i >> 24) as u8, (i >> 16) as u8, (i >> 8) as u8, i as u8]
+}
+
+fn bench<F>(name: &str, value_len: usize, mut f: F)
+    where F: FnMut(usize) -> usize
+{
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let now = Instant::now();
+    for i in 0..N_READS {
+        assert_eq!(f(i), value_len);
+    }
+    let elapsed = now.elapsed();
+    let nanos =
+        elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+
+    println!(
+        "{:>3} byte values, {:>14}: {:>6} ns/op {:>6.2} allocs/op",
+        value_len,
+        name,
+        nanos / N_READS as u64,
+        allocations as f64 / N_READS as f64
+    );
+}
+
+fn main() {
+    for &value_len in &[8, 16, 22, 32, 128] {
+        let config = sled::ConfigBuilder::new()
+            .temporary(true)
+            .cache_capacity(1_000_000_000)
+            .flush_every_ms(None)
+            .build();
+        let tree = sled::Tree::start(config).unwrap();
+
+        for i in 0..N_KEYS {
+            tree.set(key(i), vec![1; value_len]).unwrap();
+        }
+
+        bench("get", value_len, |i| {
+            tree.get(&*key(i)).unwrap().unwrap().len()
+        });
+        bench("get + to_vec", value_len, |i| {
+            tree.get(&*key(i)).unwrap().unwrap().to_vec().len()
+        });
+
+        let mut iter = tree.iter().values();
+        bench("iter", value_len, |_| {
+            let value = match iter.next() {
+                Some(value) => value,
+                None => {
+                    iter = tree.iter().values();
+                    iter.next().unwrap()
+                }
+            };
+            value.unwrap().len()
+        });
+        println!();
+    }
+}
@@ -157,7 +157,7 @@ pub unsafe extern "C" fn sled_get(
     let k = slice::from_raw_parts(key as *const u8, keylen);
     let res = (*db).get(k);
     match res {
-        Ok(Some(v)) => leak_buf(v, vallen),
+        Ok(Some(v)) => leak_buf(v.to_vec(), vallen),
         Ok(None) => ptr::null_mut(),
         // TODO proper error propagation
         Err(e) => panic!("{:?}", e),
@@ -221,7 +221,7 @@ pub unsafe extern "C" fn sled_cas(
             0
         }
         Err(Error::CasFailed(Some(v))) => {
-            *actual_val = leak_buf(v, actual_vallen) as *const u8;
+            *actual_val = leak_buf(v.to_vec(), actual_vallen) as *const u8;
             0
         }
         // TODO proper error propagation
@@ -255,7 +255,7 @@ pub unsafe extern "C" fn sled_iter_next(
     match (*iter).next() {
         Some(Ok((k, v))) => {
             *key = leak_buf(k, keylen);
-            *val = leak_buf(v, vallen);
+            *val = leak_buf(v.to_vec(), vallen);
             1
         }
         // TODO proper error propagation
@@ -4,7 +4,9 @@ use std::ops::Deref;
 use std::io::{Read, Seek, Write};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicBool, AtomicPtr, AtomicUsize,
+                        Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use serde::Serialize;
 use serde::de::DeserializeOwned;
@@ -14,6 +16,11 @@ use bincode::{Infinite, deserialize, serialize};
 use super::*;
 use io::LogReader;
 
+// The version of the format that the log and snapshots are written in,
+// which is recorded in the conf file. Systems written in any other
+// format are refused, rather than being misread as empty.
+const FORMAT_VERSION: u64 = 1;
+
 impl Deref for Config {
     type Target = ConfigBuilder;
     fn deref(&self) -> &Self::Target {
@@ -35,11 +42,23 @@ impl Deref for Config {
 /// ```
 ///
 /// ```
-/// // Read-only mode
+/// // Read-only mode, which takes no lock on the system's files,
+/// // so that it can run alongside the process that writes to them
 /// let _config = pagecache::ConfigBuilder::default()
 ///     .path("/path/to/data".to_owned())
 ///     .read_only(true);
 /// ```
+///
+/// ```
+/// // Temporary mode, which stores the system in a new directory
+/// // that is removed once the `Config` and every system started
+/// // with it have been dropped
+/// let config = pagecache::ConfigBuilder::default()
+///     .temporary(true)
+///     .build();
+/// assert!(config.get_path().starts_with(std::env::temp_dir()) ||
+///     config.get_path().starts_with("/dev/shm"));
+/// ```
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct ConfigBuilder {
     #[doc(hidden)]
@@ -88,34 +107,16 @@ pub struct ConfigBuilder {
     pub zstd_compression_factor: i32,
     #[doc(hidden)]
     pub merge_operator: Option<usize>,
+    #[doc(hidden)]
+    pub max_key_size: Option<usize>,
+    #[doc(hidden)]
+    pub max_value_size: Option<usize>,
 }
 
 unsafe impl Send for ConfigBuilder {}
 
 impl Default for ConfigBuilder {
     fn default() -> ConfigBuilder {
-        static SALT_COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
-
-        #[cfg(unix)]
-        let salt = {
-            let pid = unsafe { libc::getpid() };
-            ((pid as u64) << 32) +
-                SALT_COUNTER.fetch_add(1, Ordering::SeqCst) as u64
-        };
-
-        #[cfg(not(unix))]
-        let salt = {
-            let now = uptime();
-            (now.as_secs() * 1_000_000_000) + u64::from(now.subsec_nanos())
-        };
-
-        // use shared memory for temporary linux files
-        #[cfg(target_os = "linux")]
-        let tmp_path = format!("/dev/shm/pagecache.tmp.{}", salt);
-
-        #[cfg(not(target_os = "linux"))]
-        let tmp_path = format!("pagecache.tmp.{}", salt);
-
         ConfigBuilder {
             io_bufs: 3,
             io_buf_size: 2 << 22, // 8mb
@@ -136,10 +137,12 @@ impl Default for ConfigBuilder {
             segment_cleanup_threshold: 0.2,
             min_free_segments: 3,
             zero_copy_storage: false,
-            tmp_path: PathBuf::from(tmp_path),
+            tmp_path: PathBuf::new(),
             temporary: false,
             segment_mode: SegmentMode::Gc,
             merge_operator: None,
+            max_key_size: None,
+            max_value_size: None,
         }
     }
 }
@@ -192,14 +195,75 @@ impl ConfigBuilder {
         self
     }
 
-    /// Finalize the configuration.
-    pub fn build(self) -> Config {
+    /// Set the largest key that may be written, in bytes (builder).
+    pub fn max_key_size(mut self, to: usize) -> ConfigBuilder {
+        self.max_key_size = Some(to);
+        self
+    }
+
+    /// Set the largest key that may be written, in bytes.
+    pub fn set_max_key_size(&mut self, to: usize) {
+        self.max_key_size = Some(to);
+    }
+
+    /// Set the largest value that may be written, in bytes (builder).
+    pub fn max_value_size(mut self, to: usize) -> ConfigBuilder {
+        self.max_value_size = Some(to);
+        self
+    }
+
+    /// Set the largest value that may be written, in bytes.
+    pub fn set_max_value_size(&mut self, to: usize) {
+        self.max_value_size = Some(to);
+    }
+
+    /// The largest key that may be written, in bytes. Unless it's set
+    /// with `max_key_size`, this is 1/32 of `max_message_size`.
+    pub fn get_max_key_size(&self) -> usize {
+        self.max_key_size.unwrap_or_else(|| self.max_message_size() / 32)
+    }
+
+    /// The largest value that may be written, in bytes. Unless it's set
+    /// with `max_value_size`, this is 1/8 of `max_message_size`.
+    pub fn get_max_value_size(&self) -> usize {
+        self.max_value_size.unwrap_or_else(|| self.max_message_size() / 8)
+    }
+
+    /// The largest message that the log will write, in bytes, after
+    /// compression. This is what's left of `io_buf_size /
+    /// min_items_per_segment` after segment and message headers, and
+    /// larger writes fail with `Error::TooLarge`. Key and value limits
+    /// are kept well below it, so that a page holding several of them
+    /// can always be written.
+    pub fn max_message_size(&self) -> usize {
+        let max_overhead = if self.min_items_per_segment == 1 {
+            SEG_HEADER_LEN + SEG_TRAILER_LEN
+        } else {
+            std::cmp::max(SEG_HEADER_LEN, SEG_TRAILER_LEN)
+        };
+
+        (self.io_buf_size / self.min_items_per_segment)
+            .saturating_sub(max_overhead + MSG_HEADER_LEN)
+    }
+
+    /// Finalize the configuration. A temporary configuration is
+    /// given a path of its own under the system's temporary
+    /// directory, so every `Config` built from it is independent,
+    /// unless it was copied from a `Config` that already has one.
+    pub fn build(mut self) -> Config {
+        if self.temporary && self.tmp_path.as_os_str().is_empty() {
+            self.tmp_path = unique_tmp_path();
+        }
+
         // seal config in a Config
+        let merge_operator = self.merge_operator.unwrap_or(0);
         Config {
             inner: Arc::new(self),
             file: Arc::new(AtomicPtr::default()),
             build_locker: Arc::new(Mutex::new(())),
             refs: Arc::new(AtomicUsize::new(0)),
+            merge_operator: Arc::new(AtomicUsize::new(merge_operator)),
+            in_use: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -209,14 +273,14 @@ impl ConfigBuilder {
         (min_items_per_segment, get_min_items_per_segment, set_min_items_per_segment, usize, "minimum data chunks/pages in a segment."),
         (blink_fanout, get_blink_fanout, set_blink_fanout, u8, "b-link node fanout, minimum of 2"),
         (page_consolidation_threshold, get_page_consolidation_threshold, set_page_consolidation_threshold, usize, "page consolidation threshold"),
-        (temporary, get_temporary, set_temporary, bool, "if this database should be removed after the ConfigBuilder is dropped"),
-        (read_only, get_read_only, set_read_only, bool, "whether to run in read-only mode"),
+        (temporary, get_temporary, set_temporary, bool, "if this database should be stored in a unique temporary directory, removed once its Config and every system using it are dropped"),
+        (read_only, get_read_only, set_read_only, bool, "whether to run in read-only mode, which recovers an existing system without writing to or locking its files, so that it can run alongside a writer"),
         (cache_bits, get_cache_bits, set_cache_bits, usize, "log base 2 of the number of cache shards"),
         (cache_capacity, get_cache_capacity, set_cache_capacity, usize, "maximum size for the system page cache"),
         (use_os_cache, get_use_os_cache, set_use_os_cache, bool, "whether to use the OS page cache"),
-        (use_compression, get_use_compression, set_use_compression, bool, "whether to use zstd compression"),
+        (use_compression, get_use_compression, set_use_compression, bool, "whether to compress log messages and snapshots with zstd, which requires the zstd feature"),
         (zstd_compression_factor, get_zstd_compression_factor, set_zstd_compression_factor, i32, "the compression factor to use with zstd compression"),
-        (flush_every_ms, get_flush_every_ms, set_flush_every_ms, Option<u64>, "number of ms between IO buffer flushes"),
+        (flush_every_ms, get_flush_every_ms, set_flush_every_ms, Option<u64>, "number of ms between background syncs of the log, which bounds what a crash can lose, or None to only sync on explicit flushes"),
         (snapshot_after_ops, get_snapshot_after_ops, set_snapshot_after_ops, usize, "number of operations between page table snapshots"),
         (cache_fixup_threshold, get_cache_fixup_threshold, set_cache_fixup_threshold, usize, "the maximum length of a cached page fragment chain"),
         (segment_cleanup_threshold, get_segment_cleanup_threshold, set_segment_cleanup_threshold, f64, "the proportion of remaining valid pages in the segment"),
@@ -235,6 +299,8 @@ pub struct Config {
     file: Arc<AtomicPtr<Arc<fs::File>>>,
     build_locker: Arc<Mutex<()>>,
     refs: Arc<AtomicUsize>,
+    merge_operator: Arc<AtomicUsize>,
+    in_use: Arc<AtomicBool>,
 }
 
 unsafe impl Send for Config {}
@@ -248,6 +314,8 @@ impl Clone for Config {
             file: self.file.clone(),
             build_locker: self.build_locker.clone(),
             refs: self.refs.clone(),
+            merge_operator: self.merge_operator.clone(),
+            in_use: self.in_use.clone(),
         }
     }
 }
@@ -263,7 +331,9 @@ impl Drop for Config {
                 drop(f);
             }
 
-            if !self.temporary {
+            // a read-only system never removes files, even when
+            // it has opened a temporary one
+            if !self.temporary || self.read_only {
                 return;
             }
 
@@ -275,6 +345,66 @@ impl Drop for Config {
 }
 
 impl Config {
+    /// Set the merge operator that can be relied on during merges in
+    /// the `PageCache`. This takes effect for every user of this
+    /// `Config` and its clones, replacing any previously set operator.
+    pub fn set_merge_operator(&self, mo: MergeOperator) {
+        self.merge_operator.store(mo as usize, Ordering::SeqCst);
+    }
+
+    /// Retrieve the currently set merge operator, if any.
+    pub fn get_merge_operator(&self) -> Option<MergeOperator> {
+        let mo_ptr = self.merge_operator.load(Ordering::SeqCst);
+        if mo_ptr == 0 {
+            None
+        } else {
+            Some(unsafe { std::mem::transmute(mo_ptr) })
+        }
+    }
+
+    // Mark the system's files as being used by a running system until
+    // the returned `InUse` is dropped, by locking its lock file. Only
+    // one system may write to them at a time, whether it's in this
+    // process or another. Temporary systems can also be restarted
+    // from their `Config`, but only one may run at a time.
+    pub(crate) fn mark_in_use(&self) -> CacheResult<InUse, ()> {
+        let mut in_use = InUse {
+            flag: None,
+            _lock: None,
+        };
+
+        // read-only systems never write, so they take no lock, and can
+        // run alongside a writer or each other. a shared lock would be
+        // refused while a writer holds its exclusive one, and would stop
+        // a writer from starting while any reader was open, so a reader
+        // could never run next to a live writer.
+        if self.inner.read_only {
+            return Ok(in_use);
+        }
+
+        if self.inner.temporary {
+            if self.in_use.swap(true, Ordering::SeqCst) {
+                return Err(Error::Unsupported(
+                    "this temporary Config is already in use by a running \
+                     system, which must be dropped before it's started again"
+                        .to_owned(),
+                ));
+            }
+            in_use.flag = Some(self.in_use.clone());
+        }
+
+        let path = self.get_path();
+        if !path.exists() {
+            std::fs::create_dir_all(&path)?;
+        }
+        match try_lock(&self.lock_path())? {
+            Some(lock) => in_use._lock = Some(lock),
+            None => return Err(Error::Busy(path)),
+        }
+
+        Ok(in_use)
+    }
+
     // Retrieve a thread-local file handle to the
     // configured underlying storage,
     // or create a new one if this is the first time the
@@ -345,6 +475,9 @@ impl Config {
         let snap_dir = Path::new(&abs_prefix).parent().unwrap();
 
         if !snap_dir.exists() {
+            if self.read_only {
+                return Ok(vec![]);
+            }
             std::fs::create_dir_all(snap_dir)?;
         }
 
@@ -377,7 +510,9 @@ impl Config {
                 ));
             }
 
-            if !dir.exists() {
+            // in read-only mode, a missing directory is reported
+            // when the data file isn't found below.
+            if !dir.exists() && !self.read_only {
                 let res: std::io::Result<()> = std::fs::create_dir_all(dir);
                 res.map_err(|e: std::io::Error| {
                     let ret: Error<()> = e.into();
@@ -390,9 +525,11 @@ impl Config {
 
         // open the data file
         let mut options = fs::OpenOptions::new();
-        options.create(true);
         options.read(true);
-        options.write(true);
+        if !self.read_only {
+            options.create(true);
+            options.write(true);
+        }
 
         match options.open(&path) {
             Ok(file) => {
@@ -425,12 +562,27 @@ impl Config {
         supported!(self.inner.segment_cleanup_threshold >= 0.01, "segment_cleanup_threshold must be >= 1%");
         supported!(self.inner.zstd_compression_factor >= 1, "compression factor must be >= 0");
         supported!(self.inner.zstd_compression_factor <= 22, "compression factor must be <= 22");
+        supported!(self.inner.get_max_key_size() + self.inner.get_max_value_size() <= self.inner.max_message_size() / 4, "max_key_size and max_value_size must add up to no more than a quarter of max_message_size");
         Ok(())
     }
 
     fn verify_conf_changes_ok(&self) -> CacheResult<(), ()> {
+        let has_data = self.db_path()
+            .metadata()
+            .map(|metadata| metadata.len() > 0)
+            .unwrap_or(false);
+
         match self.read_config() {
-            Ok(Some(mut old)) => {
+            Ok(Some((version, _))) if version != FORMAT_VERSION => {
+                Err(Error::Unsupported(format!(
+                    "the system at {:?} was written in format version {}, \
+                     but this version can only read format version {}",
+                    self.get_path(),
+                    version,
+                    FORMAT_VERSION
+                )))
+            }
+            Ok(Some((_, mut old))) => {
                 let old_tmp = old.tmp_path;
                 old.tmp_path = self.inner.tmp_path.clone();
                 if old.merge_operator.is_some() {
@@ -442,6 +594,23 @@ impl Config {
 
                 old.merge_operator = self.inner.merge_operator;
 
+                // a system may be reopened in read-only mode,
+                // which doesn't change how anything is stored.
+                old.read_only = self.inner.read_only;
+
+                // how often the log is synced is only a runtime policy.
+                old.flush_every_ms = self.inner.flush_every_ms;
+
+                // size limits only apply to new writes.
+                old.max_key_size = self.inner.max_key_size;
+                old.max_value_size = self.inner.max_value_size;
+
+                // each log message records whether it's compressed,
+                // so compression can be switched on or off at will.
+                old.use_compression = self.inner.use_compression;
+                old.zstd_compression_factor =
+                    self.inner.zstd_compression_factor;
+
                 supported!(&*self.inner == &old, "changing the configuration \
                        between usages is currently unsupported");
                 // need to keep the old path so that when old gets
@@ -452,13 +621,26 @@ impl Config {
                 old.tmp_path = old_tmp;
                 Ok(())
             }
+            Ok(None) if has_data => {
+                // the conf file is written before the data file is
+                // created, so data without a readable conf was written
+                // by a version that didn't record its format.
+                Err(Error::Unsupported(format!(
+                    "the system at {:?} has no readable conf file, so it \
+                     was probably written by an older version in a format \
+                     that this version can't read",
+                    self.get_path()
+                )))
+            }
+            Ok(None) if self.read_only => Ok(()),
             Ok(None) => self.write_config().map_err(|e| e.into()),
             Err(e) => Err(e.into()),
         }
     }
 
     fn write_config(&self) -> CacheResult<(), ()> {
-        let bytes = serialize(&*self.inner, Infinite).unwrap();
+        let bytes = serialize(&(FORMAT_VERSION, &*self.inner), Infinite)
+            .unwrap();
         let crc64: [u8; 8] = unsafe { std::mem::transmute(crc64(&*bytes)) };
 
         let path = self.conf_path();
@@ -476,7 +658,9 @@ impl Config {
         Ok(())
     }
 
-    fn read_config(&self) -> std::io::Result<Option<ConfigBuilder>> {
+    // returns the format version that the system was written in,
+    // along with the configuration it was written with.
+    fn read_config(&self) -> std::io::Result<Option<(u64, ConfigBuilder)>> {
         let path = self.conf_path();
 
         let f_res = std::fs::OpenOptions::new().read(true).open(&path);
@@ -513,7 +697,7 @@ impl Config {
             warn!("crc for settings file {:?} failed! can't verify that config is safe", path);
         }
 
-        Ok(deserialize::<ConfigBuilder>(&*buf).ok())
+        Ok(deserialize::<(u64, ConfigBuilder)>(&*buf).ok())
     }
 
     fn db_path(&self) -> PathBuf {
@@ -528,6 +712,12 @@ impl Config {
         path
     }
 
+    fn lock_path(&self) -> PathBuf {
+        let mut path = self.get_path();
+        path.push("lock");
+        path
+    }
+
     #[doc(hidden)]
     pub fn verify_snapshot<PM, P, R>(&self) -> CacheResult<(), ()>
         where PM: Materializer<Recovery = R, PageFrag = P>,
@@ -540,13 +730,13 @@ impl Config {
                      + Sync,
               R: Debug + Clone + Serialize + DeserializeOwned + Send + PartialEq
     {
-        let incremental = read_snapshot_or_default::<PM, P, R>(&self)?;
+        let (incremental, _) = read_snapshot_or_default::<PM, P, R>(&self)?;
 
         for snapshot_path in self.get_snapshot_files()? {
             std::fs::remove_file(snapshot_path)?;
         }
 
-        let regenerated = read_snapshot_or_default::<PM, P, R>(&self)?;
+        let (regenerated, _) = read_snapshot_or_default::<PM, P, R>(&self)?;
 
         let f = self.file()?;
 
@@ -556,11 +746,7 @@ impl Config {
             }
             assert_eq!(incremental.pt.get(&k), Some(v), "page tables differ for pid {}", k);
             for (lsn, lid) in v.iter() {
-                f.read_message(
-                    lid,
-                    self.io_buf_size,
-                    self.use_compression
-                ).unwrap()
+                f.read_message(lid, self.io_buf_size).unwrap()
                 .expect(&*format!("could not read log data for pid {} at lsn {} lid {}", k, lsn, lid));
             }
         }
@@ -571,11 +757,7 @@ impl Config {
             }
             assert_eq!(Some(v), regenerated.pt.get(&k), "page tables differ for pid {}", k);
             for (lsn, lid) in v.iter() {
-                f.read_message(
-                    lid,
-                    self.io_buf_size,
-                    self.use_compression
-                ).unwrap()
+                f.read_message(lid, self.io_buf_size).unwrap()
                 .expect(&*format!("could not read log data for pid {} at lsn {} lid {}", k, lsn, lid));
             }
         }
@@ -621,3 +803,97 @@ impl Config {
         Ok(())
     }
 }
+
+// Releases a system's files, and its `Config` if it's temporary,
+// for use by another system when dropped.
+pub(crate) struct InUse {
+    flag: Option<Arc<AtomicBool>>,
+    // the lock is released when the file is closed
+    _lock: Option<fs::File>,
+}
+
+impl Drop for InUse {
+    fn drop(&mut self) {
+        if let Some(ref flag) = self.flag {
+            flag.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+// Takes an exclusive lock on the file at the provided path, returning
+// `None` if another open file holds it. The OS releases the lock when
+// the file is closed, including when its process dies, so a lock file
+// left behind by a crash never stops a system from starting.
+#[cfg(unix)]
+fn try_lock(path: &Path) -> std::io::Result<Option<fs::File>> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)?;
+
+    let ret =
+        unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if ret == 0 {
+        return Ok(Some(file));
+    }
+
+    let e = std::io::Error::last_os_error();
+    if e.kind() == std::io::ErrorKind::WouldBlock {
+        Ok(None)
+    } else {
+        Err(e)
+    }
+}
+
+// Windows doesn't let a file be opened again while it's open without
+// sharing, which works like a lock that the OS releases in the same way.
+#[cfg(windows)]
+fn try_lock(path: &Path) -> std::io::Result<Option<fs::File>> {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+
+    let res = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .share_mode(0)
+        .open(path);
+
+    match res {
+        Ok(file) => Ok(Some(file)),
+        Err(ref e) if e.raw_os_error() == Some(ERROR_SHARING_VIOLATION) => {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// temporary files are kept in shared memory on linux. the path
+// includes our pid and the time, so that it won't collide with
+// other processes, or with files left behind by a crashed one.
+fn unique_tmp_path() -> PathBuf {
+    static SALT_COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    let shm = Path::new("/dev/shm");
+    let mut path = if cfg!(target_os = "linux") && shm.is_dir() {
+        shm.to_path_buf()
+    } else {
+        std::env::temp_dir()
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    path.push(format!(
+        "pagecache.tmp.{}.{}.{}.{}",
+        std::process::id(),
+        now.as_secs(),
+        now.subsec_nanos(),
+        SALT_COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+    path
+}
@@ -46,6 +46,24 @@ impl Lru {
 
         rel_ids
     }
+
+    /// Returns the total size of the pages that are being tracked,
+    /// as last reported to `accessed`.
+    pub fn size(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard_mu| {
+                shard_mu
+                    .lock()
+                    .expect(
+                        "Lru was poisoned by a \
+                        thread that panicked \
+                        inside a critical section",
+                    )
+                    .sz
+            })
+            .sum()
+    }
 }
 
 #[derive(Clone)]
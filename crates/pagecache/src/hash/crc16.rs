@@ -70,7 +70,13 @@ const CRC16TAB: [u16; 256] =
 
 #[inline(always)]
 pub fn crc16(buf: &[u8]) -> u16 {
-    let mut crc = 0u16;
+    crc16_update(0, buf)
+}
+
+/// Continues a crc16 from a previous result over more bytes,
+/// so that `crc16_update(crc16(a), b) == crc16(a ++ b)`.
+#[inline(always)]
+pub fn crc16_update(mut crc: u16, buf: &[u8]) -> u16 {
     for &b in &*buf {
         let idx = ((crc >> 8) ^ u16::from(b)) & 0x00FF;
         let lookup = CRC16TAB[idx as usize];
@@ -89,4 +95,5 @@ pub fn crc16_arr(buf: &[u8]) -> [u8; 2] {
 fn test_crc16() {
     let input_str = "123456789";
     assert_eq!(crc16(input_str.as_bytes()), 0x31c3);
+    assert_eq!(crc16(b"123456789"), crc16_update(crc16(b"1234"), b"56789"));
 }
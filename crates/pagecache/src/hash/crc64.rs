@@ -171,7 +171,12 @@ const CRC64TAB: [u64; 256] = [
 ];
 
 pub fn crc64(s: &[u8]) -> u64 {
-    let mut crc = 0;
+    crc64_update(0, s)
+}
+
+/// Continues a crc64 from a previous result over more bytes,
+/// so that `crc64_update(crc64(a), b) == crc64(a ++ b)`.
+pub fn crc64_update(mut crc: u64, s: &[u8]) -> u64 {
     for byte in s {
         crc = CRC64TAB[((crc as u8) ^ byte) as usize] ^ (crc >> 8);
     }
@@ -181,4 +186,5 @@ pub fn crc64(s: &[u8]) -> u64 {
 #[test]
 fn test_crc64() {
     assert_eq!(0xe9c6_d914_c4b8_d9ca, crc64(b"123456789"));
+    assert_eq!(crc64(b"123456789"), crc64_update(crc64(b"1234"), b"56789"));
 }
@@ -2,7 +2,7 @@ mod crc16;
 mod crc64;
 
 // used for protecting large snapshot files
-pub use self::crc64::crc64;
+pub use self::crc64::{crc64, crc64_update};
 
 // used for protecting individual log entries
-pub use self::crc16::{crc16, crc16_arr};
+pub use self::crc16::{crc16, crc16_arr, crc16_update};
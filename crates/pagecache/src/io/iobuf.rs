@@ -1,4 +1,4 @@
-use std::sync::{Condvar, Mutex};
+use std::sync::{Condvar, Mutex, Weak};
 use std::sync::atomic::{AtomicBool, AtomicUsize};
 #[cfg(target_pointer_width = "32")]
 use std::sync::atomic::AtomicI64;
@@ -62,7 +62,20 @@ pub(super) struct IoBufs {
     // to stable storage due to interesting thread interleavings.
     stable_lsn: AtomicLsn,
     max_reserved_lsn: AtomicLsn,
+    // the highest Lsn that a `FlushHandle` has asked to be made stable,
+    // and whether a thread is currently working towards it. concurrent
+    // requests are coalesced onto the one thread.
+    flush_requested: AtomicLsn,
+    flush_driver_running: AtomicBool,
     segment_accountant: Mutex<SegmentAccountant>,
+    // run by the periodic flush thread after each flush. these are
+    // weak, so that tasks can hold handles to whatever owns us.
+    maintenance: Mutex<Vec<Weak<dyn Maintenance>>>,
+    // set once a write to the log has failed, along with its error.
+    // nothing written after that can become stable, so later writes
+    // and flushes report the error instead.
+    poisoned: AtomicBool,
+    poison: Mutex<Option<(io::ErrorKind, String)>>,
 
     // used for signifying that we're simulating a crash
     #[cfg(feature = "failpoints")]
@@ -90,11 +103,7 @@ impl IoBufs {
                 snapshot.last_lid = 0;
                 (0, 0)
             } else {
-                match file.read_message(
-                    snapshot_last_lid,
-                    io_buf_size,
-                    config.use_compression,
-                ) {
+                match file.read_message(snapshot_last_lid, io_buf_size) {
                     Ok(LogRead::Flush(_lsn, _buf, len)) => (
                         snapshot_max_lsn + len as Lsn +
                             MSG_HEADER_LEN as
@@ -129,7 +138,11 @@ impl IoBufs {
             next_lid
         );
 
-        if next_lsn == 0 {
+        if next_lsn == 0 && config.read_only {
+            // nothing is written in read-only mode, so
+            // the first segment is never needed
+            debug!("starting empty log in read-only mode");
+        } else if next_lsn == 0 {
             // recovering at segment boundary
             assert_eq!(next_lid, next_lsn as LogID);
             let iobuf = &bufs[current_buf];
@@ -176,13 +189,69 @@ impl IoBufs {
             interval_updated: Condvar::new(),
             stable_lsn: AtomicLsn::new(stable),
             max_reserved_lsn: AtomicLsn::new(stable),
+            flush_requested: AtomicLsn::new(stable),
+            flush_driver_running: AtomicBool::new(false),
             config: config,
             segment_accountant: Mutex::new(segment_accountant),
+            maintenance: Mutex::new(vec![]),
+            poisoned: AtomicBool::new(false),
+            poison: Mutex::new(None),
             #[cfg(feature = "failpoints")]
             _failpoint_crashing: AtomicBool::new(false),
         })
     }
 
+    pub(super) fn add_maintenance(&self, task: Weak<dyn Maintenance>) {
+        self.maintenance.lock().unwrap().push(task);
+    }
+
+    /// Returns the error that an earlier write to the log failed
+    /// with, if there was one.
+    pub(super) fn check_poisoned(&self) -> CacheResult<(), ()> {
+        if !self.poisoned.load(SeqCst) {
+            return Ok(());
+        }
+
+        let poison = self.poison.lock().unwrap();
+        let &(kind, ref e) = poison.as_ref().expect(
+            "the log should have been poisoned with an error",
+        );
+        Err(Error::Io(io::Error::new(
+            kind,
+            format!("an earlier write to the log failed: {}", e),
+        )))
+    }
+
+    // remembers the first error that writing to the log failed with,
+    // and wakes up threads that are waiting for their writes to become
+    // stable, so that they can return it.
+    fn poison(&self, e: &Error<()>) {
+        // simulated crashes are handled separately
+        #[cfg(feature = "failpoints")]
+        {
+            if let Error::FailPoint = *e {
+                return;
+            }
+        }
+
+        error!("failed to write to the log, which is now unusable: {}", e);
+
+        {
+            let mut poison = self.poison.lock().unwrap();
+            if poison.is_none() {
+                let kind = match *e {
+                    Error::Io(ref e) => e.kind(),
+                    _ => io::ErrorKind::Other,
+                };
+                *poison = Some((kind, e.to_string()));
+            }
+        }
+        self.poisoned.store(true, SeqCst);
+
+        let _intervals = self.intervals.lock().unwrap();
+        self.interval_updated.notify_all();
+    }
+
     /// SegmentAccountant access for coordination with the `PageCache`
     pub(super) fn with_sa<B, F>(&self, f: F) -> B
         where F: FnOnce(&mut SegmentAccountant) -> B
@@ -215,29 +284,41 @@ impl IoBufs {
     }
 
     // Adds a header to the buffer, and optionally compresses
-    // the buffer.
+    // the buffer. Whether a message is compressed is recorded
+    // in its header, so messages that don't shrink are left
+    // alone, and a log may mix compressed and plain messages.
     // NB the caller is responsible for later setting the Lsn
     // bytes after a reservation has been acquired.
     fn encapsulate(&self, raw_buf: Vec<u8>) -> Vec<u8> {
         #[cfg(feature = "zstd")]
-        let buf = if self.config.use_compression {
+        let (kind, buf) = if self.config.use_compression {
             let _measure = Measure::new(&M.compress);
-            compress(&*raw_buf, self.config.get_zstd_compression_factor())
-                .unwrap()
+            let compressed =
+                compress(&*raw_buf, self.config.zstd_compression_factor)
+                    .unwrap();
+            // messages are decompressed into a buffer the size of
+            // a segment, so anything larger is written as it is.
+            if compressed.len() < raw_buf.len() &&
+                raw_buf.len() <= self.config.io_buf_size
+            {
+                (MessageKind::SuccessCompressed, compressed)
+            } else {
+                (MessageKind::Success, raw_buf)
+            }
         } else {
-            raw_buf
+            (MessageKind::Success, raw_buf)
         };
 
         #[cfg(not(feature = "zstd"))]
-        let buf = raw_buf;
-
-        let crc16 = crc16_arr(&buf);
+        let (kind, buf) = (MessageKind::Success, raw_buf);
 
+        // NB the checksum is filled in by the `Reservation`, as
+        // it covers the lsn and whether the message was aborted.
         let header = MessageHeader {
-            kind: MessageKind::Success,
+            kind: kind,
             lsn: 0,
             len: buf.len(),
-            crc16: crc16,
+            crc16: [0; 2],
         };
 
         let header_bytes: [u8; MSG_HEADER_LEN] = header.into();
@@ -265,6 +346,8 @@ impl IoBufs {
     ) -> CacheResult<Reservation, ()> {
         let _measure = Measure::new(&M.reserve);
 
+        self.check_poisoned()?;
+
         let io_bufs = self.config.io_bufs;
 
         // right shift 32 on 32-bit pointer systems panics
@@ -273,26 +356,13 @@ impl IoBufs {
 
         let buf = self.encapsulate(raw_buf);
 
-        let max_overhead = if self.config.min_items_per_segment == 1 {
-            SEG_HEADER_LEN + SEG_TRAILER_LEN
-        } else {
-            std::cmp::max(SEG_HEADER_LEN, SEG_TRAILER_LEN)
-        };
-
-        let max_buf_size = (self.config.io_buf_size /
-                                self.config.min_items_per_segment) -
-            max_overhead;
-
-        if buf.len() > max_buf_size {
-            return Err(Error::Unsupported(format!(
-                "trying to write a buffer that is too large \
-                to be stored in the IO buffer. buf len: {} current max: {}. \
-                a future version of pagecache will implement automatic \
-                fragmentation of large values. feel free to open \
-                an issue if this is a pressing need of yours.",
-                buf.len(),
-                max_buf_size
-            )));
+        let max_message_size = self.config.max_message_size();
+        if buf.len() > max_message_size + MSG_HEADER_LEN {
+            return Err(Error::TooLarge {
+                limit: "max_message_size",
+                max: max_message_size,
+                size: buf.len() - MSG_HEADER_LEN,
+            });
         }
 
         trace!("reserving buf of len {}", buf.len());
@@ -460,6 +530,8 @@ impl IoBufs {
                 destination: destination,
                 flushed: false,
                 lsn: reservation_lsn,
+                segment_lsn: reservation_lsn - reservation_lsn %
+                    self.config.io_buf_size as Lsn,
                 lid: reservation_offset,
             });
         }
@@ -511,6 +583,8 @@ impl IoBufs {
 
         // NB before we write the 0th byte of the file, stable  is -1
         while self.stable() < lsn {
+            self.check_poisoned()?;
+
             let idx = self.idx();
             let header = self.bufs[idx].get_header();
             if offset(header) == 0 || is_sealed(header) {
@@ -531,6 +605,7 @@ impl IoBufs {
                         return Err(Error::FailPoint);
                     }
                 }
+                self.check_poisoned()?;
                 trace!("waiting on cond var for make_stable({})", lsn);
                 let _waiter = self.interval_updated.wait(waiter).unwrap();
             } else {
@@ -543,30 +618,75 @@ impl IoBufs {
     }
 
     /// Called by users who wish to force the current buffer
-    /// to flush some pending writes.
-    pub(super) fn flush(&self) -> CacheResult<(), ()> {
+    /// to flush some pending writes. Returns the number of bytes
+    /// of the log that became stable while this ran, which may
+    /// include bytes that another thread was already writing.
+    pub(super) fn flush(&self) -> CacheResult<usize, ()> {
+        self.check_poisoned()?;
+
+        let before = self.stable();
         let max_reserved_lsn = self.max_reserved_lsn.load(SeqCst);
-        self.make_stable(max_reserved_lsn)
+        if before >= max_reserved_lsn {
+            return Ok(0);
+        }
+        self.make_stable(max_reserved_lsn)?;
+        Ok((self.stable() - before) as usize)
     }
 
-    // ensure self.max_reserved_lsn is set to this Lsn
-    // or greater, for use in correct calls to flush.
-    fn bump_max_reserved_lsn(&self, lsn: Lsn) {
-        let mut current = self.max_reserved_lsn.load(SeqCst);
+    /// Returns the highest Lsn that has been reserved so far.
+    pub(super) fn max_reserved_lsn(&self) -> Lsn {
+        self.max_reserved_lsn.load(SeqCst)
+    }
+
+    /// Asks for the log to be made stable up to `lsn` in the
+    /// background. Returns true if no thread is currently doing
+    /// that, in which case the caller must start one that runs
+    /// `drive_flushes`.
+    pub(super) fn request_flush(&self, lsn: Lsn) -> bool {
+        bump_lsn(&self.flush_requested, lsn);
+        !self.flush_driver_running.swap(true, SeqCst)
+    }
+
+    /// Makes the log stable up to the highest requested Lsn,
+    /// including any that are requested while this runs, so
+    /// that concurrent requests share a single write and sync.
+    pub(super) fn drive_flushes(&self) {
         loop {
-            if current >= lsn {
+            let target = self.flush_requested.load(SeqCst);
+            let res = self.make_stable(target);
+
+            self.flush_driver_running.store(false, SeqCst);
+
+            if let Err(e) = res {
+                #[cfg(feature = "failpoints")]
+                {
+                    if let Error::FailPoint = e {
+                        self._failpoint_crashing.store(true, SeqCst);
+                        // wake up any waiting threads so they don't stall
+                        self.interval_updated.notify_all();
+                    }
+                }
+
+                // waiters retry the write themselves, and see the error
+                error!("failed to flush from background flush thread: {}", e);
                 return;
             }
-            let last =
-                self.max_reserved_lsn.compare_and_swap(current, lsn, SeqCst);
-            if last == current {
-                // we succeeded.
+
+            // a request that came in after we read the target may
+            // have seen that we were still running, and left it to us.
+            let pending = self.flush_requested.load(SeqCst) > self.stable();
+            if !pending || self.flush_driver_running.swap(true, SeqCst) {
                 return;
             }
-            current = last;
         }
     }
 
+    // ensure self.max_reserved_lsn is set to this Lsn
+    // or greater, for use in correct calls to flush.
+    fn bump_max_reserved_lsn(&self, lsn: Lsn) {
+        bump_lsn(&self.max_reserved_lsn, lsn)
+    }
+
     // Attempt to seal the current IO buffer, possibly
     // writing it to disk if there are no other writers
     // operating on it.
@@ -638,14 +758,15 @@ impl IoBufs {
             // take the crc of the random bytes already after where we
             // would place our header.
             let padding_bytes = vec![EVIL_BYTE; len];
-            let crc16 = crc16_arr(&*padding_bytes);
 
-            let header = MessageHeader {
+            let mut header = MessageHeader {
                 kind: MessageKind::Pad,
                 lsn: lsn + offset as Lsn,
                 len: len,
-                crc16: crc16,
+                crc16: [0; 2],
             };
+            let segment_lsn = header.lsn - header.lsn % io_buf_size as Lsn;
+            header.crc16 = header.checksum(segment_lsn, &*padding_bytes);
 
             let header_bytes: [u8; MSG_HEADER_LEN] = header.into();
 
@@ -774,9 +895,26 @@ impl IoBufs {
     }
 
     // Write an IO buffer's data to stable storage and set up the
-    // next IO buffer for writing.
+    // next IO buffer for writing. If that fails, the log is poisoned.
     fn write_to_log(&self, idx: usize) -> CacheResult<(), ()> {
+        let res = self.write_iobuf(idx);
+        if let Err(ref e) = res {
+            self.poison(e);
+        }
+        res
+    }
+
+    fn write_iobuf(&self, idx: usize) -> CacheResult<(), ()> {
         let _measure = Measure::new(&M.write_to_log);
+
+        // a crashed process doesn't get to write anything else
+        #[cfg(feature = "failpoints")]
+        {
+            if self._failpoint_crashing.load(SeqCst) {
+                return Err(Error::FailPoint);
+            }
+        }
+
         let iobuf = &self.bufs[idx];
         let header = iobuf.get_header();
         let lid = iobuf.get_lid();
@@ -801,6 +939,14 @@ impl IoBufs {
 
         let f = self.config.file()?;
         io_fail!(self, "buffer write");
+        // a failed write that the system keeps running after
+        #[cfg(feature = "failpoints")]
+        fail_point!("buffer write io", |_| {
+            Err(Error::Io(io::Error::new(
+                io::ErrorKind::Other,
+                "simulated write failure",
+            )))
+        });
         f.pwrite_all(&data[..res_len], lid)?;
         f.sync_all()?;
         io_fail!(self, "buffer write post");
@@ -951,6 +1097,22 @@ impl IoBufs {
     }
 }
 
+// ensure `atomic` is set to this Lsn or greater
+fn bump_lsn(atomic: &AtomicLsn, lsn: Lsn) {
+    let mut current = atomic.load(SeqCst);
+    loop {
+        if current >= lsn {
+            return;
+        }
+        let last = atomic.compare_and_swap(current, lsn, SeqCst);
+        if last == current {
+            // we succeeded.
+            return;
+        }
+        current = last;
+    }
+}
+
 impl Drop for IoBufs {
     fn drop(&mut self) {
         // don't do any more IO if we're simulating a crash
@@ -965,6 +1127,11 @@ impl Drop for IoBufs {
             error!("failed to flush from IoBufs::drop: {}", e);
         }
 
+        if self.config.read_only {
+            debug!("IoBufs dropped");
+            return;
+        }
+
         if let Ok(f) = self.config.file() {
             f.sync_all().unwrap();
         }
@@ -985,7 +1152,19 @@ impl periodic::Callback for std::sync::Arc<IoBufs> {
                 }
             }
 
-            error!("failed to flush from periodic flush thread: {}", e);
+            // a poisoned log has already reported its error
+            if !self.poisoned.load(SeqCst) {
+                error!("failed to flush from periodic flush thread: {}", e);
+            }
+        }
+
+        let tasks: Vec<_> = {
+            let mut maintenance = self.maintenance.lock().unwrap();
+            maintenance.retain(|task| task.upgrade().is_some());
+            maintenance.iter().filter_map(|task| task.upgrade()).collect()
+        };
+        for task in tasks {
+            task.maintain();
         }
     }
 }
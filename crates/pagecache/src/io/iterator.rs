@@ -1,6 +1,7 @@
 use std::io;
 
 use self::reader::LogReader;
+use super::Pio;
 use super::*;
 
 pub struct LogIter {
@@ -8,10 +9,12 @@ pub struct LogIter {
     pub segment_iter: Box<Iterator<Item = (Lsn, LogID)>>,
     pub segment_base: Option<LogID>,
     pub segment_len: usize,
-    pub use_compression: bool,
     pub max_lsn: Lsn,
     pub cur_lsn: Lsn,
     pub trailer: Option<Lsn>,
+    pub info: RecoveryInfo,
+    // where the first message that failed its checks was found
+    pub discarded_from: Option<LogID>,
 }
 
 impl Iterator for LogIter {
@@ -41,6 +44,7 @@ impl Iterator for LogIter {
 
                     #[cfg(target_os = "linux")] self.fadvise_willneed(next_lid);
 
+                    self.info.segments_scanned += 1;
                     if let Err(e) = self.read_segment(next_lsn, next_lid) {
                         debug!(
                             "hit snap while reading segments in \
@@ -63,14 +67,11 @@ impl Iterator for LogIter {
                 (self.cur_lsn % self.segment_len as Lsn) as LogID;
 
             if let Ok(f) = self.config.file() {
-                match f.read_message(
-                    lid,
-                    self.segment_len,
-                    self.use_compression,
-                ) {
+                match f.read_message(lid, self.segment_len) {
                     Ok(LogRead::Flush(lsn, buf, on_disk_len)) => {
                         if lsn != self.cur_lsn {
                             error!("read Flush with bad lsn");
+                            self.discard_rest_of_segment(lid);
                             return None;
                         }
                         trace!("read flush in LogIter::next");
@@ -80,6 +81,7 @@ impl Iterator for LogIter {
                     Ok(LogRead::Failed(lsn, on_disk_len)) => {
                         if lsn != self.cur_lsn {
                             error!("read Failed with bad lsn");
+                            self.discard_rest_of_segment(lid);
                             return None;
                         }
                         trace!("read zeroed in LogIter::next");
@@ -87,11 +89,13 @@ impl Iterator for LogIter {
                     }
                     Ok(LogRead::Corrupted(_len)) => {
                         trace!("read corrupted end in LogIter::next");
+                        self.discard_rest_of_segment(lid);
                         return None;
                     }
                     Ok(LogRead::Pad(lsn)) => {
                         if lsn != self.cur_lsn {
                             error!("read Pad with bad lsn");
+                            self.discard_rest_of_segment(lid);
                             return None;
                         }
 
@@ -193,6 +197,68 @@ impl LogIter {
         Ok(())
     }
 
+    /// Overwrite everything from the first message that failed its
+    /// checks to the trailer of its segment, so that none of it can be
+    /// mistaken for valid messages once the log is written past where
+    /// recovery stopped. Only call during recovery, when nothing else
+    /// is writing to the log!
+    pub fn erase_discarded(&self) -> CacheResult<(), ()> {
+        let lid = match self.discarded_from {
+            Some(lid) => lid,
+            None => return Ok(()),
+        };
+        let segment_len = self.segment_len as LogID;
+
+        // the trailer is left alone, as it tells the segment accountant
+        // whether any segments after this one need to be cleaned up.
+        let end = (lid / segment_len + 1) * segment_len -
+            SEG_TRAILER_LEN as LogID;
+
+        debug!("erasing discarded log data from {} to {}", lid, end);
+        let f = self.config.file()?;
+        maybe_fail!("erase discarded");
+        f.pwrite_all(&*vec![EVIL_BYTE; (end - lid) as usize], lid)?;
+        f.sync_all()?;
+        Ok(())
+    }
+
+    // Counts the bytes from the first message that failed its checks
+    // to the end of its segment, which are treated as garbage. The
+    // space in a segment that hasn't been written to yet isn't counted.
+    fn discard_rest_of_segment(&mut self, lid: LogID) {
+        let segment_len = self.segment_len as LogID;
+        let seg_start = lid / segment_len * segment_len;
+        let mut end = seg_start + segment_len - SEG_TRAILER_LEN as LogID;
+
+        let f = match self.config.file() {
+            Ok(f) => f,
+            Err(_) => return,
+        };
+        if let Ok(metadata) = f.metadata() {
+            end = std::cmp::min(end, metadata.len());
+        }
+        if end <= lid {
+            return;
+        }
+
+        let mut buf = vec![0; (end - lid) as usize];
+        if f.pread_exact(&mut buf, lid).is_ok() {
+            let written = buf.iter()
+                .rposition(|&b| b != 0 && b != EVIL_BYTE)
+                .map_or(0, |i| i + 1);
+            if written > 0 {
+                self.discarded_from = Some(lid);
+                warn!(
+                    "discarding {} bytes after the last valid message \
+                     in the segment at lid {}",
+                    written,
+                    seg_start
+                );
+            }
+            self.info.bytes_discarded += written as u64;
+        }
+    }
+
     #[cfg(target_os = "linux")]
     fn fadvise_willneed(&self, lid: LogID) {
         use std::os::unix::io::AsRawFd;
@@ -1,4 +1,5 @@
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
+use std::thread;
 
 use self::reader::LogReader;
 use super::*;
@@ -55,6 +56,30 @@ pub struct Log {
 unsafe impl Send for Log {}
 unsafe impl Sync for Log {}
 
+/// A flush started by `Log::flush_async`, which completes once the
+/// log is durable up to where it was when the flush was started.
+/// Dropping the handle doesn't stop the flush.
+pub struct FlushHandle {
+    iobufs: Arc<IoBufs>,
+    lsn: Lsn,
+}
+
+unsafe impl Send for FlushHandle {}
+unsafe impl Sync for FlushHandle {}
+
+impl FlushHandle {
+    /// Returns true if the flush has completed.
+    pub fn is_done(&self) -> bool {
+        self.iobufs.stable() >= self.lsn
+    }
+
+    /// Blocks until the flush has completed, or returns the
+    /// error that writing to the log failed with.
+    pub fn wait(self) -> CacheResult<(), ()> {
+        self.iobufs.make_stable(self.lsn)
+    }
+}
+
 impl Log {
     /// Start the log, open or create the configured file,
     /// and optionally start the periodic buffer flush thread.
@@ -79,10 +104,10 @@ impl Log {
     /// Starts a log for use without a materializer.
     pub fn start_raw_log(config: Config) -> CacheResult<Log, ()> {
         assert_eq!(config.segment_mode, SegmentMode::Linear);
-        let log_iter = raw_segment_iter_from(0, &config)?;
+        let mut log_iter = raw_segment_iter_from(0, &config)?;
 
         let snapshot = advance_snapshot::<NullMaterializer, (), ()>(
-            log_iter,
+            &mut log_iter,
             Snapshot::default(),
             &config,
         )?;
@@ -91,10 +116,37 @@ impl Log {
     }
 
     /// Flushes any pending IO buffers to disk to ensure durability.
-    pub fn flush(&self) -> CacheResult<(), ()> {
+    /// Returns the number of bytes of the log that were made durable,
+    /// which is 0 if everything was already stable.
+    pub fn flush(&self) -> CacheResult<usize, ()> {
         self.iobufs.flush()
     }
 
+    /// Starts flushing everything that has been written so far in the
+    /// background, returning a `FlushHandle` that completes once it's
+    /// durable. Concurrent calls share the same write and sync.
+    pub fn flush_async(&self) -> FlushHandle {
+        let lsn = self.iobufs.max_reserved_lsn();
+        if self.iobufs.stable() < lsn && self.iobufs.request_flush(lsn) {
+            let iobufs = self.iobufs.clone();
+            thread::Builder::new()
+                .name("log background flusher".to_owned())
+                .spawn(move || iobufs.drive_flushes())
+                .unwrap();
+        }
+
+        FlushHandle {
+            iobufs: self.iobufs.clone(),
+            lsn: lsn,
+        }
+    }
+
+    /// Run a task on the periodic flush thread after each flush,
+    /// until the task is dropped.
+    pub fn add_maintenance(&self, task: Weak<dyn Maintenance>) {
+        self.iobufs.add_maintenance(task)
+    }
+
     /// Reserve space in the log for a pending linearized operation.
     pub fn reserve(&self, buf: Vec<u8>) -> CacheResult<Reservation, ()> {
         self.iobufs.reserve(buf)
@@ -127,8 +179,9 @@ impl Log {
             segment_base: None,
             segment_iter: segment_iter,
             segment_len: io_buf_size,
-            use_compression: self.config.use_compression,
             trailer: None,
+            info: RecoveryInfo::default(),
+            discarded_from: None,
         }
     }
 
@@ -138,11 +191,7 @@ impl Log {
         self.make_stable(lsn)?;
         let f = self.config.file()?;
 
-        let read = f.read_message(
-            lid,
-            self.config.io_buf_size,
-            self.config.use_compression,
-        );
+        let read = f.read_message(lid, self.config.io_buf_size);
 
         read.and_then(|log_read| match log_read {
             LogRead::Flush(read_lsn, _, _) => {
@@ -176,6 +225,7 @@ impl Log {
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub(crate) enum MessageKind {
     Success,
+    SuccessCompressed,
     Failed,
     Pad,
     Corrupted,
@@ -190,6 +240,24 @@ pub(crate) struct MessageHeader {
     pub crc16: [u8; 2],
 }
 
+impl MessageHeader {
+    /// Checksums the message's kind, lsn and length along with its
+    /// contents, salted with the lsn of the segment it's written in.
+    /// A message left over from an earlier use of its segment fails
+    /// the check against the segment's current lsn, even if it was
+    /// written out in full back then.
+    pub fn checksum(&self, segment_lsn: Lsn, buf: &[u8]) -> [u8; 2] {
+        let salt: [u8; 8] = unsafe { std::mem::transmute(segment_lsn) };
+        let header_bytes: [u8; MSG_HEADER_LEN] = (*self).into();
+
+        // the crc16 itself sits at the end of the header
+        let mut crc = crc16_update(0, &salt);
+        crc = crc16_update(crc, &header_bytes[..MSG_HEADER_LEN - 2]);
+        crc = crc16_update(crc, buf);
+        [(crc >> 8) as u8, crc as u8]
+    }
+}
+
 /// A segment's header contains the new base LSN and a reference
 /// to the previous log segment.
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -291,6 +359,7 @@ impl From<[u8; MSG_HEADER_LEN]> for MessageHeader {
     fn from(buf: [u8; MSG_HEADER_LEN]) -> MessageHeader {
         let kind = match buf[0] {
             SUCCESSFUL_FLUSH => MessageKind::Success,
+            SUCCESSFUL_COMPRESSED_FLUSH => MessageKind::SuccessCompressed,
             FAILED_FLUSH => MessageKind::Failed,
             SEGMENT_PAD => MessageKind::Pad,
             _ => MessageKind::Corrupted,
@@ -322,6 +391,7 @@ impl Into<[u8; MSG_HEADER_LEN]> for MessageHeader {
         let mut buf = [0u8; MSG_HEADER_LEN];
         buf[0] = match self.kind {
             MessageKind::Success => SUCCESSFUL_FLUSH,
+            MessageKind::SuccessCompressed => SUCCESSFUL_COMPRESSED_FLUSH,
             MessageKind::Failed => FAILED_FLUSH,
             MessageKind::Pad => SEGMENT_PAD,
             MessageKind::Corrupted => EVIL_BYTE,
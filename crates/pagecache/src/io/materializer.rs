@@ -25,13 +25,25 @@ pub trait Materializer {
     fn new(Config, &Option<Self::Recovery>) -> Self where Self: Sized;
 
     /// Used to merge chains of partial pages into a form
-    /// that is useful for the `PageCache` owner.
-    fn merge(&self, &[&Self::PageFrag]) -> Self::PageFrag;
+    /// that is useful for the `PageCache` owner. An error
+    /// is returned to whoever tried to read the page.
+    fn merge(
+        &self,
+        &[&Self::PageFrag],
+    ) -> CacheResult<Self::PageFrag, ()>;
 
     /// Used to feed custom recovery information back to a higher-level abstraction
     /// during startup. For example, a B-Link tree must know what the current
     /// root node is before it can start serving requests.
     fn recover(&self, &Self::PageFrag) -> Option<Self::Recovery>;
+
+    /// The bytes of memory that a `PageFrag` takes up while it's
+    /// cached, including anything it holds on the heap. This is what
+    /// the `PageCache` counts against its `cache_capacity`, and unless
+    /// it's provided, only the `PageFrag` itself is counted.
+    fn mem_size(&self, _frag: &Self::PageFrag) -> usize {
+        std::mem::size_of::<Self::PageFrag>()
+    }
 }
 
 /// A materializer for things that have nothing to
@@ -52,8 +64,11 @@ impl Materializer for NullMaterializer {
         NullMaterializer
     }
 
-    fn merge(&self, _: &[&Self::PageFrag]) -> Self::PageFrag {
-        ()
+    fn merge(
+        &self,
+        _: &[&Self::PageFrag],
+    ) -> CacheResult<Self::PageFrag, ()> {
+        Ok(())
     }
 
     fn recover(&self, _: &Self::PageFrag) -> Option<Self::Recovery> {
@@ -31,9 +31,10 @@ pub(super) use self::reader::LogReader;
 #[doc(hidden)]
 pub use self::snapshot::{Snapshot, read_snapshot_or_default};
 
-pub use self::log::Log;
+pub use self::log::{FlushHandle, Log};
 pub use self::materializer::{Materializer, NullMaterializer};
-pub use self::page_cache::{CacheEntry, PageCache, PageGet};
+pub use self::page_cache::{CacheEntry, CacheStats, PageCache, PageGet,
+                           RecoveryInfo};
 pub use self::reservation::Reservation;
 pub use self::segment::SegmentMode;
 
@@ -57,3 +58,6 @@ const FAILED_FLUSH: u8 = 0;
 
 // This message represents a pad.
 const SEGMENT_PAD: u8 = 2;
+
+// This message represents valid data, compressed with zstd.
+const SUCCESSFUL_COMPRESSED_FLUSH: u8 = 3;
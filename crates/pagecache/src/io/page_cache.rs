@@ -1,11 +1,13 @@
 use std::collections::BinaryHeap;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, Weak};
 
 use epoch::{Guard, Owned, Shared};
 
 #[cfg(feature = "rayon")]
 use rayon::prelude::*;
 
+use config::InUse;
+
 use super::*;
 
 /// Points to either a memory location or a disk location to page-in data from.
@@ -124,6 +126,54 @@ impl<'a, P> PageGet<'a, P>
     }
 }
 
+/// How the `PageCache`'s memory is being used, as returned by
+/// `PageCache::cache_stats`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CacheStats {
+    /// The bytes of memory taken up by cached pages, as measured
+    /// by `Materializer::mem_size`. Pages are evicted to keep this
+    /// under `cache_capacity`, but it may go over by up to a page
+    /// per cache shard, as the most recently used page is kept.
+    pub resident_bytes: usize,
+    /// The number of page reads that were served from memory.
+    pub hits: usize,
+    /// The number of page reads that had to read from disk.
+    pub misses: usize,
+    /// The number of pages that have been evicted from memory.
+    pub evictions: usize,
+}
+
+impl CacheStats {
+    /// The fraction of page reads that were served from memory,
+    /// or 1 if no pages have been read.
+    pub fn hit_rate(&self) -> f64 {
+        let reads = self.hits + self.misses;
+        if reads == 0 {
+            1.
+        } else {
+            self.hits as f64 / reads as f64
+        }
+    }
+}
+
+/// What was found in the log when the `PageCache` was started, as
+/// returned by `PageCache::recovery_info`. Recovery replays the log
+/// from the last snapshot, and stops at the first message that fails
+/// its checksum or doesn't belong where it was found, which is where
+/// a crash tore the log if any writes were lost.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RecoveryInfo {
+    /// The number of log segments that were read.
+    pub segments_scanned: usize,
+    /// The number of log messages that were replayed on top of
+    /// the snapshot.
+    pub records_replayed: usize,
+    /// The bytes written from the message that recovery stopped at
+    /// to the end of its segment, which were discarded as torn or
+    /// corrupt. This is 0 after a clean shutdown.
+    pub bytes_discarded: u64,
+}
+
 /// A lock-free pagecache which supports fragmented pages
 /// for dramatically improving write throughput.
 ///
@@ -158,13 +208,16 @@ impl<'a, P> PageGet<'a, P>
 ///
 ///     // Used to merge chains of partial pages into a form
 ///     // that is useful for the `PageCache` owner.
-///     fn merge(&self, frags: &[&Self::PageFrag]) -> Self::PageFrag {
+///     fn merge(
+///         &self,
+///         frags: &[&Self::PageFrag],
+///     ) -> pagecache::CacheResult<Self::PageFrag, ()> {
 ///         let mut consolidated = String::new();
 ///         for frag in frags.into_iter() {
 ///             consolidated.push_str(&*frag);
 ///         }
 ///
-///         consolidated
+///         Ok(consolidated)
 ///     }
 ///
 ///     // Used to feed custom recovery information back to a higher-level abstraction
@@ -212,7 +265,13 @@ pub struct PageCache<PM, P, R>
     log: Log,
     lru: Lru,
     updates: AtomicUsize,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    evictions: AtomicUsize,
     last_snapshot: Arc<Mutex<Option<Snapshot<R>>>>,
+    recovery_info: RecoveryInfo,
+    // dropped last, once everything else is done with our files.
+    _in_use: InUse,
 }
 
 unsafe impl<PM, P, R> Send for PageCache<PM, P, R>
@@ -250,6 +309,8 @@ impl<PM, P, R> PageCache<PM, P, R>
 {
     /// Instantiate a new `PageCache`.
     pub fn start(config: Config) -> CacheResult<PageCache<PM, P, R>, ()> {
+        let in_use = config.mark_in_use()?;
+
         let cache_capacity = config.cache_capacity;
         let cache_shard_bits = config.cache_bits;
         let lru = Lru::new(cache_capacity, cache_shard_bits);
@@ -257,7 +318,25 @@ impl<PM, P, R> PageCache<PM, P, R>
         // try to pull any existing snapshot off disk, and
         // apply any new data to it to "catch-up" the
         // snapshot before loading it.
-        let snapshot = read_snapshot_or_default::<PM, P, R>(&config)?;
+        let (snapshot, log_iter) =
+            read_snapshot_or_default::<PM, P, R>(&config)?;
+
+        // a log that has data in it, but not a single message that
+        // passes its checks, is corrupt rather than new, and starting
+        // afresh would write over everything in it.
+        if let Some(lid) = log_iter.discarded_from {
+            if snapshot.pt.is_empty() {
+                return Err(Error::Corruption {
+                    at: lid,
+                });
+            }
+        }
+
+        // clear out any torn writes that recovery stopped at before
+        // the log is written past them
+        if !config.read_only {
+            log_iter.erase_discarded()?;
+        }
 
         let materializer =
             Arc::new(PM::new(config.clone(), &snapshot.recovery));
@@ -271,7 +350,12 @@ impl<PM, P, R> PageCache<PM, P, R>
             log: Log::start(config, snapshot.clone())?,
             lru: lru,
             updates: AtomicUsize::new(0),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            evictions: AtomicUsize::new(0),
             last_snapshot: Arc::new(Mutex::new(Some(snapshot))),
+            recovery_info: log_iter.info,
+            _in_use: in_use,
         };
 
         // now we read it back in
@@ -281,10 +365,54 @@ impl<PM, P, R> PageCache<PM, P, R>
     }
 
     /// Flushes any pending IO buffers to disk to ensure durability.
-    pub fn flush(&self) -> CacheResult<(), ()> {
+    /// Returns the number of bytes of the log that were made durable.
+    pub fn flush(&self) -> CacheResult<usize, ()> {
         self.log.flush()
     }
 
+    /// Flushes any pending IO buffers in the background, returning
+    /// a `FlushHandle` that completes once they're durable.
+    pub fn flush_async(&self) -> FlushHandle {
+        self.log.flush_async()
+    }
+
+    /// Run a task on the periodic flush thread after each flush, until
+    /// the task is dropped. Nothing is run if `flush_every_ms` is unset.
+    pub fn add_maintenance(&self, task: Weak<dyn Maintenance>) {
+        self.log.add_maintenance(task)
+    }
+
+    /// Returns the bytes of the log's segments that are in use, including
+    /// the parts of them that hold replaced state awaiting cleaning.
+    pub fn size_on_disk(&self) -> u64 {
+        self.log.with_sa(|sa| sa.size_on_disk())
+    }
+
+    /// Returns the bytes of the log messages that hold the current
+    /// state of the provided pages. This is kept up to date as pages
+    /// are written and cleaned, and counts messages before compression.
+    pub fn size_of_pages(&self, pids: &[PageID]) -> u64 {
+        self.log.with_sa(|sa| sa.pages_len(pids))
+    }
+
+    /// Returns how much memory is used by cached pages, and how
+    /// often pages have been read from memory, read from disk, and
+    /// evicted since the `PageCache` was started.
+    pub fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            resident_bytes: self.lru.size(),
+            hits: self.hits.load(SeqCst),
+            misses: self.misses.load(SeqCst),
+            evictions: self.evictions.load(SeqCst),
+        }
+    }
+
+    /// Returns what was found in the log when the `PageCache` was
+    /// started, including how much of it was discarded as torn.
+    pub fn recovery_info(&self) -> RecoveryInfo {
+        self.recovery_info
+    }
+
     /// Return the recovered state from the snapshot
     pub fn recovered_state(&self) -> Option<R> {
         let mu = match self.last_snapshot.lock() {
@@ -302,6 +430,10 @@ impl<PM, P, R> PageCache<PM, P, R>
     /// Create a new page, trying to reuse old freed pages if possible
     /// to maximize underlying `Radix` pointer density.
     pub fn allocate<'g>(&self, guard: &'g Guard) -> CacheResult<PageID, ()> {
+        if self.config.read_only {
+            return Err(Error::ReadOnly);
+        }
+
         let pid = self.free.lock().unwrap().pop().unwrap_or_else(|| {
             self.max_pid.fetch_add(1, SeqCst)
         });
@@ -323,9 +455,12 @@ impl<PM, P, R> PageCache<PM, P, R>
         let bytes =
             measure(&M.serialize, || serialize(&prepend, Infinite).unwrap());
 
+        let len = (MSG_HEADER_LEN + bytes.len()) as u64;
+
         // reserve slot in log
         // FIXME not threadsafe?
-        self.log.write(bytes)?;
+        let (lsn, _lid) = self.log.write(bytes)?;
+        self.log.with_sa(|sa| sa.mark_page_len(pid, lsn, len, true));
 
         Ok(pid)
     }
@@ -336,6 +471,10 @@ impl<PM, P, R> PageCache<PM, P, R>
         pid: PageID,
         guard: &'g Guard,
     ) -> CacheResult<(), Option<PagePtr<'g, P>>> {
+        if self.config.read_only {
+            return Err(Error::ReadOnly);
+        }
+
         let old_stack = match self.inner.get(pid, &guard) {
             // already freed or never allocated
             None => return Ok(()),
@@ -379,7 +518,9 @@ impl<PM, P, R> PageCache<PM, P, R>
             self.inner.cas(pid, old_stack, new_stack, &guard).unwrap();
 
             self.log.with_sa(|sa| {
-                sa.mark_replace(pid, lsn, lids_from_stack(cas_key, &guard), lid)
+                let lids = lids_from_stack(cas_key, &guard);
+                sa.mark_replace(pid, lsn, lids, lid);
+                sa.forget_page_len(pid);
             });
         }
 
@@ -388,6 +529,8 @@ impl<PM, P, R> PageCache<PM, P, R>
         // the segment to inactive, resulting in a race otherwise.
         res.complete().map_err(|e| e.danger_cast())?;
 
+        self.accessed(pid, 0, guard).map_err(|e| e.danger_cast())?;
+
         let free = self.free.clone();
         unsafe {
             guard.defer(move || {
@@ -414,6 +557,10 @@ impl<PM, P, R> PageCache<PM, P, R>
         new: P,
         guard: &'g Guard,
     ) -> CacheResult<PagePtr<'g, P>, Option<PagePtr<'g, P>>> {
+        if self.config.read_only {
+            return Err(Error::ReadOnly);
+        }
+
         let stack_ptr = match self.inner.get(pid, guard) {
             None => return Err(Error::CasFailed(None)),
             Some(s) => s,
@@ -430,6 +577,7 @@ impl<PM, P, R> PageCache<PM, P, R>
 
         let bytes =
             measure(&M.serialize, || serialize(&prepend, Infinite).unwrap());
+        let len = (MSG_HEADER_LEN + bytes.len()) as u64;
         let log_reservation =
             self.log.reserve(bytes).map_err(|e| e.danger_cast())?;
         let lsn = log_reservation.lsn();
@@ -444,6 +592,7 @@ impl<PM, P, R> PageCache<PM, P, R>
         } else {
             let to_clean = self.log.with_sa(|sa| {
                 sa.mark_link(pid, lsn, lid);
+                sa.mark_page_len(pid, lsn, len, old.is_null());
                 sa.clean(None)
             });
 
@@ -454,6 +603,11 @@ impl<PM, P, R> PageCache<PM, P, R>
             // is waiting to acquire a new reservation blocked by this?
             log_reservation.complete().map_err(|e| e.danger_cast())?;
 
+            if let Ok(new_head) = result {
+                let size = self.resident_size(new_head, guard);
+                self.accessed(pid, size, guard).map_err(|e| e.danger_cast())?;
+            }
+
             if let Some(to_clean) = to_clean {
                 match self.get(to_clean, guard)? {
                     PageGet::Materialized(page, key) => {
@@ -510,6 +664,10 @@ impl<PM, P, R> PageCache<PM, P, R>
         new: P,
         guard: &'g Guard,
     ) -> CacheResult<PagePtr<'g, P>, Option<PagePtr<'g, P>>> {
+        if self.config.read_only {
+            return Err(Error::ReadOnly);
+        }
+
         self.replace_recurse_once(pid, old, Update::Compact(new), guard, false)
     }
 
@@ -533,6 +691,7 @@ impl<PM, P, R> PageCache<PM, P, R>
         };
         let bytes =
             measure(&M.serialize, || serialize(&replace, Infinite).unwrap());
+        let len = (MSG_HEADER_LEN + bytes.len()) as u64;
         let log_reservation =
             self.log.reserve(bytes).map_err(|e| e.danger_cast())?;
         let lsn = log_reservation.lsn();
@@ -561,6 +720,11 @@ impl<PM, P, R> PageCache<PM, P, R>
 
             let to_clean = self.log.with_sa(|sa| {
                 sa.mark_replace(pid, lsn, lids, lid);
+                if let Update::Free = replace.update {
+                    sa.forget_page_len(pid);
+                } else {
+                    sa.mark_page_len(pid, lsn, len, true);
+                }
                 if recursed { None } else { sa.clean(Some(pid)) }
             });
 
@@ -569,6 +733,11 @@ impl<PM, P, R> PageCache<PM, P, R>
             // the segment to inactive, resulting in a race otherwise.
             log_reservation.complete().map_err(|e| e.danger_cast())?;
 
+            if let Ok(new_head) = result {
+                let size = self.resident_size(new_head, guard);
+                self.accessed(pid, size, guard).map_err(|e| e.danger_cast())?;
+            }
+
             if let Some(to_clean) = to_clean {
                 assert_ne!(pid, to_clean);
                 match self.get(to_clean, guard)? {
@@ -660,6 +829,10 @@ impl<PM, P, R> PageCache<PM, P, R>
                     if lids.is_empty() {
                         // Short circuit merging and fix-up if we only
                         // have one frag.
+                        self.hits.fetch_add(1, SeqCst);
+                        let size = self.t.mem_size(page_frag);
+                        self.accessed(pid, size, guard)
+                            .map_err(|e| e.danger_cast())?;
                         return Ok(
                             PageGet::Materialized(page_frag.clone(), head),
                         );
@@ -687,6 +860,12 @@ impl<PM, P, R> PageCache<PM, P, R>
 
         // Did not find a previously merged value in memory,
         // may need to go to disk.
+        if merged_resident || to_merge.len() == lids.len() {
+            self.hits.fetch_add(1, SeqCst);
+        } else {
+            self.misses.fetch_add(1, SeqCst);
+        }
+
         if !merged_resident {
             let to_pull = &lids[to_merge.len()..];
 
@@ -716,14 +895,13 @@ impl<PM, P, R> PageCache<PM, P, R>
             .rev()
             .collect();
 
-        let merged = measure(&M.merge_page, || self.t.merge(&*combined));
-
-        let size = std::mem::size_of_val(&merged);
-        let to_evict = self.lru.accessed(pid, size);
-        trace!("accessed pid {} -> paging out pid {:?}", pid, to_evict);
-        self.page_out(to_evict, guard).map_err(|e| e.danger_cast())?;
+        let merged = measure(&M.merge_page, || self.t.merge(&*combined))
+            .map_err(|e| e.danger_cast())?;
 
-        if lids.len() > self.config.page_consolidation_threshold {
+        // pages are only consolidated in memory in read-only mode
+        if lids.len() > self.config.page_consolidation_threshold &&
+            !self.config.read_only
+        {
             trace!("consolidating pid {} with len {}!", pid, lids.len());
             match self.replace_recurse_once(
                 pid,
@@ -783,9 +961,45 @@ impl<PM, P, R> PageCache<PM, P, R>
             }
         }
 
+        // account for whatever is cached now, which is just the merged
+        // page if it was consolidated or fixed up above.
+        let size = self.resident_size(head, guard);
+        self.accessed(pid, size, guard).map_err(|e| e.danger_cast())?;
+
         Ok(PageGet::Materialized(merged, head))
     }
 
+    // the memory taken up by the cached frags of a page.
+    fn resident_size<'g>(
+        &self,
+        head: PagePtr<'g, P>,
+        guard: &'g Guard,
+    ) -> usize {
+        StackIter::from_ptr(head, guard)
+            .map(|cache_entry_ptr| match *cache_entry_ptr {
+                CacheEntry::Resident(ref page_frag, _, _) |
+                CacheEntry::MergedResident(ref page_frag, _, _) => {
+                    self.t.mem_size(page_frag)
+                }
+                _ => 0,
+            })
+            .sum()
+    }
+
+    // record the new size of a page that was just read or written,
+    // and evict the least recently used pages if the cache is over
+    // its capacity.
+    fn accessed<'g>(
+        &self,
+        pid: PageID,
+        size: usize,
+        guard: &'g Guard,
+    ) -> CacheResult<(), ()> {
+        let to_evict = self.lru.accessed(pid, size);
+        trace!("accessed pid {} -> paging out pid {:?}", pid, to_evict);
+        self.page_out(to_evict, guard)
+    }
+
     fn page_out<'g>(
         &self,
         to_evict: Vec<PageID>,
@@ -806,7 +1020,7 @@ impl<PM, P, R> PageCache<PM, P, R>
 
             // ensure the last entry is a Flush
             let last_ce = match cache_entries.pop() {
-                None => return Ok(()),
+                None => continue,
                 Some(c) => c,
             };
 
@@ -827,7 +1041,7 @@ impl<PM, P, R> PageCache<PM, P, R>
                     // a discrepency in the Lru perceived size
                     // and the real size, but this should be
                     // minimal in anticipated workloads.
-                    return Ok(());
+                    continue;
                 }
             };
 
@@ -852,13 +1066,14 @@ impl<PM, P, R> PageCache<PM, P, R>
             new_stack.push(last);
             let node = node_from_frag_vec(new_stack);
 
+            // if this fails, the page was changed in the meantime,
+            // and whoever changed it has accounted for it again.
             debug_delay();
-            unsafe {
-                if stack_ptr
-                    .deref()
-                    .cas(head, node.into_shared(guard), guard)
-                    .is_err()
-                {}
+            let res = unsafe {
+                stack_ptr.deref().cas(head, node.into_shared(guard), guard)
+            };
+            if res.is_ok() {
+                self.evictions.fetch_add(1, SeqCst);
             }
         }
         Ok(())
@@ -956,10 +1171,13 @@ impl<PM, P, R> PageCache<PM, P, R>
             self.log.stable_offset(),
         );
 
-        let iter = self.log.iter_from(start_lsn);
+        let mut iter = self.log.iter_from(start_lsn);
 
-        let res =
-            advance_snapshot::<PM, P, R>(iter, last_snapshot, &self.config);
+        let res = advance_snapshot::<PM, P, R>(
+            &mut iter,
+            last_snapshot,
+            &self.config,
+        );
 
         // NB it's important to resume writing before replacing the snapshot
         // into the mutex, otherwise we create a race condition where the SA is
@@ -21,7 +21,6 @@ pub(crate) trait LogReader {
         &self,
         id: LogID,
         segment_len: usize,
-        use_compression: bool,
     ) -> CacheResult<LogRead, ()>;
 }
 
@@ -60,12 +59,12 @@ impl LogReader for File {
         Ok(msg_header_buf.into())
     }
 
-    /// read a buffer from the disk
+    /// read a buffer from the disk, decompressing it
+    /// if it was written compressed
     fn read_message(
         &self,
         lid: LogID,
         segment_len: usize,
-        _use_compression: bool,
     ) -> CacheResult<LogRead, ()> {
         let _measure = Measure::new(&M.read);
         let seg_start = lid / segment_len as LogID * segment_len as LogID;
@@ -78,9 +77,13 @@ impl LogReader for File {
         assert!(lid + MSG_HEADER_LEN as LogID <= ceiling);
 
         let header = self.read_message_header(lid)?;
+        let segment_header = self.read_segment_header(seg_start)?;
 
-        if header.lsn as usize % segment_len != lid as usize % segment_len {
-            // our message lsn was not aligned to our segment offset
+        if !segment_header.ok ||
+            header.lsn != segment_header.lsn + (lid - seg_start) as Lsn
+        {
+            // our message lsn doesn't match its place in the segment,
+            // or it was written during an earlier use of the segment
             return Ok(LogRead::Corrupted(header.len));
         }
 
@@ -104,7 +107,7 @@ impl LogReader for File {
         }
         self.pread_exact(&mut buf, lid + MSG_HEADER_LEN as LogID)?;
 
-        let checksum = crc16_arr(&buf);
+        let checksum = header.checksum(segment_header.lsn, &buf);
         if checksum != header.crc16 {
             trace!(
                 "read a message with a bad checksum with header {:?}",
@@ -125,24 +128,33 @@ impl LogReader for File {
             _ => {}
         }
 
-        #[cfg(feature = "zstd")]
-        let res = {
-            if _use_compression {
-                let _measure = Measure::new(&M.decompress);
-                Ok(LogRead::Flush(
-                    header.lsn,
-                    decompress(&*buf, segment_len).unwrap(),
-                    len,
-                ))
-            } else {
-                Ok(LogRead::Flush(header.lsn, buf, len))
-            }
-        };
-
-        #[cfg(not(feature = "zstd"))]
-        let res = Ok(LogRead::Flush(header.lsn, buf, header.len));
+        if header.kind == MessageKind::SuccessCompressed {
+            buf = decompress_message(buf, segment_len)?;
+        }
 
         trace!("read a successful flushed message");
-        res
+        Ok(LogRead::Flush(header.lsn, buf, header.len))
     }
 }
+
+#[cfg(feature = "zstd")]
+fn decompress_message(
+    buf: Vec<u8>,
+    segment_len: usize,
+) -> CacheResult<Vec<u8>, ()> {
+    let _measure = Measure::new(&M.decompress);
+    // the checksum matched, so this was compressed by us
+    decompress(&*buf, segment_len).map_err(|e| e.into())
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress_message(
+    _buf: Vec<u8>,
+    _segment_len: usize,
+) -> CacheResult<Vec<u8>, ()> {
+    Err(Error::Unsupported(
+        "found a compressed message in the log, which can't be read \
+         without the zstd feature"
+            .to_owned(),
+    ))
+}
@@ -12,6 +12,8 @@ pub struct Reservation<'a> {
     pub(super) flushed: bool,
     pub(super) lsn: Lsn,
     pub(super) lid: LogID,
+    // the lsn of the segment we're in, which salts our checksum
+    pub(super) segment_lsn: Lsn,
 }
 
 impl<'a> Drop for Reservation<'a> {
@@ -54,12 +56,21 @@ impl<'a> Reservation<'a> {
 
         self.flushed = true;
 
+        let mut header_bytes = [0u8; MSG_HEADER_LEN];
+        header_bytes.copy_from_slice(&self.data[..MSG_HEADER_LEN]);
+        let mut header = MessageHeader::from(header_bytes);
+
         if !valid {
-            self.data[0] = FAILED_FLUSH;
+            header.kind = MessageKind::Failed;
             // don't actually zero the message, still check its hash
             // on recovery to find corruption.
         }
 
+        header.crc16 =
+            header.checksum(self.segment_lsn, &self.data[MSG_HEADER_LEN..]);
+        let header_bytes: [u8; MSG_HEADER_LEN] = header.into();
+        self.data[..MSG_HEADER_LEN].copy_from_slice(&header_bytes);
+
         self.destination.copy_from_slice(&*self.data);
 
         self.iobufs.exit_reservation(self.idx)?;
@@ -61,7 +61,7 @@
 //!    previous segment Lsn pointers don't match up, we know
 //!    we have encountered a lost segment, and we will not
 //!    continue the recovery past the detected gap.
-use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::fs::File;
 use std::sync::{Arc, Mutex};
 use std::mem;
@@ -92,6 +92,9 @@ pub struct SegmentAccountant {
     pause_rewriting: bool,
     safety_buffer: Vec<LogID>,
     ordering: BTreeMap<Lsn, LogID>,
+    // the bytes of the log messages that hold each page's current
+    // state, and the lsn of the replacement they start with.
+    page_lens: HashMap<PageID, (u64, Lsn)>,
 }
 
 /// A `Segment` holds the bookkeeping information for
@@ -307,6 +310,7 @@ impl SegmentAccountant {
             pause_rewriting: false,
             safety_buffer: vec![],
             ordering: BTreeMap::new(),
+            page_lens: HashMap::new(),
         };
 
         if let SegmentMode::Linear = ret.config.segment_mode {
@@ -334,6 +338,12 @@ impl SegmentAccountant {
     fn initialize_from_snapshot<R>(&mut self, snapshot: Snapshot<R>) {
         let io_buf_size = self.config.io_buf_size;
 
+        self.page_lens = snapshot
+            .page_lens
+            .into_iter()
+            .map(|(pid, len)| (pid, (len, 0)))
+            .collect();
+
         // generate segments from snapshot lids
         let mut segments = vec![];
 
@@ -525,18 +535,22 @@ impl SegmentAccountant {
         // if our ordering contains anything higher than
         // what our snapshot logic scanned, it means it's
         // empty, and we should nuke it to prevent incorrect
-        // recoveries.
+        // recoveries. in read-only mode the file is left alone,
+        // and the segment is only forgotten.
         let mut to_zero = vec![];
         for (&lsn, &lid) in &self.ordering {
             if lsn <= snapshot_max_lsn {
                 continue;
             }
+            to_zero.push(lsn);
+            if self.config.read_only {
+                continue;
+            }
             warn!(
                 "zeroing out empty segment header at lsn {} lid {}",
                 lsn,
                 lid
             );
-            to_zero.push(lsn);
             let f = self.config.file()?;
             maybe_fail!("zero garbage segment");
             f.pwrite_all(&*vec![EVIL_BYTE; SEG_HEADER_LEN], lid)?;
@@ -783,6 +797,50 @@ impl SegmentAccountant {
         segment.insert_pid(pid, segment_lsn);
     }
 
+    /// Called from `PageCache` after a message of `len` bytes has been
+    /// linked onto a page, or has replaced it if `replaced` is set. We
+    /// track how much of the log holds the page's current state.
+    pub fn mark_page_len(
+        &mut self,
+        pid: PageID,
+        lsn: Lsn,
+        len: u64,
+        replaced: bool,
+    ) {
+        let entry = self.page_lens.entry(pid).or_insert((0, 0));
+        if entry.1 > lsn {
+            // the page was replaced after this message was
+            // reserved, so the replacement already covers it
+            return;
+        }
+        if replaced {
+            *entry = (len, lsn);
+        } else {
+            entry.0 += len;
+        }
+    }
+
+    /// Called from `PageCache` when a page has been freed.
+    pub fn forget_page_len(&mut self, pid: PageID) {
+        self.page_lens.remove(&pid);
+    }
+
+    /// Returns the bytes of the log messages that
+    /// hold the current state of the provided pages.
+    pub fn pages_len(&self, pids: &[PageID]) -> u64 {
+        pids.iter()
+            .filter_map(|pid| self.page_lens.get(pid))
+            .map(|&(len, _lsn)| len)
+            .sum()
+    }
+
+    /// Returns the bytes of every segment that is not free, including
+    /// the parts of them that hold replaced state awaiting cleaning.
+    pub fn size_on_disk(&self) -> u64 {
+        let live = self.segments.iter().filter(|s| s.state != Free).count();
+        live as u64 * self.config.io_buf_size as u64
+    }
+
     /// Called after the trailer of a segment has been written to disk,
     /// indicating that no more pids will be added to a segment. Moves
     /// the segment into the Inactive state.
@@ -1224,7 +1282,8 @@ pub fn raw_segment_iter_from(
         segment_base: None,
         segment_iter: segment_iter,
         segment_len: config.io_buf_size,
-        use_compression: config.use_compression,
         trailer: None,
+        info: RecoveryInfo::default(),
+        discarded_from: None,
     })
 }
@@ -24,6 +24,8 @@ pub struct Snapshot<R> {
     pub replacements: HashMap<SegmentID, HashSet<(PageID, Lsn)>>,
     /// the free pids
     pub free: HashSet<PageID>,
+    /// the bytes of the log messages that hold each page's state
+    pub page_lens: HashMap<PageID, u64>,
     /// the `Materializer`-specific recovered state
     pub recovery: Option<R>,
 }
@@ -78,6 +80,7 @@ impl<R> Default for Snapshot<R> {
             pt: HashMap::new(),
             replacements: HashMap::new(),
             free: HashSet::new(),
+            page_lens: HashMap::new(),
             recovery: None,
         }
     }
@@ -119,6 +122,7 @@ impl<R> Snapshot<R> {
 
         let prepend = deserialization.unwrap();
         let pid = prepend.pid;
+        let len = (MSG_HEADER_LEN + bytes.len()) as u64;
 
         if pid >= self.max_pid {
             self.max_pid = pid + 1;
@@ -152,6 +156,7 @@ impl<R> Snapshot<R> {
                     }
 
                     lids.push((lsn, log_id));
+                    *self.page_lens.entry(pid).or_insert(0) += len;
                 }
                 self.free.remove(&pid);
             }
@@ -163,6 +168,7 @@ impl<R> Snapshot<R> {
 
                 self.replace_pid(pid, replaced_at_idx, lsn, io_buf_size);
                 self.pt.insert(pid, PageState::Present(vec![(lsn, log_id)]));
+                self.page_lens.insert(pid, len);
                 self.free.remove(&pid);
             }
             Update::Allocate => {
@@ -174,12 +180,14 @@ impl<R> Snapshot<R> {
                 );
                 self.replace_pid(pid, replaced_at_idx, lsn, io_buf_size);
                 self.pt.insert(pid, PageState::Allocated(lsn, log_id));
+                self.page_lens.insert(pid, len);
                 self.free.remove(&pid);
             }
             Update::Free => {
                 trace!("free of pid {} at lid {} lsn {}", pid, log_id, lsn);
                 self.replace_pid(pid, replaced_at_idx, lsn, io_buf_size);
                 self.pt.insert(pid, PageState::Free(lsn, log_id));
+                self.page_lens.remove(&pid);
                 self.free.insert(pid);
             }
         }
@@ -221,7 +229,7 @@ impl<R> Snapshot<R> {
 }
 
 pub(super) fn advance_snapshot<PM, P, R>(
-    iter: LogIter,
+    iter: &mut LogIter,
     mut snapshot: Snapshot<R>,
     config: &Config,
 ) -> CacheResult<Snapshot<R>, ()>
@@ -243,7 +251,7 @@ pub(super) fn advance_snapshot<PM, P, R>(
 
     let io_buf_size = config.io_buf_size;
 
-    for (lsn, log_id, bytes) in iter {
+    while let Some((lsn, log_id, bytes)) = iter.next() {
         let segment_idx = log_id as SegmentID / io_buf_size;
 
         trace!(
@@ -265,6 +273,7 @@ pub(super) fn advance_snapshot<PM, P, R>(
         }
 
         assert!(lsn > snapshot.max_lsn);
+        iter.info.records_replayed += 1;
         snapshot.max_lsn = lsn;
         snapshot.last_lid = log_id;
 
@@ -276,7 +285,10 @@ pub(super) fn advance_snapshot<PM, P, R>(
         }
     }
 
-    write_snapshot(config, &snapshot)?;
+    // a read-only system only recovers into memory
+    if !config.read_only {
+        write_snapshot(config, &snapshot)?;
+    }
 
     trace!("generated new snapshot: {:?}", snapshot);
 
@@ -286,10 +298,11 @@ pub(super) fn advance_snapshot<PM, P, R>(
 }
 
 /// Read a `Snapshot` or generate a default, then advance it to
-/// the tip of the data file, if present.
+/// the tip of the data file, if present. Also returns the iterator
+/// that read the log, which knows what was found along the way.
 pub fn read_snapshot_or_default<PM, P, R>(
     config: &Config,
-) -> CacheResult<Snapshot<R>, ()>
+) -> CacheResult<(Snapshot<R>, LogIter), ()>
     where PM: Materializer<Recovery = R, PageFrag = P>,
           P: 'static
                  + Debug
@@ -300,14 +313,22 @@ pub fn read_snapshot_or_default<PM, P, R>(
                  + Sync,
           R: Debug + Clone + Serialize + DeserializeOwned + Send
 {
+    // opening the data file checks that the system was written in a
+    // format we can read, before any of it is recovered or discarded.
+    config.file()?;
+
     let last_snap = read_snapshot(config)?.unwrap_or_else(Snapshot::default);
 
-    let log_iter = raw_segment_iter_from(last_snap.max_lsn, config)?;
+    let mut log_iter = raw_segment_iter_from(last_snap.max_lsn, config)?;
 
-    advance_snapshot::<PM, P, R>(log_iter, last_snap, config)
+    let snapshot =
+        advance_snapshot::<PM, P, R>(&mut log_iter, last_snap, config)?;
+
+    Ok((snapshot, log_iter))
 }
 
-/// Read a `Snapshot` from disk.
+/// Read the newest `Snapshot` from disk that passes its checks,
+/// falling back to older generations if newer ones are corrupt.
 fn read_snapshot<R>(config: &Config) -> std::io::Result<Option<Snapshot<R>>>
     where R: Debug + Clone + Serialize + DeserializeOwned + Send
 {
@@ -319,11 +340,50 @@ fn read_snapshot<R>(config: &Config) -> std::io::Result<Option<Snapshot<R>>>
 
     candidates.sort();
 
-    let path = candidates.pop().unwrap();
+    while let Some(path) = candidates.pop() {
+        match read_snapshot_file(&path) {
+            Ok(Some(snapshot)) => return Ok(Some(snapshot)),
+            Ok(None) => {
+                warn!(
+                    "falling back to the snapshot before {:?}, \
+                     and replaying more of the log",
+                    path
+                );
 
-    let mut f = std::fs::OpenOptions::new().read(true).open(&path)?;
+                // a corrupt snapshot can never be used, and must not
+                // be kept around as the fallback for a newer one.
+                if !config.read_only {
+                    if let Err(e) = std::fs::remove_file(&path) {
+                        warn!(
+                            "failed to remove corrupt snapshot file {:?}: {}",
+                            path,
+                            e
+                        );
+                    }
+                }
+            }
+            // a system that's running in another process may have
+            // replaced it with a newer snapshot since we looked.
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return read_snapshot(config);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    warn!("no valid snapshot found, replaying the whole log");
+    Ok(None)
+}
+
+/// Read a `Snapshot` from a file, returning `None` if it's corrupt.
+fn read_snapshot_file<R>(
+    path: &std::path::Path,
+) -> std::io::Result<Option<Snapshot<R>>>
+    where R: Debug + Clone + Serialize + DeserializeOwned + Send
+{
+    let mut f = std::fs::OpenOptions::new().read(true).open(path)?;
     if f.metadata()?.len() <= 16 {
-        warn!("empty/corrupt snapshot file found");
+        warn!("empty/corrupt snapshot file {:?} found", path);
         return Ok(None);
     }
 
@@ -333,12 +393,12 @@ fn read_snapshot<R>(config: &Config) -> std::io::Result<Option<Snapshot<R>>>
     buf.split_off(len - 16);
 
     let mut len_expected_bytes = [0u8; 8];
-    f.seek(std::io::SeekFrom::End(-16)).unwrap();
-    f.read_exact(&mut len_expected_bytes).unwrap();
+    f.seek(std::io::SeekFrom::End(-16))?;
+    f.read_exact(&mut len_expected_bytes)?;
 
     let mut crc_expected_bytes = [0u8; 8];
-    f.seek(std::io::SeekFrom::End(-8)).unwrap();
-    f.read_exact(&mut crc_expected_bytes).unwrap();
+    f.seek(std::io::SeekFrom::End(-8))?;
+    f.read_exact(&mut crc_expected_bytes)?;
     let crc_expected: u64 = unsafe { std::mem::transmute(crc_expected_bytes) };
 
     let crc_actual = crc64(&*buf);
@@ -348,19 +408,44 @@ fn read_snapshot<R>(config: &Config) -> std::io::Result<Option<Snapshot<R>>>
         return Ok(None);
     }
 
+    // snapshots are only stored compressed if that made them
+    // smaller, so they're compressed if they're shorter than
+    // their uncompressed length.
+    let len_expected: u64 = unsafe { std::mem::transmute(len_expected_bytes) };
+    let compressed = (buf.len() as u64) < len_expected;
+
     #[cfg(feature = "zstd")]
-    let bytes = if config.use_compression {
-        let len_expected: u64 =
-            unsafe { std::mem::transmute(len_expected_bytes) };
-        decompress(&*buf, len_expected as usize).unwrap()
+    let bytes = if compressed {
+        match decompress(&*buf, len_expected as usize) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                error!("failed to decompress snapshot file {:?}: {}", path, e);
+                return Ok(None);
+            }
+        }
     } else {
         buf
     };
 
     #[cfg(not(feature = "zstd"))]
-    let bytes = buf;
+    let bytes = if compressed {
+        warn!(
+            "ignoring snapshot file {:?}, which is compressed and \
+             can't be read without the zstd feature",
+            path
+        );
+        return Ok(None);
+    } else {
+        buf
+    };
 
-    Ok(deserialize::<Snapshot<R>>(&*bytes).ok())
+    match deserialize::<Snapshot<R>>(&*bytes) {
+        Ok(snapshot) => Ok(Some(snapshot)),
+        Err(e) => {
+            error!("failed to deserialize snapshot file {:?}: {}", path, e);
+            Ok(None)
+        }
+    }
 }
 
 pub fn write_snapshot<R>(
@@ -374,7 +459,13 @@ pub fn write_snapshot<R>(
 
     #[cfg(feature = "zstd")]
     let bytes = if config.use_compression {
-        compress(&*raw_bytes, config.zstd_compression_factor).unwrap()
+        let compressed =
+            compress(&*raw_bytes, config.zstd_compression_factor).unwrap();
+        if compressed.len() < raw_bytes.len() {
+            compressed
+        } else {
+            raw_bytes
+        }
     } else {
         raw_bytes
     };
@@ -419,23 +510,39 @@ pub fn write_snapshot<R>(
 
     trace!("renamed snapshot to {}", path_2.to_string_lossy());
 
-    // clean up any old snapshots
-    let candidates = config.get_snapshot_files()?;
+    // make the rename durable before removing anything it replaces
+    sync_dir(path_2.parent().unwrap())?;
+
+    // clean up old snapshots, keeping the one before this as a
+    // fallback in case this one is found to be corrupt later.
+    let mut candidates = config.get_snapshot_files()?;
+    candidates.retain(|path| path.file_name() < path_2.file_name());
+    candidates.sort();
+    candidates.pop();
     for path in candidates {
-        let path_str = path.file_name().unwrap().to_str().unwrap();
-        if !path_2.to_string_lossy().ends_with(&*path_str) {
-            debug!("removing old snapshot file {:?}", path);
+        debug!("removing old snapshot file {:?}", path);
 
-            maybe_fail!("snap write rm old");
+        maybe_fail!("snap write rm old");
 
-            if let Err(_e) = std::fs::remove_file(&path) {
-                // TODO should this just be a try return?
-                warn!(
-                    "failed to remove old snapshot file, maybe snapshot race? {}",
-                    _e
-                );
-            }
+        if let Err(_e) = std::fs::remove_file(&path) {
+            // TODO should this just be a try return?
+            warn!(
+                "failed to remove old snapshot file, maybe snapshot race? {}",
+                _e
+            );
         }
     }
     Ok(())
 }
+
+#[cfg(unix)]
+fn sync_dir(dir: &std::path::Path) -> std::io::Result<()> {
+    std::fs::File::open(dir)?.sync_all()
+}
+
+// directories can't be opened as files on windows, where
+// renames are made durable with the file system's journal.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &std::path::Path) -> std::io::Result<()> {
+    Ok(())
+}
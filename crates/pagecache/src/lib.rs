@@ -35,7 +35,10 @@ pub use ds::{Radix, Stack};
 
 /// general-purpose configuration
 pub use config::{Config, ConfigBuilder};
+/// streaming checksums
+pub use hash::crc64_update;
 pub use io::*;
+pub use periodic::Maintenance;
 pub use result::{CacheResult, Error};
 
 macro_rules! maybe_fail {
@@ -69,7 +72,7 @@ mod result;
 // use log::{Iter, MessageHeader, SegmentHeader, SegmentTrailer};
 use metrics::Metrics;
 use ds::*;
-use hash::{crc16_arr, crc64};
+use hash::{crc16_arr, crc16_update, crc64};
 use historian::Histo;
 
 /// An offset for a storage file segment.
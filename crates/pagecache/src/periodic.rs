@@ -1,9 +1,7 @@
 use std::marker::PhantomData;
-use std::sync::Arc;
-use std::sync::atomic::AtomicBool;
-use std::sync::atomic::Ordering::{Acquire, Release};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use super::*;
 
@@ -11,8 +9,18 @@ pub trait Callback: Send + 'static {
     fn call(&self);
 }
 
+/// Work that is performed on the log's periodic flush thread, after
+/// each flush. See `PageCache::add_maintenance`.
+pub trait Maintenance: Send + Sync {
+    /// Perform a small, bounded amount of work. Flushes are delayed
+    /// until this returns.
+    fn maintain(&self);
+}
+
 pub struct Periodic<C: Callback> {
-    shutdown: Arc<AtomicBool>,
+    // set when we're dropped, which wakes the thread up
+    // rather than waiting for the rest of its interval.
+    shutdown: Arc<(Mutex<bool>, Condvar)>,
     join_handle: Option<std::thread::JoinHandle<()>>,
     _marker: PhantomData<C>,
 }
@@ -24,16 +32,31 @@ impl<C: Callback> Periodic<C> {
         callback: C,
         flush_every_ms: Option<u64>,
     ) -> Periodic<C> {
-        let shutdown = Arc::new(AtomicBool::new(false));
+        let shutdown = Arc::new((Mutex::new(false), Condvar::new()));
 
         let join_handle = flush_every_ms.map(|flush_every_ms| {
             let shutdown = shutdown.clone();
+            let interval = Duration::from_millis(flush_every_ms);
             thread::Builder::new()
                 .name(name)
-                .spawn(move || while !shutdown.load(Acquire) {
+                .spawn(move || loop {
                     callback.call();
 
-                    thread::sleep(Duration::from_millis(flush_every_ms));
+                    let deadline = Instant::now() + interval;
+                    let (ref lock, ref cvar) = *shutdown;
+                    let mut stopped = lock.lock().unwrap();
+                    loop {
+                        if *stopped {
+                            return;
+                        }
+                        let now = Instant::now();
+                        if now >= deadline {
+                            break;
+                        }
+                        stopped = cvar.wait_timeout(stopped, deadline - now)
+                            .unwrap()
+                            .0;
+                    }
                 })
                 .unwrap()
         });
@@ -49,7 +72,17 @@ impl<C: Callback> Periodic<C> {
 impl<C: Callback> Drop for Periodic<C> {
     fn drop(&mut self) {
         if let Some(join_handle) = self.join_handle.take() {
-            self.shutdown.store(true, Release);
+            {
+                let (ref lock, ref cvar) = *self.shutdown;
+                *lock.lock().unwrap() = true;
+                cvar.notify_all();
+            }
+            // maintenance tasks run on our thread, and may drop
+            // the last handle to whatever owns us. the thread
+            // exits on its own after its current call.
+            if join_handle.thread().id() == thread::current().id() {
+                return;
+            }
             if let Err(e) = join_handle.join() {
                 error!("error joining Periodic thread: {:?}", e);
             }
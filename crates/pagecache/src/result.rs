@@ -1,6 +1,7 @@
 use std::cmp::PartialEq;
 use std::fmt::{self, Debug, Display};
 use std::io;
+use std::path::PathBuf;
 use std::error::Error as StdError;
 
 use super::*;
@@ -26,6 +27,23 @@ pub enum Error<Actual> {
         /// The file location that corrupted data was found at.
         at: LogID,
     },
+    /// Something was larger than a configured limit allows.
+    TooLarge {
+        /// The name of the limit, such as `max_value_size`.
+        limit: &'static str,
+        /// The limit, in bytes.
+        max: usize,
+        /// The size that was over the limit, in bytes.
+        size: usize,
+    },
+    /// Stored bytes could not be decoded as the type they were
+    /// expected to hold, or a value could not be encoded.
+    Serialization(String),
+    /// A write was attempted on a system opened in read-only mode.
+    ReadOnly,
+    /// The files at the provided path are in use by another running
+    /// system, which may be in another process.
+    Busy(PathBuf),
     // a failpoint has been triggered for testing purposes
     #[doc(hidden)]
     #[cfg(feature = "failpoints")]
@@ -60,6 +78,21 @@ impl<A> PartialEq for Error<A>
                     false
                 }
             }
+            &Serialization(ref l) => {
+                if let &Serialization(ref r) = other {
+                    l == r
+                } else {
+                    false
+                }
+            }
+            &ReadOnly => if let &ReadOnly = other { true } else { false },
+            &Busy(ref l) => {
+                if let &Busy(ref r) = other {
+                    l == r
+                } else {
+                    false
+                }
+            }
             #[cfg(feature = "failpoints")]
             &FailPoint => if let &FailPoint = other { true } else { false },
             &Corruption {
@@ -74,6 +107,22 @@ impl<A> PartialEq for Error<A>
                     false
                 }
             }
+            &TooLarge {
+                limit: l_limit,
+                max: l_max,
+                size: l_size,
+            } => {
+                if let &TooLarge {
+                    limit: r_limit,
+                    max: r_max,
+                    size: r_size,
+                } = other
+                {
+                    l_limit == r_limit && l_max == r_max && l_size == r_size
+                } else {
+                    false
+                }
+            }
             &Io(_) => false,
         }
     }
@@ -94,12 +143,18 @@ impl<T> StdError for Error<T>
             CasFailed(_) => "Compare and swap failed to successfully compare.",
             Unsupported(ref e) => &*e,
             ReportableBug(ref e) => &*e,
+            Serialization(ref e) => &*e,
+            ReadOnly => "The system is in read-only mode.",
+            Busy(_) => "The system is in use by another running system.",
             #[cfg(feature = "failpoints")]
             FailPoint => "Fail point has been triggered.",
             Io(ref e) => e.description(),
             Corruption {
                 ..
             } => "Read corrupted data.",
+            TooLarge {
+                ..
+            } => "Something was larger than a configured limit allows.",
         }
     }
 }
@@ -126,12 +181,35 @@ impl<A> Display for Error<A>
                     e
                 )
             }
+            Serialization(ref e) => write!(f, "Serialization error: {}", e),
+            ReadOnly => write!(f, "The system is in read-only mode."),
+            Busy(ref path) => {
+                write!(
+                    f,
+                    "The system at {:?} is in use by another running \
+                    system, which may be in another process.",
+                    path
+                )
+            }
             #[cfg(feature = "failpoints")]
             FailPoint => write!(f, "Fail point has been triggered."),
             Io(ref e) => write!(f, "IO error: {}", e),
             Corruption {
                 at,
             } => write!(f, "Read corrupted data at file offset {}", at),
+            TooLarge {
+                limit,
+                max,
+                size,
+            } => {
+                write!(
+                    f,
+                    "Too large: {} bytes is more than the {} of {} bytes",
+                    size,
+                    limit,
+                    max
+                )
+            }
         }
     }
 }
@@ -152,6 +230,9 @@ impl<T> Error<T> {
             }
             Unsupported(s) => Unsupported(s),
             ReportableBug(s) => ReportableBug(s),
+            Serialization(s) => Serialization(s),
+            ReadOnly => ReadOnly,
+            Busy(path) => Busy(path),
             #[cfg(feature = "failpoints")]
             FailPoint => FailPoint,
             Io(e) => Io(e),
@@ -160,6 +241,15 @@ impl<T> Error<T> {
             } => Corruption {
                 at,
             },
+            TooLarge {
+                limit,
+                max,
+                size,
+            } => TooLarge {
+                limit,
+                max,
+                size,
+            },
         }
     }
 
@@ -171,6 +261,9 @@ impl<T> Error<T> {
             CasFailed(other) => CasFailed(other.into()),
             Unsupported(s) => Unsupported(s),
             ReportableBug(s) => ReportableBug(s),
+            Serialization(s) => Serialization(s),
+            ReadOnly => ReadOnly,
+            Busy(path) => Busy(path),
             #[cfg(feature = "failpoints")]
             FailPoint => FailPoint,
             Io(e) => Io(e),
@@ -179,6 +272,15 @@ impl<T> Error<T> {
             } => Corruption {
                 at,
             },
+            TooLarge {
+                limit,
+                max,
+                size,
+            } => TooLarge {
+                limit,
+                max,
+                size,
+            },
         }
     }
 }
@@ -0,0 +1,58 @@
+use std::collections::BTreeMap;
+use std::mem;
+
+use super::*;
+
+/// A group of inserts and removals that `Tree::apply_batch`
+/// applies atomically. If the same key is written more than
+/// once, the last write wins.
+///
+/// # Examples
+///
+/// ```
+/// let config = sled::ConfigBuilder::new().temporary(true).build();
+/// let t = sled::Tree::start(config).unwrap();
+/// t.set(vec![1], vec![10]).unwrap();
+///
+/// let mut batch = sled::Batch::default();
+/// batch.insert(vec![2], vec![20]);
+/// batch.insert(vec![3], vec![30]);
+/// batch.remove(vec![1]);
+/// batch.remove(vec![3]);
+/// t.apply_batch(batch).unwrap();
+///
+/// assert_eq!(t.get(&[1]), Ok(None));
+/// assert_eq!(t.get(&[2]), Ok(Some(vec![20].into())));
+/// assert_eq!(t.get(&[3]), Ok(None));
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Batch {
+    pub(crate) writes: BTreeMap<Key, Option<Value>>,
+}
+
+impl Batch {
+    // roughly the bytes held on the heap, by the map and its entries
+    pub(crate) fn heap_size(&self) -> usize {
+        self.writes
+            .iter()
+            .map(|(k, v)| {
+                mem::size_of::<(Key, Option<Value>)>() + k.capacity() +
+                    v.as_ref().map(|v| v.capacity()).unwrap_or(0)
+            })
+            .sum()
+    }
+
+    /// Set a key to a new value.
+    pub fn insert(&mut self, key: Key, value: Value) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Remove a key.
+    pub fn remove(&mut self, key: Key) {
+        self.writes.insert(key, None);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+}
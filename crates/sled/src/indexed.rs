@@ -0,0 +1,275 @@
+use std::collections::BTreeSet;
+use std::sync::RwLockWriteGuard;
+
+use super::*;
+
+/// Extracts the keys that a primary key and value are found
+/// under in a secondary index. Returning an empty `Vec` leaves
+/// the entry out of the index.
+pub type IndexExtractor = fn(&[u8], &[u8]) -> Vec<Vec<u8>>;
+
+struct Index {
+    name: Vec<u8>,
+    tree: Tree,
+    extractor: IndexExtractor,
+}
+
+/// A `Tree` of primary records, along with secondary indexes over
+/// them that are stored in `Tree`s of their own. Writing through an
+/// `IndexedTree` updates the records and every index atomically, so
+/// concurrent readers and crashes never see a record without its
+/// index entries, or index entries for a record that's gone.
+///
+/// Each index maps the keys that its extractor returns for a record
+/// to that record's primary key, and many records may share an index
+/// key. Index trees are only kept up to date by writes made through
+/// the `IndexedTree`, so they shouldn't be written to directly, and
+/// an index added for records that already exist will be missing
+/// them until they're written again.
+///
+/// # Examples
+///
+/// ```
+/// fn by_city(_id: &[u8], user: &[u8]) -> Vec<Vec<u8>> {
+///     // users are stored as "name,city"
+///     user.split(|&b| b == b',').skip(1).map(|c| c.to_vec()).collect()
+/// }
+///
+/// let config = sled::ConfigBuilder::new().temporary(true).build();
+/// let db = sled::Db::start(config).unwrap();
+/// let users = db.open_tree(b"users".to_vec()).unwrap();
+/// let users = sled::IndexedTree::new(users)
+///     .with_index(
+///         b"by_city".to_vec(),
+///         db.open_tree(b"users_by_city".to_vec()).unwrap(),
+///         by_city,
+///     )
+///     .unwrap();
+///
+/// users.set(vec![1], b"alice,paris".to_vec()).unwrap();
+/// users.set(vec![2], b"bob,oslo".to_vec()).unwrap();
+/// users.set(vec![3], b"carol,paris".to_vec()).unwrap();
+/// users.set(vec![1], b"alice,oslo".to_vec()).unwrap();
+///
+/// assert_eq!(
+///     users.get_by_index(b"by_city", b"oslo"),
+///     Ok(vec![
+///         (vec![1], b"alice,oslo".to_vec().into()),
+///         (vec![2], b"bob,oslo".to_vec().into()),
+///     ])
+/// );
+/// assert_eq!(
+///     users.get_by_index(b"by_city", b"paris"),
+///     Ok(vec![(vec![3], b"carol,paris".to_vec().into())])
+/// );
+/// ```
+pub struct IndexedTree {
+    primary: Tree,
+    indexes: Vec<Index>,
+}
+
+impl IndexedTree {
+    /// Wrap a `Tree` of primary records, which starts out without
+    /// any indexes.
+    pub fn new(primary: Tree) -> IndexedTree {
+        IndexedTree {
+            primary: primary,
+            indexes: vec![],
+        }
+    }
+
+    /// Add an index with the provided name, stored in `tree`, whose
+    /// keys are found by `extractor`. The index's `Tree` must belong to
+    /// the same `Db` as the primary `Tree`, so that they can be written
+    /// atomically, and can't be shared with the primary or another
+    /// index.
+    pub fn with_index(
+        mut self,
+        name: Vec<u8>,
+        tree: Tree,
+        extractor: IndexExtractor,
+    ) -> DbResult<IndexedTree, ()> {
+        if tree.db_id() != self.primary.db_id() {
+            return Err(Error::Unsupported(
+                "an index must be stored in the same Db as its primary Tree"
+                    .to_owned(),
+            ));
+        }
+        let shared = tree.tree_id() == self.primary.tree_id() ||
+            self.indexes.iter().any(|i| i.tree.tree_id() == tree.tree_id());
+        if shared {
+            return Err(Error::Unsupported(
+                "an index must be stored in a Tree of its own".to_owned(),
+            ));
+        }
+        if self.indexes.iter().any(|i| i.name == name) {
+            return Err(Error::Unsupported(format!(
+                "there is already an index named {:?}",
+                String::from_utf8_lossy(&*name)
+            )));
+        }
+
+        self.indexes.push(Index {
+            name: name,
+            tree: tree,
+            extractor: extractor,
+        });
+        Ok(self)
+    }
+
+    /// The `Tree` holding the primary records.
+    pub fn primary(&self) -> &Tree {
+        &self.primary
+    }
+
+    /// Retrieve a primary record.
+    pub fn get(&self, key: &[u8]) -> DbResult<Option<IVec>, ()> {
+        self.primary.get(key)
+    }
+
+    /// Retrieve the primary records that the named index finds under
+    /// `index_key`, in order of their primary keys. The index and the
+    /// records are read separately, so records written in the meantime
+    /// are only returned if they still have `index_key`.
+    pub fn get_by_index(
+        &self,
+        name: &[u8],
+        index_key: &[u8],
+    ) -> DbResult<Vec<(Key, IVec)>, ()> {
+        let index = match self.indexes.iter().find(|i| i.name == name) {
+            Some(index) => index,
+            None => {
+                return Err(Error::Unsupported(format!(
+                    "there is no index named {:?}",
+                    String::from_utf8_lossy(name)
+                )))
+            }
+        };
+
+        let mut keys = vec![];
+        for res in index.tree.scan_prefix(&*index_prefix(index_key)).values() {
+            keys.push(res?);
+        }
+
+        let values = self.primary.multi_get(&keys);
+        let mut records = vec![];
+        for (key, value) in keys.into_iter().zip(values) {
+            if let Some(value) = value? {
+                if extract(index, &key, Some(&value)).contains(index_key) {
+                    records.push((key.to_vec(), value));
+                }
+            }
+        }
+        Ok(records)
+    }
+
+    /// Set a primary record, and update every index to match.
+    pub fn set(&self, key: Key, value: Value) -> DbResult<(), ()> {
+        let mut batch = Batch::default();
+        batch.insert(key, value);
+        self.apply_batch(batch)
+    }
+
+    /// Delete a primary record and its index entries, returning the
+    /// record if it existed.
+    pub fn del(&self, key: &[u8]) -> DbResult<Option<IVec>, ()> {
+        if self.primary.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+
+        let _cc = self.write_locks();
+        self.primary.check_dropped()?;
+        let old = self.primary.get_inner(key)?;
+        if old.is_some() {
+            let mut batch = Batch::default();
+            batch.remove(key.to_vec());
+            self.apply_batch_inner(batch)?;
+        }
+        Ok(old)
+    }
+
+    /// Atomically apply a `Batch` of writes to primary records, along
+    /// with the changes they make to every index.
+    pub fn apply_batch(&self, batch: Batch) -> DbResult<(), ()> {
+        if self.primary.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+
+        let _cc = self.write_locks();
+        self.primary.check_dropped()?;
+        self.apply_batch_inner(batch)
+    }
+
+    // takes the write locks of the primary tree and every index
+    fn write_locks(&self) -> Vec<RwLockWriteGuard<()>> {
+        let mut trees = vec![&self.primary];
+        trees.extend(self.indexes.iter().map(|index| &index.tree));
+        Tree::write_locks(&trees)
+    }
+
+    // callers must hold the write locks.
+    fn apply_batch_inner(&self, batch: Batch) -> DbResult<(), ()> {
+        let mut index_batches: Vec<Batch> =
+            self.indexes.iter().map(|_| Batch::default()).collect();
+
+        for (key, new) in &batch.writes {
+            let old = self.primary.get_inner(key)?;
+            for (index, index_batch) in
+                self.indexes.iter().zip(index_batches.iter_mut())
+            {
+                let old_keys = extract(index, key, old.as_ref());
+                let new_keys = extract(index, key, new.as_ref());
+                for stale in old_keys.difference(&new_keys) {
+                    index_batch.remove(index_entry(stale, key));
+                }
+                for fresh in new_keys.difference(&old_keys) {
+                    index_batch.insert(index_entry(fresh, key), key.clone());
+                }
+            }
+        }
+
+        let mut batches = vec![(&self.primary, batch)];
+        for (index, index_batch) in self.indexes.iter().zip(index_batches) {
+            if !index_batch.is_empty() {
+                batches.push((&index.tree, index_batch));
+            }
+        }
+        Tree::apply_batches_inner(batches)
+    }
+}
+
+fn extract<V: AsRef<[u8]>>(
+    index: &Index,
+    key: &[u8],
+    value: Option<&V>,
+) -> BTreeSet<Vec<u8>> {
+    match value {
+        Some(value) => {
+            (index.extractor)(key, value.as_ref()).into_iter().collect()
+        }
+        None => BTreeSet::new(),
+    }
+}
+
+// index entries are keyed by the index key followed by the primary
+// key, so that records sharing an index key are kept apart. the index
+// key has its 0 bytes escaped as [0, 255] and is ended by [0, 0], so
+// no index key's entries are a prefix of another's, and index keys
+// keep their order.
+fn index_prefix(index_key: &[u8]) -> Key {
+    let mut prefix = Vec::with_capacity(index_key.len() + 2);
+    for &b in index_key {
+        prefix.push(b);
+        if b == 0 {
+            prefix.push(255);
+        }
+    }
+    prefix.extend_from_slice(&[0, 0]);
+    prefix
+}
+
+fn index_entry(index_key: &[u8], key: &[u8]) -> Key {
+    let mut entry = index_prefix(index_key);
+    entry.extend_from_slice(key);
+    entry
+}
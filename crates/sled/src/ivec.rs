@@ -0,0 +1,196 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::{self, SeqAccess, Visitor};
+
+// the most bytes that are stored in an `IVec` itself. with the length
+// and the tag, this makes an inline `IVec` as large as a remote one.
+const INLINE_LEN: usize = 22;
+
+/// A buffer of bytes that is cheap to clone, returned as the value of
+/// reads from a `Tree`. Values of up to 22 bytes are stored inline,
+/// without allocating, and larger values are shared between clones
+/// instead of being copied. `IVec` derefs to `[u8]`, and is compared,
+/// ordered and hashed by its contents.
+///
+/// # Examples
+///
+/// ```
+/// let config = sled::ConfigBuilder::new().temporary(true).build();
+/// let t = sled::Tree::start(config).unwrap();
+/// t.set(vec![1], vec![10, 20]).unwrap();
+///
+/// let value: sled::IVec = t.get(&[1]).unwrap().unwrap();
+/// assert_eq!(&*value, &[10, 20]);
+/// assert_eq!(value, vec![10, 20]);
+/// assert_eq!(value.to_vec(), vec![10, 20]);
+/// ```
+#[derive(Clone)]
+pub struct IVec(Inner);
+
+#[derive(Clone)]
+enum Inner {
+    Inline(u8, [u8; INLINE_LEN]),
+    Remote(Arc<[u8]>),
+}
+
+impl IVec {
+    // the bytes held on the heap, which are shared between clones
+    pub(crate) fn heap_size(&self) -> usize {
+        match self.0 {
+            Inner::Inline(..) => 0,
+            // the data, along with the Arc's two counts
+            Inner::Remote(ref buf) => buf.len() + 2 * mem::size_of::<usize>(),
+        }
+    }
+}
+
+impl Deref for IVec {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        match self.0 {
+            Inner::Inline(len, ref buf) => &buf[..len as usize],
+            Inner::Remote(ref buf) => &*buf,
+        }
+    }
+}
+
+impl AsRef<[u8]> for IVec {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        &**self
+    }
+}
+
+impl<'a> From<&'a [u8]> for IVec {
+    fn from(bytes: &'a [u8]) -> IVec {
+        if bytes.len() <= INLINE_LEN {
+            let mut buf = [0; INLINE_LEN];
+            buf[..bytes.len()].copy_from_slice(bytes);
+            IVec(Inner::Inline(bytes.len() as u8, buf))
+        } else {
+            IVec(Inner::Remote(bytes.into()))
+        }
+    }
+}
+
+impl From<Vec<u8>> for IVec {
+    fn from(bytes: Vec<u8>) -> IVec {
+        if bytes.len() <= INLINE_LEN {
+            IVec::from(&*bytes)
+        } else {
+            IVec(Inner::Remote(bytes.into()))
+        }
+    }
+}
+
+impl From<IVec> for Vec<u8> {
+    fn from(ivec: IVec) -> Vec<u8> {
+        ivec.to_vec()
+    }
+}
+
+impl PartialEq for IVec {
+    fn eq(&self, other: &IVec) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for IVec {}
+
+impl PartialEq<[u8]> for IVec {
+    fn eq(&self, other: &[u8]) -> bool {
+        &**self == other
+    }
+}
+
+impl PartialEq<Vec<u8>> for IVec {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        **self == **other
+    }
+}
+
+impl PartialEq<IVec> for [u8] {
+    fn eq(&self, other: &IVec) -> bool {
+        self == &**other
+    }
+}
+
+impl PartialEq<IVec> for Vec<u8> {
+    fn eq(&self, other: &IVec) -> bool {
+        **self == **other
+    }
+}
+
+impl PartialOrd for IVec {
+    fn partial_cmp(&self, other: &IVec) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for IVec {
+    fn cmp(&self, other: &IVec) -> Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl Hash for IVec {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl fmt::Debug for IVec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+// serialized the same way as a `Vec<u8>`, so that
+// either can be read back as the other.
+impl Serialize for IVec {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&*self)
+    }
+}
+
+impl<'de> Deserialize<'de> for IVec {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<IVec, D::Error> {
+        deserializer.deserialize_bytes(IVecVisitor)
+    }
+}
+
+struct IVecVisitor;
+
+impl<'de> Visitor<'de> for IVecVisitor {
+    type Value = IVec;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a byte array")
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<IVec, E> {
+        Ok(IVec::from(bytes))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<IVec, E> {
+        Ok(IVec::from(bytes))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<IVec, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(b) = seq.next_element()? {
+            bytes.push(b);
+        }
+        Ok(IVec::from(bytes))
+    }
+}
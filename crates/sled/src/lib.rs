@@ -8,7 +8,7 @@
 //! let t = sled::Tree::start(config).unwrap();
 //!
 //! t.set(b"yo!".to_vec(), b"v1".to_vec());
-//! assert_eq!(t.get(b"yo!"), Ok(Some(b"v1".to_vec())));
+//! assert_eq!(t.get(b"yo!"), Ok(Some(b"v1".to_vec().into())));
 //!
 //! t.cas(
 //!     b"yo!".to_vec(),       // key
@@ -17,7 +17,7 @@
 //! ).unwrap();
 //!
 //! let mut iter = t.scan(b"a non-present key before yo!");
-//! assert_eq!(iter.next(), Some(Ok((b"yo!".to_vec(), b"v2".to_vec()))));
+//! assert_eq!(iter.next(), Some(Ok((b"yo!".to_vec(), b"v2".to_vec().into()))));
 //! assert_eq!(iter.next(), None);
 //!
 //! t.del(b"yo!");
@@ -38,15 +38,57 @@ extern crate crossbeam_epoch as epoch;
 extern crate bincode;
 #[macro_use]
 extern crate log as _log;
+#[cfg(feature = "failpoints")]
+#[macro_use]
+extern crate fail;
+
+macro_rules! maybe_fail {
+    ($e:expr) => {
+        #[cfg(feature = "failpoints")]
+        fail_point!($e, |_| Err(Error::FailPoint));
+    }
+}
 
 /// atomic lock-free tree
-pub use tree::{Iter, Tree};
+pub use tree::{BulkLoader, ChangesIter, Clock, Db, Export, Iter, Keys,
+               ResumeToken, SnapshotIter, SpaceUsage, Tree, TreeExport,
+               TreeSnapshot, Values};
+
+/// cheaply cloned values
+pub use ivec::IVec;
+
+/// atomic multi-key writes
+pub use batch::Batch;
+
+/// atomic multi-tree transactions
+pub use transaction::{TransactionError, TransactionResult, Transactional,
+                      TransactionalTree};
+
+/// secondary indexes kept in step with a tree
+pub use indexed::{IndexExtractor, IndexedTree};
+
+/// trees of typed keys and values
+#[cfg(feature = "serde")]
+pub use typed::{KeyEncode, TypedIter, TypedTree};
+
+/// prefix change notification
+pub use subscription::{Event, Subscriber};
 
 use pagecache::*;
+use subscription::Subscriptions;
 
-pub use pagecache::{CacheResult as DbResult, Config, ConfigBuilder, Error};
+pub use pagecache::{CacheResult as DbResult, CacheStats, Config,
+                    ConfigBuilder, Error, FlushHandle, MergeOperator,
+                    RecoveryInfo};
 
+mod batch;
+mod indexed;
+mod ivec;
+mod subscription;
+mod transaction;
 mod tree;
+#[cfg(feature = "serde")]
+mod typed;
 
 type Key = Vec<u8>;
 type KeyRef<'a> = &'a [u8];
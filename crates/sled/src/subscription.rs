@@ -0,0 +1,225 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, TrySendError,
+                      sync_channel};
+use std::time::Duration;
+
+use super::*;
+
+// the number of events that may be waiting for each
+// subscriber before it is considered too slow.
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+type Senders = HashMap<usize, SyncSender<Event>>;
+
+/// A committed change to a key watched by a `Subscriber`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// The key was set, or merged into, and now has this value.
+    Insert {
+        /// The key that changed.
+        key: Key,
+        /// The value it changed to.
+        value: IVec,
+    },
+    /// The key was removed.
+    Remove {
+        /// The key that was removed.
+        key: Key,
+    },
+    /// Every key was removed by `Tree::clear`. This is sent to
+    /// every subscriber, regardless of the prefix it watches.
+    Clear,
+}
+
+/// Receives an `Event` for every change to keys starting with the
+/// prefix passed to `Tree::watch_prefix`, in the order the changes
+/// were committed. The events of a `Batch` or transaction arrive
+/// contiguously.
+///
+/// Each `Subscriber` buffers a bounded number of events. If it falls
+/// that far behind, it is disconnected instead of slowing down
+/// writers: the events buffered so far are still delivered, after
+/// which the `Subscriber` ends as if the `Tree` had been dropped.
+/// Anything relying on seeing every change, such as a cache, should
+/// treat the end of a `Subscriber` as having missed changes.
+///
+/// Dropping the `Subscriber` unregisters it.
+///
+/// # Examples
+///
+/// ```
+/// let config = sled::ConfigBuilder::new().temporary(true).build();
+/// let t = sled::Tree::start(config).unwrap();
+/// let mut subscriber = t.watch_prefix(vec![1]);
+///
+/// t.set(vec![1, 1], vec![10]).unwrap();
+/// t.set(vec![2, 1], vec![20]).unwrap();
+/// t.del(&[1, 1]).unwrap();
+///
+/// assert_eq!(
+///     subscriber.next(),
+///     Some(sled::Event::Insert { key: vec![1, 1], value: vec![10].into() })
+/// );
+/// assert_eq!(
+///     subscriber.next(),
+///     Some(sled::Event::Remove { key: vec![1, 1] })
+/// );
+/// ```
+pub struct Subscriber {
+    id: usize,
+    prefix: Vec<u8>,
+    rx: Receiver<Event>,
+    home: Arc<Subscriptions>,
+}
+
+impl Subscriber {
+    /// Wait up to `timeout` for the next `Event`. A zero timeout
+    /// polls without blocking. Returns `RecvTimeoutError::Disconnected`
+    /// once the `Subscriber` has ended and its buffer is drained.
+    pub fn next_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Event, RecvTimeoutError> {
+        self.rx.recv_timeout(timeout)
+    }
+}
+
+impl Iterator for Subscriber {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        self.rx.recv().ok()
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        let mut watched = self.home.lock();
+        let now_empty = match watched.get_mut(&self.prefix) {
+            Some(senders) => {
+                if senders.remove(&self.id).is_some() {
+                    self.home.count.fetch_sub(1, SeqCst);
+                }
+                senders.is_empty()
+            }
+            None => false,
+        };
+        if now_empty {
+            watched.remove(&self.prefix);
+        }
+    }
+}
+
+// the subscribers of one tree, keyed by the prefix they watch.
+#[derive(Default)]
+pub(crate) struct Subscriptions {
+    watched: Mutex<BTreeMap<Vec<u8>, Senders>>,
+    // the number of registered subscribers, so that
+    // writes can skip the mutex when there are none.
+    count: AtomicUsize,
+    next_id: AtomicUsize,
+}
+
+impl Subscriptions {
+    pub(crate) fn register(
+        this: &Arc<Subscriptions>,
+        prefix: Vec<u8>,
+    ) -> Subscriber {
+        let (tx, rx) = sync_channel(SUBSCRIBER_BUFFER);
+        let id = this.next_id.fetch_add(1, SeqCst);
+
+        this.lock()
+            .entry(prefix.clone())
+            .or_insert_with(HashMap::new)
+            .insert(id, tx);
+        this.count.fetch_add(1, SeqCst);
+
+        Subscriber {
+            id: id,
+            prefix: prefix,
+            rx: rx,
+            home: this.clone(),
+        }
+    }
+
+    // must be called before writing the key. while the returned
+    // reservation is held, writes to other watched keys wait, so
+    // that events are sent in the same order the writes landed.
+    pub(crate) fn reserve(&self, key: &[u8]) -> Option<Reservation> {
+        if self.count.load(SeqCst) == 0 {
+            return None;
+        }
+
+        let watched = self.lock();
+        let is_watched = watched.keys().any(|prefix| key.starts_with(prefix));
+        if is_watched {
+            Some(Reservation {
+                watched: watched,
+                count: &self.count,
+            })
+        } else {
+            None
+        }
+    }
+
+    // like reserve, for an event that every subscriber receives.
+    pub(crate) fn reserve_all(&self) -> Option<Reservation> {
+        if self.count.load(SeqCst) == 0 {
+            return None;
+        }
+
+        Some(Reservation {
+            watched: self.lock(),
+            count: &self.count,
+        })
+    }
+
+    // disconnects every subscriber, ending them once they're drained.
+    pub(crate) fn clear(&self) {
+        let mut watched = self.lock();
+        watched.clear();
+        self.count.store(0, SeqCst);
+    }
+
+    fn lock(&self) -> MutexGuard<BTreeMap<Vec<u8>, Senders>> {
+        self.watched.lock().expect(
+            "a thread panicked and poisoned a Tree's subscriptions mutex",
+        )
+    }
+}
+
+// the exclusive right to send the event for a write to a watched key.
+pub(crate) struct Reservation<'a> {
+    watched: MutexGuard<'a, BTreeMap<Vec<u8>, Senders>>,
+    count: &'a AtomicUsize,
+}
+
+impl<'a> Reservation<'a> {
+    pub(crate) fn complete(mut self, event: Event) {
+        let count = self.count;
+        let key = match event {
+            Event::Insert { ref key, .. } |
+            Event::Remove { ref key } => Some(key.clone()),
+            Event::Clear => None,
+        };
+        for (prefix, senders) in self.watched.iter_mut() {
+            match key {
+                Some(ref key) if !key.starts_with(prefix) => continue,
+                _ => {}
+            }
+            senders.retain(|_id, tx| match tx.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) |
+                Err(TrySendError::Disconnected(_)) => {
+                    // the subscriber is too slow or is being dropped
+                    count.fetch_sub(1, SeqCst);
+                    false
+                }
+            });
+        }
+        self.watched.retain(|_prefix, senders| !senders.is_empty());
+    }
+}
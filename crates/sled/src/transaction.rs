@@ -0,0 +1,349 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+use super::*;
+
+/// The result of a transaction.
+pub type TransactionResult<T, E = ()> = Result<T, TransactionError<E>>;
+
+/// An error that caused a transaction to stop without committing.
+#[derive(Debug, PartialEq)]
+pub enum TransactionError<E = ()> {
+    /// The transaction was explicitly aborted by returning
+    /// this variant from the transaction closure, and carries
+    /// the provided payload back to the caller.
+    Abort(E),
+    /// A storage error occurred while reading or committing.
+    Storage(Error<()>),
+}
+
+impl<E> From<Error<()>> for TransactionError<E> {
+    fn from(e: Error<()>) -> TransactionError<E> {
+        TransactionError::Storage(e)
+    }
+}
+
+/// A handle to a `Tree` used from within a transaction. Writes are
+/// buffered until the transaction commits, and are visible to later
+/// reads within the same transaction.
+pub struct TransactionalTree<'a> {
+    tree: &'a Tree,
+    // the first value observed for each key read from the tree
+    reads: RefCell<HashMap<Key, Option<IVec>>>,
+    // whether each key only checked with contains_key was present
+    presence: RefCell<HashMap<Key, bool>>,
+    writes: RefCell<BTreeMap<Key, Option<Value>>>,
+}
+
+impl<'a> TransactionalTree<'a> {
+    fn new(tree: &'a Tree) -> TransactionalTree<'a> {
+        TransactionalTree {
+            tree: tree,
+            reads: RefCell::new(HashMap::new()),
+            presence: RefCell::new(HashMap::new()),
+            writes: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    /// Retrieve a value from the `Tree` if it exists.
+    pub fn get(&self, key: &[u8]) -> DbResult<Option<IVec>, ()> {
+        if let Some(written) = self.writes.borrow().get(key) {
+            return Ok(written.as_ref().map(|v| IVec::from(&**v)));
+        }
+
+        if let Some(read) = self.reads.borrow().get(key) {
+            return Ok(read.clone());
+        }
+
+        let cur = self.tree.get(key)?;
+        self.reads.borrow_mut().insert(key.to_vec(), cur.clone());
+        Ok(cur)
+    }
+
+    /// Returns `true` if the `Tree` contains a value for the key,
+    /// without copying the value out of the `Tree`.
+    pub fn contains_key(&self, key: &[u8]) -> DbResult<bool, ()> {
+        if let Some(written) = self.writes.borrow().get(key) {
+            return Ok(written.is_some());
+        }
+
+        if let Some(read) = self.reads.borrow().get(key) {
+            return Ok(read.is_some());
+        }
+
+        if let Some(&present) = self.presence.borrow().get(key) {
+            return Ok(present);
+        }
+
+        let present = self.tree.contains_key(key)?;
+        self.presence.borrow_mut().insert(key.to_vec(), present);
+        Ok(present)
+    }
+
+    /// Set a key to a new value.
+    pub fn set(&self, key: Key, value: Value) {
+        self.writes.borrow_mut().insert(key, Some(value));
+    }
+
+    /// Delete a value, returning the last result if it existed.
+    pub fn del(&self, key: &[u8]) -> DbResult<Option<IVec>, ()> {
+        let last = self.get(key)?;
+        self.writes.borrow_mut().insert(key.to_vec(), None);
+        Ok(last)
+    }
+
+    /// Remove and return the entry with the smallest key, taking
+    /// this transaction's own writes into account. The popped key
+    /// joins the transaction's reads, so if a concurrent writer
+    /// changes or removes it before this transaction commits, the
+    /// transaction is re-run, and concurrent transactions never pop
+    /// the same entry.
+    pub fn pop_min(&self) -> DbResult<Option<(IVec, IVec)>, ()> {
+        let written = self.writes
+            .borrow()
+            .iter()
+            .find(|&(_, v)| v.is_some())
+            .map(|(k, _)| k.clone());
+
+        let mut cur = self.tree.first()?;
+        while let Some((k, _)) = cur {
+            match written {
+                Some(ref w) if *w <= k => break,
+                _ => {}
+            }
+            if self.get(&k)?.is_some() {
+                return self.pop_key(k);
+            }
+            cur = self.tree.get_gt(&k)?;
+        }
+
+        match written {
+            Some(k) => self.pop_key(k),
+            None => Ok(None),
+        }
+    }
+
+    /// Remove and return the entry with the largest key, taking
+    /// this transaction's own writes into account. Conflicts are
+    /// detected in the same way as for `pop_min`.
+    pub fn pop_max(&self) -> DbResult<Option<(IVec, IVec)>, ()> {
+        let written = self.writes
+            .borrow()
+            .iter()
+            .rev()
+            .find(|&(_, v)| v.is_some())
+            .map(|(k, _)| k.clone());
+
+        let mut cur = self.tree.last()?;
+        while let Some((k, _)) = cur {
+            match written {
+                Some(ref w) if *w >= k => break,
+                _ => {}
+            }
+            if self.get(&k)?.is_some() {
+                return self.pop_key(k);
+            }
+            cur = self.tree.get_lt(&k)?;
+        }
+
+        match written {
+            Some(k) => self.pop_key(k),
+            None => Ok(None),
+        }
+    }
+
+    // removes a key known to be present, recording its value in
+    // the read set if it came from the tree.
+    fn pop_key(&self, key: Key) -> DbResult<Option<(IVec, IVec)>, ()> {
+        let last = self.del(&key)?;
+        Ok(last.map(|v| (IVec::from(key), v)))
+    }
+
+    fn reset(&self) {
+        self.reads.borrow_mut().clear();
+        self.presence.borrow_mut().clear();
+        self.writes.borrow_mut().clear();
+    }
+}
+
+/// Types that can run a transaction across one or more `Tree`s.
+///
+/// # Examples
+///
+/// ```
+/// use sled::{Transactional, TransactionError};
+///
+/// let config = sled::ConfigBuilder::new().temporary(true).build();
+/// let db = sled::Db::start(config).unwrap();
+/// let primary = db.open_tree(b"primary".to_vec()).unwrap();
+/// let index = db.open_tree(b"index".to_vec()).unwrap();
+///
+/// // write to both trees, or to neither
+/// (&primary, &index).transaction::<_, _, ()>(|&(ref p, ref i)| {
+///     p.set(b"user_1".to_vec(), b"alice".to_vec());
+///     i.set(b"alice".to_vec(), b"user_1".to_vec());
+///     Ok(())
+/// }).unwrap();
+///
+/// // aborting discards all writes and returns the payload
+/// let res = (&primary, &index).transaction::<_, (), _>(|&(ref p, _)| {
+///     p.del(b"user_1")?;
+///     Err(TransactionError::Abort("changed my mind"))
+/// });
+/// assert_eq!(res, Err(TransactionError::Abort("changed my mind")));
+/// assert_eq!(primary.get(b"user_1"), Ok(Some(b"alice".to_vec().into())));
+/// ```
+pub trait Transactional {
+    /// The transactional handles passed to the closure.
+    type View;
+
+    /// Run the closure as a transaction over the underlying `Tree`s,
+    /// committing its writes atomically. If a concurrent write
+    /// changed anything the closure read, the closure is re-run,
+    /// so it may be called more than once and should not have side
+    /// effects outside of the `Tree`s it operates on.
+    ///
+    /// Concurrent readers and transactions observe either none or all
+    /// of a committed transaction's writes. The writes destined for
+    /// the trees of a single `Db` are also atomic across crashes, but
+    /// separate `Db`s are stored in separate files, so a crash while
+    /// committing may apply one `Db`'s writes without another's.
+    fn transaction<F, R, E>(&self, f: F) -> TransactionResult<R, E>
+        where F: Fn(&Self::View) -> TransactionResult<R, E>;
+}
+
+impl<'a> Transactional for &'a Tree {
+    type View = TransactionalTree<'a>;
+
+    fn transaction<F, R, E>(&self, f: F) -> TransactionResult<R, E>
+        where F: Fn(&TransactionalTree<'a>) -> TransactionResult<R, E>
+    {
+        let view = TransactionalTree::new(*self);
+        run(&view, |v| vec![v], f)
+    }
+}
+
+impl<'a> Transactional for (&'a Tree, &'a Tree) {
+    type View = (TransactionalTree<'a>, TransactionalTree<'a>);
+
+    fn transaction<F, R, E>(&self, f: F) -> TransactionResult<R, E>
+        where F: Fn(&Self::View) -> TransactionResult<R, E>
+    {
+        let view =
+            (TransactionalTree::new(self.0), TransactionalTree::new(self.1));
+        run(&view, |v| vec![&v.0, &v.1], f)
+    }
+}
+
+impl<'a> Transactional for (&'a Tree, &'a Tree, &'a Tree) {
+    type View = (
+        TransactionalTree<'a>,
+        TransactionalTree<'a>,
+        TransactionalTree<'a>,
+    );
+
+    fn transaction<F, R, E>(&self, f: F) -> TransactionResult<R, E>
+        where F: Fn(&Self::View) -> TransactionResult<R, E>
+    {
+        let view = (
+            TransactionalTree::new(self.0),
+            TransactionalTree::new(self.1),
+            TransactionalTree::new(self.2),
+        );
+        run(&view, |v| vec![&v.0, &v.1, &v.2], f)
+    }
+}
+
+fn run<'a, 'v, V, R, E, F, T>(
+    view: &'v V,
+    trees: T,
+    f: F,
+) -> TransactionResult<R, E>
+    where F: Fn(&V) -> TransactionResult<R, E>,
+          T: Fn(&'v V) -> Vec<&'v TransactionalTree<'a>>,
+          'a: 'v
+{
+    let trees = trees(view);
+    loop {
+        for tree in &trees {
+            tree.reset();
+        }
+
+        let ret = f(view)?;
+
+        if commit(&trees)? {
+            return Ok(ret);
+        }
+
+        M.tree_looped();
+    }
+}
+
+// returns false if something read by the transaction
+// was changed before it could commit.
+fn commit(views: &[&TransactionalTree]) -> DbResult<bool, ()> {
+    let mut trees: Vec<&Tree> = views.iter().map(|v| v.tree).collect();
+
+    // the trees of each Db are kept together, so that
+    // their batches can be logged in a single message.
+    trees.sort_by_key(|t| (t.db_id(), t.tree_id()));
+    trees.dedup_by_key(|t| t.tree_id());
+
+    let mut dbs = trees.clone();
+    dbs.dedup_by_key(|t| t.db_id());
+
+    let _locks = Tree::write_locks(&trees);
+
+    for tree in &trees {
+        tree.check_dropped()?;
+    }
+
+    for view in views {
+        for (k, read) in view.reads.borrow().iter() {
+            if view.tree.get_inner(k)? != *read {
+                return Ok(false);
+            }
+        }
+        for (k, &present) in view.presence.borrow().iter() {
+            if view.tree.contains_key_inner(k)? != present {
+                return Ok(false);
+            }
+        }
+    }
+
+    let mut batches = vec![];
+    for tree in &trees {
+        // views of the same tree are merged, with later views
+        // taking precedence over earlier ones.
+        let mut batch = Batch::default();
+        let same_tree = |v: &&&TransactionalTree| {
+            v.tree.tree_id() == tree.tree_id()
+        };
+        for view in views.iter().filter(same_tree) {
+            for (k, v) in view.writes.borrow().iter() {
+                batch.writes.insert(k.clone(), v.clone());
+            }
+        }
+
+        if !batch.is_empty() && tree.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+
+        if !batch.is_empty() {
+            batches.push((*tree, batch));
+        }
+    }
+
+    // the batches for every tree in a Db are logged together,
+    // so they are recovered atomically after a crash.
+    for db in &dbs {
+        let db_batches = batches
+            .iter()
+            .filter(|&&(t, _)| t.db_id() == db.db_id())
+            .map(|&(t, ref batch)| (t, batch.clone()))
+            .collect();
+        Tree::apply_batches_inner(db_batches)?;
+    }
+
+    Ok(true)
+}
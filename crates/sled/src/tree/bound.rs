@@ -15,6 +15,14 @@ impl Bound {
             Bound::Inf => panic!("inner() called on Bound::Inf"),
         }
     }
+
+    pub fn heap_size(&self) -> usize {
+        match *self {
+            Bound::Inclusive(ref v) |
+            Bound::Exclusive(ref v) => v.capacity(),
+            Bound::Inf => 0,
+        }
+    }
 }
 
 impl PartialOrd for Bound {
@@ -0,0 +1,421 @@
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::sync::atomic::Ordering::SeqCst;
+
+use epoch::{Shared, pin};
+
+use super::*;
+
+/// Builds the contents of an empty `Tree` from entries that are
+/// already sorted by key, created by `Tree::bulk_loader`.
+///
+/// Rather than inserting entries one at a time, which descends the
+/// `Tree` and splits its nodes as it grows, the loader packs entries
+/// into leaves from left to right and builds the index above them as
+/// it goes. Leaves and index nodes are filled to the fill factor of
+/// the largest size that wouldn't cause them to split.
+///
+/// The new contents are written to pages that nothing points to yet,
+/// and replace the empty `Tree` all at once when every entry has been
+/// written, as `Tree::clear` does. So readers and writers of the
+/// `Tree` aren't blocked while it loads, and if the load fails or
+/// crashes partway through, the `Tree` stays empty.
+///
+/// The result is the same as if the entries had been inserted with
+/// `set`, except that subscribers are not sent an event for them.
+pub struct BulkLoader<'a> {
+    tree: &'a Tree,
+    fill_factor: f64,
+}
+
+impl<'a> BulkLoader<'a> {
+    /// Set how full to pack nodes, from just above 0 for nearly
+    /// empty to 1 for full, which is the default. Leaving room
+    /// in each node makes later inserts less likely to split them.
+    pub fn fill_factor(mut self, fill_factor: f64) -> BulkLoader<'a> {
+        self.fill_factor = fill_factor;
+        self
+    }
+
+    /// Load entries in ascending order of their keys into the
+    /// `Tree`, which must be empty, and return how many there were.
+    /// If a key is not greater than the one before it, or if any
+    /// key or value is larger than the `Config` allows, an error is
+    /// returned and the `Tree` is left empty.
+    pub fn load<I>(self, entries: I) -> DbResult<usize, ()>
+        where I: IntoIterator<Item = (Key, Value)>
+    {
+        self.load_results(entries.into_iter().map(Ok))
+    }
+
+    // like load, for entries that can fail to be read
+    pub(super) fn load_results<I>(self, entries: I) -> DbResult<usize, ()>
+        where I: IntoIterator<Item = DbResult<(Key, Value), ()>>
+    {
+        let tree = self.tree;
+        if tree.config.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if !(self.fill_factor > 0. && self.fill_factor <= 1.) {
+            return Err(Error::Unsupported(format!(
+                "the fill factor of a bulk load must be more than 0 \
+                and at most 1, but it was {}",
+                self.fill_factor
+            )));
+        }
+        tree.check_dropped()?;
+        tree.check_empty()?;
+
+        let max_items = std::cmp::max(
+            2,
+            (tree.config.blink_fanout as f64 * self.fill_factor) as usize,
+        );
+        let max_bytes = ((tree.config.max_message_size() / 4) as f64 *
+                             self.fill_factor) as usize;
+        let mut builder = Builder {
+            tree: tree,
+            max_items: max_items,
+            max_bytes: max_bytes,
+            allocated: vec![],
+            leaf: None,
+            index: vec![],
+            entries: HashMap::new(),
+        };
+
+        let res = builder.push_all(entries).and_then(|counts| {
+            let root = builder.finish()?;
+            let old_root = tree.install_bulk_load(root, counts)?;
+            Ok((counts, old_root))
+        });
+        match res {
+            Ok(((count, _, _), old_root)) => {
+                maybe_fail!("bulk load free");
+                tree.free_pages(old_root)?;
+                Ok(count)
+            }
+            Err(e) => {
+                // none of the new pages were reachable from the tree
+                let guard = pin();
+                for &pid in &builder.allocated {
+                    tree.pages.free(pid, &guard).map_err(|e| e.danger_cast())?;
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+// the rightmost node of a level of the tree being built,
+// which entries or children are still being added to.
+struct OpenNode<T> {
+    id: PageID,
+    lo: Key,
+    items: Vec<(Key, T)>,
+    bytes: usize,
+}
+
+impl<T> OpenNode<T> {
+    fn new(id: PageID, lo: Key) -> OpenNode<T> {
+        OpenNode {
+            id: id,
+            lo: lo,
+            items: vec![],
+            bytes: 0,
+        }
+    }
+
+    // whether an item of this many bytes should go in a new node.
+    // every node but the last on its level holds at least two items,
+    // so that each level of the index is smaller than the one below.
+    fn is_full(&self, bytes: usize, max_items: usize, max_bytes: usize) -> bool {
+        self.items.len() >= 2 &&
+            (self.items.len() >= max_items || self.bytes + bytes > max_bytes)
+    }
+
+    fn push(&mut self, key: &[u8], item: T, bytes: usize) {
+        let encoded_key = prefix_encode(&*self.lo, key);
+        self.items.push((encoded_key, item));
+        self.bytes += bytes;
+    }
+
+    fn into_node<F>(self, next: Option<PageID>, hi: Bound, into_data: F) -> Node
+        where F: FnOnce(Vec<(Key, T)>) -> Data
+    {
+        let mut items = self.items;
+        items.sort_unstable_by(|a, b| prefix_cmp(&*a.0, &*b.0));
+        Node {
+            id: self.id,
+            data: into_data(items),
+            next: next,
+            lo: Bound::Inclusive(self.lo),
+            hi: hi,
+        }
+    }
+}
+
+struct Builder<'a> {
+    tree: &'a Tree,
+    max_items: usize,
+    max_bytes: usize,
+    // every page allocated so far, to be freed if the load fails
+    allocated: Vec<PageID>,
+    leaf: Option<OpenNode<IVec>>,
+    // the index levels, from the one above the leaves upwards.
+    // each node is added to its parent as soon as it's created.
+    index: Vec<OpenNode<PageID>>,
+    // the number of entries under each node that has been written,
+    // until its parent is written, which keeps count of them.
+    entries: HashMap<PageID, i64>,
+}
+
+impl<'a> Builder<'a> {
+    // adds every entry, returning how many there were
+    // and the total length of their keys and values.
+    fn push_all<I>(&mut self, entries: I) -> DbResult<(usize, u64, u64), ()>
+        where I: IntoIterator<Item = DbResult<(Key, Value), ()>>
+    {
+        let mut count = 0;
+        let mut key_bytes = 0;
+        let mut value_bytes = 0;
+        let mut last: Option<Key> = None;
+        for res in entries {
+            let (k, v) = res?;
+            if let Some(ref last) = last {
+                if k <= *last {
+                    return Err(Error::Unsupported(format!(
+                        "bulk loaded keys must be in ascending order \
+                        without duplicates, but {:?} came after {:?}",
+                        k,
+                        last
+                    )));
+                }
+            }
+            self.tree.check_sizes(&*k, &*v)?;
+            key_bytes += k.len() as u64;
+            value_bytes += v.len() as u64;
+            self.push_entry(&*k, v)?;
+            last = Some(k);
+            count += 1;
+        }
+        Ok((count, key_bytes, value_bytes))
+    }
+
+    fn push_entry(&mut self, key: &[u8], value: Value) -> DbResult<(), ()> {
+        if self.leaf.is_none() {
+            // the leftmost leaf covers every key from the empty one
+            let id = self.allocate()?;
+            self.leaf = Some(OpenNode::new(id, vec![]));
+        }
+
+        // prefix encoding adds at most a byte to the key
+        let bytes = key.len() + 1 + value.len();
+        let is_full = match self.leaf {
+            Some(ref leaf) => {
+                leaf.is_full(bytes, self.max_items, self.max_bytes)
+            }
+            None => false,
+        };
+        if is_full {
+            let id = self.allocate()?;
+            let full = self.leaf
+                .replace(OpenNode::new(id, key.to_vec()))
+                .expect("the leaf was just checked");
+            let full_id = full.id;
+            let hi = Bound::Exclusive(key.to_vec());
+            self.write(full.into_node(Some(id), hi, Data::Leaf), None)?;
+            if self.index.is_empty() {
+                self.push_child(0, vec![], full_id)?;
+            }
+            self.push_child(0, key.to_vec(), id)?;
+        }
+
+        self.leaf
+            .as_mut()
+            .expect("the leaf was just created")
+            .push(key, value.into(), bytes);
+        Ok(())
+    }
+
+    // adds a child to the index level, starting a new node
+    // on that level and adding it to its parent if needed.
+    fn push_child(
+        &mut self,
+        level: usize,
+        lo: Key,
+        child: PageID,
+    ) -> DbResult<(), ()> {
+        if level == self.index.len() {
+            // the first child of a new level is always the leftmost
+            let id = self.allocate()?;
+            self.index.push(OpenNode::new(id, lo.clone()));
+        }
+
+        let bytes = lo.len() + 1 + mem::size_of::<PageID>();
+        if self.index[level].is_full(bytes, self.max_items, self.max_bytes) {
+            let id = self.allocate()?;
+            let full =
+                mem::replace(&mut self.index[level], OpenNode::new(id, lo.clone()));
+            let full_id = full.id;
+            let hi = Bound::Exclusive(lo.clone());
+            let full = full.into_node(Some(id), hi, |ptrs| self.index(ptrs));
+            self.write(full, None)?;
+            if level + 1 == self.index.len() {
+                self.push_child(level + 1, vec![], full_id)?;
+            }
+            self.push_child(level + 1, lo.clone(), id)?;
+        }
+
+        self.index[level].push(&*lo, child, bytes);
+        Ok(())
+    }
+
+    // writes the rightmost node of each level, returning the root
+    fn finish(&mut self) -> DbResult<PageID, ()> {
+        let leaf = match self.leaf.take() {
+            Some(leaf) => leaf,
+            None => {
+                let id = self.allocate()?;
+                OpenNode::new(id, vec![])
+            }
+        };
+        let leaf_id = leaf.id;
+        self.write(leaf.into_node(None, Bound::Inf, Data::Leaf), None)?;
+        if self.index.is_empty() {
+            self.push_child(0, vec![], leaf_id)?;
+        }
+
+        // the top level only ever has one node, which is the root
+        let top = self.index.len() - 1;
+        let root = self.index[top].id;
+        let index = mem::replace(&mut self.index, vec![]);
+        for (level, node) in index.into_iter().enumerate() {
+            let prev_root = if level == top {
+                Some(std::usize::MAX)
+            } else {
+                None
+            };
+            let node =
+                node.into_node(None, Bound::Inf, |ptrs| self.index(ptrs));
+            self.write(node, prev_root)?;
+        }
+        Ok(root)
+    }
+
+    fn allocate(&mut self) -> DbResult<PageID, ()> {
+        let guard = pin();
+        let id = self.tree.pages.allocate(&guard)?;
+        self.allocated.push(id);
+        Ok(id)
+    }
+
+    // the data of an index node with these children, which have
+    // all been written already.
+    fn index(&mut self, ptrs: Vec<(Key, PageID)>) -> Data {
+        let mut counts = BTreeMap::new();
+        for &(_, pid) in &ptrs {
+            let entries = self.entries.remove(&pid).unwrap_or(0);
+            counts.insert(pid, entries);
+        }
+        Data::Index(ptrs, ChildCounts::new(counts))
+    }
+
+    fn write(
+        &mut self,
+        node: Node,
+        prev_root: Option<PageID>,
+    ) -> DbResult<(), ()> {
+        self.entries.insert(node.id, node.data.entries());
+        let guard = pin();
+        self.tree
+            .pages
+            .replace(node.id, Shared::null(), Frag::Base(node, prev_root), &guard)
+            .map_err(|e| e.danger_cast())?;
+        Ok(())
+    }
+}
+
+impl Tree {
+    /// Create a `BulkLoader` for filling this empty `Tree` with
+    /// sorted entries much faster than inserting them one by one.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    ///
+    /// let entries = (0..100u8).map(|i| (vec![i], vec![i]));
+    /// let loaded = t.bulk_loader().fill_factor(0.75).load(entries).unwrap();
+    /// assert_eq!(loaded, 100);
+    /// assert_eq!(t.get(&[42]), Ok(Some(vec![42].into())));
+    ///
+    /// // only empty trees can be bulk loaded
+    /// assert!(t.bulk_load(vec![(vec![200], vec![])]).is_err());
+    /// ```
+    pub fn bulk_loader(&self) -> BulkLoader {
+        BulkLoader {
+            tree: self,
+            fill_factor: 1.,
+        }
+    }
+
+    /// Load entries in ascending order of their keys into this empty
+    /// `Tree`, returning how many there were. This is shorthand for
+    /// `tree.bulk_loader().load(entries)`; see `BulkLoader` for details.
+    pub fn bulk_load<I>(&self, entries: I) -> DbResult<usize, ()>
+        where I: IntoIterator<Item = (Key, Value)>
+    {
+        self.bulk_loader().load(entries)
+    }
+
+    fn check_empty(&self) -> DbResult<(), ()> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Unsupported(
+                "only an empty tree can be bulk loaded".to_owned(),
+            ))
+        }
+    }
+
+    // replaces the empty tree with the bulk loaded one,
+    // returning the old root for its pages to be freed.
+    fn install_bulk_load(
+        &self,
+        root: PageID,
+        counts: (usize, u64, u64),
+    ) -> DbResult<PageID, ()> {
+        let _cc = self.write_lock();
+        self.check_dropped()?;
+        self.check_empty()?;
+        if self.snapshots.is_active() {
+            return Err(Error::Unsupported(
+                "cannot bulk load a tree while it has snapshots".to_owned(),
+            ));
+        }
+        if self.changes.tree().is_some() {
+            return Err(Error::Unsupported(
+                "cannot bulk load a tree that tracks its changes".to_owned(),
+            ));
+        }
+
+        // any deadlines left behind are for keys that no longer
+        // exist, and must not apply to the keys that were loaded.
+        if let Some(deadlines) = self.deadlines.tree() {
+            deadlines.clear_inner()?;
+        }
+        self.mark_lens_dirty()?;
+
+        // like clearing, pointing the meta page at the new root is
+        // the point after which the load survives a crash.
+        maybe_fail!("bulk load meta");
+        self.update_meta(|meta| {
+            meta.insert(self.name.clone(), root);
+        })?;
+
+        let old_root = self.root.swap(root, SeqCst);
+        self.clears.fetch_add(1, SeqCst);
+        self.counts.store(counts);
+        Ok(old_root)
+    }
+}
@@ -0,0 +1,420 @@
+use std::collections::VecDeque;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::atomic::Ordering::SeqCst;
+
+use epoch::pin;
+
+use super::*;
+use super::hidden::*;
+use super::tree::EPOCH_PID;
+
+// the prefix of the name of the hidden tree that records
+// which epoch each of another tree's keys was last modified in.
+pub(super) const CHANGES_PREFIX: &[u8] = b"__sled__changes__";
+
+// each key's last modification is stamped with its epoch, so that
+// changes can be read from any epoch onwards. the epoch that tracking
+// started at is stored on its own, after the stamps.
+const SINCE: u8 = BY_STAMP + 1;
+
+// the most modifications that `ChangesIter` reads from the hidden
+// tree at a time, before looking up the keys' current values.
+const CHUNK_LEN: usize = 64;
+
+// the durability epoch of a Db, shared by its trees
+#[derive(Default)]
+pub(super) struct Epoch {
+    pub(super) current: AtomicUsize,
+    // set once a write has been stamped with the current epoch,
+    // so that flushes only end epochs that something happened in.
+    dirty: AtomicBool,
+    // taken shared by writes to trees that track their changes, from
+    // stamping them until they're made, and exclusively to end the
+    // epoch, which waits for them.
+    writers: RwLock<()>,
+}
+
+// the hidden tree that a tree's changes are recorded
+// in, if it tracks them, shared between its handles.
+#[derive(Default)]
+pub(super) struct ChangeLog {
+    tree: RwLock<Option<Tree>>,
+    // set once the tree exists, so that trees that don't track
+    // their changes don't pay for looking it up on every write.
+    active: AtomicBool,
+}
+
+impl ChangeLog {
+    pub(super) fn tree(&self) -> Option<Tree> {
+        if !self.active.load(SeqCst) {
+            return None;
+        }
+        self.tree
+            .read()
+            .expect("a thread panicked and poisoned a Tree's changes lock")
+            .clone()
+    }
+
+    pub(super) fn is_active(&self) -> bool {
+        self.active.load(SeqCst)
+    }
+
+    pub(super) fn set_tree(&self, tree: Tree) {
+        *self.tree.write().expect(
+            "a thread panicked and poisoned a Tree's changes lock",
+        ) = Some(tree);
+        self.active.store(true, SeqCst);
+    }
+}
+
+impl Tree {
+    /// Returns the `Db`'s current durability epoch. Writes to a
+    /// `Tree` that tracks its changes are stamped with the epoch they
+    /// are made in, and `flush` ends the epoch if anything was stamped
+    /// with it, so that the writes made after it returns are stamped
+    /// with a later epoch than the ones it made durable. The epoch is
+    /// persisted, and carries on from where it was after a restart.
+    ///
+    /// To copy a `Tree` incrementally, note the current epoch, copy
+    /// the entries from `changes_since` the epoch noted last time,
+    /// and then `flush`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let db = sled::Db::start(config).unwrap();
+    /// db.track_changes().unwrap();
+    ///
+    /// let epoch = db.current_epoch();
+    /// db.flush().unwrap();
+    /// assert_eq!(db.current_epoch(), epoch);
+    ///
+    /// db.set(vec![1], vec![10]).unwrap();
+    /// db.flush().unwrap();
+    /// assert_eq!(db.current_epoch(), epoch + 1);
+    /// ```
+    pub fn current_epoch(&self) -> u64 {
+        self.epoch.current.load(SeqCst) as u64
+    }
+
+    /// Start recording which keys of this `Tree` are modified in each
+    /// epoch, so that they can be read back with `changes_since`. This
+    /// ends the current epoch, and changes are tracked from the next
+    /// one onwards, including after a restart. It does nothing if this
+    /// `Tree` already tracks its changes.
+    ///
+    /// Each key's modifications are recorded in a hidden `Tree`, which
+    /// is written to the first time the key is modified in an epoch.
+    /// That first modification costs a read and two insertions into
+    /// the hidden `Tree`, plus a removal if the key was modified in an
+    /// earlier epoch, so each key a workload touches in an epoch is
+    /// written up to four times rather than once, with entries of
+    /// about its own size. Later modifications of it in the same epoch
+    /// only cost the read. The hidden `Tree` keeps two entries for
+    /// every key modified since tracking started, including removed
+    /// ones, each holding the key and an 8 byte epoch. For a 100GB
+    /// `Tree` where 1% of the keys change between flushes, that's the
+    /// extra writes for that 1% of keys, and `changes_since` reads two
+    /// hidden entries and the current value of each of them, about 1GB
+    /// of entries rather than all 100GB.
+    ///
+    /// Clearing a `Tree` that tracks its changes records the removal
+    /// of every entry, and it can't be bulk loaded.
+    pub fn track_changes(&self) -> DbResult<(), ()> {
+        if self.config.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        let _cc = self.write_lock();
+        self.check_dropped()?;
+        if self.changes.tree().is_some() {
+            return Ok(());
+        }
+
+        let mut name = CHANGES_PREFIX.to_vec();
+        name.extend_from_slice(&*self.name);
+        let mut changes = self.create_tenant(name)?;
+        changes.expirer = None;
+        changes.concurrency_control = self.concurrency_control.clone();
+
+        // writes made before now weren't stamped, so
+        // tracking starts from the next epoch.
+        let since = {
+            let _writers = self.epoch_write_lock();
+            self.end_epoch()?
+        };
+        changes.set_inner(vec![SINCE], encode_stamp(since).to_vec())?;
+        self.changes.set_tree(changes);
+        Ok(())
+    }
+
+    /// Iterate over every key of this `Tree` that was modified in
+    /// `epoch` or later, along with its current value, or `None` if
+    /// it has been removed. Keys are returned in the order of the
+    /// last epoch they were modified in, and then in key order. Only
+    /// the keys that were modified are read, however large the `Tree`
+    /// is.
+    ///
+    /// Changes are only recorded once `track_changes` has been called,
+    /// so it must be called before the epoch you want to read from.
+    /// The iterator returns an error, and nothing else, for a `Tree`
+    /// that doesn't track its changes, or for an `epoch` earlier than
+    /// the one it started tracking them at.
+    ///
+    /// A key modified while this iterates may be returned twice,
+    /// with the value it has each time. Entries that expire are
+    /// returned once they're removed in the background.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.track_changes().unwrap();
+    /// t.set(vec![1], vec![10]).unwrap();
+    /// t.set(vec![2], vec![20]).unwrap();
+    /// t.flush().unwrap();
+    ///
+    /// let epoch = t.current_epoch();
+    /// t.set(vec![3], vec![30]).unwrap();
+    /// t.del(&[1]).unwrap();
+    ///
+    /// let changes: Vec<_> =
+    ///     t.changes_since(epoch).map(|res| res.unwrap()).collect();
+    /// assert_eq!(
+    ///     changes,
+    ///     vec![
+    ///         (vec![1].into(), None),
+    ///         (vec![3].into(), Some(vec![30].into())),
+    ///     ]
+    /// );
+    /// ```
+    pub fn changes_since(&self, epoch: u64) -> ChangesIter {
+        let mut iter = ChangesIter {
+            tree: self,
+            changes: None,
+            next_stamp: by_stamp(epoch, b""),
+            pending: VecDeque::new(),
+            error: None,
+        };
+        match self.tracking_since() {
+            Ok(since) if since <= epoch => {
+                iter.changes = self.changes.tree();
+            }
+            Ok(since) => {
+                iter.error = Some(Error::Unsupported(format!(
+                    "changes to this tree are only tracked since epoch {}",
+                    since
+                )))
+            }
+            Err(e) => iter.error = Some(e),
+        }
+        iter
+    }
+
+    // the epoch that this tree started tracking its changes at
+    fn tracking_since(&self) -> DbResult<u64, ()> {
+        let changes = match self.changes.tree() {
+            Some(changes) => changes,
+            None => {
+                return Err(Error::Unsupported(
+                    "changes to this tree are not tracked, \
+                    see Tree::track_changes"
+                        .to_owned(),
+                ))
+            }
+        };
+        match changes.get(&[SINCE])? {
+            Some(since) => Ok(decode_stamp(&*since)),
+            None => Err(Error::ReportableBug(
+                "a tree that tracks its changes is missing \
+                the epoch it started at"
+                    .to_owned(),
+            )),
+        }
+    }
+
+    // keeps the current epoch from ending until the returned guard is
+    // dropped, if this tree tracks its changes. writers take this
+    // before stamping their writes, and hold it until they're made.
+    pub(super) fn hold_epoch(&self) -> Option<RwLockReadGuard<()>> {
+        if !self.changes.is_active() {
+            return None;
+        }
+        Some(self.epoch.writers.read().expect(
+            "a thread panicked and poisoned the Db's epoch lock",
+        ))
+    }
+
+    fn epoch_write_lock(&self) -> RwLockWriteGuard<()> {
+        self.epoch.writers.write().expect(
+            "a thread panicked and poisoned the Db's epoch lock",
+        )
+    }
+
+    // records that `key` is being modified in the current epoch, if
+    // this tree tracks its changes. this must be called before the
+    // write is logged, so that the record is durable whenever the write
+    // is. callers must hold the read or write lock, and the guard
+    // returned by `hold_epoch`.
+    pub(super) fn stamp_change(&self, key: &[u8]) -> DbResult<(), ()> {
+        let changes = match self.changes.tree() {
+            Some(changes) => changes,
+            None => return Ok(()),
+        };
+
+        // the epoch only ends once writers let go of it
+        let epoch = self.current_epoch();
+        let by_key = by_key(key);
+        let last = match changes.get_inner(&*by_key)? {
+            Some(ref last) if decode_stamp(&*last) == epoch => return Ok(()),
+            Some(last) => Some(decode_stamp(&*last)),
+            None => None,
+        };
+
+        self.epoch.dirty.store(true, SeqCst);
+        changes.set_inner(by_stamp(epoch, key), vec![])?;
+        changes.set_inner(by_key, encode_stamp(epoch).to_vec())?;
+        if let Some(last) = last {
+            changes.del_inner(&*by_stamp(last, key))?;
+        }
+        Ok(())
+    }
+
+    // ends the current epoch if a write was stamped with it. callers
+    // must not hold the guard returned by `hold_epoch`.
+    pub(super) fn advance_epoch(&self) -> DbResult<(), ()> {
+        if self.config.read_only || !self.epoch.dirty.load(SeqCst) {
+            return Ok(());
+        }
+
+        // waits for the writes stamped with the current epoch to finish
+        let _writers = self.epoch_write_lock();
+        if self.epoch.dirty.load(SeqCst) {
+            self.end_epoch()?;
+        }
+        Ok(())
+    }
+
+    // ends the current epoch, returning the next one. callers must hold
+    // the epoch's write lock or be starting the Db.
+    fn end_epoch(&self) -> DbResult<u64, ()> {
+        let next = self.current_epoch() + 1;
+        self.write_epoch_page(Frag::Epoch(next))?;
+        self.epoch.current.store(next as usize, SeqCst);
+        self.epoch.dirty.store(false, SeqCst);
+        Ok(next)
+    }
+
+    fn write_epoch_page(&self, frag: Frag) -> DbResult<(), ()> {
+        let guard = pin();
+        loop {
+            let get_cursor = self.pages.get(EPOCH_PID, &guard).map_err(
+                |e| e.danger_cast(),
+            )?;
+
+            let cas_key = match get_cursor {
+                PageGet::Materialized(Frag::Epoch(_), cas_key) => cas_key,
+                broken => {
+                    return Err(Error::ReportableBug(format!(
+                        "got non-epoch page while writing epoch: {:?}",
+                        broken
+                    )))
+                }
+            };
+
+            match self.pages.replace(EPOCH_PID, cas_key, frag.clone(), &guard) {
+                Ok(_) => return Ok(()),
+                Err(Error::CasFailed(_)) => {}
+                Err(other) => return Err(other.danger_cast()),
+            }
+            M.tree_looped();
+        }
+    }
+
+    // finishes starting to track changes, if we crashed after the
+    // hidden tree was created but before its starting epoch was
+    // written. called while starting the Db.
+    pub(super) fn recover_tracking(&self) -> DbResult<(), ()> {
+        let changes = match self.changes.tree() {
+            Some(changes) => changes,
+            None => return Ok(()),
+        };
+        if changes.get_inner(&[SINCE])?.is_none() {
+            let since = self.end_epoch()?;
+            changes.set_inner(vec![SINCE], encode_stamp(since).to_vec())?;
+        }
+        Ok(())
+    }
+}
+
+/// An iterator over the keys of a `Tree` that were modified since an
+/// epoch, and their current values. See `Tree::changes_since`.
+pub struct ChangesIter<'a> {
+    tree: &'a Tree,
+    // None once every modification has been read
+    changes: Option<Tree>,
+    // the first record of a modification that hasn't been read yet
+    next_stamp: Key,
+    pending: VecDeque<Key>,
+    error: Option<Error<()>>,
+}
+
+impl<'a> ChangesIter<'a> {
+    // reads the next chunk of modifications, keeping those that
+    // haven't been followed by a later one of the same key.
+    fn read_chunk(&mut self) -> DbResult<(), ()> {
+        let changes = match self.changes {
+            Some(ref changes) => changes.clone(),
+            None => return Ok(()),
+        };
+
+        let range = self.next_stamp.clone()..vec![BY_STAMP + 1];
+        let mut read = 0;
+        for res in changes.range(range).keys().take(CHUNK_LEN) {
+            let stamp = res?;
+            read += 1;
+
+            let epoch = decode_stamp(&stamp[1..9]);
+            let key = &stamp[9..];
+            if let Some(last) = changes.get(&*by_key(key))? {
+                if decode_stamp(&*last) == epoch {
+                    self.pending.push_back(key.to_vec());
+                }
+            }
+
+            self.next_stamp = stamp.clone();
+            self.next_stamp.push(0);
+        }
+
+        if read < CHUNK_LEN {
+            self.changes = None;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for ChangesIter<'a> {
+    type Item = DbResult<(IVec, Option<IVec>), ()>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+        loop {
+            if let Some(key) = self.pending.pop_front() {
+                let value = self.tree.get(&*key);
+                return Some(value.map(|value| (IVec::from(key), value)));
+            }
+            if self.changes.is_none() {
+                return None;
+            }
+            if let Err(e) = self.read_chunk() {
+                self.changes = None;
+                return Some(Err(e));
+            }
+        }
+    }
+}
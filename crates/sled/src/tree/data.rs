@@ -1,21 +1,122 @@
+use std::collections::BTreeMap;
 use std::fmt::Debug;
+use std::mem;
 
 use super::*;
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Data {
-    Index(Vec<(Key, PageID)>),
-    Leaf(Vec<(Key, Value)>),
+    Index(Vec<(Key, PageID)>, ChildCounts),
+    Leaf(Vec<(Key, IVec)>),
+}
+
+/// How many entries are under each child of an index node.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChildCounts {
+    // by child id. a count can dip below zero while the removal
+    // of an entry is counted before its insertion is.
+    pub(super) children: BTreeMap<PageID, i64>,
+    // the children that have split off from one of ours, but that
+    // we don't point to yet, by id, with their lowest key. they're
+    // counted under the child whose keys they cover until we do.
+    pub(super) unlinked: BTreeMap<PageID, (Key, i64)>,
+}
+
+impl ChildCounts {
+    pub fn new(children: BTreeMap<PageID, i64>) -> ChildCounts {
+        ChildCounts {
+            children: children,
+            unlinked: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&self, child: PageID) -> i64 {
+        self.children.get(&child).cloned().unwrap_or(0)
+    }
+
+    pub fn total(&self) -> i64 {
+        self.children.values().sum()
+    }
+
+    // keeps the counts of these children, and of the unlinked
+    // children whose lowest keys are kept.
+    fn retain<F>(&mut self, ptrs: &[(Key, PageID)], keep: F)
+        where F: Fn(&[u8]) -> bool
+    {
+        self.children.retain(
+            |child, _| ptrs.iter().any(|&(_, pid)| pid == *child),
+        );
+        self.unlinked.retain(|_, &mut (ref lo, _)| keep(lo));
+    }
+
+    fn size_in_bytes(&self) -> usize {
+        let child = mem::size_of::<(PageID, i64)>();
+        self.children.len() * child +
+            self.unlinked
+                .values()
+                .map(|&(ref lo, _)| lo.len() + child)
+                .sum::<usize>()
+    }
+
+    fn heap_size(&self) -> usize {
+        self.children.len() * mem::size_of::<(PageID, i64)>() +
+            self.unlinked
+                .values()
+                .map(|&(ref lo, _)| {
+                    mem::size_of::<(PageID, (Key, i64))>() + lo.capacity()
+                })
+                .sum::<usize>()
+    }
 }
 
 impl Data {
     pub fn len(&self) -> usize {
         match *self {
-            Data::Index(ref ptrs) => ptrs.len(),
+            Data::Index(ref ptrs, _) => ptrs.len(),
             Data::Leaf(ref items) => items.len(),
         }
     }
 
+    // the number of entries under this node
+    pub fn entries(&self) -> i64 {
+        match *self {
+            Data::Index(_, ref counts) => counts.total(),
+            Data::Leaf(ref items) => items.len() as i64,
+        }
+    }
+
+    // the bytes taken up by keys, values, child ids and counts
+    pub fn size_in_bytes(&self) -> usize {
+        match *self {
+            Data::Index(ref ptrs, ref counts) => {
+                ptrs.iter()
+                    .map(|&(ref k, _)| k.len() + mem::size_of::<PageID>())
+                    .sum::<usize>() + counts.size_in_bytes()
+            }
+            Data::Leaf(ref items) => {
+                items.iter().map(|&(ref k, ref v)| k.len() + v.len()).sum()
+            }
+        }
+    }
+
+    // the bytes held on the heap, including unused capacity
+    pub fn heap_size(&self) -> usize {
+        match *self {
+            Data::Index(ref ptrs, ref counts) => {
+                ptrs.capacity() * mem::size_of::<(Key, PageID)>() +
+                    ptrs.iter().map(|&(ref k, _)| k.capacity()).sum::<usize>() +
+                    counts.heap_size()
+            }
+            Data::Leaf(ref items) => {
+                items.capacity() * mem::size_of::<(Key, IVec)>() +
+                    items
+                        .iter()
+                        .map(|&(ref k, ref v)| k.capacity() + v.heap_size())
+                        .sum::<usize>()
+            }
+        }
+    }
+
     pub fn split(&self, lhs_prefix: &[u8]) -> (Key, Data) {
         fn split_inner<T>(
             xs: &[(Key, T)],
@@ -47,9 +148,11 @@ impl Data {
         }
 
         match *self {
-            Data::Index(ref ptrs) => {
+            Data::Index(ref ptrs, ref counts) => {
                 let (split, rhs) = split_inner(ptrs, lhs_prefix);
-                (split, Data::Index(rhs))
+                let mut rhs_counts = counts.clone();
+                rhs_counts.retain(&rhs, |lo| lo >= &*split);
+                (split, Data::Index(rhs, rhs_counts))
             }
             Data::Leaf(ref items) => {
                 let (split, rhs) = split_inner(items, lhs_prefix);
@@ -61,11 +164,12 @@ impl Data {
     pub fn drop_gte(&mut self, at: &Bound, prefix: &[u8]) {
         let bound = at.inner();
         match *self {
-            Data::Index(ref mut ptrs) => {
+            Data::Index(ref mut ptrs, ref mut counts) => {
                 ptrs.retain(|&(ref k, _)| {
                     let decoded_k = prefix_decode(prefix, &*k);
                     &*decoded_k < bound
-                })
+                });
+                counts.retain(ptrs, |lo| lo < bound);
             }
             Data::Leaf(ref mut items) => {
                 items.retain(|&(ref k, _)| {
@@ -76,16 +180,9 @@ impl Data {
         }
     }
 
-    pub fn leaf(&self) -> Option<Vec<(Key, Value)>> {
-        match *self {
-            Data::Index(_) => None,
-            Data::Leaf(ref items) => Some(items.clone()),
-        }
-    }
-
-    pub fn leaf_ref(&self) -> Option<&Vec<(Key, Value)>> {
+    pub fn leaf_ref(&self) -> Option<&Vec<(Key, IVec)>> {
         match *self {
-            Data::Index(_) => None,
+            Data::Index(..) => None,
             Data::Leaf(ref items) => Some(items),
         }
     }
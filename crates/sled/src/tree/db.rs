@@ -0,0 +1,742 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, Weak};
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::atomic::Ordering::SeqCst;
+
+use epoch::{Shared, pin};
+
+use super::*;
+use super::changes::{CHANGES_PREFIX, ChangeLog, Epoch};
+use super::tree::{BATCH_PID, COUNTER_PID, Counts, EPOCH_PID, LENS_CLEAN,
+                  LENS_DIRTY, LENS_PID, Lens, META_PID, Pending};
+use super::snapshot::Snapshots;
+use super::ttl::{DEADLINES_PREFIX, Deadlines, Expirer};
+
+// the name of the tree that a `Db` dereferences to.
+const DEFAULT_TREE: &[u8] = b"__sled__default";
+
+/// A collection of named `Tree`s that share a single pagecache and
+/// log. Operations on the `Db` itself act on its default `Tree`.
+#[derive(Clone)]
+pub struct Db {
+    default: Tree,
+    tenants: Arc<RwLock<HashMap<Vec<u8>, Tree>>>,
+}
+
+unsafe impl Send for Db {}
+unsafe impl Sync for Db {}
+
+impl Deref for Db {
+    type Target = Tree;
+
+    fn deref(&self) -> &Tree {
+        &self.default
+    }
+}
+
+impl Db {
+    /// Load existing or create a new `Db`. Only one `Db` may write to
+    /// a path at a time, so this returns `Error::Busy` if another is
+    /// running there, whether it's in this process or another one.
+    /// `Db`s in read-only mode may run alongside it.
+    pub fn start(config: Config) -> DbResult<Db, ()> {
+        // verifying the snapshot regenerates it, which a
+        // read-only system can't do.
+        #[cfg(any(test, feature = "check_snapshot_integrity"))]
+        {
+            if !config.read_only {
+                match config.verify_snapshot::<
+                    BLinkMaterializer,
+                    Frag,
+                    Vec<(PageID, PageID)>,
+                >() {
+                    Ok(_) => {}
+                    // systems written in a format that can't be read
+                    // are refused by PageCache::start below, too.
+                    Err(Error::Unsupported(_)) => {}
+                    #[cfg(feature = "failpoints")]
+                    Err(Error::FailPoint) => {}
+                    other => panic!("failed to verify snapshot: {:?}", other),
+                }
+            }
+        }
+
+        let pages = PageCache::start(config.clone())?;
+
+        let roots_opt = pages.recovered_state().clone().and_then(
+            |roots: Vec<(PageID, PageID)>| if roots.is_empty() {
+                None
+            } else {
+                Some(roots)
+            },
+        );
+
+        let roots = if let Some(roots) = roots_opt {
+            roots
+        } else {
+            let guard = pin();
+            let root_id = pages.allocate(&guard)?;
+            assert_eq!(
+                root_id,
+                0,
+                "we expect that this is the first page ever allocated"
+            );
+            debug!("allocated pid {} for root of new tree", root_id);
+
+            let leaf_id = pages.allocate(&guard)?;
+            trace!("allocated pid {} for leaf in new", leaf_id);
+
+            let batch_id = pages.allocate(&guard)?;
+            assert_eq!(
+                batch_id,
+                BATCH_PID,
+                "we expect that the batch page is the third page allocated"
+            );
+
+            let counter_id = pages.allocate(&guard)?;
+            assert_eq!(
+                counter_id,
+                COUNTER_PID,
+                "we expect that the counter page is the fourth page allocated"
+            );
+
+            let meta_id = pages.allocate(&guard)?;
+            assert_eq!(
+                meta_id,
+                META_PID,
+                "we expect that the meta page is the fifth page allocated"
+            );
+
+            let lens_id = pages.allocate(&guard)?;
+            assert_eq!(
+                lens_id,
+                LENS_PID,
+                "we expect that the lens page is the sixth page allocated"
+            );
+
+            let epoch_id = pages.allocate(&guard)?;
+            assert_eq!(
+                epoch_id,
+                EPOCH_PID,
+                "we expect that the epoch page is the seventh page allocated"
+            );
+
+            // the batch, counter, meta, lens and epoch pages are written
+            // before the root, so that they're always present once a
+            // root is recovered.
+            pages
+                .replace(batch_id, Shared::null(), Frag::Batch(vec![]), &guard)
+                .map_err(|e| e.danger_cast())?;
+            pages
+                .replace(counter_id, Shared::null(), Frag::Counter(0), &guard)
+                .map_err(|e| e.danger_cast())?;
+
+            let mut meta = BTreeMap::new();
+            meta.insert(DEFAULT_TREE.to_vec(), root_id);
+            pages
+                .replace(meta_id, Shared::null(), Frag::Meta(meta), &guard)
+                .map_err(|e| e.danger_cast())?;
+
+            let mut lens = BTreeMap::new();
+            lens.insert(DEFAULT_TREE.to_vec(), (0, 0, 0));
+            let lens = Frag::Lens(Some(lens));
+            pages
+                .replace(lens_id, Shared::null(), lens, &guard)
+                .map_err(|e| e.danger_cast())?;
+            pages
+                .replace(epoch_id, Shared::null(), Frag::Epoch(0), &guard)
+                .map_err(|e| e.danger_cast())?;
+
+            let (leaf, root) = new_root(root_id, leaf_id);
+            pages
+                .replace(leaf_id, Shared::null(), leaf, &guard)
+                .map_err(|e| e.danger_cast())?;
+            pages
+                .replace(root_id, Shared::null(), root, &guard)
+                .map_err(|e| e.danger_cast())?;
+
+            vec![(root_id, std::usize::MAX)]
+        };
+
+        let guard = pin();
+        let meta = match pages.get(META_PID, &guard) {
+            Ok(PageGet::Materialized(Frag::Meta(meta), _)) => meta,
+            broken => {
+                return Err(Error::Unsupported(format!(
+                    "expected pid {} to contain the meta page, \
+                    was this database created by an older version? {:?}",
+                    META_PID,
+                    broken
+                )))
+            }
+        };
+        let persisted_lens = match pages.get(LENS_PID, &guard) {
+            Ok(PageGet::Materialized(Frag::Lens(lens), _)) => lens,
+            broken => {
+                return Err(Error::Unsupported(format!(
+                    "expected pid {} to contain the lens page, \
+                    was this database created by an older version? {:?}",
+                    LENS_PID,
+                    broken
+                )))
+            }
+        };
+        drop(guard);
+
+        // expired entries are removed by the flusher thread,
+        // unless nothing can be removed in read-only mode
+        let expirer = Arc::new(Expirer::default());
+        if !config.read_only {
+            let task: Weak<Expirer> = Arc::downgrade(&expirer);
+            pages.add_maintenance(task);
+        }
+
+        let default = Tree {
+            pages: Arc::new(pages),
+            config: config,
+            root: Arc::new(AtomicUsize::new(0)),
+            name: DEFAULT_TREE.to_vec(),
+            dropped: Arc::new(AtomicBool::new(false)),
+            concurrency_control: Arc::new(RwLock::new(())),
+            pending: Arc::new(Pending::default()),
+            idgen: Arc::new(AtomicUsize::new(0)),
+            idgen_persisted: Arc::new(AtomicUsize::new(0)),
+            counts: Arc::new(Counts::default()),
+            lens: Arc::new(Lens {
+                state: AtomicUsize::new(LENS_CLEAN),
+                trees: Mutex::new(BTreeMap::new()),
+            }),
+            subscriptions: Arc::new(Subscriptions::default()),
+            clears: Arc::new(AtomicUsize::new(0)),
+            deadlines: Arc::new(Deadlines::default()),
+            snapshots: Arc::new(Snapshots::default()),
+            epoch: Arc::new(Epoch::default()),
+            changes: Arc::new(ChangeLog::default()),
+            expirer: Some(expirer.clone()),
+        };
+        default
+            .lens
+            .trees
+            .lock()
+            .expect("a thread panicked and poisoned the Db's lens mutex")
+            .insert(DEFAULT_TREE.to_vec(), default.counts.clone());
+
+        let mut tenants = HashMap::new();
+        let mut deadlines = vec![];
+        let mut changes = vec![];
+        for (name, initial) in meta {
+            let root_id = current_root(&roots, initial);
+            debug!("recovered root {} for tree {:?}", root_id, name);
+            let tree = if name == DEFAULT_TREE {
+                default.root.store(root_id, SeqCst);
+                default.clone()
+            } else if name.starts_with(DEADLINES_PREFIX) {
+                let mut tree = default.tenant(name.clone(), root_id);
+                tree.expirer = None;
+                deadlines.push(tree.clone());
+                tree
+            } else if name.starts_with(CHANGES_PREFIX) {
+                let mut tree = default.tenant(name.clone(), root_id);
+                tree.expirer = None;
+                changes.push(tree.clone());
+                tree
+            } else {
+                let tree = default.tenant(name.clone(), root_id);
+                tenants.insert(name, tree.clone());
+                tree
+            };
+
+            // the persisted counts are only accurate if nothing was
+            // written after they were, otherwise we count again. trees
+            // missing from them were created, but not written to, since.
+            // a crash may also have lost the changes to the counts that
+            // the index keeps, which are rebuilt along with them.
+            let counts = match persisted_lens {
+                Some(ref lens) => {
+                    lens.get(&tree.name).cloned().unwrap_or((0, 0, 0))
+                }
+                None => {
+                    if !tree.config.read_only {
+                        tree.recount_children()?;
+                    }
+                    tree.count_leaves()?
+                }
+            };
+            tree.counts.store(counts);
+        }
+        if persisted_lens.is_none() {
+            default.lens.state.store(LENS_DIRTY, SeqCst);
+        }
+
+        // hand each tree the deadlines of its entries. they're created
+        // and dropped along with their tree in the meta page, so there
+        // is always a tree for them.
+        for tree in deadlines {
+            let owner_name = &tree.name[DEADLINES_PREFIX.len()..];
+            let owner = if owner_name == DEFAULT_TREE {
+                Some(&default)
+            } else {
+                tenants.get(owner_name)
+            };
+            if let Some(owner) = owner {
+                let mut tree = tree;
+                tree.concurrency_control = owner.concurrency_control.clone();
+                owner.deadlines.set_tree(tree);
+                expirer.watch(owner);
+            }
+        }
+
+        // and the records of their changes, for those that track them
+        for tree in changes {
+            let owner_name = &tree.name[CHANGES_PREFIX.len()..];
+            let owner = if owner_name == DEFAULT_TREE {
+                Some(&default)
+            } else {
+                tenants.get(owner_name)
+            };
+            if let Some(owner) = owner {
+                let mut tree = tree;
+                tree.concurrency_control = owner.concurrency_control.clone();
+                owner.changes.set_tree(tree);
+            }
+        }
+
+        let db = Db {
+            default: default,
+            tenants: Arc::new(RwLock::new(tenants)),
+        };
+
+        db.recover_epoch()?;
+        db.recover_batch()?;
+        db.recover_idgen()?;
+
+        Ok(db)
+    }
+
+    /// Open or create a new `Tree` with the provided name. Trees
+    /// share the `Db`'s pagecache and log, but their keys are
+    /// completely isolated from each other.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let db = sled::Db::start(config).unwrap();
+    /// let users = db.open_tree(b"users".to_vec()).unwrap();
+    /// users.set(b"k".to_vec(), vec![1]).unwrap();
+    /// assert_eq!(users.get(b"k"), Ok(Some(vec![1].into())));
+    /// assert_eq!(db.get(b"k"), Ok(None));
+    /// ```
+    pub fn open_tree(&self, name: Vec<u8>) -> DbResult<Tree, ()> {
+        if name == DEFAULT_TREE {
+            return Ok(self.default.clone());
+        }
+        // the hidden trees that hold other trees' deadlines
+        // and changes can't be opened directly.
+        for &prefix in &[DEADLINES_PREFIX, CHANGES_PREFIX] {
+            if name.starts_with(prefix) {
+                return Err(Error::Unsupported(format!(
+                    "tree names starting with {:?} are reserved",
+                    prefix
+                )));
+            }
+        }
+        if let Some(tree) = self.read_tenants().get(&name) {
+            return Ok(tree.clone());
+        }
+
+        if self.config.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        let mut tenants = self.tenants.write().expect(
+            "a thread panicked and poisoned the Db's tenants lock",
+        );
+        if let Some(tree) = tenants.get(&name) {
+            return Ok(tree.clone());
+        }
+
+        let tree = self.default.create_tenant(name.clone())?;
+        tenants.insert(name, tree.clone());
+        Ok(tree)
+    }
+
+    /// Remove the `Tree` with the provided name and all of its
+    /// contents, returning `true` if it existed. Existing handles to
+    /// the dropped `Tree` return errors afterwards, and live iterators
+    /// over it return an error and then end. The default `Tree` can't
+    /// be dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let db = sled::Db::start(config).unwrap();
+    /// let tmp = db.open_tree(b"tmp".to_vec()).unwrap();
+    /// tmp.set(b"k".to_vec(), vec![1]).unwrap();
+    ///
+    /// assert_eq!(db.drop_tree(b"tmp"), Ok(true));
+    /// assert!(tmp.get(b"k").is_err());
+    /// assert_eq!(db.drop_tree(b"tmp"), Ok(false));
+    /// ```
+    pub fn drop_tree(&self, name: &[u8]) -> DbResult<bool, ()> {
+        if name == DEFAULT_TREE {
+            return Err(Error::Unsupported(
+                "the default tree cannot be dropped".to_owned(),
+            ));
+        }
+
+        if self.config.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        let mut tenants = self.tenants.write().expect(
+            "a thread panicked and poisoned the Db's tenants lock",
+        );
+        let tree = match tenants.remove(name) {
+            Some(tree) => tree,
+            None => return Ok(false),
+        };
+
+        // wait for the writes and scans in flight on the tree to finish,
+        // and make sure no new ones start. point reads don't take the
+        // lock, but they stop once they notice that it was dropped.
+        let _cc = tree.write_lock();
+        tree.dropped.store(true, SeqCst);
+        tree.subscriptions.clear();
+
+        // the hidden trees holding its deadlines and changes go with it
+        let hidden: Vec<Tree> = tree.deadlines
+            .tree()
+            .into_iter()
+            .chain(tree.changes.tree())
+            .collect();
+        for hidden in &hidden {
+            hidden.dropped.store(true, SeqCst);
+        }
+
+        // a tree created later with the same name must not
+        // inherit this tree's persisted count.
+        self.mark_lens_dirty()?;
+        {
+            let mut lens = self.lens.trees.lock().expect(
+                "a thread panicked and poisoned the Db's lens mutex",
+            );
+            lens.remove(name);
+            for hidden in &hidden {
+                lens.remove(&hidden.name);
+            }
+        }
+
+        // removing the tree from the meta page is the point after
+        // which it is considered dropped, even after a crash.
+        self.update_meta(|meta| {
+            meta.remove(name);
+            for hidden in &hidden {
+                meta.remove(&hidden.name);
+            }
+        })?;
+
+        tree.free_pages(tree.root.load(SeqCst))?;
+        for hidden in hidden {
+            hidden.free_pages(hidden.root.load(SeqCst))?;
+        }
+
+        Ok(true)
+    }
+
+    /// Returns the names of all `Tree`s in the `Db`, including
+    /// the default one, in sorted order.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let db = sled::Db::start(config).unwrap();
+    /// db.open_tree(b"b".to_vec()).unwrap();
+    /// db.open_tree(b"a".to_vec()).unwrap();
+    /// let names = db.tree_names();
+    /// assert_eq!(names.len(), 3);
+    /// assert!(names.contains(&b"a".to_vec()));
+    /// assert!(names.contains(&b"b".to_vec()));
+    /// ```
+    pub fn tree_names(&self) -> Vec<Vec<u8>> {
+        let mut names: Vec<Vec<u8>> =
+            self.read_tenants().keys().cloned().collect();
+        names.push(DEFAULT_TREE.to_vec());
+        names.sort();
+        names
+    }
+
+    /// Returns the bytes of storage that the `Db` is using. This counts
+    /// every segment of the log that holds live data, including the
+    /// overwritten and removed data in them that hasn't been cleaned up
+    /// yet, and not the segments that have been freed for reuse. Unlike
+    /// the size of the file, this shrinks as segments are cleaned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let db = sled::Db::start(config).unwrap();
+    /// db.set(vec![1], vec![0; 1024]).unwrap();
+    /// assert!(db.size_on_disk().unwrap() > 1024);
+    /// ```
+    pub fn size_on_disk(&self) -> DbResult<u64, ()> {
+        Ok(self.pages.size_on_disk())
+    }
+
+    /// Returns how much memory the `Db`'s cached pages take up, and
+    /// how often pages have been read from the cache, read from disk,
+    /// and evicted since the `Db` was started. Pages are evicted,
+    /// least recently used first, to keep `resident_bytes` close to
+    /// the configured `cache_capacity`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new()
+    ///     .temporary(true)
+    ///     .cache_capacity(1024 * 1024)
+    ///     .build();
+    /// let db = sled::Db::start(config).unwrap();
+    /// db.set(vec![1], vec![0; 1024]).unwrap();
+    /// assert_eq!(db.get(&[1]).unwrap().unwrap().len(), 1024);
+    ///
+    /// let stats = db.cache_stats();
+    /// assert!(stats.resident_bytes > 1024);
+    /// assert!(stats.resident_bytes < 1024 * 1024);
+    /// assert!(stats.hits > 0);
+    /// ```
+    pub fn cache_stats(&self) -> CacheStats {
+        self.pages.cache_stats()
+    }
+
+    /// Returns what recovery found in the log when the `Db` was
+    /// started. Recovery replays the log from the last snapshot, and
+    /// stops at the first message that fails its checksum, so writes
+    /// that a crash tore are lost rather than read back as garbage,
+    /// along with any that came after them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let db = sled::Db::start(config.clone()).unwrap();
+    /// db.set(vec![1], vec![1]).unwrap();
+    /// drop(db);
+    ///
+    /// let db = sled::Db::start(config).unwrap();
+    /// let info = db.recovery_info();
+    /// assert!(info.segments_scanned > 0);
+    /// assert_eq!(info.bytes_discarded, 0);
+    /// ```
+    pub fn recovery_info(&self) -> RecoveryInfo {
+        self.pages.recovery_info()
+    }
+
+    /// Set the merge operator used by `merge`, replacing any operator
+    /// previously configured. There is one merge operator for the
+    /// whole `Db`, which every one of its trees uses. Merges are
+    /// stored as unmerged fragments and resolved lazily when pages are
+    /// read or consolidated, so the same operator must be set again
+    /// after restarting, before any previously merged keys are read.
+    /// Until it is, reading them returns `Error::Unsupported`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// fn concatenate_merge(
+    ///   _key: &[u8],
+    ///   old_value: Option<&[u8]>,
+    ///   merged_bytes: &[u8]
+    /// ) -> Option<Vec<u8>> {
+    ///   let mut ret = old_value
+    ///     .map(|ov| ov.to_vec())
+    ///     .unwrap_or_else(|| vec![]);
+    ///   ret.extend_from_slice(merged_bytes);
+    ///   Some(ret)
+    /// }
+    ///
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let db = sled::Db::start(config).unwrap();
+    /// let tree = db.open_tree(b"a".to_vec()).unwrap();
+    ///
+    /// // merging is unsupported until an operator is set
+    /// assert!(tree.merge(vec![1], vec![1]).is_err());
+    ///
+    /// db.set_merge_operator(concatenate_merge);
+    /// tree.merge(vec![1], vec![1]).unwrap();
+    /// tree.merge(vec![1], vec![2]).unwrap();
+    /// assert_eq!(tree.get(&[1]), Ok(Some(vec![1, 2].into())));
+    /// ```
+    pub fn set_merge_operator(&self, merge_operator: MergeOperator) {
+        self.config.set_merge_operator(merge_operator);
+    }
+
+    fn read_tenants(&self) -> RwLockReadGuard<HashMap<Vec<u8>, Tree>> {
+        self.tenants.read().expect(
+            "a thread panicked and poisoned the Db's tenants lock",
+        )
+    }
+
+    // finds a tree by the name that its writes are logged under,
+    // including the hidden trees that belong to another one.
+    pub(super) fn tree_by_name(&self, name: &[u8]) -> Option<Tree> {
+        if name.starts_with(DEADLINES_PREFIX) {
+            let owner = self.tree_by_name(&name[DEADLINES_PREFIX.len()..]);
+            owner.and_then(|owner| owner.deadlines.tree())
+        } else if name.starts_with(CHANGES_PREFIX) {
+            let owner = self.tree_by_name(&name[CHANGES_PREFIX.len()..]);
+            owner.and_then(|owner| owner.changes.tree())
+        } else if name == DEFAULT_TREE {
+            Some(self.default.clone())
+        } else {
+            self.read_tenants().get(name).cloned()
+        }
+    }
+
+    // finishes applying batches that were logged, but possibly only
+    // partially applied, before the database was last shut down.
+    fn recover_batch(&self) -> DbResult<(), ()> {
+        let guard = pin();
+        let get_cursor = self.pages.get(BATCH_PID, &guard).map_err(
+            |e| e.danger_cast(),
+        )?;
+
+        let batches = match get_cursor {
+            PageGet::Materialized(Frag::Batch(batches), _) => batches,
+            broken => {
+                return Err(Error::Unsupported(format!(
+                    "expected pid {} to contain the batch page, \
+                    was this database created by an older version? {:?}",
+                    BATCH_PID,
+                    broken
+                )))
+            }
+        };
+
+        if batches.is_empty() {
+            return Ok(());
+        }
+
+        if self.config.read_only {
+            return Err(Error::Unsupported(
+                "the database contains a partially applied batch, \
+                which cannot be recovered in read-only mode"
+                    .to_owned(),
+            ));
+        }
+
+        debug!("recovering partially applied batch while starting db");
+        for (name, batch) in batches {
+            // writes to trees that were dropped since are discarded
+            if let Some(tree) = self.tree_by_name(&name) {
+                tree.apply_batch_writes(batch)?;
+            }
+        }
+        self.update_batch_page(|batches| batches.clear())
+    }
+
+    // picks up the epoch from where it was when last persisted, and
+    // makes sure every tree that tracks its changes knows which
+    // epoch that started at.
+    fn recover_epoch(&self) -> DbResult<(), ()> {
+        let guard = pin();
+        let get_cursor = self.pages.get(EPOCH_PID, &guard).map_err(
+            |e| e.danger_cast(),
+        )?;
+
+        match get_cursor {
+            PageGet::Materialized(Frag::Epoch(persisted), _) => {
+                self.epoch.current.store(persisted as usize, SeqCst);
+            }
+            broken => {
+                return Err(Error::Unsupported(format!(
+                    "expected pid {} to contain the epoch page, \
+                    was this database created by an older version? {:?}",
+                    EPOCH_PID,
+                    broken
+                )))
+            }
+        }
+
+        if self.config.read_only {
+            return Ok(());
+        }
+        let mut trees = vec![self.default.clone()];
+        trees.extend(self.read_tenants().values().cloned());
+        for tree in trees {
+            tree.recover_tracking()?;
+        }
+        Ok(())
+    }
+
+    // starts id generation at the end of the last
+    // lease that was persisted before shutting down.
+    fn recover_idgen(&self) -> DbResult<(), ()> {
+        let guard = pin();
+        let get_cursor = self.pages.get(COUNTER_PID, &guard).map_err(
+            |e| e.danger_cast(),
+        )?;
+
+        match get_cursor {
+            PageGet::Materialized(Frag::Counter(persisted), _) => {
+                self.idgen.store(persisted, SeqCst);
+                self.idgen_persisted.store(persisted, SeqCst);
+                Ok(())
+            }
+            broken => Err(Error::Unsupported(format!(
+                "expected pid {} to contain the counter page, \
+                was this database created by an older version? {:?}",
+                COUNTER_PID,
+                broken
+            ))),
+        }
+    }
+}
+
+// the initial leaf and root frags for a new tree
+pub(super) fn new_root(root_id: PageID, leaf_id: PageID) -> (Frag, Frag) {
+    let leaf = Frag::Base(
+        Node {
+            id: leaf_id,
+            data: Data::Leaf(vec![]),
+            next: None,
+            lo: Bound::Inclusive(vec![]),
+            hi: Bound::Inf,
+        },
+        None,
+    );
+
+    // vec![0] represents a prefix-encoded empty prefix
+    let root_index_vec = vec![(vec![0], leaf_id)];
+    let mut counts = BTreeMap::new();
+    counts.insert(leaf_id, 0);
+
+    let root = Frag::Base(
+        Node {
+            id: root_id,
+            data: Data::Index(root_index_vec, ChildCounts::new(counts)),
+            next: None,
+            lo: Bound::Inclusive(vec![]),
+            hi: Bound::Inf,
+        },
+        Some(std::usize::MAX),
+    );
+
+    (leaf, root)
+}
+
+// follows the chain of root hoists that started at a tree's
+// initial root, returning the most recent root.
+fn current_root(roots: &[(PageID, PageID)], initial: PageID) -> PageID {
+    let mut last = initial;
+    while let Some(&(root, _)) =
+        roots.iter().find(|&&(_, prev_root)| prev_root == last)
+    {
+        last = root;
+    }
+    last
+}
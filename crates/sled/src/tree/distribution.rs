@@ -0,0 +1,453 @@
+use std::cmp;
+use std::ops::RangeBounds;
+use std::slice;
+use std::sync::atomic::Ordering::SeqCst;
+use std::thread;
+
+use epoch::{Guard, pin};
+
+use super::*;
+use super::tree::range_bounds;
+
+// `split_points` reads down the index until a level has at least this
+// many nodes for each chunk it's asked for, so that the chunks can be
+// balanced to within a fraction of a node.
+const SPLIT_NODES_PER_CHUNK: usize = 8;
+
+// the nodes of one level that together hold what one child of their
+// parent does. a child that has split since its parent last heard
+// about it is spread across itself and its new right siblings.
+type Span = Vec<Node>;
+
+impl Tree {
+    /// Count the entries in the provided range, without reading most
+    /// of them. Every index node keeps count of the entries under each
+    /// of its children, so the index is followed from the root down to
+    /// the leaves at the ends of the range, whose entries are counted
+    /// one by one, and everything in between is counted from the index.
+    /// This reads a few nodes for each level of the `Tree`, however many
+    /// entries the range holds.
+    ///
+    /// The index is told about each insertion or removal just after it's
+    /// made, so the count is exact except for insertions and removals
+    /// that are still in flight while this runs, which may or may not
+    /// be counted. After a crash, the counts in the index are rebuilt
+    /// when the `Tree` is next started, unless it's read-only. Entries
+    /// written with `set_with_ttl` that have expired but not yet been
+    /// removed are counted.
+    ///
+    /// Keeping these counts costs every write that inserts or removes an
+    /// entry a small write to each index node above its leaf, all the
+    /// way up to the root, which every such write contends for.
+    /// Overwriting an existing entry costs nothing extra.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// for i in 0..10 {
+    ///     t.set(vec![i], vec![]).unwrap();
+    /// }
+    /// assert_eq!(t.count_range(vec![2]..vec![5]), Ok(3));
+    /// assert_eq!(t.count_range::<Vec<u8>, _>(..), Ok(10));
+    /// ```
+    pub fn count_range<K, R>(&self, range: R) -> DbResult<usize, ()>
+        where K: AsRef<[u8]> + ?Sized,
+              R: RangeBounds<K>
+    {
+        let (lo, hi) = range_bounds(&range);
+        let _cc = self.read_lock()?;
+        let guard = pin();
+
+        let root = self.root.load(SeqCst);
+        let root = self.read_span(root, &Bound::Inf, &guard)?;
+        let count = self.count_span(&root, &*lo, &hi, &guard)?;
+        Ok(cmp::min(cmp::max(count, 0) as usize, self.len()))
+    }
+
+    // counts the span's entries that are in the range. only the
+    // children that straddle either end of the range are read.
+    fn count_span(
+        &self,
+        span: &[Node],
+        lo: &[u8],
+        hi: &Bound,
+        guard: &Guard,
+    ) -> DbResult<i64, ()> {
+        if is_leaf(span) {
+            let within = leaf_keys(span)
+                .iter()
+                .filter(|k| {
+                    &***k >= lo && Bound::Inclusive(k.to_vec()) < *hi
+                })
+                .count();
+            return Ok(within as i64);
+        }
+
+        let mut count = 0;
+        for (child_lo, child_hi, pid, entries) in children(span) {
+            let before = child_hi <= Bound::Inclusive(lo.to_vec());
+            let after = Bound::Inclusive(child_lo.clone()) >= *hi;
+            if before || after {
+                continue;
+            }
+
+            if &*child_lo >= lo && child_hi <= *hi {
+                count += entries;
+            } else {
+                let child = self.read_span(pid, &child_hi, guard)?;
+                count += self.count_span(&child, lo, hi, guard)?;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Find up to `n - 1` keys that split the `Tree` into `n` chunks
+    /// holding roughly the same number of entries, for example to scan
+    /// it in parallel. The first chunk is everything before the first
+    /// key, each key starts the next chunk, and the last chunk runs to
+    /// the end of the `Tree`.
+    ///
+    /// The index is read from the root down until it has several nodes
+    /// for each chunk, so this reads a number of nodes proportional to
+    /// `n` rather than to the number of entries. The entries under each
+    /// of those nodes are counted from the index, as `count_range` does,
+    /// and the chunks are split at the nodes' lowest keys, unless the
+    /// leaves are reached, whose entries the chunks can be split
+    /// between exactly. So each chunk is off from an even split by at
+    /// most half of the entries under one of the nodes the chunks are
+    /// split at, plus any insertions and removals in flight while this
+    /// runs. Fewer keys are returned when the `Tree` has too few entries
+    /// to split that finely.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// for i in 0..100 {
+    ///     t.set(vec![i], vec![]).unwrap();
+    /// }
+    /// assert_eq!(t.split_points(4), Ok(vec![vec![25], vec![50], vec![75]]));
+    /// ```
+    pub fn split_points(&self, n: usize) -> DbResult<Vec<Key>, ()> {
+        let _cc = self.read_lock()?;
+        if n < 2 {
+            return Ok(vec![]);
+        }
+        let guard = pin();
+
+        let root = self.root.load(SeqCst);
+        let root = self.read_span(root, &Bound::Inf, &guard)?;
+        let mut level = vec![(root, 0)];
+        while !is_leaf(&level[0].0) && level.len() < n * SPLIT_NODES_PER_CHUNK {
+            let mut next = vec![];
+            for (span, _) in level {
+                for (_, child_hi, pid, entries) in children(&span) {
+                    let child = self.read_span(pid, &child_hi, &guard)?;
+                    next.push((child, entries));
+                }
+            }
+            level = next;
+        }
+
+        // the keys that chunks could start at, and how many
+        // entries there are from each one up to the next.
+        let mut starts: Vec<(Key, f64)> = vec![];
+        if is_leaf(&level[0].0) {
+            // every leaf has been read, so we can count exactly
+            for &(ref span, _) in &level {
+                starts.extend(leaf_keys(span).into_iter().map(|k| (k, 1.)));
+            }
+        } else {
+            for (span, entries) in level {
+                let entries = cmp::max(entries, 0) as f64;
+                starts.push((span[0].lo.inner().to_vec(), entries));
+            }
+        }
+
+        let total: f64 = starts.iter().map(|&(_, weight)| weight).sum();
+        let mut splits = vec![];
+        let mut before = 0.;
+        let mut chunk = 1;
+        for (key, weight) in starts {
+            // this key starts the next chunk if that chunk should
+            // start closer to here than to the following key.
+            let mut starts_chunk = false;
+            while chunk < n &&
+                total * chunk as f64 / n as f64 <= before + weight / 2.
+            {
+                chunk += 1;
+                starts_chunk = true;
+            }
+            if starts_chunk && before > 0. {
+                splits.push(key);
+            }
+            before += weight;
+        }
+        Ok(splits)
+    }
+
+    // reads the node with this id, and its right siblings up to the
+    // one that reaches `hi`. callers must hold the read or write lock,
+    // so that the pages can't be freed by a concurrent clear.
+    fn read_span(
+        &self,
+        mut id: PageID,
+        hi: &Bound,
+        guard: &Guard,
+    ) -> DbResult<Span, ()> {
+        let mut span = vec![];
+        loop {
+            let (node, _) = self.node_for_pid(id, guard)?;
+            let next = match node.next {
+                Some(next) if node.hi < *hi => Some(next),
+                _ => None,
+            };
+            span.push(node);
+            match next {
+                Some(next) => id = next,
+                None => return Ok(span),
+            }
+        }
+    }
+
+    // adds changes to the counts that the index nodes at `height` above
+    // the leaves keep of their children, and to those of the nodes above
+    // them in turn. each change is given with the lowest key and the id
+    // of its child. `ancestors` are the ids of the nodes that a traversal
+    // passed through on the way down to that height, which may have
+    // split or been hoisted under a new root since. callers must hold
+    // the read or write lock, or be starting the Db.
+    pub(super) fn count_children(
+        &self,
+        ancestors: &[PageID],
+        height: usize,
+        counts: Vec<(Key, PageID, i64)>,
+        guard: &Guard,
+    ) -> DbResult<(), ()> {
+        let res = self.count_children_inner(ancestors, height, counts, guard);
+        if res.is_err() {
+            self.lose_counts();
+        }
+        res
+    }
+
+    fn count_children_inner(
+        &self,
+        ancestors: &[PageID],
+        mut height: usize,
+        mut counts: Vec<(Key, PageID, i64)>,
+        guard: &Guard,
+    ) -> DbResult<(), ()> {
+        let mut ancestors = ancestors.to_vec();
+        counts.sort();
+        while !counts.is_empty() {
+            let mut id = match ancestors.pop() {
+                Some(id) => id,
+                None => self.node_above(&*counts[0].0, height, guard)?,
+            };
+
+            // the changes under each node that they land in
+            // become changes to the level above.
+            let mut above = vec![];
+            let mut i = 0;
+            while i < counts.len() {
+                let (node, cas_key) = self.node_for_pid(id, guard)?;
+
+                // the node may have split since it was read
+                if node.hi <= Bound::Inclusive(counts[i].0.clone()) {
+                    id = node.next.expect(
+                        "if our hi bound is not Inf (inity), \
+                        we should have a right sibling",
+                    );
+                    continue;
+                }
+
+                let end = i +
+                    counts[i..]
+                        .iter()
+                        .take_while(|&&(ref lo, _, _)| {
+                            Bound::Inclusive(lo.clone()) < node.hi
+                        })
+                        .count();
+                let frag = Frag::Count(counts[i..end].to_vec());
+                match self.pages.link(id, cas_key, frag, guard) {
+                    Ok(_) => {}
+                    Err(Error::CasFailed(_)) => {
+                        M.tree_looped();
+                        continue;
+                    }
+                    Err(other) => return Err(other.danger_cast()),
+                }
+
+                // the only node that covers every key is the root
+                let delta: i64 = counts[i..end].iter().map(|c| c.2).sum();
+                let is_root = node.lo == Bound::Inclusive(vec![]) &&
+                    node.hi == Bound::Inf;
+                if delta != 0 && !is_root {
+                    above.push((node.lo.inner().to_vec(), node.id, delta));
+                }
+                i = end;
+            }
+            counts = above;
+            height += 1;
+        }
+        Ok(())
+    }
+
+    // finds the node at `height` above the leaves whose keys include
+    // this one, for when a traversal's path didn't reach that high.
+    fn node_above(
+        &self,
+        key: &[u8],
+        height: usize,
+        guard: &Guard,
+    ) -> DbResult<PageID, ()> {
+        loop {
+            let path = self.path_for_key(key, guard)?;
+            if let Some(idx) = path.len().checked_sub(height + 1) {
+                return Ok(path[idx].0.id);
+            }
+            // the root has split, but the new
+            // root hasn't been hoisted above it yet.
+            M.tree_looped();
+            thread::yield_now();
+        }
+    }
+
+    // rebuilds the counts that index nodes keep of their children from
+    // the entries under them, which a crash may have lost changes to.
+    // callers must be starting the Db.
+    pub(super) fn recount_children(&self) -> DbResult<(), ()> {
+        let guard = pin();
+        let root = self.root.load(SeqCst);
+        self.recount_span(root, &Bound::Inf, &guard)?;
+        Ok(())
+    }
+
+    // recounts the node with this id and its right siblings up to the
+    // one that reaches `hi`, returning the lowest key, the id and the
+    // number of entries under each.
+    fn recount_span(
+        &self,
+        mut id: PageID,
+        hi: &Bound,
+        guard: &Guard,
+    ) -> DbResult<Vec<(Key, PageID, i64)>, ()> {
+        let mut span = vec![];
+        loop {
+            let (node, _) = self.node_for_pid(id, guard)?;
+
+            let entries = if node.data.leaf_ref().is_some() {
+                node.data.entries()
+            } else {
+                let mut counts = ChildCounts::default();
+                for (_, child_hi, pid, _) in children(slice::from_ref(&node)) {
+                    let child = self.recount_span(pid, &child_hi, guard)?;
+                    let entries = child.iter().map(|c| c.2).sum();
+                    counts.children.insert(pid, entries);
+                    for &(ref lo, unlinked, entries) in &child[1..] {
+                        counts.unlinked.insert(unlinked, (lo.clone(), entries));
+                    }
+                }
+                let entries = counts.total();
+                self.replace_counts(id, counts, guard)?;
+                entries
+            };
+
+            span.push((node.lo.inner().to_vec(), node.id, entries));
+            match node.next {
+                Some(next) if node.hi < *hi => id = next,
+                _ => return Ok(span),
+            }
+        }
+    }
+
+    // replaces the counts that an index node keeps of its children
+    fn replace_counts(
+        &self,
+        id: PageID,
+        counts: ChildCounts,
+        guard: &Guard,
+    ) -> DbResult<(), ()> {
+        loop {
+            let get_cursor = self.pages.get(id, guard).map_err(
+                |e| e.danger_cast(),
+            )?;
+            let (mut node, prev_root, cas_key) = match get_cursor {
+                PageGet::Materialized(Frag::Base(node, prev_root), cas_key) => {
+                    (node, prev_root, cas_key)
+                }
+                broken => {
+                    return Err(Error::ReportableBug(format!(
+                        "got non-base page while counting entries: {:?}",
+                        broken
+                    )))
+                }
+            };
+            match node.data {
+                Data::Index(_, ref old) if *old == counts => return Ok(()),
+                Data::Index(_, ref mut old) => *old = counts.clone(),
+                Data::Leaf(_) => {
+                    return Err(Error::ReportableBug(
+                        "got a leaf while counting an index node's children"
+                            .to_owned(),
+                    ))
+                }
+            }
+
+            let frag = Frag::Base(node, prev_root);
+            match self.pages.replace(id, cas_key, frag, guard) {
+                Ok(_) => return Ok(()),
+                Err(Error::CasFailed(_)) => {}
+                Err(other) => return Err(other.danger_cast()),
+            }
+            M.tree_looped();
+        }
+    }
+}
+
+fn is_leaf(span: &[Node]) -> bool {
+    match span.first() {
+        Some(node) => node.data.leaf_ref().is_some(),
+        None => true,
+    }
+}
+
+// the decoded keys of a span of leaves, in order
+fn leaf_keys(span: &[Node]) -> Vec<Key> {
+    let mut keys = vec![];
+    for node in span {
+        if let Some(entries) = node.data.leaf_ref() {
+            let prefix = node.lo.inner();
+            keys.extend(
+                entries.iter().map(|&(ref k, _)| prefix_decode(prefix, k)),
+            );
+        }
+    }
+    keys
+}
+
+// the bounds, ids and entry counts of the children
+// of a span of index nodes, in order
+fn children(span: &[Node]) -> Vec<(Key, Bound, PageID, i64)> {
+    let mut children = vec![];
+    for node in span {
+        if let Data::Index(ref ptrs, ref counts) = node.data {
+            let prefix = node.lo.inner();
+            for (i, &(ref sep, pid)) in ptrs.iter().enumerate() {
+                let hi = match ptrs.get(i + 1) {
+                    Some(&(ref next, _)) => {
+                        Bound::Exclusive(prefix_decode(prefix, next))
+                    }
+                    None => node.hi.clone(),
+                };
+                let lo = prefix_decode(prefix, sep);
+                children.push((lo, hi, pid, counts.get(pid)));
+            }
+        }
+    }
+    children
+}
@@ -0,0 +1,177 @@
+use std::collections::VecDeque;
+use std::vec;
+
+use super::*;
+
+// the most entries, and roughly the most bytes, that
+// an export reads from a tree before handing them out.
+const EXPORT_CHUNK_ENTRIES: usize = 1024;
+const EXPORT_CHUNK_BYTES: usize = 1 << 20;
+
+/// An iterator over the name and contents of every `Tree` in a `Db`,
+/// created by `Db::export`. Trees are returned in order of their
+/// names, and each one's contents are read as they're iterated over.
+pub struct Export {
+    trees: vec::IntoIter<(Vec<u8>, Tree)>,
+}
+
+impl Iterator for Export {
+    type Item = (Vec<u8>, TreeExport);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.trees.next().map(|(name, tree)| {
+            let export = TreeExport {
+                tree: tree,
+                buf: VecDeque::new(),
+                last: None,
+                done: false,
+            };
+            (name, export)
+        })
+    }
+}
+
+/// An iterator over the keys and values of one `Tree`, in key order,
+/// as returned by `Export`. Only a bounded number of entries are held
+/// in memory at a time. If reading the `Tree` fails, the error is
+/// returned and then the iterator ends.
+pub struct TreeExport {
+    tree: Tree,
+    buf: VecDeque<(Key, Value)>,
+    last: Option<Key>,
+    done: bool,
+}
+
+impl Iterator for TreeExport {
+    type Item = DbResult<(Key, Value), ()>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() && !self.done {
+            if let Err(e) = self.refill() {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+        self.buf.pop_front().map(Ok)
+    }
+}
+
+impl TreeExport {
+    // reads the next few entries after the last one handed out
+    fn refill(&mut self) -> DbResult<(), ()> {
+        let iter = match self.last {
+            Some(ref last) => self.tree.scan(last),
+            None => self.tree.iter(),
+        };
+
+        let mut bytes = 0;
+        let mut exhausted = true;
+        for res in iter {
+            let (k, v) = res?;
+            if Some(&k) == self.last.as_ref() {
+                continue;
+            }
+            bytes += k.len() + v.len();
+            self.buf.push_back((k, v.to_vec()));
+            if self.buf.len() >= EXPORT_CHUNK_ENTRIES ||
+                bytes >= EXPORT_CHUNK_BYTES
+            {
+                exhausted = false;
+                break;
+            }
+        }
+
+        if exhausted {
+            self.done = true;
+        }
+        if let Some(&(ref k, _)) = self.buf.back() {
+            self.last = Some(k.clone());
+        }
+        Ok(())
+    }
+}
+
+impl Db {
+    /// Stream the contents of every `Tree` in the `Db`, in key order,
+    /// for loading into another `Db` with `Db::import`. This reads
+    /// the trees as it goes instead of loading them into memory, and
+    /// works on a `Db` opened in read-only mode.
+    ///
+    /// This is the supported way to upgrade between versions of sled
+    /// whose on-disk formats differ: export the `Db` using the version
+    /// that wrote it, and import it into a fresh `Db` using the next
+    /// version.
+    ///
+    /// Each tree is read as it's iterated over rather than at a single
+    /// point in time, so writes made during an export may or may not be
+    /// included. The deadlines of entries written with `set_with_ttl`
+    /// are not exported, and entries that have already expired are
+    /// skipped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let db = sled::Db::start(config).unwrap();
+    /// let users = db.open_tree(b"users".to_vec()).unwrap();
+    /// users.set(b"alice".to_vec(), vec![1]).unwrap();
+    ///
+    /// for (name, entries) in db.export() {
+    ///     let entries: Vec<_> = entries.map(|res| res.unwrap()).collect();
+    ///     if name == b"users" {
+    ///         assert_eq!(entries, vec![(b"alice".to_vec(), vec![1])]);
+    ///     } else {
+    ///         assert!(entries.is_empty());
+    ///     }
+    /// }
+    /// ```
+    pub fn export(&self) -> Export {
+        let trees: Vec<(Vec<u8>, Tree)> = self.tree_names()
+            .into_iter()
+            .filter_map(|name| {
+                self.tree_by_name(&name).map(|tree| (name, tree))
+            })
+            .collect();
+        Export { trees: trees.into_iter() }
+    }
+
+    /// Load trees exported by `Db::export`, creating them as needed.
+    /// Every imported tree must be empty beforehand, so this is meant
+    /// for filling in a fresh `Db`. Each tree is filled with
+    /// `Tree::bulk_load`, so its entries must be in key order, as they
+    /// are when exported. An error while reading the exported entries
+    /// stops the import and is returned, leaving the tree that was
+    /// being imported empty.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let old_config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let old = sled::Db::start(old_config).unwrap();
+    /// let users = old.open_tree(b"users".to_vec()).unwrap();
+    /// users.set(b"alice".to_vec(), vec![1]).unwrap();
+    ///
+    /// let new_config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let new = sled::Db::start(new_config).unwrap();
+    /// new.import(old.export()).unwrap();
+    ///
+    /// let users = new.open_tree(b"users".to_vec()).unwrap();
+    /// assert_eq!(users.get(b"alice"), Ok(Some(vec![1].into())));
+    /// ```
+    pub fn import<I, T>(&self, export: I) -> DbResult<(), ()>
+        where I: IntoIterator<Item = (Vec<u8>, T)>,
+              T: IntoIterator<Item = DbResult<(Key, Value), ()>>
+    {
+        for (name, entries) in export {
+            let tree = self.open_tree(name.clone())?;
+            if !tree.is_empty() {
+                return Err(Error::Unsupported(format!(
+                    "cannot import into the tree {:?}, which is not empty",
+                    String::from_utf8_lossy(&*name)
+                )));
+            }
+            tree.bulk_loader().load_results(entries)?;
+        }
+        Ok(())
+    }
+}
@@ -1,3 +1,6 @@
+use std::collections::BTreeMap;
+use std::mem;
+
 use super::*;
 
 // TODO
@@ -10,7 +13,7 @@ use super::*;
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Frag {
-    Set(Key, Value),
+    Set(Key, IVec),
     Del(Key),
     Merge(Key, Value),
     /// The optional page in Base means this node has replaced
@@ -18,6 +21,76 @@ pub enum Frag {
     Base(Node, Option<PageID>),
     ChildSplit(ChildSplit),
     ParentSplit(ParentSplit),
+    /// Entries added to or removed from under some of an index
+    /// node's children, with the lowest key of each child.
+    Count(Vec<(Key, PageID, i64)>),
+    /// The contents of the batch page, which holds any batches
+    /// that have not yet been fully applied, by tree name.
+    Batch(Vec<(Vec<u8>, Batch)>),
+    /// The contents of the counter page, which holds the end
+    /// of the last lease of ids reserved by `generate_id`.
+    Counter(usize),
+    /// The contents of the meta page, which maps the name of
+    /// each tree in the `Db` to the first root it was created with.
+    Meta(BTreeMap<Vec<u8>, PageID>),
+    /// The contents of the lens page, which holds the number of
+    /// entries in each tree, and the total length of their keys and
+    /// of their values, as of the last clean shutdown, or `None` if
+    /// any tree has been written to since.
+    Lens(Option<BTreeMap<Vec<u8>, (usize, u64, u64)>>),
+    /// The contents of the epoch page, which holds the `Db`'s
+    /// current durability epoch.
+    Epoch(u64),
+}
+
+impl Frag {
+    /// The bytes of memory this frag takes up while it's cached,
+    /// including what it holds on the heap. Allocator overhead isn't
+    /// counted, so this is a slight underestimate.
+    pub fn mem_size(&self) -> usize {
+        let heap = match *self {
+            Frag::Set(ref k, ref v) => k.capacity() + v.heap_size(),
+            Frag::Del(ref k) => k.capacity(),
+            Frag::Merge(ref k, ref v) => k.capacity() + v.capacity(),
+            Frag::Base(ref node, _) => node.heap_size(),
+            Frag::ChildSplit(ChildSplit { ref at, .. }) |
+            Frag::ParentSplit(ParentSplit { ref at, .. }) => at.heap_size(),
+            Frag::Count(ref counts) => {
+                counts.capacity() * mem::size_of::<(Key, PageID, i64)>() +
+                    counts
+                        .iter()
+                        .map(|&(ref lo, _, _)| lo.capacity())
+                        .sum::<usize>()
+            }
+            Frag::Batch(ref batches) => {
+                batches.capacity() * mem::size_of::<(Vec<u8>, Batch)>() +
+                    batches
+                        .iter()
+                        .map(|&(ref name, ref batch)| {
+                            name.capacity() + batch.heap_size()
+                        })
+                        .sum::<usize>()
+            }
+            Frag::Counter(_) | Frag::Epoch(_) => 0,
+            Frag::Meta(ref meta) => {
+                meta.keys()
+                    .map(|name| {
+                        mem::size_of::<(Vec<u8>, PageID)>() + name.capacity()
+                    })
+                    .sum()
+            }
+            Frag::Lens(ref lens) => {
+                lens.iter()
+                    .flat_map(|lens| lens.keys())
+                    .map(|name| {
+                        mem::size_of::<(Vec<u8>, (usize, u64, u64))>() +
+                            name.capacity()
+                    })
+                    .sum()
+            }
+        };
+        mem::size_of::<Frag>() + heap
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
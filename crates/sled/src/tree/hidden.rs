@@ -0,0 +1,39 @@
+use super::*;
+
+// the hidden trees that hold another tree's deadlines and changes
+// record a stamp for each of its keys twice: under the key, so that
+// the stamp can be looked up and replaced, and under the stamp
+// followed by the key, so that keys can be read in stamp order.
+pub(super) const BY_KEY: u8 = 0;
+pub(super) const BY_STAMP: u8 = 1;
+
+pub(super) fn by_key(key: &[u8]) -> Key {
+    let mut ret = Vec::with_capacity(1 + key.len());
+    ret.push(BY_KEY);
+    ret.extend_from_slice(key);
+    ret
+}
+
+pub(super) fn by_stamp(stamp: u64, key: &[u8]) -> Key {
+    let mut ret = Vec::with_capacity(9 + key.len());
+    ret.push(BY_STAMP);
+    ret.extend_from_slice(&encode_stamp(stamp));
+    ret.extend_from_slice(key);
+    ret
+}
+
+// big-endian, so that stamps sort in order
+pub(super) fn encode_stamp(stamp: u64) -> [u8; 8] {
+    let mut ret = [0u8; 8];
+    for (i, b) in ret.iter_mut().enumerate() {
+        *b = (stamp >> ((7 - i) * 8)) as u8;
+    }
+    ret
+}
+
+pub(super) fn decode_stamp(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .take(8)
+        .fold(0, |stamp, &b| (stamp << 8) | u64::from(b))
+}
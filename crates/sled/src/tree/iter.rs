@@ -1,23 +1,165 @@
 use super::*;
 
+use std::sync::atomic::Ordering::SeqCst;
+
 use pagecache::PageGet;
 use epoch::pin;
 
+use super::tree::successor;
+
 /// An iterator over keys and values in a `Tree`.
+///
+/// Forward iteration follows the right-sibling links between leaves,
+/// reading each leaf once, so the entries returned from one leaf
+/// reflect a single point in time. Leaves are only linked in one
+/// direction, so each call to `next_back` descends from the root to
+/// find the leaf below the current upper bound, costing O(log n) per
+/// step.
+///
+/// Entries written with `Tree::set_with_ttl` are skipped once they
+/// have expired.
+///
+/// If the `Tree` is dropped from its `Db` during iteration, the
+/// iterator returns an `Error::Unsupported` and then ends. If the
+/// `Tree` is cleared during iteration, the iterator ends.
 pub struct Iter<'a> {
     pub(super) id: PageID,
-    pub(super) inner:
-        &'a PageCache<BLinkMaterializer, Frag, Vec<(PageID, PageID)>>,
+    pub(super) tree: &'a Tree,
     pub(super) last_key: Bound,
+    pub(super) hi: Bound,
     pub(super) broken: Option<Error<()>>,
     pub(super) done: bool,
-    // TODO we have to refactor this in light of pages being deleted
+    // the number of times the tree had been cleared when we started
+    pub(super) clears: usize,
+    // the leaf we're iterating over, and our position in it, so that
+    // it's only read once rather than for every entry.
+    pub(super) leaf: Option<Node>,
+    pub(super) pos: usize,
+    // snapshots see expired entries, and decide for themselves
+    pub(super) skip_expired: bool,
+}
+
+/// Where an `Iter` left off, returned by `Iter::resume_token` and
+/// passed to `Tree::range_resumed` to carry on from there. It only
+/// holds keys, so it can be serialized and stored or handed to a
+/// client, and it stays valid across restarts and writes.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ResumeToken {
+    // the smallest key that hasn't been passed over yet
+    lo: Key,
+    // the key below which nothing has been passed over yet, if any
+    hi: Option<Key>,
+}
+
+impl ResumeToken {
+    // the inclusive lower key and upper bound of what's left
+    pub(super) fn bounds(self) -> (Key, Bound) {
+        let hi = match self.hi {
+            Some(hi) => Bound::Exclusive(hi),
+            None => Bound::Inf,
+        };
+        (self.lo, hi)
+    }
 }
 
 impl<'a> Iterator for Iter<'a> {
-    type Item = DbResult<(Vec<u8>, Vec<u8>), ()>;
+    type Item = DbResult<(Key, IVec), ()>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        self.next_inner(|v| v.clone())
+    }
+}
+
+impl<'a> DoubleEndedIterator for Iter<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.next_back_inner(|v| v.clone())
+    }
+}
+
+impl<'a> Iter<'a> {
+    /// Iterate over only the keys, without copying the values.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![10]).unwrap();
+    /// t.set(vec![2], vec![20]).unwrap();
+    ///
+    /// let mut keys = t.iter().keys();
+    /// assert_eq!(keys.next(), Some(Ok(vec![1])));
+    /// assert_eq!(keys.next(), Some(Ok(vec![2])));
+    /// assert_eq!(keys.next(), None);
+    /// ```
+    pub fn keys(self) -> Keys<'a> {
+        Keys(self)
+    }
+
+    /// Iterate over only the values.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![10]).unwrap();
+    /// t.set(vec![2], vec![20]).unwrap();
+    ///
+    /// let mut values = t.iter().values().rev();
+    /// assert_eq!(values.next(), Some(Ok(vec![20].into())));
+    /// assert_eq!(values.next(), Some(Ok(vec![10].into())));
+    /// assert_eq!(values.next(), None);
+    /// ```
+    pub fn values(self) -> Values<'a> {
+        Values(self)
+    }
+
+    /// Return a token that `Tree::range_resumed` can use to continue
+    /// exactly after the entries this iterator has returned so far,
+    /// from either end, even if those entries are removed meanwhile.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// for i in 0..5 {
+    ///     t.set(vec![i], vec![]).unwrap();
+    /// }
+    ///
+    /// let mut page = t.range(vec![1]..);
+    /// assert_eq!(page.next(), Some(Ok((vec![1], vec![].into()))));
+    /// assert_eq!(page.next(), Some(Ok((vec![2], vec![].into()))));
+    /// let token = page.resume_token();
+    /// drop(page);
+    ///
+    /// t.del(&[2]).unwrap();
+    /// let mut page = t.range_resumed(vec![1].., token);
+    /// assert_eq!(page.next(), Some(Ok((vec![3], vec![].into()))));
+    /// ```
+    pub fn resume_token(&self) -> ResumeToken {
+        let lo = match self.last_key {
+            Bound::Exclusive(ref lo) => lo.clone(),
+            Bound::Inclusive(ref last) => successor(last),
+            Bound::Inf => vec![],
+        };
+        let hi = match self.hi {
+            Bound::Exclusive(ref hi) => Some(hi.clone()),
+            Bound::Inclusive(ref hi) => Some(successor(hi)),
+            Bound::Inf => None,
+        };
+        ResumeToken {
+            lo: lo,
+            hi: hi,
+        }
+    }
+
+    // returns the next key, along with whatever the
+    // provided function extracts from its value.
+    fn next_inner<T, F>(&mut self, f: F) -> Option<DbResult<(Key, T), ()>>
+        where F: FnOnce(&IVec) -> T
+    {
         if self.done {
             return None;
         } else if let Some(broken) = self.broken.take() {
@@ -25,46 +167,186 @@ impl<'a> Iterator for Iter<'a> {
             return Some(Err(broken));
         };
 
+        let _cc = match self.tree.read_lock() {
+            Ok(cc) => cc,
+            Err(e) => {
+                // the tree was dropped out from under us
+                self.done = true;
+                return Some(Err(e));
+            }
+        };
+        if self.tree.clears.load(SeqCst) != self.clears {
+            // the pages we were iterating over were freed
+            self.done = true;
+            return None;
+        }
         let guard = pin();
         loop {
-            let res = self.inner.get(self.id, &guard);
-
-            let node = match res {
-                Ok(PageGet::Materialized(Frag::Base(base, _), _)) => base,
-                Err(e) => {
-                    // TODO(when implementing merge support) this could
-                    // be None if the node was removed since the last
-                    // iteration, and we need to just get the inner
-                    // node again...
-                    error!("iteration failed: {:?}", e);
-                    self.done = true;
-                    return Some(Err(e.danger_cast()));
-                }
-                other => {
-                    panic!(
-                        "the pagecache returned an unexpected value \
-                        to the Tree iterator: {:?}",
-                        other
-                    )
+            if self.leaf.is_none() {
+                let res = self.tree.pages.get(self.id, &guard);
+
+                let node = match res {
+                    Ok(PageGet::Materialized(Frag::Base(base, _), _)) => base,
+                    Err(e) => {
+                        // TODO(when implementing merge support) this could
+                        // be None if the node was removed since the last
+                        // iteration, and we need to just get the inner
+                        // node again...
+                        error!("iteration failed: {:?}", e);
+                        self.done = true;
+                        return Some(Err(e.danger_cast()));
+                    }
+                    other => {
+                        panic!(
+                            "the pagecache returned an unexpected value \
+                            to the Tree iterator: {:?}",
+                            other
+                        )
+                    }
+                };
+                self.leaf = Some(node);
+                self.pos = 0;
+            }
+
+            let next = {
+                let node = self.leaf.as_ref().expect("leaf was just loaded");
+                let prefix = node.lo.inner();
+                let items =
+                    node.data.leaf_ref().expect("node should be a leaf");
+                while let Some(&(ref k, ref v)) = items.get(self.pos) {
+                    self.pos += 1;
+                    let decoded_k = prefix_decode(prefix, k);
+                    if !self.below_hi(&*decoded_k) {
+                        self.done = true;
+                        return None;
+                    }
+                    if self.above_last(&*decoded_k) {
+                        self.last_key = Bound::Inclusive(decoded_k.clone());
+                        if self.skip_expired {
+                            match self.tree.is_expired(&*decoded_k) {
+                                Ok(true) => continue,
+                                Ok(false) => {}
+                                Err(e) => {
+                                    self.done = true;
+                                    return Some(Err(e));
+                                }
+                            }
+                        }
+                        let ret = Ok((decoded_k, f(v)));
+                        return Some(ret);
+                    }
                 }
+                node.next
             };
+            match next {
+                Some(id) => {
+                    self.id = id;
+                    self.leaf = None;
+                }
+                None => return None,
+            }
+        }
+    }
+
+    fn next_back_inner<T, F>(
+        &mut self,
+        f: F,
+    ) -> Option<DbResult<(Key, T), ()>>
+        where F: FnOnce(&IVec) -> T
+    {
+        if self.done {
+            return None;
+        } else if let Some(broken) = self.broken.take() {
+            self.done = true;
+            return Some(Err(broken));
+        };
 
-            let prefix = node.lo.inner();
-            for (ref k, ref v) in node.data.leaf().expect(
-                "node should be a leaf",
-            )
-            {
-                let decoded_k = prefix_decode(prefix, k);
-                if Bound::Inclusive(decoded_k.clone()) > self.last_key {
-                    self.last_key = Bound::Inclusive(decoded_k.to_vec());
-                    let ret = Ok((decoded_k, v.clone()));
-                    return Some(ret);
+        let _cc = match self.tree.read_lock() {
+            Ok(cc) => cc,
+            Err(e) => {
+                // the tree was dropped out from under us
+                self.done = true;
+                return Some(Err(e));
+            }
+        };
+        if self.tree.clears.load(SeqCst) != self.clears {
+            // the pages we were iterating over were freed
+            self.done = true;
+            return None;
+        }
+        let guard = pin();
+        match self.tree.max_lt_by(self.hi.clone(), &guard, f) {
+            Ok(Some((k, v))) => {
+                if Bound::Inclusive(k.clone()) > self.last_key {
+                    // shrinking the upper bound keeps forward iteration
+                    // from returning anything we've already returned
+                    self.hi = Bound::Exclusive(k.clone());
+                    Some(Ok((k, v)))
+                } else {
+                    // we've met the forward half of the iterator
+                    self.done = true;
+                    None
                 }
             }
-            match node.next {
-                Some(id) => self.id = id,
-                None => return None,
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                error!("iteration failed: {:?}", e);
+                self.done = true;
+                Some(Err(e))
             }
         }
     }
+
+    fn above_last(&self, key: &[u8]) -> bool {
+        match self.last_key {
+            Bound::Inf => false,
+            Bound::Exclusive(ref last) => key >= &**last,
+            Bound::Inclusive(ref last) => key > &**last,
+        }
+    }
+
+    fn below_hi(&self, key: &[u8]) -> bool {
+        match self.hi {
+            Bound::Inf => true,
+            Bound::Exclusive(ref hi) => key < &**hi,
+            Bound::Inclusive(ref hi) => key <= &**hi,
+        }
+    }
+}
+
+/// An iterator over the keys in a `Tree`, created by `Iter::keys`.
+pub struct Keys<'a>(Iter<'a>);
+
+impl<'a> Iterator for Keys<'a> {
+    type Item = DbResult<Key, ()>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_inner(|_| ()).map(|res| res.map(|(k, _)| k))
+    }
+}
+
+impl<'a> DoubleEndedIterator for Keys<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.0.next_back_inner(|_| ()).map(|res| res.map(|(k, _)| k))
+    }
+}
+
+/// An iterator over the values in a `Tree`, created by `Iter::values`.
+pub struct Values<'a>(Iter<'a>);
+
+impl<'a> Iterator for Values<'a> {
+    type Item = DbResult<IVec, ()>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_inner(|v| v.clone()).map(|res| res.map(|(_, v)| v))
+    }
+}
+
+impl<'a> DoubleEndedIterator for Values<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.0.next_back_inner(|v| v.clone()).map(|res| res.map(|(_, v)| v))
+    }
 }
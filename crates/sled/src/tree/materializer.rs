@@ -24,17 +24,40 @@ impl Materializer for BLinkMaterializer {
         }
     }
 
-    fn merge(&self, frags: &[&Frag]) -> Frag {
+    fn merge(&self, frags: &[&Frag]) -> DbResult<Frag, ()> {
         let (mut base_node, is_root) = match frags[0].clone() {
             Frag::Base(base_node, is_root) => (base_node, is_root),
+            Frag::Batch(_) |
+            Frag::Counter(_) |
+            Frag::Meta(_) |
+            Frag::Lens(_) |
+            Frag::Epoch(_) => {
+                // the batch, counter, meta, lens and epoch pages are only
+                // ever replaced, so the last frag is always their
+                // complete state.
+                return Ok(frags[frags.len() - 1].clone());
+            }
             _ => panic!("non-Base in first element of frags slice"),
         };
 
         for &frag in &frags[1..] {
-            base_node.apply(frag, self.config.merge_operator);
+            base_node.apply(frag, self.config.get_merge_operator())?;
         }
 
-        Frag::Base(base_node, is_root)
+        // nodes split long before they could outgrow a log message,
+        // as keys and values are limited to a fraction of one. only
+        // values grown by a merge operator can get past those limits.
+        debug_assert!(
+            base_node.data.size_in_bytes() <= self.config.max_message_size(),
+            "a node of {} bytes is too large to be written to the log",
+            base_node.data.size_in_bytes()
+        );
+
+        Ok(Frag::Base(base_node, is_root))
+    }
+
+    fn mem_size(&self, frag: &Frag) -> usize {
+        frag.mem_size()
     }
 
     fn recover(&self, frag: &Frag) -> Option<Vec<(PageID, PageID)>> {
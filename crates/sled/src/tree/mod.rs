@@ -1,21 +1,39 @@
 use super::*;
 
 mod bound;
+mod bulk;
+mod changes;
 mod data;
+mod db;
+mod distribution;
+mod export;
 mod frag;
+mod hidden;
 mod iter;
 mod materializer;
 mod node;
 mod prefix;
+mod rename;
+mod retain;
+mod snapshot;
+mod space;
 mod tree;
+mod ttl;
 
 use self::bound::Bound;
-use self::data::Data;
+use self::data::{ChildCounts, Data};
 use self::frag::{ChildSplit, ParentSplit};
 use self::node::Node;
 use self::prefix::{prefix_cmp, prefix_decode, prefix_encode};
 
+pub use self::bulk::BulkLoader;
+pub use self::changes::ChangesIter;
 pub use self::frag::Frag;
-pub use self::iter::Iter;
+pub use self::db::Db;
+pub use self::export::{Export, TreeExport};
+pub use self::iter::{Iter, Keys, ResumeToken, Values};
 pub use self::materializer::BLinkMaterializer;
+pub use self::snapshot::{SnapshotIter, TreeSnapshot};
+pub use self::space::SpaceUsage;
 pub use self::tree::Tree;
+pub use self::ttl::Clock;
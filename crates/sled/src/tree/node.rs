@@ -10,7 +10,11 @@ pub struct Node {
 }
 
 impl Node {
-    pub fn apply(&mut self, frag: &Frag, merge_operator: Option<usize>) {
+    pub fn apply(
+        &mut self,
+        frag: &Frag,
+        merge_operator: Option<MergeOperator>,
+    ) -> DbResult<(), ()> {
         use self::Frag::*;
 
         match *frag {
@@ -25,13 +29,16 @@ impl Node {
             Merge(ref k, ref v) => {
                 let decoded_k = prefix_decode(self.lo.inner(), k);
                 if Bound::Inclusive(decoded_k) < self.hi {
-                    let merge_fn_ptr =
-                        merge_operator.expect("must have a merge operator set");
-                    unsafe {
-                        let merge_fn: MergeOperator =
-                            std::mem::transmute(merge_fn_ptr);
-                        self.merge_leaf(k.clone(), v.clone(), merge_fn);
-                    }
+                    // a system with merges in it may be reopened
+                    // without the merge operator that wrote them.
+                    let merge_fn = merge_operator.ok_or_else(|| {
+                        Error::Unsupported(
+                            "must set a merge operator on the Config to \
+                             read values that were written with merges"
+                                .to_owned(),
+                        )
+                    })?;
+                    self.merge_leaf(k.clone(), v.clone(), merge_fn);
                 } else {
                     panic!("tried to consolidate set at key <= hi")
                 }
@@ -42,6 +49,15 @@ impl Node {
             ParentSplit(ref parent_split) => {
                 self.parent_split(parent_split);
             }
+            Count(ref counts) => {
+                for &(ref lo, child, delta) in counts {
+                    if Bound::Inclusive(lo.clone()) < self.hi {
+                        self.count_child(lo, child, delta);
+                    } else {
+                        panic!("tried to consolidate count at key <= hi")
+                    }
+                }
+            }
             Del(ref k) => {
                 let decoded_k = prefix_decode(self.lo.inner(), k);
                 if Bound::Inclusive(decoded_k) < self.hi {
@@ -51,10 +67,17 @@ impl Node {
                 }
             }
             Base(_, _) => panic!("encountered base page in middle of chain"),
+            Batch(_) => panic!("encountered batch in a tree node's chain"),
+            Counter(_) => panic!("encountered counter in a tree node's chain"),
+            Meta(_) => panic!("encountered meta in a tree node's chain"),
+            Lens(_) => panic!("encountered lens in a tree node's chain"),
+            Epoch(_) => panic!("encountered epoch in a tree node's chain"),
         }
+
+        Ok(())
     }
 
-    pub fn set_leaf(&mut self, key: Key, val: Value) {
+    pub fn set_leaf(&mut self, key: Key, val: IVec) {
         if let Data::Leaf(ref mut records) = self.data {
             let search = records.binary_search_by(
                 |&(ref k, ref _v)| prefix_cmp(k, &*key),
@@ -86,7 +109,7 @@ impl Node {
             if let Ok(idx) = search {
                 let new = merge_fn(&*decoded_k, Some(&records[idx].1), &val);
                 if let Some(new) = new {
-                    records.push((key, new));
+                    records.push((key, new.into()));
                     records.swap_remove(idx);
                 } else {
                     records.remove(idx);
@@ -94,7 +117,7 @@ impl Node {
             } else {
                 let new = merge_fn(&*decoded_k, None, &val);
                 if let Some(new) = new {
-                    records.push((key, new));
+                    records.push((key, new.into()));
                     records.sort_unstable_by(|a, b| prefix_cmp(&*a.0, &*b.0));
                 }
             }
@@ -110,8 +133,34 @@ impl Node {
     }
 
     pub fn parent_split(&mut self, ps: &ParentSplit) {
-        if let Data::Index(ref mut ptrs) = self.data {
-            let encoded_sep = prefix_encode(self.lo.inner(), ps.at.inner());
+        let prefix = self.lo.inner();
+        let hi = &self.hi;
+        if let Data::Index(ref mut ptrs, ref mut counts) = self.data {
+            // the split may be completed by more than one thread
+            if ptrs.iter().any(|&(_, pid)| pid == ps.to) {
+                return;
+            }
+
+            // the entries of the new child, and of the unlinked children
+            // to the right of it that it now covers, move over to it.
+            let at = ps.at.inner();
+            let covering = covering_child(ptrs, prefix, at);
+            let next = ptrs.iter()
+                .map(|&(ref sep, _)| prefix_decode(prefix, sep))
+                .find(|sep| &**sep > at)
+                .map(Bound::Exclusive)
+                .unwrap_or_else(|| hi.clone());
+            let mut moved = 0;
+            for &(ref lo, count) in counts.unlinked.values() {
+                if &**lo >= at && Bound::Inclusive(lo.clone()) < next {
+                    moved += count;
+                }
+            }
+            counts.unlinked.remove(&ps.to);
+            *counts.children.entry(covering).or_insert(0) -= moved;
+            counts.children.insert(ps.to, moved);
+
+            let encoded_sep = prefix_encode(prefix, at);
             ptrs.push((encoded_sep, ps.to));
             ptrs.sort_unstable_by(|a, b| prefix_cmp(&*a.0, &*b.0));
         } else {
@@ -119,6 +168,45 @@ impl Node {
         }
     }
 
+    // counts entries added to or removed from under a child, given its
+    // lowest key. a child that we don't point to yet has split off from
+    // the one that covers its keys, which counts them until we do.
+    pub fn count_child(&mut self, lo: &[u8], child: PageID, delta: i64) {
+        let prefix = self.lo.inner();
+        if let Data::Index(ref ptrs, ref mut counts) = self.data {
+            if ptrs.iter().any(|&(_, pid)| pid == child) {
+                *counts.children.entry(child).or_insert(0) += delta;
+                return;
+            }
+            let covering = covering_child(ptrs, prefix, lo);
+            *counts.children.entry(covering).or_insert(0) += delta;
+            counts
+                .unlinked
+                .entry(child)
+                .or_insert_with(|| (lo.to_vec(), 0))
+                .1 += delta;
+        } else {
+            panic!("tried to attach a Count to a Leaf chain");
+        }
+    }
+
+    // takes a prefix-encoded key
+    pub fn get_leaf(&self, key: KeyRef) -> Option<&IVec> {
+        if let Data::Leaf(ref records) = self.data {
+            records
+                .binary_search_by(|&(ref k, ref _v)| prefix_cmp(k, &*key))
+                .ok()
+                .map(|idx| &records[idx].1)
+        } else {
+            panic!("tried to search for a key in an Index node");
+        }
+    }
+
+    // takes a prefix-encoded key
+    pub fn contains_leaf(&self, key: KeyRef) -> bool {
+        self.get_leaf(key).is_some()
+    }
+
     pub fn del_leaf(&mut self, key: KeyRef) {
         if let Data::Leaf(ref mut records) = self.data {
             let search = records.binary_search_by(
@@ -132,8 +220,19 @@ impl Node {
         }
     }
 
-    pub fn should_split(&self, fanout: u8) -> bool {
-        self.data.len() > fanout as usize
+    // nodes split once they have more than `blink_fanout` children,
+    // or once their keys and values reach a quarter of the largest
+    // log message, which keeps every node small enough to be written.
+    pub fn should_split(&self, config: &Config) -> bool {
+        let len = self.data.len();
+        len > config.blink_fanout as usize ||
+            (len > 2 &&
+                 self.data.size_in_bytes() > config.max_message_size() / 4)
+    }
+
+    // the bytes this node holds on the heap
+    pub fn heap_size(&self) -> usize {
+        self.lo.heap_size() + self.hi.heap_size() + self.data.heap_size()
     }
 
     pub fn split(&self, id: PageID) -> Node {
@@ -147,3 +246,18 @@ impl Node {
         }
     }
 }
+
+// the child of an index node whose keys include this one
+fn covering_child(ptrs: &[(Key, PageID)], prefix: &[u8], key: &[u8]) -> PageID {
+    let mut covering = ptrs.first().map(|&(_, pid)| pid).expect(
+        "index nodes should always have at least one child",
+    );
+    for &(ref sep, pid) in ptrs {
+        if &*prefix_decode(prefix, sep) <= key {
+            covering = pid;
+        } else {
+            break;
+        }
+    }
+    covering
+}
@@ -0,0 +1,78 @@
+use super::*;
+
+impl Tree {
+    /// Atomically move the value of `from` to `to`, returning the value
+    /// that `to` had before, if any. Concurrent readers observe the
+    /// value under exactly one of the two keys, and after a crash the
+    /// `Tree` recovers either none or all of the move. Subscribers see
+    /// an `Event::Remove` for `from` immediately followed by an
+    /// `Event::Insert` for `to`.
+    ///
+    /// If `from` has no value, or is the same key as `to`, nothing is
+    /// changed and `None` is returned. An entry written with
+    /// `set_with_ttl` keeps its deadline when it's moved.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![10]).unwrap();
+    /// t.set(vec![2], vec![20]).unwrap();
+    ///
+    /// assert_eq!(t.rename(&[1], vec![2]), Ok(Some(vec![20].into())));
+    /// assert_eq!(t.get(&[1]), Ok(None));
+    /// assert_eq!(t.get(&[2]), Ok(Some(vec![10].into())));
+    ///
+    /// assert_eq!(t.rename(&[2], vec![3]), Ok(None));
+    /// assert_eq!(t.get(&[3]), Ok(Some(vec![10].into())));
+    /// ```
+    pub fn rename(&self, from: &[u8], to: Key) -> DbResult<Option<IVec>, ()> {
+        if self.config.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        let _cc = self.write_lock();
+        self.check_dropped()?;
+
+        if from == &*to {
+            return Ok(None);
+        }
+        let value = match self.get_inner(from)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let displaced = self.get_inner(&*to)?;
+
+        let from = from.to_vec();
+        let deadlines = self.deadlines.tree();
+        let deadline_batch = match deadlines {
+            Some(ref deadlines) => Tree::deadline_move(deadlines, &from, &to)?,
+            None => Batch::default(),
+        };
+
+        let mut batch = Batch::default();
+        batch.remove(from);
+        batch.insert(to, value.to_vec());
+
+        let mut batches = vec![(self, batch)];
+        if let Some(ref deadlines) = deadlines {
+            batches.push((deadlines, deadline_batch));
+        }
+        Tree::write_batches_with(batches, Tree::apply_removals_first)?;
+
+        Ok(displaced)
+    }
+
+    // applies the removals of a batch before its insertions, rather
+    // than in key order, so that subscribers see a value leave its old
+    // key before it shows up under its new one.
+    fn apply_removals_first(&self, batch: Batch) -> DbResult<(), ()> {
+        let (removals, insertions) = batch
+            .writes
+            .into_iter()
+            .partition(|&(_, ref value)| value.is_none());
+        self.apply_batch_writes(Batch { writes: removals })?;
+        self.apply_batch_writes(Batch { writes: insertions })
+    }
+}
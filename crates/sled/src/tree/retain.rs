@@ -0,0 +1,112 @@
+use super::*;
+use super::tree::successor;
+
+// the most entries that `retain` reads before it
+// removes the ones that the predicate rejected.
+const RETAIN_CHUNK_ENTRIES: usize = 1024;
+
+impl Tree {
+    /// Remove every entry for which the predicate returns false, and
+    /// return how many were removed. The `Tree` is read a chunk of
+    /// entries at a time, and the rejected entries of each chunk are
+    /// removed before the next one is read, so only one chunk of keys
+    /// is held in memory.
+    ///
+    /// Each entry is removed with `cas`, so an entry that a concurrent
+    /// writer changes after the predicate saw it is only removed if the
+    /// predicate also rejects its new value. Entries written during the
+    /// call to parts of the `Tree` that have already been read are
+    /// kept. Subscribers see an `Event::Remove` for each removed entry.
+    ///
+    /// The removals are not atomic, so after a crash partway through,
+    /// some of the rejected entries may have been removed and others
+    /// not, but each entry is either untouched or removed entirely.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// for i in 0..10 {
+    ///     t.set(vec![i], vec![i % 3]).unwrap();
+    /// }
+    ///
+    /// let removed = t.retain(|_key, value| value[0] != 0).unwrap();
+    /// assert_eq!(removed, 4);
+    /// assert_eq!(t.len(), 6);
+    /// assert_eq!(t.get(&[3]), Ok(None));
+    /// assert_eq!(t.get(&[4]), Ok(Some(vec![1].into())));
+    /// ```
+    pub fn retain<F>(&self, mut f: F) -> DbResult<usize, ()>
+        where F: FnMut(&[u8], &[u8]) -> bool
+    {
+        if self.config.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        let mut removed = 0;
+        let mut last: Option<Key> = None;
+        loop {
+            let mut rejected = vec![];
+            let mut exhausted = true;
+            {
+                let iter = match last {
+                    Some(ref last) => {
+                        self.range_internal(&*successor(last), Bound::Inf)
+                    }
+                    None => self.iter(),
+                };
+                for (i, res) in iter.enumerate() {
+                    let (k, v) = res?;
+                    if !f(&*k, &*v) {
+                        rejected.push((k.clone(), v));
+                    }
+                    last = Some(k);
+                    if i + 1 == RETAIN_CHUNK_ENTRIES {
+                        exhausted = false;
+                        break;
+                    }
+                }
+            }
+
+            for (k, v) in rejected {
+                if self.remove_rejected(k, v.to_vec(), &mut f)? {
+                    removed += 1;
+                }
+            }
+
+            if exhausted {
+                return Ok(removed);
+            }
+        }
+    }
+
+    // removes an entry that the predicate rejected, unless its value
+    // changes to one that the predicate accepts before it's removed.
+    // returns whether it was removed.
+    fn remove_rejected<F>(
+        &self,
+        key: Key,
+        mut value: Value,
+        f: &mut F,
+    ) -> DbResult<bool, ()>
+        where F: FnMut(&[u8], &[u8]) -> bool
+    {
+        loop {
+            maybe_fail!("retain remove");
+            match self.cas(key.clone(), Some(value), None) {
+                Ok(()) => return Ok(true),
+                Err(Error::CasFailed(Some(actual))) => {
+                    if f(&*key, &*actual) {
+                        return Ok(false);
+                    }
+                    value = actual.to_vec();
+                }
+                // someone else removed it
+                Err(Error::CasFailed(None)) => return Ok(false),
+                Err(other) => return Err(other.danger_cast()),
+            }
+            M.tree_looped();
+        }
+    }
+}
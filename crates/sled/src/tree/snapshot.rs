@@ -0,0 +1,331 @@
+use std::collections::BTreeMap;
+use std::ops::{self, RangeBounds};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::SeqCst;
+
+use epoch::pin;
+
+use super::*;
+use super::tree::{prefix_hi, range_bounds};
+use super::hidden::{by_key, decode_stamp};
+
+// the values that a snapshot's keys had when it was taken, recorded
+// by writers before they change them. None means the key was absent.
+type Preserved = Mutex<BTreeMap<Key, Option<IVec>>>;
+
+// the snapshots of a tree that are alive, shared between its handles
+#[derive(Default)]
+pub(super) struct Snapshots {
+    // the number of live snapshots, so that writers
+    // can skip preserving anything when there are none.
+    active: AtomicUsize,
+    views: Mutex<Vec<Arc<Preserved>>>,
+}
+
+impl Snapshots {
+    // callers must hold the write lock, so that no
+    // write is in flight while the snapshot is taken.
+    fn register(&self) -> Arc<Preserved> {
+        let preserved = Arc::new(Mutex::new(BTreeMap::new()));
+        let mut views = self.lock();
+        views.push(preserved.clone());
+        self.active.store(views.len(), SeqCst);
+        preserved
+    }
+
+    fn release(&self, preserved: &Arc<Preserved>) {
+        let mut views = self.lock();
+        views.retain(|view| !Arc::ptr_eq(view, preserved));
+        self.active.store(views.len(), SeqCst);
+    }
+
+    // records the value that a key had before a write changes it, for
+    // every live snapshot that hasn't already recorded one. callers
+    // must hold the read or write lock, and call this before the write
+    // is linked into the tree, each time they try.
+    pub(super) fn preserve(&self, key: &[u8], old: Option<&IVec>) {
+        if self.active.load(SeqCst) == 0 {
+            return;
+        }
+        for view in self.lock().iter() {
+            let mut preserved = lock(view);
+            if !preserved.contains_key(key) {
+                preserved.insert(key.to_vec(), old.cloned());
+            }
+        }
+    }
+
+    pub(super) fn is_active(&self) -> bool {
+        self.active.load(SeqCst) != 0
+    }
+
+    fn lock(&self) -> MutexGuard<Vec<Arc<Preserved>>> {
+        self.views.lock().expect(
+            "a thread panicked and poisoned a Tree's snapshots mutex",
+        )
+    }
+}
+
+fn lock(preserved: &Preserved) -> MutexGuard<BTreeMap<Key, Option<IVec>>> {
+    preserved.lock().expect(
+        "a thread panicked and poisoned a snapshot's preserved values",
+    )
+}
+
+/// A read-only view of a `Tree` as it was when `Tree::snapshot` was
+/// called, which is unaffected by later writes.
+///
+/// Rather than copying the `Tree`, a snapshot reads the live `Tree`
+/// and makes up for any changes since it was taken. To make that
+/// possible, while a snapshot is alive each write to its `Tree` keeps
+/// a copy of the value it replaces, and clearing the `Tree` keeps a
+/// copy of everything in it. These copies are held in memory and are
+/// released when the snapshot is dropped, so long-lived snapshots of
+/// busy trees can use a lot of memory.
+///
+/// Entries written with `set_with_ttl` are seen if they had not
+/// expired when the snapshot was taken, however long ago that was.
+pub struct TreeSnapshot {
+    tree: Tree,
+    preserved: Arc<Preserved>,
+    // the snapshot of the tree's deadlines, if it had any
+    deadlines: Option<Box<TreeSnapshot>>,
+    // the time that entries are considered expired after
+    now: u64,
+}
+
+impl Drop for TreeSnapshot {
+    fn drop(&mut self) {
+        self.tree.snapshots.release(&self.preserved);
+    }
+}
+
+impl Tree {
+    /// Take a snapshot of the `Tree`, which returns the same results
+    /// for as long as it's kept, no matter what is written to the
+    /// `Tree` in the meantime. See `TreeSnapshot` for what this costs.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![10]).unwrap();
+    ///
+    /// let snapshot = t.snapshot().unwrap();
+    /// t.set(vec![1], vec![11]).unwrap();
+    /// t.set(vec![2], vec![20]).unwrap();
+    ///
+    /// assert_eq!(snapshot.get(&[1]), Ok(Some(vec![10].into())));
+    /// assert_eq!(snapshot.get(&[2]), Ok(None));
+    /// assert_eq!(t.get(&[1]), Ok(Some(vec![11].into())));
+    /// ```
+    pub fn snapshot(&self) -> DbResult<TreeSnapshot, ()> {
+        let _cc = self.write_lock();
+        self.check_dropped()?;
+        Ok(self.snapshot_inner())
+    }
+
+    // callers must hold the write lock.
+    fn snapshot_inner(&self) -> TreeSnapshot {
+        let deadlines = self.deadlines
+            .tree()
+            .map(|deadlines| Box::new(deadlines.snapshot_inner()));
+        TreeSnapshot {
+            tree: self.clone(),
+            preserved: self.snapshots.register(),
+            deadlines: deadlines,
+            now: self.deadlines.now(),
+        }
+    }
+}
+
+impl TreeSnapshot {
+    /// Retrieve a value as it was when the snapshot was taken.
+    pub fn get(&self, key: &[u8]) -> DbResult<Option<IVec>, ()> {
+        let value = self.get_raw(key)?;
+        if value.is_some() && self.is_expired(key)? {
+            return Ok(None);
+        }
+        Ok(value)
+    }
+
+    // returns the value the key had, whether or not it had expired
+    fn get_raw(&self, key: &[u8]) -> DbResult<Option<IVec>, ()> {
+        self.tree.check_dropped()?;
+        let live = {
+            let guard = pin();
+            let (_, live) = self.tree.get_internal(key, &guard)?;
+            live
+        };
+
+        // anything written since we read the live value
+        // will have preserved it before writing.
+        match lock(&self.preserved).get(key) {
+            Some(old) => Ok(old.clone()),
+            None => Ok(live),
+        }
+    }
+
+    fn is_expired(&self, key: &[u8]) -> DbResult<bool, ()> {
+        let deadlines = match self.deadlines {
+            Some(ref deadlines) => deadlines,
+            None => return Ok(false),
+        };
+        match deadlines.get_raw(&*by_key(key))? {
+            Some(deadline) => Ok(decode_stamp(&*deadline) <= self.now),
+            None => Ok(false),
+        }
+    }
+
+    /// Iterate over the keys and values that were in the `Tree`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![10]).unwrap();
+    /// t.set(vec![2], vec![20]).unwrap();
+    ///
+    /// let snapshot = t.snapshot().unwrap();
+    /// t.del(&[1]).unwrap();
+    /// t.set(vec![3], vec![30]).unwrap();
+    ///
+    /// let mut iter = snapshot.iter();
+    /// assert_eq!(iter.next(), Some(Ok((vec![1], vec![10].into()))));
+    /// assert_eq!(iter.next(), Some(Ok((vec![2], vec![20].into()))));
+    /// assert_eq!(iter.next(), None);
+    /// ```
+    pub fn iter(&self) -> SnapshotIter {
+        self.range_internal(vec![], Bound::Inf)
+    }
+
+    /// Iterate over the keys and values that were in the provided
+    /// range, with the same bounds as `Tree::range`.
+    pub fn range<K, R>(&self, range: R) -> SnapshotIter
+        where K: AsRef<[u8]> + ?Sized,
+              R: RangeBounds<K>
+    {
+        let (lo, hi) = range_bounds(&range);
+        self.range_internal(lo, hi)
+    }
+
+    /// Iterate over the keys and values that started with the
+    /// provided prefix.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> SnapshotIter {
+        self.range_internal(prefix.to_vec(), prefix_hi(prefix))
+    }
+
+    fn range_internal(&self, lo: Key, hi: Bound) -> SnapshotIter {
+        let mut live = self.tree.range_internal(&*lo, hi.clone());
+        live.skip_expired = false;
+        SnapshotIter {
+            snapshot: self,
+            live: live,
+            lo: lo,
+            hi: hi,
+            last: None,
+            peeked: None,
+            done: false,
+        }
+    }
+}
+
+/// An iterator over keys and values in a `TreeSnapshot`, in order.
+pub struct SnapshotIter<'a> {
+    snapshot: &'a TreeSnapshot,
+    // the entries of the live tree, which take a back seat
+    // to the preserved ones for keys written since the snapshot.
+    live: Iter<'a>,
+    lo: Key,
+    hi: Bound,
+    last: Option<Key>,
+    peeked: Option<(Key, IVec)>,
+    done: bool,
+}
+
+impl<'a> Iterator for SnapshotIter<'a> {
+    type Item = DbResult<(Key, IVec), ()>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.done {
+                return None;
+            }
+
+            if self.peeked.is_none() {
+                match self.live.next() {
+                    Some(Ok(kv)) => self.peeked = Some(kv),
+                    Some(Err(e)) => {
+                        self.done = true;
+                        return Some(Err(e));
+                    }
+                    None => {}
+                }
+            }
+
+            // this is looked up after the live entry was read, so
+            // if the live entry has been written since the snapshot,
+            // its preserved value is at or before it.
+            let preserved = self.next_preserved();
+
+            let use_preserved = match (&self.peeked, &preserved) {
+                (&None, &None) => {
+                    self.done = true;
+                    return None;
+                }
+                (&Some(_), &None) => false,
+                (&None, &Some(_)) => true,
+                (&Some((ref live_k, _)), &Some((ref k, _))) => k <= live_k,
+            };
+
+            let (k, v) = if use_preserved {
+                let (k, v) = preserved.expect("checked above");
+                if self.peeked.as_ref().map(|&(ref live_k, _)| live_k) ==
+                    Some(&k)
+                {
+                    self.peeked = None;
+                }
+                self.last = Some(k.clone());
+                match v {
+                    Some(v) => (k, v),
+                    // the key didn't exist when the snapshot was taken
+                    None => continue,
+                }
+            } else {
+                let (k, v) = self.peeked.take().expect("checked above");
+                self.last = Some(k.clone());
+                (k, v)
+            };
+
+            match self.snapshot.is_expired(&*k) {
+                Ok(true) => continue,
+                Ok(false) => return Some(Ok((k, v))),
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+impl<'a> SnapshotIter<'a> {
+    // the first preserved entry after the last key we returned
+    fn next_preserved(&self) -> Option<(Key, Option<IVec>)> {
+        let start = match self.last {
+            Some(ref last) => ops::Bound::Excluded(last.clone()),
+            None => ops::Bound::Included(self.lo.clone()),
+        };
+        let preserved = lock(&self.snapshot.preserved);
+        let next = preserved.range((start, ops::Bound::Unbounded)).next();
+        match next {
+            Some((k, v)) if Bound::Inclusive(k.clone()) < self.hi => {
+                Some((k.clone(), v.clone()))
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use super::*;
+
+/// How much space a `Tree` takes up, as returned by `Tree::space_usage`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpaceUsage {
+    /// The total length of the keys in the `Tree`.
+    pub key_bytes: u64,
+    /// The total length of the values in the `Tree`.
+    pub value_bytes: u64,
+    /// The bytes of the log that hold the current state of the
+    /// `Tree`'s pages, before compression. This doesn't count the
+    /// overwritten state that is still waiting to be cleaned up,
+    /// which is included in `Db::size_on_disk`.
+    pub disk_bytes: u64,
+    /// The fraction of `disk_bytes` that doesn't hold keys or
+    /// values, from 0 for none of it to 1 for all of it. This is
+    /// made up of node metadata and of updates to the `Tree`'s
+    /// pages that haven't been consolidated yet.
+    pub fragmentation: f64,
+}
+
+impl Tree {
+    /// Returns how much space the `Tree` takes up. The lengths of the
+    /// keys and values are counted as they're written, like `len`, and
+    /// the on-disk bytes of each page are kept up to date as the page
+    /// is written and cleaned. So this reads the `Tree`'s nodes to
+    /// find its pages, but doesn't go through the entries in them,
+    /// and nothing is read from disk to size them.
+    ///
+    /// Entries written with `set_with_ttl` that have expired but have
+    /// not yet been removed in the background are counted.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1, 2], vec![0; 100]).unwrap();
+    /// let usage = t.space_usage().unwrap();
+    /// assert_eq!(usage.key_bytes, 2);
+    /// assert_eq!(usage.value_bytes, 100);
+    /// assert!(usage.disk_bytes > 102);
+    /// ```
+    pub fn space_usage(&self) -> DbResult<SpaceUsage, ()> {
+        let _cc = self.read_lock()?;
+
+        let mut pids = vec![];
+        self.for_each_node(|node| {
+            pids.push(node.id);
+            Ok(())
+        })?;
+        let (_, key_bytes, value_bytes) = self.counts.load();
+
+        let disk_bytes = self.pages.size_of_pages(&*pids);
+        let logical_bytes = key_bytes + value_bytes;
+        let fragmentation = if disk_bytes <= logical_bytes {
+            0.
+        } else {
+            (disk_bytes - logical_bytes) as f64 / disk_bytes as f64
+        };
+
+        Ok(SpaceUsage {
+            key_bytes: key_bytes,
+            value_bytes: value_bytes,
+            disk_bytes: disk_bytes,
+            fragmentation: fragmentation,
+        })
+    }
+}
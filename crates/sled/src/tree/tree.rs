@@ -1,14 +1,57 @@
+use std::collections::{BTreeMap, HashSet};
 use std::fmt::{self, Debug};
-use std::sync::Arc;
-use std::sync::atomic::AtomicUsize;
+use std::ops::{self, RangeBounds};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread;
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize};
 use std::sync::atomic::Ordering::SeqCst;
+use std::time::Duration;
 
-use epoch::{Guard, Shared, pin};
+use epoch::{Atomic, Guard, Owned, Shared, pin, unprotected};
 
 use super::*;
+use super::changes::{ChangeLog, Epoch};
+use super::db::new_root;
+use super::snapshot::Snapshots;
+use super::ttl::{Deadlines, Expirer};
+
+// the page holding the batches that have been logged but not yet
+// fully applied. batches to different trees can be in flight at once.
+pub(super) const BATCH_PID: PageID = 2;
+
+// the page holding the end of the last
+// persisted lease of ids for generate_id.
+pub(super) const COUNTER_PID: PageID = 3;
+
+// the page mapping each tree's name to its first root.
+pub(super) const META_PID: PageID = 4;
+
+// the page holding each tree's entry and byte counts as of the last
+// shutdown.
+pub(super) const LENS_PID: PageID = 5;
+
+// the page holding the Db's current durability epoch.
+pub(super) const EPOCH_PID: PageID = 6;
+
+// the states of the lens page. writers must make sure that it no
+// longer claims to be accurate before their writes hit the log.
+pub(super) const LENS_CLEAN: usize = 0;
+const LENS_MARKING: usize = 1;
+pub(super) const LENS_DIRTY: usize = 2;
+// a write failed before the counts in the index were told about it, so
+// the lens page is left claiming to be inaccurate, and the counts are
+// rebuilt when the Db next starts.
+const LENS_LOST: usize = 3;
+
+// how many times a split is tried before it is left to a later write
+const SPLIT_ATTEMPTS: usize = 3;
+
+// the number of ids reserved by each durable
+// write of the counter page.
+const ID_LEASE: usize = 1_000_000;
 
 impl<'a> IntoIterator for &'a Tree {
-    type Item = DbResult<(Vec<u8>, Vec<u8>), ()>;
+    type Item = DbResult<(Key, IVec), ()>;
     type IntoIter = Iter<'a>;
 
     fn into_iter(self) -> Iter<'a> {
@@ -19,189 +62,1813 @@ impl<'a> IntoIterator for &'a Tree {
 /// A flash-sympathetic persistent lock-free B+ tree
 #[derive(Clone)]
 pub struct Tree {
-    pages: Arc<PageCache<BLinkMaterializer, Frag, Vec<(PageID, PageID)>>>,
-    config: Config,
-    root: Arc<AtomicUsize>,
-    merge_operator: Option<MergeOperator>,
+    pub(super) pages:
+        Arc<PageCache<BLinkMaterializer, Frag, Vec<(PageID, PageID)>>>,
+    pub(super) config: Config,
+    pub(super) root: Arc<AtomicUsize>,
+    pub(super) name: Vec<u8>,
+    // set by Db::drop_tree, after which operations on
+    // this tree fail instead of touching freed pages.
+    pub(super) dropped: Arc<AtomicBool>,
+    // taken shared by writes and scans, and exclusively by batches, so
+    // that they can't observe a partially-applied batch. point reads
+    // don't take it, and check `pending` instead. the hidden trees
+    // holding this tree's deadlines and changes share it.
+    pub(super) concurrency_control: Arc<RwLock<()>>,
+    // the batch being applied to this tree, if any
+    pub(super) pending: Arc<Pending>,
+    // the next id to hand out from generate_id, and
+    // the end of the last lease persisted to disk.
+    pub(super) idgen: Arc<AtomicUsize>,
+    pub(super) idgen_persisted: Arc<AtomicUsize>,
+    // the number of entries in this tree, and their sizes
+    pub(super) counts: Arc<Counts>,
+    pub(super) lens: Arc<Lens>,
+    pub(super) subscriptions: Arc<Subscriptions>,
+    // the number of times this tree has been cleared, so that
+    // iterators can tell that the pages they point to were freed.
+    pub(super) clears: Arc<AtomicUsize>,
+    // the deadlines of entries written with set_with_ttl
+    pub(super) deadlines: Arc<Deadlines>,
+    pub(super) snapshots: Arc<Snapshots>,
+    // shared by every tree in a Db
+    pub(super) epoch: Arc<Epoch>,
+    // the record of which keys were modified in which
+    // epoch, for trees that track their changes
+    pub(super) changes: Arc<ChangeLog>,
+    // shared by the handles to a Db's trees that are given out to
+    // users, and None for the handles the Db only uses internally.
+    pub(super) expirer: Option<Arc<Expirer>>,
+}
+
+// the entry counts of every tree in a Db
+pub(super) struct Lens {
+    pub(super) state: AtomicUsize,
+    pub(super) trees: Mutex<BTreeMap<Vec<u8>, Arc<Counts>>>,
+}
+
+// the number of entries in a tree, and the total length of their keys
+// and values. these are signed because a removal may be counted before
+// the insertion it removes.
+#[derive(Default)]
+pub(super) struct Counts {
+    len: AtomicIsize,
+    key_bytes: AtomicIsize,
+    value_bytes: AtomicIsize,
+}
+
+impl Counts {
+    // counts a write to a key of this length, given the lengths of the
+    // value it replaced and the value it wrote, or None for no value.
+    pub(super) fn record(
+        &self,
+        key_len: usize,
+        old_len: Option<usize>,
+        new_len: Option<usize>,
+    ) {
+        if let Some(old_len) = old_len {
+            self.len.fetch_sub(1, SeqCst);
+            self.key_bytes.fetch_sub(key_len as isize, SeqCst);
+            self.value_bytes.fetch_sub(old_len as isize, SeqCst);
+        }
+        if let Some(new_len) = new_len {
+            self.len.fetch_add(1, SeqCst);
+            self.key_bytes.fetch_add(key_len as isize, SeqCst);
+            self.value_bytes.fetch_add(new_len as isize, SeqCst);
+        }
+    }
+
+    // the number of entries, the length of their keys and the length
+    // of their values, as they're persisted in the lens page.
+    pub(super) fn load(&self) -> (usize, u64, u64) {
+        let load = |count: &AtomicIsize| {
+            let count = count.load(SeqCst);
+            if count < 0 { 0 } else { count as usize }
+        };
+        (
+            load(&self.len),
+            load(&self.key_bytes) as u64,
+            load(&self.value_bytes) as u64,
+        )
+    }
+
+    pub(super) fn store(&self, counts: (usize, u64, u64)) {
+        let (len, key_bytes, value_bytes) = counts;
+        self.len.store(len as isize, SeqCst);
+        self.key_bytes.store(key_bytes as isize, SeqCst);
+        self.value_bytes.store(value_bytes as isize, SeqCst);
+    }
+}
+
+// the writes of a batch that has been logged but may not have been
+// fully applied to a tree yet. they're published before the first of
+// them is applied and withdrawn after the last, so that a point read
+// that checks here before reading the tree sees either none or all
+// of the batch.
+#[derive(Default)]
+pub(super) struct Pending {
+    writes: Atomic<BTreeMap<Key, Option<IVec>>>,
+}
+
+impl Pending {
+    // callers must hold the write lock, so that
+    // the tree has no other batch in flight.
+    fn publish(&self, batch: &Batch) {
+        let guard = pin();
+        let mut writes = match unsafe {
+            self.writes.load(SeqCst, &guard).as_ref()
+        } {
+            Some(writes) => writes.clone(),
+            None => BTreeMap::new(),
+        };
+        for (k, v) in &batch.writes {
+            writes.insert(k.clone(), v.clone().map(IVec::from));
+        }
+        let old = self.writes.swap(Owned::new(writes), SeqCst, &guard);
+        if !old.is_null() {
+            unsafe { guard.defer(move || old.into_owned()) };
+        }
+    }
+
+    fn withdraw(&self) {
+        let guard = pin();
+        let old = self.writes.swap(Shared::null(), SeqCst, &guard);
+        if !old.is_null() {
+            unsafe { guard.defer(move || old.into_owned()) };
+        }
+    }
+
+    // Some with the value that the pending batch gives the key, which
+    // is None if it removes it, or None if it doesn't write the key.
+    pub(super) fn get(
+        &self,
+        key: &[u8],
+        guard: &Guard,
+    ) -> Option<Option<IVec>> {
+        match unsafe { self.writes.load(SeqCst, guard).as_ref() } {
+            Some(writes) => writes.get(key).cloned(),
+            None => None,
+        }
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        unsafe {
+            let writes = self.writes.load(SeqCst, unprotected());
+            if !writes.is_null() {
+                drop(writes.into_owned());
+            }
+        }
+    }
 }
 
 unsafe impl Send for Tree {}
 unsafe impl Sync for Tree {}
 
 impl Tree {
-    /// Load existing or create a new `Tree`.
+    /// Load existing or create a new `Tree`. This opens the `Db` at
+    /// the configured path and returns its default `Tree`.
     pub fn start(config: Config) -> DbResult<Tree, ()> {
-        #[cfg(any(test, feature = "check_snapshot_integrity"))]
-        match config
-            .verify_snapshot::<BLinkMaterializer, Frag, Vec<(PageID, PageID)>>() {
-                Ok(_) => {}
-                #[cfg(feature = "failpoints")]
-                Err(Error::FailPoint) => {},
-                other => panic!("failed to verify snapshot: {:?}", other),
+        let db = Db::start(config)?;
+        Ok((*db).clone())
+    }
+
+    // creates a handle for another tree in the same `Db`, sharing
+    // our pagecache and coordination state, but with a lock of its own.
+    pub(super) fn tenant(&self, name: Vec<u8>, root: PageID) -> Tree {
+        let counts = Arc::new(Counts::default());
+        self.lens
+            .trees
+            .lock()
+            .expect("a thread panicked and poisoned the Db's lens mutex")
+            .insert(name.clone(), counts.clone());
+        Tree {
+            pages: self.pages.clone(),
+            config: self.config.clone(),
+            root: Arc::new(AtomicUsize::new(root)),
+            name: name,
+            dropped: Arc::new(AtomicBool::new(false)),
+            concurrency_control: Arc::new(RwLock::new(())),
+            pending: Arc::new(Pending::default()),
+            idgen: self.idgen.clone(),
+            idgen_persisted: self.idgen_persisted.clone(),
+            counts: counts,
+            lens: self.lens.clone(),
+            subscriptions: Arc::new(Subscriptions::default()),
+            clears: Arc::new(AtomicUsize::new(0)),
+            deadlines: Arc::new(Deadlines::default()),
+            snapshots: Arc::new(Snapshots::default()),
+            epoch: self.epoch.clone(),
+            changes: Arc::new(ChangeLog::default()),
+            expirer: self.expirer.clone(),
         }
+    }
 
-        let pages = PageCache::start(config.clone())?;
+    // creates a new, empty tree in the same `Db`. callers must hold
+    // the Db's tenants write lock, or the write lock when creating a
+    // tree's deadlines or the record of its changes.
+    pub(super) fn create_tenant(&self, name: Vec<u8>) -> DbResult<Tree, ()> {
+        let guard = pin();
+        let root_id = self.pages.allocate(&guard)?;
+        let leaf_id = self.pages.allocate(&guard)?;
+        debug!("allocated root {} and leaf {} for tree", root_id, leaf_id);
 
-        let roots_opt = pages.recovered_state().clone().and_then(
-            |mut roots: Vec<(PageID, PageID)>| if roots.is_empty() {
-                None
-            } else {
-                let mut last = std::usize::MAX;
-                let mut last_idx = std::usize::MAX;
-                while !roots.is_empty() {
-                    // find the root that links to the last one
-                    for (i, &(root, prev_root)) in roots.iter().enumerate() {
-                        if prev_root == last {
-                            last = root;
-                            last_idx = i;
-                            break;
-                        }
-                        assert_ne!(
-                            i + 1,
-                            roots.len(),
-                            "encountered gap in root chain"
-                        );
+        let (leaf, root) = new_root(root_id, leaf_id);
+        self.pages
+            .replace(leaf_id, Shared::null(), leaf, &guard)
+            .map_err(|e| e.danger_cast())?;
+        self.pages
+            .replace(root_id, Shared::null(), root, &guard)
+            .map_err(|e| e.danger_cast())?;
+
+        // the tree only exists once it's in the meta page
+        self.update_meta(|meta| {
+            meta.insert(name.clone(), root_id);
+        })?;
+
+        Ok(self.tenant(name, root_id))
+    }
+
+    /// Flushes any pending IO buffers to disk to ensure durability,
+    /// blocking until they have been synced. Returns the number of
+    /// bytes of the log that were made durable, which is 0 if
+    /// everything was already stable. The log is shared by every
+    /// `Tree` in a `Db`, so this flushes writes to all of them. If
+    /// anything was written to a `Tree` that tracks its changes since
+    /// the last flush, this also ends the current epoch, as described
+    /// in `current_epoch`. Returns `Error::ReadOnly` in read-only
+    /// mode, where there's nothing to flush.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![10]).unwrap();
+    /// assert!(t.flush().unwrap() > 0);
+    /// assert_eq!(t.flush(), Ok(0));
+    /// ```
+    pub fn flush(&self) -> CacheResult<usize, ()> {
+        if self.config.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let flushed = self.pages.flush()?;
+        // the epoch only ends once the writes stamped with it are
+        // durable, so that a failed flush leaves it to the next one.
+        self.advance_epoch()?;
+        Ok(flushed)
+    }
+
+    /// Starts flushing everything written so far in the background,
+    /// without blocking. The returned `FlushHandle` completes once
+    /// the log is durable up to where it was when this was called,
+    /// and can be polled with `is_done` or waited on with `wait`.
+    /// Concurrent calls are coalesced, so that a single sync
+    /// completes all of them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![10]).unwrap();
+    /// let flush = t.flush_async();
+    /// t.set(vec![2], vec![20]).unwrap();
+    /// flush.wait().unwrap();
+    /// ```
+    pub fn flush_async(&self) -> FlushHandle {
+        self.pages.flush_async()
+    }
+
+    /// Returns the number of entries in the `Tree`. This is exact
+    /// once concurrent writes have completed, but may briefly be
+    /// off while writes are in flight. Entries written with
+    /// `set_with_ttl` are counted until they're removed in the
+    /// background, which may be some time after they expire.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![10]).unwrap();
+    /// t.set(vec![1], vec![11]).unwrap();
+    /// t.set(vec![2], vec![20]).unwrap();
+    /// assert_eq!(t.len(), 2);
+    /// t.del(&[1]).unwrap();
+    /// assert_eq!(t.len(), 1);
+    /// ```
+    pub fn len(&self) -> usize {
+        self.counts.load().0
+    }
+
+    /// Returns `true` if the `Tree` contains no entries.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// assert!(t.is_empty());
+    /// t.set(vec![1], vec![10]).unwrap();
+    /// assert!(!t.is_empty());
+    /// ```
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Compute a checksum of every key and value in the `Tree`, in key
+    /// order. Trees with the same entries have the same checksum, no
+    /// matter how their nodes were split or written, so this can be
+    /// used to compare replicas. The entries are read from a
+    /// `TreeSnapshot`, so the checksum covers a consistent point in
+    /// time without blocking writes while they're read, and the values
+    /// that are overwritten in the meantime are kept in memory until
+    /// it's done.
+    ///
+    /// This is not a cryptographic hash.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config_a = sled::ConfigBuilder::new().temporary(true).build();
+    /// let config_b = sled::ConfigBuilder::new().temporary(true).build();
+    /// let a = sled::Tree::start(config_a).unwrap();
+    /// let b = sled::Tree::start(config_b).unwrap();
+    /// a.set(vec![1], vec![10]).unwrap();
+    /// a.set(vec![2], vec![20]).unwrap();
+    /// b.set(vec![2], vec![20]).unwrap();
+    /// assert_ne!(a.checksum(), b.checksum());
+    /// b.set(vec![1], vec![10]).unwrap();
+    /// assert_eq!(a.checksum(), b.checksum());
+    /// ```
+    pub fn checksum(&self) -> DbResult<u64, ()> {
+        let snapshot = self.snapshot()?;
+
+        let mut crc = 0;
+        for res in snapshot.iter() {
+            let (k, v) = res?;
+            crc = checksum_field(crc, &*k);
+            crc = checksum_field(crc, &*v);
+        }
+        Ok(crc)
+    }
+
+    // must be called before writing anything that may change the
+    // number of entries in a tree, so that a crash after the write
+    // causes the counts to be rederived instead of trusted.
+    pub(super) fn mark_lens_dirty(&self) -> DbResult<(), ()> {
+        loop {
+            match self.lens.state.compare_and_swap(
+                LENS_CLEAN,
+                LENS_MARKING,
+                SeqCst,
+            ) {
+                LENS_CLEAN => {
+                    if let Err(e) = self.write_lens_page(Frag::Lens(None)) {
+                        self.lens.state.store(LENS_CLEAN, SeqCst);
+                        return Err(e);
                     }
-                    roots.remove(last_idx);
+                    self.lens.state.store(LENS_DIRTY, SeqCst);
+                    return Ok(());
                 }
-                assert_ne!(last, std::usize::MAX);
-                Some(last)
-            },
+                LENS_DIRTY | LENS_LOST => return Ok(()),
+                _ => {
+                    // another writer is marking the page
+                    M.tree_looped();
+                }
+            }
+        }
+    }
+
+    // called when a write fails after it may have reached the
+    // log, so the lengths and child counts it would have changed
+    // are rebuilt on the next start instead of trusted.
+    pub(super) fn lose_counts(&self) {
+        self.lens.state.store(LENS_LOST, SeqCst);
+    }
+
+    // called when the last handle to the Db is dropped, so
+    // nothing can change the counts while they're written.
+    fn persist_lens(&self) -> DbResult<(), ()> {
+        if self.lens.state.load(SeqCst) != LENS_DIRTY {
+            return Ok(());
+        }
+
+        maybe_fail!("lens persist");
+
+        let lens = self
+            .lens
+            .trees
+            .lock()
+            .expect("a thread panicked and poisoned the Db's lens mutex")
+            .iter()
+            .map(|(name, counts)| (name.clone(), counts.load()))
+            .collect();
+
+        self.write_lens_page(Frag::Lens(Some(lens)))?;
+        self.lens.state.store(LENS_CLEAN, SeqCst);
+        Ok(())
+    }
+
+    fn write_lens_page(&self, frag: Frag) -> DbResult<(), ()> {
+        let guard = pin();
+        loop {
+            let get_cursor = self.pages.get(LENS_PID, &guard).map_err(
+                |e| e.danger_cast(),
+            )?;
+
+            let cas_key = match get_cursor {
+                PageGet::Materialized(Frag::Lens(_), cas_key) => cas_key,
+                broken => {
+                    return Err(Error::ReportableBug(format!(
+                        "got non-lens page while writing lens: {:?}",
+                        broken
+                    )))
+                }
+            };
+
+            match self.pages.replace(LENS_PID, cas_key, frag.clone(), &guard) {
+                Ok(_) => return Ok(()),
+                Err(Error::CasFailed(_)) => {}
+                Err(other) => return Err(other.danger_cast()),
+            }
+            M.tree_looped();
+        }
+    }
+
+    // counts the entries in every leaf, and the
+    // total length of their keys and values.
+    pub(super) fn count_leaves(&self) -> DbResult<(usize, u64, u64), ()> {
+        let mut count = 0;
+        let mut key_bytes = 0;
+        let mut value_bytes = 0;
+        self.for_each_leaf(|node| {
+            let prefix = node.lo.inner();
+            let entries = node.data.leaf_ref().expect("node should be a leaf");
+            for &(ref k, ref v) in entries {
+                count += 1;
+                key_bytes += prefix_decode(prefix, k).len() as u64;
+                value_bytes += v.len() as u64;
+            }
+            Ok(())
+        })?;
+        Ok((count, key_bytes, value_bytes))
+    }
+
+    // visits every leaf in key order, by following the
+    // right-sibling links from the leftmost one.
+    fn for_each_leaf<F>(&self, mut f: F) -> DbResult<(), ()>
+        where F: FnMut(&Node) -> DbResult<(), ()>
+    {
+        let guard = pin();
+        let path = self.path_for_key(b"", &guard)?;
+        match path.last() {
+            Some(&(ref leaf, _)) => self.for_each_sibling(leaf.id, &mut f),
+            None => Err(Error::ReportableBug(
+                "failed to get path for the leftmost leaf".to_owned(),
+            )),
+        }
+    }
+
+    // visits every node, one level at a time from the root
+    // down, and each level from left to right.
+    pub(super) fn for_each_node<F>(&self, mut f: F) -> DbResult<(), ()>
+        where F: FnMut(&Node) -> DbResult<(), ()>
+    {
+        let mut id = self.root.load(SeqCst);
+        loop {
+            let mut leftmost_child = None;
+            self.for_each_sibling(id, &mut |node| {
+                if leftmost_child.is_none() {
+                    if let Data::Index(ref ptrs, _) = node.data {
+                        leftmost_child = ptrs.first().map(|&(_, pid)| pid);
+                    }
+                }
+                f(node)
+            })?;
+            match leftmost_child {
+                Some(child) => id = child,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    // visits the node with this id and every node to the right
+    // of it on the same level, by following right-sibling links.
+    fn for_each_sibling<F>(
+        &self,
+        mut id: PageID,
+        f: &mut F,
+    ) -> DbResult<(), ()>
+        where F: FnMut(&Node) -> DbResult<(), ()>
+    {
+        let guard = pin();
+        loop {
+            let get_cursor = self.pages.get(id, &guard).map_err(
+                |e| e.danger_cast(),
+            )?;
+            let node = match get_cursor {
+                PageGet::Materialized(Frag::Base(node, _), _) => node,
+                broken => {
+                    return Err(Error::ReportableBug(format!(
+                        "got non-base page while visiting nodes: {:?}",
+                        broken
+                    )))
+                }
+            };
+            f(&node)?;
+            match node.next {
+                Some(next) => id = next,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Retrieve a value from the `Tree` if it exists.
+    pub fn get(&self, key: &[u8]) -> DbResult<Option<IVec>, ()> {
+        self.check_dropped()?;
+        self.get_inner(key)
+    }
+
+    pub(crate) fn get_inner(&self, key: &[u8]) -> DbResult<Option<IVec>, ()> {
+        let guard = pin();
+        let ret = match self.pending.get(key, &guard) {
+            Some(pending) => pending,
+            None => self.get_internal(key, &guard)?.1,
+        };
+        if ret.is_some() && self.is_expired(key)? {
+            return Ok(None);
+        }
+        Ok(ret)
+    }
+
+    /// Returns `true` if the `Tree` contains a value for the key. This
+    /// sees the same entries as `get`, but stops at the key's leaf
+    /// instead of copying its value out.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![0; 4096]).unwrap();
+    /// assert_eq!(t.contains_key(&[1]), Ok(true));
+    /// assert_eq!(t.contains_key(&[2]), Ok(false));
+    /// ```
+    pub fn contains_key(&self, key: &[u8]) -> DbResult<bool, ()> {
+        self.check_dropped()?;
+        self.contains_key_inner(key)
+    }
+
+    pub(crate) fn contains_key_inner(&self, key: &[u8]) -> DbResult<bool, ()> {
+        let guard = pin();
+        match self.pending.get(key, &guard) {
+            Some(Some(_)) => {
+                return self.is_expired(key).map(|expired| !expired)
+            }
+            Some(None) => return Ok(false),
+            None => {}
+        }
+        let path = self.path_for_key(key, &guard)?;
+        let (leaf, _) = path.last().expect(
+            "path_for_key should always return a path \
+            of length >= 2 (root + leaf)",
         );
+        let encoded_key = prefix_encode(leaf.lo.inner(), key);
+        if !leaf.contains_leaf(&*encoded_key) {
+            return Ok(false);
+        }
+        self.is_expired(key).map(|expired| !expired)
+    }
 
-        let root_id = if let Some(root_id) = roots_opt {
-            debug!("recovered root {} while starting tree", root_id);
-            root_id
-        } else {
-            let guard = pin();
-            let root_id = pages.allocate(&guard)?;
-            assert_eq!(
-                root_id,
-                0,
-                "we expect that this is the first page ever allocated"
-            );
-            debug!("allocated pid {} for root of new tree", root_id);
-
-            let leaf_id = pages.allocate(&guard)?;
-            trace!("allocated pid {} for leaf in new", leaf_id);
-
-            let leaf = Frag::Base(
-                Node {
-                    id: leaf_id,
-                    data: Data::Leaf(vec![]),
-                    next: None,
-                    lo: Bound::Inclusive(vec![]),
-                    hi: Bound::Inf,
-                },
-                None,
-            );
+    /// Retrieve the values for many keys at once, returned in the same
+    /// order as the keys. The keys are looked up in sorted order, so
+    /// each leaf holding one or more of them is found and read once,
+    /// which saves most of the work of separate calls to `get` when
+    /// the keys are clustered together. Each leaf's keys see the leaf
+    /// at a single point in time, but different leaves may be read at
+    /// different times.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![10]).unwrap();
+    /// t.set(vec![2], vec![20]).unwrap();
+    ///
+    /// let values = t.multi_get(vec![vec![2], vec![3], vec![1], vec![2]]);
+    /// assert_eq!(values[0], Ok(Some(vec![20].into())));
+    /// assert_eq!(values[1], Ok(None));
+    /// assert_eq!(values[2], Ok(Some(vec![10].into())));
+    /// assert_eq!(values[3], Ok(Some(vec![20].into())));
+    /// ```
+    pub fn multi_get<K, I>(&self, keys: I) -> Vec<DbResult<Option<IVec>, ()>>
+        where K: AsRef<[u8]>,
+              I: IntoIterator<Item = K>
+    {
+        let keys: Vec<K> = keys.into_iter().collect();
+
+        let _cc = match self.read_lock() {
+            Ok(cc) => cc,
+            Err(_) => {
+                // the tree was dropped, so every lookup fails the same way
+                return keys
+                    .iter()
+                    .map(|_| self.check_dropped().map(|()| None))
+                    .collect();
+            }
+        };
+
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by(|&a, &b| keys[a].as_ref().cmp(keys[b].as_ref()));
+
+        let guard = pin();
+        let mut results: Vec<Option<DbResult<Option<IVec>, ()>>> =
+            keys.iter().map(|_| None).collect();
+        let mut leaf: Option<Node> = None;
+
+        for idx in order {
+            let key = keys[idx].as_ref();
+
+            // the keys are sorted, so the last leaf we read holds this
+            // key too unless the key is at or past its upper bound.
+            let covered = match leaf {
+                Some(ref node) => Bound::Inclusive(key.to_vec()) < node.hi,
+                None => false,
+            };
+            if !covered {
+                match self.path_for_key(key, &guard) {
+                    Ok(mut path) => leaf = path.pop().map(|(node, _)| node),
+                    Err(e) => {
+                        leaf = None;
+                        results[idx] = Some(Err(e));
+                        continue;
+                    }
+                }
+            }
 
-            // vec![0] represents a prefix-encoded empty prefix
-            let root_index_vec = vec![(vec![0], leaf_id)];
-
-            let root = Frag::Base(
-                Node {
-                    id: root_id,
-                    data: Data::Index(root_index_vec),
-                    next: None,
-                    lo: Bound::Inclusive(vec![]),
-                    hi: Bound::Inf,
-                },
-                Some(std::usize::MAX),
+            let node = leaf.as_ref().expect(
+                "path_for_key should always return a path \
+                of length >= 2 (root + leaf)",
             );
+            let encoded_key = prefix_encode(node.lo.inner(), key);
+            let res = match node.get_leaf(&*encoded_key) {
+                Some(v) => self.is_expired(key).map(|expired| if expired {
+                    None
+                } else {
+                    Some(v.clone())
+                }),
+                None => Ok(None),
+            };
+            results[idx] = Some(res);
+        }
+
+        results
+            .into_iter()
+            .map(|res| res.expect("every key should have been looked up"))
+            .collect()
+    }
+
+    /// Generate a monotonic id, unique across restarts and crashes.
+    /// Ids are reserved on disk in large leases, so most calls are a
+    /// single atomic increment. Ids that were leased but unused before
+    /// a restart are skipped, so there may be gaps between ids.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// let first = t.generate_id().unwrap();
+    /// let second = t.generate_id().unwrap();
+    /// assert!(second > first);
+    /// ```
+    pub fn generate_id(&self) -> DbResult<u64, ()> {
+        if self.config.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        let ret = self.idgen.fetch_add(1, SeqCst);
+
+        if ret >= self.idgen_persisted.load(SeqCst) {
+            self.persist_id_lease(ret)?;
+        }
+
+        Ok(ret as u64)
+    }
+
+    // durably reserves a new lease that contains the provided id,
+    // unless a concurrent caller has already done so.
+    fn persist_id_lease(&self, id: usize) -> DbResult<(), ()> {
+        let guard = pin();
+        let lease_end = loop {
+            let get_cursor = self.pages.get(COUNTER_PID, &guard).map_err(
+                |e| e.danger_cast(),
+            )?;
+
+            let (persisted, cas_key) = match get_cursor {
+                PageGet::Materialized(Frag::Counter(persisted), cas_key) => {
+                    (persisted, cas_key)
+                }
+                broken => {
+                    return Err(Error::ReportableBug(format!(
+                        "got non-counter page while leasing ids: {:?}",
+                        broken
+                    )))
+                }
+            };
+
+            if persisted > id {
+                // another thread has reserved a lease for us, but
+                // it may not be stable yet.
+                self.pages.flush()?;
+                break persisted;
+            }
 
-            pages
-                .replace(root_id, Shared::null(), root, &guard)
-                .map_err(|e| e.danger_cast())?;
-            pages
-                .replace(leaf_id, Shared::null(), leaf, &guard)
-                .map_err(|e| e.danger_cast())?;
-            root_id
+            let new_lease = id + ID_LEASE;
+            let frag = Frag::Counter(new_lease);
+            match self.pages.replace(COUNTER_PID, cas_key, frag, &guard) {
+                Ok(_) => {
+                    // the lease must be durable before handing out ids
+                    self.pages.flush()?;
+                    break new_lease;
+                }
+                Err(Error::CasFailed(_)) => {}
+                Err(other) => return Err(other.danger_cast()),
+            }
+            M.tree_looped();
         };
 
-        Ok(Tree {
-            pages: Arc::new(pages),
-            config: config,
-            root: Arc::new(AtomicUsize::new(root_id)),
-            merge_operator: None,
-        })
+        // bump our in-memory view of the persisted lease,
+        // without moving backwards if someone beat us to it.
+        let mut current = self.idgen_persisted.load(SeqCst);
+        while current < lease_end {
+            let last = self.idgen_persisted.compare_and_swap(
+                current,
+                lease_end,
+                SeqCst,
+            );
+            if last == current {
+                break;
+            }
+            current = last;
+        }
+
+        Ok(())
+    }
+
+    /// Retrieve the entry with the greatest key that is strictly
+    /// less than the provided key, if one exists.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![10]);
+    /// t.set(vec![3], vec![30]);
+    /// assert_eq!(t.get_lt(&[3]), Ok(Some((vec![1], vec![10].into()))));
+    /// assert_eq!(t.get_lt(&[4]), Ok(Some((vec![3], vec![30].into()))));
+    /// assert_eq!(t.get_lt(&[1]), Ok(None));
+    /// ```
+    pub fn get_lt(&self, key: &[u8]) -> DbResult<Option<(Key, IVec)>, ()> {
+        let _cc = self.read_lock()?;
+        let guard = pin();
+        self.max_lt(Bound::Exclusive(key.to_vec()), &guard)
+    }
+
+    /// Retrieve the entry with the smallest key that is strictly
+    /// greater than the provided key, if one exists.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![10]);
+    /// t.set(vec![3], vec![30]);
+    /// assert_eq!(t.get_gt(&[]), Ok(Some((vec![1], vec![10].into()))));
+    /// assert_eq!(t.get_gt(&[1]), Ok(Some((vec![3], vec![30].into()))));
+    /// assert_eq!(t.get_gt(&[3]), Ok(None));
+    /// ```
+    pub fn get_gt(&self, key: &[u8]) -> DbResult<Option<(Key, IVec)>, ()> {
+        let _cc = self.read_lock()?;
+        let guard = pin();
+        let path = self.path_for_key(key, &guard)?;
+        let (mut node, _) = path.into_iter().last().expect(
+            "path_for_key should always return a path \
+            of length >= 2 (root + leaf)",
+        );
+
+        loop {
+            {
+                let prefix = node.lo.inner();
+                let items =
+                    node.data.leaf_ref().expect("node should be a leaf");
+                for &(ref k, ref v) in items {
+                    let decoded_k = prefix_decode(prefix, k);
+                    if &*decoded_k > key && !self.is_expired(&*decoded_k)? {
+                        return Ok(Some((decoded_k, v.clone())));
+                    }
+                }
+            }
+
+            // everything in this leaf was too low, so
+            // keep searching among the leaves to the right.
+            let next = match node.next {
+                Some(next) => next,
+                None => return Ok(None),
+            };
+
+            node = match self.pages.get(next, &guard) {
+                Ok(PageGet::Materialized(Frag::Base(base, _), _)) => base,
+                Err(e) => return Err(e.danger_cast()),
+                broken => {
+                    return Err(Error::ReportableBug(format!(
+                        "got non-base node while traversing tree: {:?}",
+                        broken
+                    )))
+                }
+            };
+        }
+    }
+
+    /// Compare and swap. Capable of unique creation, conditional modification,
+    /// or deletion. If old is None, this will only set the value if it doesn't
+    /// exist yet. If new is None, will delete the value if old is correct.
+    /// If both old and new are Some, will modify the value if old is correct.
+    /// On failure, `Error::CasFailed` carries the value that was present
+    /// when the comparison was made, so retry loops don't need to issue
+    /// another read. If Tree is read-only, returns `Error::ReadOnly`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sled::{ConfigBuilder, Error};
+    /// let config = ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    ///
+    /// // unique creation
+    /// assert_eq!(t.cas(vec![1], None, Some(vec![1])), Ok(()));
+    /// assert_eq!(t.cas(vec![1], None, Some(vec![1])), Err(Error::CasFailed(Some(vec![1].into()))));
+    ///
+    /// // conditional modification
+    /// assert_eq!(t.cas(vec![1], Some(vec![1]), Some(vec![2])), Ok(()));
+    /// assert_eq!(t.cas(vec![1], Some(vec![1]), Some(vec![2])), Err(Error::CasFailed(Some(vec![2].into()))));
+    ///
+    /// // conditional deletion
+    /// assert_eq!(t.cas(vec![1], Some(vec![2]), None), Ok(()));
+    /// assert_eq!(t.get(&*vec![1]), Ok(None));
+    /// ```
+    pub fn cas(
+        &self,
+        key: Key,
+        old: Option<Value>,
+        new: Option<Value>,
+    ) -> DbResult<(), Option<IVec>> {
+        if self.config.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if let Some(ref new) = new {
+            self.check_sizes(&*key, &*new).map_err(|e| e.danger_cast())?;
+        }
+        let cc = self.read_lock().map_err(|e| e.danger_cast())?;
+        if self.has_deadline(&*key).map_err(|e| e.danger_cast())? {
+            drop(cc);
+            return self.cas_with_deadline(key, old, new);
+        }
+        let new = new.map(IVec::from);
+        let reservation = self.subscriptions.reserve(&*key);
+        let _epoch = self.hold_epoch();
+        // we need to retry caps until old != cur, since just because
+        // cap fails it doesn't mean our value was changed.
+        let guard = pin();
+        loop {
+            let (path, cur) =
+                self.get_internal(&*key, &guard).map_err(
+                    |e| e.danger_cast(),
+                )?;
+
+            if !cas_matches(&old, &cur) {
+                return Err(Error::CasFailed(cur));
+            }
+
+            self.mark_lens_dirty().map_err(|e| e.danger_cast())?;
+            self.stamp_change(&*key).map_err(|e| e.danger_cast())?;
+
+            let (&(ref node, ref cas_key), ancestors) =
+                path.split_last().expect(
+                    "get_internal somehow returned a path of length zero",
+                );
+            self.snapshots.preserve(&*key, cur.as_ref());
+            let encoded_key = prefix_encode(node.lo.inner(), &*key);
+            let frag = if let Some(ref n) = new {
+                Frag::Set(encoded_key, n.clone())
+            } else {
+                Frag::Del(encoded_key)
+            };
+            let link = self.pages.link(
+                node.id,
+                cas_key.clone(),
+                frag.clone(),
+                &guard,
+            );
+            match link {
+                Ok(_) => {
+                    self.counts.record(
+                        key.len(),
+                        cur.as_ref().map(|v| v.len()),
+                        new.as_ref().map(|v| v.len()),
+                    );
+                    let delta = entry_delta(cur.is_some(), new.is_some());
+                    self.count_entry(ancestors, node, delta, &guard)
+                        .map_err(|e| e.danger_cast())?;
+                    if let Some(reservation) = reservation {
+                        let event = match new {
+                            Some(value) => Some(Event::Insert {
+                                key: key,
+                                value: value,
+                            }),
+                            None if cur.is_some() => {
+                                Some(Event::Remove { key: key })
+                            }
+                            None => None,
+                        };
+                        if let Some(event) = event {
+                            reservation.complete(event);
+                        }
+                    }
+                    return Ok(());
+                }
+                Err(Error::CasFailed(_)) => {}
+                Err(other) => {
+                    self.lose_counts();
+                    return Err(other.danger_cast());
+                }
+            }
+            M.tree_looped();
+        }
+    }
+
+    // like cas, for a key that has a deadline, which
+    // is removed in the same batch as the write.
+    fn cas_with_deadline(
+        &self,
+        key: Key,
+        old: Option<Value>,
+        new: Option<Value>,
+    ) -> DbResult<(), Option<IVec>> {
+        let _cc = self.write_lock();
+        self.check_dropped().map_err(|e| e.danger_cast())?;
+
+        let cur = self.get_inner(&*key).map_err(|e| e.danger_cast())?;
+        if !cas_matches(&old, &cur) {
+            return Err(Error::CasFailed(cur));
+        }
+
+        let mut batch = Batch::default();
+        match new {
+            Some(value) => batch.insert(key, value),
+            None => batch.remove(key),
+        }
+        self.apply_batch_inner(batch).map_err(|e| e.danger_cast())
+    }
+
+    /// Fetch the value, apply a function to it and return the result.
+    /// Returning None from the function will delete the value.
+    ///
+    /// The function is retried with the freshly observed value
+    /// whenever a concurrent writer changes the key before our update
+    /// lands, so it may be called multiple times and should not have
+    /// side effects. It is always called with a complete value that
+    /// was present in the `Tree` at some point.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// fn increment(old: Option<&[u8]>) -> Option<Vec<u8>> {
+    ///     let number = match old {
+    ///         Some(bytes) => bytes[0] + 1,
+    ///         None => 0,
+    ///     };
+    ///     Some(vec![number])
+    /// }
+    ///
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// assert_eq!(t.update_and_fetch(vec![1], increment), Ok(Some(vec![0].into())));
+    /// assert_eq!(t.update_and_fetch(vec![1], increment), Ok(Some(vec![1].into())));
+    /// assert_eq!(t.update_and_fetch(vec![1], |_| None), Ok(None));
+    /// assert_eq!(t.get(&[1]), Ok(None));
+    /// ```
+    pub fn update_and_fetch<F>(
+        &self,
+        key: Key,
+        f: F,
+    ) -> DbResult<Option<IVec>, ()>
+        where F: FnMut(Option<&[u8]>) -> Option<Value>
+    {
+        self.update(key, f).map(|(_old, new)| new)
+    }
+
+    /// Fetch the value, apply a function to it and return the previous
+    /// value. Returning None from the function will delete the value.
+    ///
+    /// The function may be called multiple times, as described
+    /// for `update_and_fetch`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// fn increment(old: Option<&[u8]>) -> Option<Vec<u8>> {
+    ///     let number = match old {
+    ///         Some(bytes) => bytes[0] + 1,
+    ///         None => 0,
+    ///     };
+    ///     Some(vec![number])
+    /// }
+    ///
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// assert_eq!(t.fetch_and_update(vec![1], increment), Ok(None));
+    /// assert_eq!(t.fetch_and_update(vec![1], increment), Ok(Some(vec![0].into())));
+    /// assert_eq!(t.get(&[1]), Ok(Some(vec![1].into())));
+    /// ```
+    pub fn fetch_and_update<F>(
+        &self,
+        key: Key,
+        f: F,
+    ) -> DbResult<Option<IVec>, ()>
+        where F: FnMut(Option<&[u8]>) -> Option<Value>
+    {
+        self.update(key, f).map(|(old, _new)| old)
+    }
+
+    // returns the (old, new) values of a successful update
+    fn update<F>(
+        &self,
+        key: Key,
+        mut f: F,
+    ) -> DbResult<(Option<IVec>, Option<IVec>), ()>
+        where F: FnMut(Option<&[u8]>) -> Option<Value>
+    {
+        if self.config.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let mut cur = self.get(&*key)?;
+        loop {
+            let new = f(cur.as_ref().map(|v| &**v));
+            let old = cur.as_ref().map(|v| v.to_vec());
+            match self.cas(key.clone(), old, new.clone()) {
+                Ok(()) => return Ok((cur, new.map(IVec::from))),
+                Err(Error::CasFailed(actual)) => cur = actual,
+                Err(other) => return Err(other.danger_cast()),
+            }
+            M.tree_looped();
+        }
+    }
+
+    /// Set a key to a new value. This removes any deadline
+    /// given to the key by `set_with_ttl`.
+    ///
+    /// Keys may be empty. Keys and values larger than the `Config`'s
+    /// `max_key_size` and `max_value_size` are refused with
+    /// `Error::TooLarge`, as they are by every other way of writing.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sled::{ConfigBuilder, Error};
+    /// let config = ConfigBuilder::new()
+    ///     .temporary(true)
+    ///     .max_value_size(4)
+    ///     .build();
+    /// let t = sled::Tree::start(config).unwrap();
+    ///
+    /// t.set(vec![], vec![1, 2, 3, 4]).unwrap();
+    /// assert_eq!(
+    ///     t.set(vec![], vec![1, 2, 3, 4, 5]),
+    ///     Err(Error::TooLarge { limit: "max_value_size", max: 4, size: 5 })
+    /// );
+    /// assert_eq!(t.get(&[]), Ok(Some(vec![1, 2, 3, 4].into())));
+    /// ```
+    pub fn set(&self, key: Key, value: Value) -> DbResult<(), ()> {
+        if self.config.read_only {
+            return Err(Error::ReadOnly);
+        }
+        self.check_sizes(&*key, &*value)?;
+        let cc = self.read_lock()?;
+        if !self.has_deadline(&*key)? {
+            return self.set_inner(key, value);
+        }
+        drop(cc);
+
+        // the deadline is removed in the same batch
+        let mut batch = Batch::default();
+        batch.insert(key, value);
+        self.apply_batch(batch)
+    }
+
+    /// Set a key to a value that expires once `ttl` has passed. From
+    /// then on, the entry is treated as absent by reads, iterators and
+    /// `cas`, and it's removed in the background, a few entries at a
+    /// time, by the thread that flushes every `flush_every_ms`. Its
+    /// removal is sent to subscribers as an `Event::Remove`.
+    ///
+    /// Deadlines are persisted along with the value, so expiration
+    /// carries on after a restart. Writing the key again with `set`,
+    /// `cas`, `del`, a `Batch` or a transaction removes its deadline,
+    /// while `merge` keeps it. Expiration is measured against the
+    /// `Clock` set with `set_expiration_clock`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set_with_ttl(vec![1], vec![10], Duration::from_secs(60)).unwrap();
+    /// t.set_with_ttl(vec![2], vec![20], Duration::from_secs(0)).unwrap();
+    /// assert_eq!(t.get(&[1]), Ok(Some(vec![10].into())));
+    /// assert_eq!(t.get(&[2]), Ok(None));
+    /// ```
+    pub fn set_with_ttl(
+        &self,
+        key: Key,
+        value: Value,
+        ttl: Duration,
+    ) -> DbResult<(), ()> {
+        if self.config.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        let _cc = self.write_lock();
+        self.check_dropped()?;
+
+        let deadlines = self.deadlines_tree()?;
+        let deadline_batch = self.deadline_insertion(&deadlines, &key, ttl)?;
+        let mut batch = Batch::default();
+        batch.insert(key, value);
+        Tree::write_batches(vec![(self, batch), (&deadlines, deadline_batch)])
+    }
+
+    /// Set the clock that entries written with `set_with_ttl` expire
+    /// according to, which is the system clock by default. Deadlines
+    /// are persisted as milliseconds since the unix epoch, so the clock
+    /// should keep counting from where it was before a restart.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    ///
+    /// fn stopped() -> SystemTime {
+    ///     UNIX_EPOCH + Duration::from_secs(1)
+    /// }
+    ///
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set_expiration_clock(stopped);
+    /// t.set_with_ttl(vec![1], vec![10], Duration::from_millis(1)).unwrap();
+    /// std::thread::sleep(Duration::from_millis(10));
+    /// assert_eq!(t.get(&[1]), Ok(Some(vec![10].into())));
+    /// ```
+    pub fn set_expiration_clock(&self, clock: Clock) {
+        self.deadlines.set_clock(clock);
+    }
+
+    pub(super) fn set_inner(
+        &self,
+        key: Key,
+        value: Value,
+    ) -> DbResult<(), ()> {
+        let value = IVec::from(value);
+        self.mark_lens_dirty()?;
+        let _epoch = self.hold_epoch();
+        self.stamp_change(&*key)?;
+        let reservation = self.subscriptions.reserve(&*key);
+        let guard = pin();
+        loop {
+            let mut path = self.path_for_key(&*key, &guard)?;
+            let (mut last_node, last_cas_key) = path.pop().expect(
+                "path_for_key should always return a path \
+                of length >= 2 (root + leaf)",
+            );
+            let encoded_key = prefix_encode(last_node.lo.inner(), &*key);
+            let old_len = last_node.get_leaf(&*encoded_key).map(|v| v.len());
+            self.snapshots.preserve(&*key, last_node.get_leaf(&*encoded_key));
+            let frag = Frag::Set(encoded_key, value.clone());
+            let link = self.pages.link(
+                last_node.id,
+                last_cas_key,
+                frag.clone(),
+                &guard,
+            );
+            match link {
+                Ok(new_cas_key) => {
+                    self.counts.record(key.len(), old_len, Some(value.len()));
+                    let delta = entry_delta(old_len.is_some(), true);
+                    if let Some(reservation) = reservation {
+                        reservation.complete(Event::Insert {
+                            key: key.clone(),
+                            value: value.clone(),
+                        });
+                    }
+                    last_node.apply(&frag, self.config.get_merge_operator())?;
+                    let should_split =
+                        last_node.should_split(&self.config);
+                    path.push((last_node.clone(), new_cas_key));
+                    // success
+                    if should_split {
+                        self.recursive_split(&path, &guard)?;
+                    }
+                    // counted after splitting, as the counts link to
+                    // the nodes above the leaf that may be split.
+                    let ancestors = &path[..path.len() - 1];
+                    self.count_entry(ancestors, &last_node, delta, &guard)?;
+                    return Ok(());
+                }
+                Err(Error::CasFailed(_)) => {}
+                Err(other) => {
+                    self.lose_counts();
+                    return Err(other.danger_cast());
+                }
+            }
+            M.tree_looped();
+        }
+    }
+
+    /// Merge a new value into the total state for a key.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// fn concatenate_merge(
+    ///   _key: &[u8],               // the key being merged
+    ///   old_value: Option<&[u8]>,  // the previous value, if one existed
+    ///   merged_bytes: &[u8]        // the new bytes being merged in
+    /// ) -> Option<Vec<u8>> {       // set the new value, return None to delete
+    ///   let mut ret = old_value
+    ///     .map(|ov| ov.to_vec())
+    ///     .unwrap_or_else(|| vec![]);
+    ///
+    ///   ret.extend_from_slice(merged_bytes);
+    ///
+    ///   Some(ret)
+    /// }
+    ///
+    /// let config = sled::ConfigBuilder::new()
+    ///   .temporary(true)
+    ///   .merge_operator(concatenate_merge)
+    ///   .build();
+    ///
+    /// let tree = sled::Tree::start(config).unwrap();
+    ///
+    /// let k = b"k1".to_vec();
+    ///
+    /// tree.set(k.clone(), vec![0]);
+    /// tree.merge(k.clone(), vec![1]);
+    /// tree.merge(k.clone(), vec![2]);
+    /// assert_eq!(tree.get(&k), Ok(Some(vec![0, 1, 2].into())));
+    ///
+    /// // sets replace previously merged data,
+    /// // bypassing the merge function.
+    /// tree.set(k.clone(), vec![3]);
+    /// assert_eq!(tree.get(&k), Ok(Some(vec![3].into())));
+    ///
+    /// // merges on non-present values will add them
+    /// tree.del(&k);
+    /// tree.merge(k.clone(), vec![4]);
+    /// assert_eq!(tree.get(&k), Ok(Some(vec![4].into())));
+    /// ```
+    pub fn merge(&self, key: Key, value: Value) -> DbResult<(), ()> {
+        if self.config.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if self.config.get_merge_operator().is_none() {
+            return Err(Error::Unsupported(
+                "must set a merge operator on the Config or with \
+                Db::set_merge_operator before calling merge"
+                    .to_owned(),
+            ));
+        }
+        self.check_sizes(&*key, &*value)?;
+        let cc = self.read_lock()?;
+        if !self.has_deadline(&*key)? {
+            return self.merge_inner(key, value);
+        }
+        drop(cc);
+
+        let _cc = self.write_lock();
+        self.check_dropped()?;
+        if self.is_expired(&*key)? {
+            // merging into an expired entry starts from
+            // nothing, rather than inheriting its deadline.
+            let mut batch = Batch::default();
+            batch.remove(key.clone());
+            self.apply_batch_inner(batch)?;
+        }
+        self.merge_inner(key, value)
+    }
+
+    fn merge_inner(&self, key: Key, value: Value) -> DbResult<(), ()> {
+        self.mark_lens_dirty()?;
+        let _epoch = self.hold_epoch();
+        self.stamp_change(&*key)?;
+        let reservation = self.subscriptions.reserve(&*key);
+        let guard = pin();
+        loop {
+            let mut path = self.path_for_key(&*key, &guard)?;
+            let (mut last_node, last_cas_key) = path.pop().expect(
+                "path_for_key should always return a path \
+                of length >= 2 (root + leaf)",
+            );
+
+            let encoded_key = prefix_encode(last_node.lo.inner(), &*key);
+            let old_len = last_node.get_leaf(&*encoded_key).map(|v| v.len());
+            self.snapshots.preserve(&*key, last_node.get_leaf(&*encoded_key));
+            let frag = Frag::Merge(encoded_key.clone(), value.clone());
+
+            let link = self.pages.link(
+                last_node.id,
+                last_cas_key,
+                frag.clone(),
+                &guard,
+            );
+            match link {
+                Ok(new_cas_key) => {
+                    last_node.apply(&frag, self.config.get_merge_operator())?;
+                    // the merge operator may have created or
+                    // removed the entry
+                    let merged = last_node.get_leaf(&*encoded_key).cloned();
+                    self.counts.record(
+                        key.len(),
+                        old_len,
+                        merged.as_ref().map(|v| v.len()),
+                    );
+                    let delta =
+                        entry_delta(old_len.is_some(), merged.is_some());
+                    if let Some(reservation) = reservation {
+                        let event = match merged {
+                            Some(value) => Some(Event::Insert {
+                                key: key.clone(),
+                                value: value,
+                            }),
+                            None if old_len.is_some() => {
+                                Some(Event::Remove { key: key.clone() })
+                            }
+                            None => None,
+                        };
+                        if let Some(event) = event {
+                            reservation.complete(event);
+                        }
+                    }
+                    let should_split =
+                        last_node.should_split(&self.config);
+                    path.push((last_node.clone(), new_cas_key));
+                    // success
+                    if should_split {
+                        self.recursive_split(&path, &guard)?;
+                    }
+                    // counted after splitting, as the counts link to
+                    // the nodes above the leaf that may be split.
+                    let ancestors = &path[..path.len() - 1];
+                    self.count_entry(ancestors, &last_node, delta, &guard)?;
+                    return Ok(());
+                }
+                Err(Error::CasFailed(_)) => {}
+                Err(other) => {
+                    self.lose_counts();
+                    return Err(other.danger_cast());
+                }
+            }
+            M.tree_looped();
+        }
+    }
+
+
+    /// Delete a value, returning the last result if it existed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![1]);
+    /// assert_eq!(t.del(&*vec![1]), Ok(Some(vec![1].into())));
+    /// assert_eq!(t.del(&*vec![1]), Ok(None));
+    /// ```
+    pub fn del(&self, key: &[u8]) -> DbResult<Option<IVec>, ()> {
+        if self.config.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let cc = self.read_lock()?;
+        if !self.has_deadline(key)? {
+            return self.del_inner(key);
+        }
+        drop(cc);
+
+        // the deadline is removed in the same batch
+        let _cc = self.write_lock();
+        self.check_dropped()?;
+        let last = self.get_inner(key)?;
+        let mut batch = Batch::default();
+        batch.remove(key.to_vec());
+        self.apply_batch_inner(batch)?;
+        Ok(last)
+    }
+
+    pub(super) fn del_inner(
+        &self,
+        key: &[u8],
+    ) -> DbResult<Option<IVec>, ()> {
+        self.mark_lens_dirty()?;
+        let _epoch = self.hold_epoch();
+        let reservation = self.subscriptions.reserve(key);
+        let guard = pin();
+        let mut ret: Option<IVec>;
+        loop {
+            let mut path = self.path_for_key(&*key, &guard)?;
+            let (leaf_node, leaf_cas_key) = path.pop().expect(
+                "path_for_key should always return a path \
+                of length >= 2 (root + leaf)",
+            );
+            let encoded_key = prefix_encode(leaf_node.lo.inner(), key);
+            match leaf_node.data {
+                Data::Leaf(ref items) => {
+                    let search = items.binary_search_by(|&(ref k, ref _v)| {
+                        prefix_cmp(k, &*encoded_key)
+                    });
+                    if let Ok(idx) = search {
+                        ret = Some(items[idx].1.clone());
+                    } else {
+                        ret = None;
+                        break;
+                    }
+                }
+                _ => panic!("last node in path is not leaf"),
+            }
+            self.snapshots.preserve(key, ret.as_ref());
+            self.stamp_change(key)?;
+
+            let frag = Frag::Del(encoded_key);
+            let link =
+                self.pages.link(leaf_node.id, leaf_cas_key, frag, &guard);
+
+            match link {
+                Ok(_) => {
+                    self.counts.record(
+                        key.len(),
+                        ret.as_ref().map(|v| v.len()),
+                        None,
+                    );
+                    self.count_entry(&path, &leaf_node, -1, &guard)?;
+                    if let Some(reservation) = reservation {
+                        reservation.complete(Event::Remove {
+                            key: key.to_vec(),
+                        });
+                    }
+                    break;
+                }
+                Err(Error::CasFailed(_)) => {
+                    M.tree_looped();
+                    continue;
+                }
+                Err(other) => {
+                    self.lose_counts();
+                    return Err(other.danger_cast());
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Remove every entry from the `Tree`. Instead of removing entries
+    /// one by one, this swaps in a new empty root and frees the old
+    /// tree's pages, taking time proportional to the number of pages.
+    /// After a crash, the `Tree` recovers either all of its entries or
+    /// none of them.
+    ///
+    /// Subscribers receive a single `Event::Clear`, and iterators
+    /// created before the `Tree` was cleared end.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![10]).unwrap();
+    /// t.set(vec![2], vec![20]).unwrap();
+    /// t.clear().unwrap();
+    /// assert_eq!(t.get(&[1]), Ok(None));
+    /// assert!(t.is_empty());
+    /// ```
+    pub fn clear(&self) -> DbResult<(), ()> {
+        if self.config.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        let _cc = self.write_lock();
+        self.check_dropped()?;
+        self.clear_inner()?;
+
+        // a crash before this leaves deadlines for keys that no longer
+        // exist, which are ignored, rather than keys without deadlines.
+        match self.deadlines.tree() {
+            Some(deadlines) => deadlines.clear_inner(),
+            None => Ok(()),
+        }
+    }
+
+    // callers must hold the write lock.
+    pub(super) fn clear_inner(&self) -> DbResult<(), ()> {
+        if self.snapshots.is_active() {
+            // every entry is about to disappear from under them
+            self.for_each_leaf(|node| {
+                let prefix = node.lo.inner();
+                let items = node.data.leaf_ref().expect("node should be a leaf");
+                for &(ref k, ref v) in items {
+                    let decoded_k = prefix_decode(prefix, k);
+                    self.snapshots.preserve(&*decoded_k, Some(v));
+                }
+                Ok(())
+            })?;
+        }
+        let _epoch = self.hold_epoch();
+        if self.changes.tree().is_some() {
+            // so is every entry's removal
+            self.for_each_leaf(|node| {
+                let prefix = node.lo.inner();
+                let items = node.data.leaf_ref().expect("node should be a leaf");
+                for &(ref k, _) in items {
+                    self.stamp_change(&*prefix_decode(prefix, k))?;
+                }
+                Ok(())
+            })?;
+        }
+
+        let reservation = self.subscriptions.reserve_all();
+        self.mark_lens_dirty()?;
+
+        let guard = pin();
+        let root_id = self.pages.allocate(&guard)?;
+        let leaf_id = self.pages.allocate(&guard)?;
+        debug!("allocated root {} and leaf {} for tree", root_id, leaf_id);
+
+        let (leaf, root) = new_root(root_id, leaf_id);
+        self.pages
+            .replace(leaf_id, Shared::null(), leaf, &guard)
+            .map_err(|e| e.danger_cast())?;
+        self.pages
+            .replace(root_id, Shared::null(), root, &guard)
+            .map_err(|e| e.danger_cast())?;
+
+        // pointing the meta page at the new root is the point after
+        // which the tree is considered cleared, even after a crash.
+        maybe_fail!("clear meta");
+        self.update_meta(|meta| {
+            meta.insert(self.name.clone(), root_id);
+        })?;
+
+        let old_root = self.root.swap(root_id, SeqCst);
+        self.clears.fetch_add(1, SeqCst);
+        self.counts.store((0, 0, 0));
+        if let Some(reservation) = reservation {
+            reservation.complete(Event::Clear);
+        }
+
+        maybe_fail!("clear free");
+        self.free_pages(old_root)
+    }
+
+    /// Atomically apply a `Batch` of inserts and removals. Concurrent
+    /// readers will observe either none or all of the batch, and
+    /// after a crash the `Tree` recovers either none or all of it.
+    /// Other writes and scans of this `Tree` wait for the batch to be
+    /// applied, while point reads like `get` and writes to other trees
+    /// carry on.
+    ///
+    /// A batch is logged as a single message, so besides its keys and
+    /// values being within `max_key_size` and `max_value_size`, all of
+    /// it together must fit within the `Config`'s `max_message_size`.
+    /// Otherwise `Error::TooLarge` is returned and none of it is applied.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    ///
+    /// let mut batch = sled::Batch::default();
+    /// batch.insert(vec![1], vec![10]);
+    /// batch.insert(vec![2], vec![20]);
+    /// t.apply_batch(batch).unwrap();
+    ///
+    /// assert_eq!(t.get(&[1]), Ok(Some(vec![10].into())));
+    /// assert_eq!(t.get(&[2]), Ok(Some(vec![20].into())));
+    /// ```
+    pub fn apply_batch(&self, batch: Batch) -> DbResult<(), ()> {
+        if self.config.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        let _cc = self.write_lock();
+        self.check_dropped()?;
+        self.apply_batch_inner(batch)
+    }
+
+    /// Run the closure as a transaction over this `Tree`. See
+    /// `Transactional` for running transactions across several trees.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(b"from".to_vec(), vec![10]).unwrap();
+    ///
+    /// t.transaction::<_, _, ()>(|tx| {
+    ///     let from = tx.get(b"from")?.unwrap();
+    ///     tx.set(b"to".to_vec(), from.to_vec());
+    ///     tx.del(b"from")?;
+    ///     Ok(())
+    /// }).unwrap();
+    ///
+    /// assert_eq!(t.get(b"from"), Ok(None));
+    /// assert_eq!(t.get(b"to"), Ok(Some(vec![10].into())));
+    /// ```
+    pub fn transaction<F, R, E>(&self, f: F) -> TransactionResult<R, E>
+        where F: Fn(&TransactionalTree) -> TransactionResult<R, E>
+    {
+        Transactional::transaction(&self, f)
+    }
+
+    // callers must hold the write lock.
+    pub(crate) fn apply_batch_inner(&self, batch: Batch) -> DbResult<(), ()> {
+        Tree::apply_batches_inner(vec![(self, batch)])
+    }
+
+    // atomically applies batches to one or more trees of the same Db,
+    // removing the deadlines of the keys they write. callers must hold
+    // the write lock of every tree.
+    pub(crate) fn apply_batches_inner(
+        batches: Vec<(&Tree, Batch)>,
+    ) -> DbResult<(), ()> {
+        let mut removals = vec![];
+        for &(tree, ref batch) in &batches {
+            tree.check_dropped()?;
+            if let Some(deadlines) = tree.deadlines.tree() {
+                let removal =
+                    Tree::deadline_removals(&deadlines, batch.writes.keys())?;
+                if !removal.is_empty() {
+                    removals.push((deadlines, removal));
+                }
+            }
+        }
+
+        let batches = batches
+            .into_iter()
+            .chain(removals.iter().map(|&(ref t, ref b)| (t, b.clone())))
+            .collect();
+        Tree::write_batches(batches)
+    }
+
+    // atomically applies batches to one or more trees of the same Db.
+    // callers must hold the write lock of every tree.
+    pub(super) fn write_batches(
+        batches: Vec<(&Tree, Batch)>,
+    ) -> DbResult<(), ()> {
+        Tree::write_batches_with(batches, Tree::apply_batch_writes)
+    }
+
+    // like `write_batches`, but applies each logged batch to its tree
+    // with `apply`, for callers that care about the order of the writes.
+    pub(super) fn write_batches_with(
+        batches: Vec<(&Tree, Batch)>,
+        apply: fn(&Tree, Batch) -> DbResult<(), ()>,
+    ) -> DbResult<(), ()> {
+        let first = match batches.first() {
+            Some(&(tree, _)) => tree.clone(),
+            None => return Ok(()),
+        };
+        for &(tree, ref batch) in &batches {
+            tree.check_dropped()?;
+            for (k, v) in &batch.writes {
+                if let Some(ref v) = *v {
+                    tree.check_sizes(k, v)?;
+                }
+            }
+        }
+
+        // log every batch in a single message before touching
+        // any leaves. if we crash before every write below has been
+        // applied, `Db::recover_batch` will finish the job on restart.
+        let record: Vec<(Vec<u8>, Batch)> = batches
+            .iter()
+            .map(|&(tree, ref batch)| (tree.name.clone(), batch.clone()))
+            .collect();
+        maybe_fail!("batch write");
+        first.log_batches(&record)?;
+
+        // point reads don't wait for the batches, so they're shown the
+        // batches' writes until every one of them has been applied.
+        let trees: Vec<&Tree> = batches.iter().map(|&(tree, _)| tree).collect();
+        for &(tree, ref batch) in &batches {
+            tree.pending.publish(batch);
+        }
+        let mut applied = Ok(());
+        for (tree, batch) in batches {
+            applied = apply(tree, batch);
+            if applied.is_err() {
+                break;
+            }
+        }
+        for tree in trees {
+            tree.pending.withdraw();
+        }
+        applied?;
+
+        maybe_fail!("batch clear");
+        first.unlog_batches(&record)
     }
 
-    /// Flushes any pending IO buffers to disk to ensure durability.
-    pub fn flush(&self) -> CacheResult<(), ()> {
-        self.pages.flush()
+    pub(super) fn apply_batch_writes(&self, batch: Batch) -> DbResult<(), ()> {
+        for (key, value) in batch.writes {
+            maybe_fail!("batch apply");
+            if let Some(value) = value {
+                self.set_inner(key, value)?;
+            } else {
+                self.del_inner(&*key)?;
+            }
+        }
+        Ok(())
     }
 
-    /// Retrieve a value from the `Tree` if it exists.
-    pub fn get(&self, key: &[u8]) -> DbResult<Option<Value>, ()> {
-        let guard = pin();
-        let (_, ret) = self.get_internal(key, &guard)?;
-        Ok(ret)
+    // adds batches to the batch page, alongside those in flight to
+    // other trees. batches that don't fit in a log message alongside
+    // them wait for them to finish.
+    fn log_batches(&self, record: &[(Vec<u8>, Batch)]) -> DbResult<(), ()> {
+        loop {
+            let mut others = false;
+            let logged = self.update_batch_page(|batches| {
+                // we hold the locks of the trees we're writing to, so
+                // any batch left to them was logged by one that failed.
+                batches.retain(|&(ref name, _)| {
+                    !record.iter().any(|&(ref ours, _)| ours == name)
+                });
+                others = !batches.is_empty();
+                batches.extend(record.iter().cloned());
+            });
+            match logged {
+                Err(Error::TooLarge { .. }) if others => {}
+                other => return other,
+            }
+            M.tree_looped();
+            thread::yield_now();
+        }
     }
 
-    /// Compare and swap. Capable of unique creation, conditional modification,
-    /// or deletion. If old is None, this will only set the value if it doesn't
-    /// exist yet. If new is None, will delete the value if old is correct.
-    /// If both old and new are Some, will modify the value if old is correct.
-    /// If Tree is read-only, will do nothing.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use sled::{ConfigBuilder, Error};
-    /// let config = ConfigBuilder::new().temporary(true).build();
-    /// let t = sled::Tree::start(config).unwrap();
-    ///
-    /// // unique creation
-    /// assert_eq!(t.cas(vec![1], None, Some(vec![1])), Ok(()));
-    /// assert_eq!(t.cas(vec![1], None, Some(vec![1])), Err(Error::CasFailed(Some(vec![1]))));
-    ///
-    /// // conditional modification
-    /// assert_eq!(t.cas(vec![1], Some(vec![1]), Some(vec![2])), Ok(()));
-    /// assert_eq!(t.cas(vec![1], Some(vec![1]), Some(vec![2])), Err(Error::CasFailed(Some(vec![2]))));
-    ///
-    /// // conditional deletion
-    /// assert_eq!(t.cas(vec![1], Some(vec![2]), None), Ok(()));
-    /// assert_eq!(t.get(&*vec![1]), Ok(None));
-    /// ```
-    pub fn cas(
+    // removes batches that have been fully applied from the batch page
+    fn unlog_batches(
         &self,
-        key: Key,
-        old: Option<Value>,
-        new: Option<Value>,
-    ) -> DbResult<(), Option<Value>> {
-        if self.config.read_only {
-            return Err(Error::CasFailed(None));
-        }
-        // we need to retry caps until old != cur, since just because
-        // cap fails it doesn't mean our value was changed.
+        record: &[(Vec<u8>, Batch)],
+    ) -> DbResult<(), ()> {
+        self.update_batch_page(|batches| {
+            batches.retain(|batch| !record.contains(batch));
+        })
+    }
+
+    // applies a change to the batch page. callers must hold the write
+    // locks of the trees whose batches they add or remove, or be
+    // starting the Db.
+    pub(super) fn update_batch_page<F>(&self, mut f: F) -> DbResult<(), ()>
+        where F: FnMut(&mut Vec<(Vec<u8>, Batch)>)
+    {
         let guard = pin();
         loop {
-            let (mut path, cur) =
-                self.get_internal(&*key, &guard).map_err(
-                    |e| e.danger_cast(),
-                )?;
-
-            if old != cur {
-                return Err(Error::CasFailed(cur));
-            }
+            let get_cursor = self.pages.get(BATCH_PID, &guard).map_err(
+                |e| e.danger_cast(),
+            )?;
 
-            let &mut (ref node, ref cas_key) = path.last_mut().expect(
-                "get_internal somehow returned a path of length zero",
-            );
-            let encoded_key = prefix_encode(node.lo.inner(), &*key);
-            let frag = if let Some(ref n) = new {
-                Frag::Set(encoded_key, n.clone())
-            } else {
-                Frag::Del(encoded_key)
+            let (mut batches, cas_key) = match get_cursor {
+                PageGet::Materialized(Frag::Batch(batches), cas_key) => {
+                    (batches, cas_key)
+                }
+                broken => {
+                    return Err(Error::ReportableBug(format!(
+                        "got non-batch page while writing batch: {:?}",
+                        broken
+                    )))
+                }
             };
-            let link = self.pages.link(
-                node.id,
-                cas_key.clone(),
-                frag.clone(),
-                &guard,
-            );
-            match link {
+
+            f(&mut batches);
+            let frag = Frag::Batch(batches);
+            match self.pages.replace(BATCH_PID, cas_key, frag, &guard) {
                 Ok(_) => return Ok(()),
                 Err(Error::CasFailed(_)) => {}
                 Err(other) => return Err(other.danger_cast()),
@@ -210,40 +1877,35 @@ impl Tree {
         }
     }
 
-    /// Set a key to a new value.
-    pub fn set(&self, key: Key, value: Value) -> DbResult<(), ()> {
-        if self.config.read_only {
-            return Err(Error::Unsupported(
-                "the database is in read-only mode".to_owned(),
-            ));
-        }
+    // applies a change to the meta page. callers must hold the
+    // Db's tenants write lock when adding or removing trees, or
+    // the write lock when pointing an existing tree at a new root.
+    pub(super) fn update_meta<F>(&self, f: F) -> DbResult<(), ()>
+        where F: Fn(&mut BTreeMap<Vec<u8>, PageID>)
+    {
         let guard = pin();
         loop {
-            let mut path = self.path_for_key(&*key, &guard)?;
-            let (mut last_node, last_cas_key) = path.pop().expect(
-                "path_for_key should always return a path \
-                of length >= 2 (root + leaf)",
-            );
-            let encoded_key = prefix_encode(last_node.lo.inner(), &*key);
-            let frag = Frag::Set(encoded_key, value.clone());
-            let link = self.pages.link(
-                last_node.id,
-                last_cas_key,
-                frag.clone(),
-                &guard,
-            );
-            match link {
-                Ok(new_cas_key) => {
-                    last_node.apply(&frag, self.config.merge_operator);
-                    let should_split =
-                        last_node.should_split(self.config.blink_fanout);
-                    path.push((last_node.clone(), new_cas_key));
-                    // success
-                    if should_split {
-                        self.recursive_split(&path, &guard)?;
-                    }
-                    return Ok(());
+            let get_cursor = self.pages.get(META_PID, &guard).map_err(
+                |e| e.danger_cast(),
+            )?;
+
+            let (mut meta, cas_key) = match get_cursor {
+                PageGet::Materialized(Frag::Meta(meta), cas_key) => {
+                    (meta, cas_key)
                 }
+                broken => {
+                    return Err(Error::ReportableBug(format!(
+                        "got non-meta page while updating meta: {:?}",
+                        broken
+                    )))
+                }
+            };
+
+            f(&mut meta);
+
+            let frag = Frag::Meta(meta);
+            match self.pages.replace(META_PID, cas_key, frag, &guard) {
+                Ok(_) => return Ok(()),
                 Err(Error::CasFailed(_)) => {}
                 Err(other) => return Err(other.danger_cast()),
             }
@@ -251,84 +1913,108 @@ impl Tree {
         }
     }
 
-    /// Merge a new value into the total state for a key.
+    // frees the pages of the tree below the provided root, after
+    // it's no longer reachable. callers must hold the write lock.
+    pub(super) fn free_pages(&self, root: PageID) -> DbResult<(), ()> {
+        // reclaim every node reachable from the root. former
+        // roots are reachable as children of the roots that replaced
+        // them, but are left allocated so that their pids are never
+        // reused. otherwise, the chain of root hoists recovered for
+        // another tree could run into this tree's old hoists.
+        let guard = pin();
+        let mut to_free = vec![root];
+        let mut visited = HashSet::new();
+        while let Some(pid) = to_free.pop() {
+            if !visited.insert(pid) {
+                continue;
+            }
+            let get_cursor = self.pages.get(pid, &guard).map_err(
+                |e| e.danger_cast(),
+            )?;
+            let is_root = match get_cursor {
+                PageGet::Materialized(Frag::Base(node, prev_root), _) => {
+                    if let Data::Index(ref children, _) = node.data {
+                        to_free
+                            .extend(children.iter().map(|&(_, child)| child));
+                    }
+                    if let Some(next) = node.next {
+                        to_free.push(next);
+                    }
+                    prev_root.is_some()
+                }
+                _ => false,
+            };
+            if !is_root {
+                self.pages.free(pid, &guard).map_err(|e| e.danger_cast())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Atomically remove and return the entry with the smallest key,
+    /// if the `Tree` is not empty. Concurrent callers will never
+    /// receive the same entry.
     ///
     /// # Examples
     ///
     /// ```
-    /// fn concatenate_merge(
-    ///   _key: &[u8],               // the key being merged
-    ///   old_value: Option<&[u8]>,  // the previous value, if one existed
-    ///   merged_bytes: &[u8]        // the new bytes being merged in
-    /// ) -> Option<Vec<u8>> {       // set the new value, return None to delete
-    ///   let mut ret = old_value
-    ///     .map(|ov| ov.to_vec())
-    ///     .unwrap_or_else(|| vec![]);
-    ///
-    ///   ret.extend_from_slice(merged_bytes);
-    ///
-    ///   Some(ret)
-    /// }
-    ///
-    /// let config = sled::ConfigBuilder::new()
-    ///   .temporary(true)
-    ///   .merge_operator(concatenate_merge)
-    ///   .build();
-    ///
-    /// let tree = sled::Tree::start(config).unwrap();
-    ///
-    /// let k = b"k1".to_vec();
-    ///
-    /// tree.set(k.clone(), vec![0]);
-    /// tree.merge(k.clone(), vec![1]);
-    /// tree.merge(k.clone(), vec![2]);
-    /// assert_eq!(tree.get(&k), Ok(Some(vec![0, 1, 2])));
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![10]);
+    /// t.set(vec![2], vec![20]);
+    /// assert_eq!(t.pop_min(), Ok(Some((vec![1].into(), vec![10].into()))));
+    /// assert_eq!(t.pop_min(), Ok(Some((vec![2].into(), vec![20].into()))));
+    /// assert_eq!(t.pop_min(), Ok(None));
+    /// ```
+    pub fn pop_min(&self) -> DbResult<Option<(IVec, IVec)>, ()> {
+        self.pop_with(|| match self.iter().next() {
+            Some(Ok(kv)) => Ok(Some(kv)),
+            Some(Err(e)) => Err(e),
+            None => Ok(None),
+        })
+    }
+
+    /// Atomically remove and return the entry with the largest key,
+    /// if the `Tree` is not empty. Concurrent callers will never
+    /// receive the same entry.
     ///
-    /// // sets replace previously merged data,
-    /// // bypassing the merge function.
-    /// tree.set(k.clone(), vec![3]);
-    /// assert_eq!(tree.get(&k), Ok(Some(vec![3])));
+    /// # Examples
     ///
-    /// // merges on non-present values will add them
-    /// tree.del(&k);
-    /// tree.merge(k.clone(), vec![4]);
-    /// assert_eq!(tree.get(&k), Ok(Some(vec![4])));
     /// ```
-    pub fn merge(&self, key: Key, value: Value) -> DbResult<(), ()> {
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![10]);
+    /// t.set(vec![2], vec![20]);
+    /// assert_eq!(t.pop_max(), Ok(Some((vec![2].into(), vec![20].into()))));
+    /// assert_eq!(t.pop_max(), Ok(Some((vec![1].into(), vec![10].into()))));
+    /// assert_eq!(t.pop_max(), Ok(None));
+    /// ```
+    pub fn pop_max(&self) -> DbResult<Option<(IVec, IVec)>, ()> {
+        self.pop_with(|| {
+            let _cc = self.read_lock()?;
+            let guard = pin();
+            self.max_lt(Bound::Inf, &guard)
+        })
+    }
+
+    // repeatedly locates a candidate entry and tries to
+    // remove it with a cas, until either the cas succeeds
+    // or the tree is empty.
+    fn pop_with<F>(&self, find: F) -> DbResult<Option<(IVec, IVec)>, ()>
+        where F: Fn() -> DbResult<Option<(Key, IVec)>, ()>
+    {
         if self.config.read_only {
-            return Err(Error::Unsupported(
-                "the database is in read-only mode".to_owned(),
-            ));
+            return Err(Error::ReadOnly);
         }
-        let guard = pin();
         loop {
-            let mut path = self.path_for_key(&*key, &guard)?;
-            let (mut last_node, last_cas_key) = path.pop().expect(
-                "path_for_key should always return a path \
-                of length >= 2 (root + leaf)",
-            );
-
-            let encoded_key = prefix_encode(last_node.lo.inner(), &*key);
-            let frag = Frag::Merge(encoded_key, value.clone());
+            let (k, v) = match find()? {
+                Some(kv) => kv,
+                None => return Ok(None),
+            };
 
-            let link = self.pages.link(
-                last_node.id,
-                last_cas_key,
-                frag.clone(),
-                &guard,
-            );
-            match link {
-                Ok(new_cas_key) => {
-                    last_node.apply(&frag, self.config.merge_operator);
-                    let should_split =
-                        last_node.should_split(self.config.blink_fanout);
-                    path.push((last_node.clone(), new_cas_key));
-                    // success
-                    if should_split {
-                        self.recursive_split(&path, &guard)?;
-                    }
-                    return Ok(());
-                }
+            match self.cas(k.clone(), Some(v.to_vec()), None) {
+                Ok(()) => return Ok(Some((IVec::from(k), v))),
                 Err(Error::CasFailed(_)) => {}
                 Err(other) => return Err(other.danger_cast()),
             }
@@ -336,63 +2022,139 @@ impl Tree {
         }
     }
 
-
-    /// Delete a value, returning the last result if it existed.
+    /// Returns the entry with the smallest key, if the `Tree` is not
+    /// empty. This descends directly to the leftmost leaf instead of
+    /// setting up an iterator. The returned entry was the smallest
+    /// in the `Tree` at some point during the call.
     ///
     /// # Examples
     ///
     /// ```
     /// let config = sled::ConfigBuilder::new().temporary(true).build();
     /// let t = sled::Tree::start(config).unwrap();
-    /// t.set(vec![1], vec![1]);
-    /// assert_eq!(t.del(&*vec![1]), Ok(Some(vec![1])));
-    /// assert_eq!(t.del(&*vec![1]), Ok(None));
+    /// assert_eq!(t.first(), Ok(None));
+    /// t.set(vec![2], vec![20]);
+    /// t.set(vec![1], vec![10]);
+    /// assert_eq!(t.first(), Ok(Some((vec![1], vec![10].into()))));
     /// ```
-    pub fn del(&self, key: &[u8]) -> DbResult<Option<Value>, ()> {
-        if self.config.read_only {
-            return Ok(None);
+    pub fn first(&self) -> DbResult<Option<(Key, IVec)>, ()> {
+        if self.deadlines.tree().is_some() {
+            // the first entry may have expired, so search like an
+            // iterator does, skipping those that have.
+            return match self.iter().next() {
+                Some(Ok(kv)) => Ok(Some(kv)),
+                Some(Err(e)) => Err(e),
+                None => Ok(None),
+            };
         }
+        let _cc = self.read_lock()?;
         let guard = pin();
-        let mut ret: Option<Value>;
-        loop {
-            let mut path = self.path_for_key(&*key, &guard)?;
-            let (leaf_node, leaf_cas_key) = path.pop().expect(
+        'restart: loop {
+            let mut path = self.path_for_key(b"", &guard)?;
+            let (mut node, mut cas_key) = path.pop().expect(
                 "path_for_key should always return a path \
                 of length >= 2 (root + leaf)",
             );
-            let encoded_key = prefix_encode(leaf_node.lo.inner(), key);
-            match leaf_node.data {
-                Data::Leaf(ref items) => {
-                    let search = items.binary_search_by(|&(ref k, ref _v)| {
-                        prefix_cmp(k, &*encoded_key)
+
+            // leaves may be empty after removals, in which case
+            // we walk right until we find one that isn't.
+            let mut skipped = vec![];
+            loop {
+                let first = node.data
+                    .leaf_ref()
+                    .expect("node should be a leaf")
+                    .first()
+                    .map(|&(ref k, ref v)| {
+                        (prefix_decode(node.lo.inner(), k), v.clone())
                     });
-                    if let Ok(idx) = search {
-                        ret = Some(items[idx].1.clone());
-                    } else {
-                        ret = None;
-                        break;
+                if first.is_none() {
+                    skipped.push((node.id, cas_key));
+                }
+                if first.is_some() || node.next.is_none() {
+                    if self.unchanged(&skipped, &guard)? {
+                        return Ok(first);
                     }
+                    M.tree_looped();
+                    continue 'restart;
                 }
-                _ => panic!("last node in path is not leaf"),
-            }
 
-            let frag = Frag::Del(encoded_key);
-            let link =
-                self.pages.link(leaf_node.id, leaf_cas_key, frag, &guard);
+                let next = node.next.expect("checked above");
+                let get_cursor = self.pages.get(next, &guard).map_err(
+                    |e| e.danger_cast(),
+                )?;
+                match get_cursor {
+                    PageGet::Materialized(Frag::Base(base, _), next_key) => {
+                        node = base;
+                        cas_key = next_key;
+                    }
+                    ref free if free.is_free() || free.is_allocated() => {
+                        M.tree_looped();
+                        continue 'restart;
+                    }
+                    broken => {
+                        return Err(Error::ReportableBug(format!(
+                            "got non-base node while traversing tree: {:?}",
+                            broken
+                        )))
+                    }
+                }
+            }
+        }
+    }
 
-            match link {
-                Ok(_) => {
-                    // success
-                    break;
+    /// Returns the entry with the largest key, if the `Tree` is not
+    /// empty. This descends directly to the rightmost leaf instead of
+    /// setting up an iterator. The returned entry was the largest
+    /// in the `Tree` at some point during the call.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// assert_eq!(t.last(), Ok(None));
+    /// t.set(vec![1], vec![10]);
+    /// t.set(vec![2], vec![20]);
+    /// assert_eq!(t.last(), Ok(Some((vec![2], vec![20].into()))));
+    /// ```
+    pub fn last(&self) -> DbResult<Option<(Key, IVec)>, ()> {
+        let _cc = self.read_lock()?;
+        let guard = pin();
+        if self.deadlines.tree().is_some() {
+            // max_lt skips expired entries
+            return self.max_lt(Bound::Inf, &guard);
+        }
+        'restart: loop {
+            // leaves may be empty after removals, in which case
+            // we search again among the leaves to their left.
+            let mut skipped = vec![];
+            let mut bound = Bound::Inf;
+            loop {
+                let (node, cas_key) = self.leaf_lt(&bound, &guard)?;
+                let prefix = node.lo.inner();
+                let last = node.data
+                    .leaf_ref()
+                    .expect("node should be a leaf")
+                    .iter()
+                    .rev()
+                    .map(|&(ref k, ref v)| (prefix_decode(prefix, k), v))
+                    .find(|&(ref k, _)| Bound::Inclusive(k.clone()) < bound)
+                    .map(|(k, v)| (k, v.clone()));
+                if last.is_none() {
+                    skipped.push((node.id, cas_key));
                 }
-                Err(Error::CasFailed(_)) => {
+
+                if last.is_some() || prefix.is_empty() {
+                    if self.unchanged(&skipped, &guard)? {
+                        return Ok(last);
+                    }
                     M.tree_looped();
-                    continue;
+                    continue 'restart;
                 }
-                Err(other) => return Err(other.danger_cast()),
+
+                bound = Bound::Exclusive(prefix.to_vec());
             }
         }
-        Ok(ret)
     }
 
     /// Iterate over tuples of keys and values, starting at the provided key.
@@ -406,14 +2168,168 @@ impl Tree {
     /// t.set(vec![2], vec![20]);
     /// t.set(vec![3], vec![30]);
     /// let mut iter = t.scan(&*vec![2]);
-    /// assert_eq!(iter.next(), Some(Ok((vec![2], vec![20]))));
-    /// assert_eq!(iter.next(), Some(Ok((vec![3], vec![30]))));
+    /// assert_eq!(iter.next(), Some(Ok((vec![2], vec![20].into()))));
+    /// assert_eq!(iter.next(), Some(Ok((vec![3], vec![30].into()))));
     /// assert_eq!(iter.next(), None);
     /// ```
     pub fn scan(&self, key: &[u8]) -> Iter {
+        self.range_internal(key, Bound::Inf)
+    }
+
+    /// Iterate over tuples of keys and values within the provided range
+    /// of keys. Either end may be included, excluded, or unbounded,
+    /// with the same meaning as in `BTreeMap::range`, but a range whose
+    /// start is after its end is empty rather than a cause for a panic.
+    /// The returned iterator may also be consumed in reverse using `rev`
+    /// or `next_back`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::ops::Bound::{Excluded, Unbounded};
+    ///
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![10]);
+    /// t.set(vec![2], vec![20]);
+    /// t.set(vec![3], vec![30]);
+    /// t.set(vec![4], vec![40]);
+    /// let mut iter = t.range(vec![2]..vec![4]);
+    /// assert_eq!(iter.next(), Some(Ok((vec![2], vec![20].into()))));
+    /// assert_eq!(iter.next(), Some(Ok((vec![3], vec![30].into()))));
+    /// assert_eq!(iter.next(), None);
+    ///
+    /// let mut iter = t.range(&[1][..]..=&[3][..]).rev();
+    /// assert_eq!(iter.next(), Some(Ok((vec![3], vec![30].into()))));
+    /// assert_eq!(iter.next(), Some(Ok((vec![2], vec![20].into()))));
+    /// assert_eq!(iter.next(), Some(Ok((vec![1], vec![10].into()))));
+    /// assert_eq!(iter.next(), None);
+    ///
+    /// let mut iter = t.range((Excluded(vec![3]), Unbounded));
+    /// assert_eq!(iter.next(), Some(Ok((vec![4], vec![40].into()))));
+    /// assert_eq!(iter.next(), None);
+    ///
+    /// assert_eq!(t.range(vec![3]..vec![2]).next(), None);
+    /// ```
+    pub fn range<K, R>(&self, range: R) -> Iter
+        where K: AsRef<[u8]> + ?Sized,
+              R: RangeBounds<K>
+    {
+        let (lo, hi) = range_bounds(&range);
+        self.range_internal(&*lo, hi)
+    }
+
+    /// Continue iterating over a range where an earlier `Iter` left
+    /// off, as recorded by its `resume_token`. Entries that the earlier
+    /// iterator returned, from either end, are skipped even if they've
+    /// been removed since, and entries written since then in the part
+    /// of the range that's left are included. The range would usually
+    /// be the same one the earlier iterator was created with.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// for i in 0..10 {
+    ///     t.set(vec![i], vec![i]).unwrap();
+    /// }
+    ///
+    /// // list the keys three at a time
+    /// let mut pages = vec![];
+    /// let mut token = None;
+    /// loop {
+    ///     let mut iter = match token {
+    ///         Some(token) => t.range_resumed(vec![2]..vec![9], token),
+    ///         None => t.range(vec![2]..vec![9]),
+    ///     };
+    ///     let page: Vec<_> =
+    ///         iter.by_ref().take(3).map(|res| res.unwrap().0).collect();
+    ///     if page.is_empty() {
+    ///         break;
+    ///     }
+    ///     pages.push(page);
+    ///     token = Some(iter.resume_token());
+    /// }
+    /// assert_eq!(
+    ///     pages,
+    ///     vec![
+    ///         vec![vec![2], vec![3], vec![4]],
+    ///         vec![vec![5], vec![6], vec![7]],
+    ///         vec![vec![8]],
+    ///     ]
+    /// );
+    /// ```
+    pub fn range_resumed<K, R>(&self, range: R, token: ResumeToken) -> Iter
+        where K: AsRef<[u8]> + ?Sized,
+              R: RangeBounds<K>
+    {
+        let (mut lo, mut hi) = range_bounds(&range);
+        let (token_lo, token_hi) = token.bounds();
+        if token_lo > lo {
+            lo = token_lo;
+        }
+        if token_hi < hi {
+            hi = token_hi;
+        }
+        self.range_internal(&*lo, hi)
+    }
+
+    /// Iterate over all tuples of keys and values whose keys
+    /// start with the provided prefix.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![0, 0], vec![0]);
+    /// t.set(vec![1, 0], vec![1]);
+    /// t.set(vec![1, 255], vec![2]);
+    /// t.set(vec![2, 0], vec![3]);
+    /// let mut iter = t.scan_prefix(&[1]);
+    /// assert_eq!(iter.next(), Some(Ok((vec![1, 0], vec![1].into()))));
+    /// assert_eq!(iter.next(), Some(Ok((vec![1, 255], vec![2].into()))));
+    /// assert_eq!(iter.next(), None);
+    /// ```
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Iter {
+        self.range_internal(prefix, prefix_hi(prefix))
+    }
+
+    /// Subscribe to every change to keys starting with the provided
+    /// prefix, from this point on. See `Subscriber` for how events
+    /// are delivered, and what happens when a subscriber falls behind.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// let mut subscriber = t.watch_prefix(vec![]);
+    ///
+    /// t.set(vec![1], vec![10]).unwrap();
+    /// assert_eq!(
+    ///     subscriber.next_timeout(Duration::from_secs(0)),
+    ///     Ok(sled::Event::Insert { key: vec![1], value: vec![10].into() })
+    /// );
+    /// assert!(subscriber.next_timeout(Duration::from_secs(0)).is_err());
+    /// ```
+    pub fn watch_prefix(&self, prefix: Key) -> Subscriber {
+        Subscriptions::register(&self.subscriptions, prefix)
+    }
+
+    // iterates from the inclusive lower key up to the upper bound
+    pub(super) fn range_internal(&self, key: &[u8], hi: Bound) -> Iter {
         let guard = pin();
         let mut broken = None;
-        let id = match self.get_internal(key, &guard) {
+        let mut clears = 0;
+        let res = self.read_lock().and_then(|_cc| {
+            clears = self.clears.load(SeqCst);
+            self.get_internal(key, &guard)
+        });
+        let id = match res {
             Ok((ref path, _)) if !path.is_empty() => {
                 let &(ref last_node, ref _last_cas_key) =
                     path.last().expect("path is not empty");
@@ -432,10 +2348,15 @@ impl Tree {
         };
         Iter {
             id: id,
-            inner: &self.pages,
+            tree: self,
             last_key: Bound::Exclusive(key.to_vec()),
+            hi: hi,
             broken: broken,
             done: false,
+            clears: clears,
+            leaf: None,
+            pos: 0,
+            skip_expired: true,
         }
     }
 
@@ -450,9 +2371,9 @@ impl Tree {
     /// t.set(vec![2], vec![20]);
     /// t.set(vec![3], vec![30]);
     /// let mut iter = t.iter();
-    /// assert_eq!(iter.next(), Some(Ok((vec![1], vec![10]))));
-    /// assert_eq!(iter.next(), Some(Ok((vec![2], vec![20]))));
-    /// assert_eq!(iter.next(), Some(Ok((vec![3], vec![30]))));
+    /// assert_eq!(iter.next(), Some(Ok((vec![1], vec![10].into()))));
+    /// assert_eq!(iter.next(), Some(Ok((vec![2], vec![20].into()))));
+    /// assert_eq!(iter.next(), Some(Ok((vec![3], vec![30].into()))));
     /// assert_eq!(iter.next(), None);
     /// ```
     pub fn iter(&self) -> Iter {
@@ -463,6 +2384,20 @@ impl Tree {
         &self,
         path: &[(Node, TreePtr<'g>)],
         guard: &'g Guard,
+    ) -> DbResult<(), ()> {
+        // the entries moved by splits that were installed
+        // before an error aren't counted in the index.
+        let res = self.recursive_split_inner(path, guard);
+        if res.is_err() {
+            self.lose_counts();
+        }
+        res
+    }
+
+    fn recursive_split_inner<'g>(
+        &self,
+        path: &[(Node, TreePtr<'g>)],
+        guard: &'g Guard,
     ) -> DbResult<(), ()> {
         // to split, we pop the path, see if it's in need of split, recurse up
         // two-phase: (in prep for lock-free, not necessary for single threaded)
@@ -488,77 +2423,162 @@ impl Tree {
         let mut all_page_views = path.to_vec();
         let mut root_and_key = all_page_views.remove(0);
 
+        // the entries that moved to new nodes, to be counted in the index
+        // once the splits are done, so that counting them doesn't change
+        // the parents that are yet to be split.
+        let mut moves = vec![];
+
         while let Some((node, cas_key)) = all_page_views.pop() {
-            if node.should_split(self.config.blink_fanout) {
-                // try to child split
-                if let Ok(parent_split) = self.child_split(
-                    &node,
-                    cas_key,
+            let (node, split) = self.split_node(node, cas_key, guard)?;
+            let (parent_split, moved) = match split {
+                Some(split) => split,
+                None => continue,
+            };
+
+            let mut ancestors = vec![root_and_key.0.id];
+            ancestors.extend(
+                all_page_views.iter().map(|&(ref node, _)| node.id),
+            );
+            let height = path.len() - 1 - all_page_views.len();
+            moves.push((
+                ancestors,
+                height,
+                split_counts(&node, &parent_split, moved),
+            ));
+
+            // now try to parent split
+            let &mut (ref mut parent_node, ref mut parent_cas_key) =
+                all_page_views.last_mut().unwrap_or(&mut root_and_key);
+
+            loop {
+                let res = self.parent_split(
+                    parent_node.clone(),
+                    parent_cas_key.clone(),
+                    parent_split.clone(),
                     guard,
-                )
-                {
-                    // now try to parent split
-                    let &mut (ref mut parent_node, ref mut parent_cas_key) =
-                        all_page_views.last_mut().unwrap_or(&mut root_and_key);
-
-                    let res = self.parent_split(
-                        parent_node.clone(),
-                        parent_cas_key.clone(),
-                        parent_split.clone(),
-                        guard,
-                    );
+                );
 
-                    match res {
-                        Ok(res) => {
-                            parent_node.apply(
-                                &Frag::ParentSplit(parent_split),
-                                self.config.merge_operator,
-                            );
-                            *parent_cas_key = res;
-                        }
-                        Err(Error::CasFailed(_)) => continue,
-                        other => {
-                            return other.map(|_| ()).map_err(
-                                |e| e.danger_cast(),
-                            )
-                        }
+                match res {
+                    Ok(res) => {
+                        parent_node.apply(
+                            &Frag::ParentSplit(parent_split),
+                            self.config.get_merge_operator(),
+                        )?;
+                        *parent_cas_key = res;
+                        break;
+                    }
+                    Err(Error::CasFailed(_)) => {}
+                    other => {
+                        return other.map(|_| ()).map_err(|e| e.danger_cast())
                     }
                 }
+
+                // the counts of other writes land in the parent too,
+                // so retry unless it split away from the new child,
+                // which traversals will then link.
+                M.tree_looped();
+                let (node, cas_key) =
+                    self.node_for_pid(parent_node.id, guard)?;
+                if node.hi <= parent_split.at {
+                    break;
+                }
+                *parent_node = node;
+                *parent_cas_key = cas_key;
             }
         }
 
         let (root_node, root_cas_key) = root_and_key;
 
-        if root_node.should_split(self.config.blink_fanout) {
-            if let Ok(parent_split) = self.child_split(
-                &root_node,
-                root_cas_key,
+        let (root_node, split) =
+            self.split_node(root_node, root_cas_key, guard)?;
+        if let Some((parent_split, moved)) = split {
+            let entries = root_node.data.entries();
+            let hoisted = self.root_hoist(
+                root_node.id,
+                parent_split.to,
+                parent_split.at.inner().to_vec(),
+                (entries - moved, moved),
                 guard,
-            )
-            {
-                return self.root_hoist(
-                    root_node.id,
-                    parent_split.to,
-                    parent_split.at.inner().to_vec(),
-                    guard,
-                ).map(|_| ())
-                    .map_err(|e| e.danger_cast());
+            ).map_err(|e| e.danger_cast())?;
+
+            // the root was split again after another thread hoisted
+            // a new root above it, which counts its entries.
+            if !hoisted {
+                moves.push((
+                    vec![],
+                    path.len(),
+                    split_counts(&root_node, &parent_split, moved),
+                ));
             }
         }
+
+        for (ancestors, height, counts) in moves {
+            self.count_children(&ancestors, height, counts, guard)?;
+        }
         Ok(())
     }
 
+    // splits the node if it's too large. writes below an index node
+    // count their entries in it, racing its split, so a lost race is
+    // retried with the node reread a few times before the split is
+    // left to the next write that finds the node too large. returns
+    // the node that was split, and the split if it was installed.
+    fn split_node<'g>(
+        &self,
+        mut node: Node,
+        mut cas_key: TreePtr<'g>,
+        guard: &'g Guard,
+    ) -> DbResult<(Node, Option<(ParentSplit, i64)>), ()> {
+        for _ in 0..SPLIT_ATTEMPTS {
+            if !node.should_split(&self.config) {
+                break;
+            }
+            match self.child_split(&node, cas_key, guard) {
+                Ok(split) => return Ok((node, Some(split))),
+                Err(Error::CasFailed(())) => {
+                    M.tree_looped();
+                    let (new_node, new_cas_key) =
+                        self.node_for_pid(node.id, guard)?;
+                    node = new_node;
+                    cas_key = new_cas_key;
+                }
+                Err(_) => break,
+            }
+        }
+        Ok((node, None))
+    }
+
+    // counts an entry inserted into a leaf, or removed from it if
+    // `delta` is negative, in the index nodes above it, which
+    // `path` passed through on the way down to the leaf.
+    fn count_entry<'g>(
+        &self,
+        path: &[(Node, TreePtr<'g>)],
+        leaf: &Node,
+        delta: i64,
+        guard: &'g Guard,
+    ) -> DbResult<(), ()> {
+        if delta == 0 {
+            return Ok(());
+        }
+        let ancestors: Vec<PageID> =
+            path.iter().map(|&(ref node, _)| node.id).collect();
+        let lo = leaf.lo.inner().to_vec();
+        self.count_children(&ancestors, 1, vec![(lo, leaf.id, delta)], guard)
+    }
+
     fn child_split<'g>(
         &self,
         node: &Node,
         node_cas_key: TreePtr<'g>,
         guard: &'g Guard,
-    ) -> DbResult<ParentSplit, ()> {
+    ) -> DbResult<(ParentSplit, i64), ()> {
         let new_pid = self.pages.allocate(guard)?;
         trace!("allocated pid {} in child_split", new_pid);
 
         // split the node in half
         let rhs = node.split(new_pid);
+        let moved = rhs.data.entries();
 
         let child_split = Frag::ChildSplit(ChildSplit {
             at: rhs.lo.clone(),
@@ -588,7 +2608,7 @@ impl Tree {
             Err(other) => return Err(other.danger_cast()),
         }
 
-        Ok(parent_split)
+        Ok((parent_split, moved))
     }
 
     fn parent_split<'g>(
@@ -607,13 +2627,16 @@ impl Tree {
         )
     }
 
+    // returns whether the new root was hoisted, given the number of
+    // entries under each side of the split.
     fn root_hoist<'g>(
         &self,
         from: PageID,
         to: PageID,
         at: Key,
+        entries: (i64, i64),
         guard: &'g Guard,
-    ) -> DbResult<(), ()> {
+    ) -> DbResult<bool, ()> {
         // hoist new root, pointing to lhs & rhs
         let new_root_pid = self.pages.allocate(guard)?;
         debug!("allocated pid {} in root_hoist", new_root_pid);
@@ -624,10 +2647,13 @@ impl Tree {
 
         let encoded_at = prefix_encode(root_lo, &*at);
         new_root_vec.push((encoded_at, to));
+        let mut counts = BTreeMap::new();
+        counts.insert(from, entries.0);
+        counts.insert(to, entries.1);
         let new_root = Frag::Base(
             Node {
                 id: new_root_pid,
-                data: Data::Index(new_root_vec),
+                data: Data::Index(new_root_vec, ChildCounts::new(counts)),
                 next: None,
                 lo: Bound::Inclusive(vec![]),
                 hi: Bound::Inf,
@@ -641,21 +2667,41 @@ impl Tree {
             debug!("root hoist from {} to {} successful", from, new_root_pid);
             self.pages
                 .replace(new_root_pid, Shared::null(), new_root, guard)
-                .map(|_| ())
+                .map(|_| true)
                 .map_err(|e| e.danger_cast())
         } else {
             debug!("root hoist from {} to {} failed", from, new_root_pid);
-            self.pages.free(new_root_pid, guard).map_err(
-                |e| e.danger_cast(),
-            )
+            self.pages
+                .free(new_root_pid, guard)
+                .map(|_| false)
+                .map_err(|e| e.danger_cast())
+        }
+    }
+
+    pub(super) fn node_for_pid<'g>(
+        &self,
+        pid: PageID,
+        guard: &'g Guard,
+    ) -> DbResult<(Node, TreePtr<'g>), ()> {
+        let get_cursor =
+            self.pages.get(pid, guard).map_err(|e| e.danger_cast())?;
+        match get_cursor {
+            PageGet::Materialized(Frag::Base(node, _), cas_key) => {
+                Ok((node, cas_key))
+            }
+            broken => Err(Error::ReportableBug(format!(
+                "got non-base page {}: {:?}",
+                pid,
+                broken
+            ))),
         }
     }
 
-    fn get_internal<'g>(
+    pub(super) fn get_internal<'g>(
         &self,
         key: &[u8],
         guard: &'g Guard,
-    ) -> DbResult<(Vec<(Node, TreePtr<'g>)>, Option<Value>), ()> {
+    ) -> DbResult<(Vec<(Node, TreePtr<'g>)>, Option<IVec>), ()> {
         let path = self.path_for_key(&*key, guard)?;
 
         let ret = path.last().and_then(|&(ref last_node, ref _last_cas_key)| {
@@ -677,6 +2723,224 @@ impl Tree {
         Ok((path, ret))
     }
 
+    // returns the largest entry whose key is below the
+    // provided upper bound, which may be Exclusive or Inf.
+    pub(super) fn max_lt<'g>(
+        &self,
+        bound: Bound,
+        guard: &'g Guard,
+    ) -> DbResult<Option<(Key, IVec)>, ()> {
+        self.max_lt_by(bound, guard, |v| v.clone())
+    }
+
+    // like max_lt, but only extracts what the provided
+    // function needs from the value.
+    pub(super) fn max_lt_by<'g, T, F>(
+        &self,
+        mut bound: Bound,
+        guard: &'g Guard,
+        f: F,
+    ) -> DbResult<Option<(Key, T)>, ()>
+        where F: FnOnce(&IVec) -> T
+    {
+        loop {
+            if bound == Bound::Exclusive(vec![]) {
+                // nothing sorts before the empty key
+                return Ok(None);
+            }
+
+            let (node, _) = self.leaf_lt(&bound, guard)?;
+            let prefix = node.lo.inner();
+            let items = node.data.leaf_ref().expect("node should be a leaf");
+
+            for &(ref k, ref v) in items.iter().rev() {
+                let decoded_k = prefix_decode(prefix, k);
+                if Bound::Inclusive(decoded_k.clone()) < bound &&
+                    !self.is_expired(&*decoded_k)?
+                {
+                    return Ok(Some((decoded_k, f(v))));
+                }
+            }
+
+            // everything in this leaf was too high, so
+            // keep searching among the leaves to the left.
+            bound = Bound::Exclusive(prefix.to_vec());
+        }
+    }
+
+    // returns the leaf responsible for the keys directly below
+    // the provided upper bound. Unlike `path_for_key`, this does
+    // not try to complete partial splits it encounters.
+    fn leaf_lt<'g>(
+        &self,
+        bound: &Bound,
+        guard: &'g Guard,
+    ) -> DbResult<(Node, TreePtr<'g>), ()> {
+        let mut cursor = self.root.load(SeqCst);
+
+        let mut not_found_loops = 0;
+        loop {
+            let get_cursor =
+                self.pages.get(cursor, guard).map_err(|e| e.danger_cast())?;
+            if get_cursor.is_free() || get_cursor.is_allocated() {
+                // restart search from the tree's root
+                not_found_loops += 1;
+                debug_assert_ne!(
+                    not_found_loops,
+                    10_000,
+                    "cannot find pid {} in leaf_lt",
+                    cursor
+                );
+                // point reads don't take the lock, so the pages
+                // may have been freed by dropping the tree.
+                self.check_dropped()?;
+                cursor = self.root.load(SeqCst);
+                continue;
+            }
+
+            let (node, cas_key) = match get_cursor {
+                PageGet::Materialized(Frag::Base(base, _), cas_key) => {
+                    (base, cas_key)
+                }
+                broken => {
+                    return Err(Error::ReportableBug(format!(
+                        "got non-base node while traversing tree: {:?}",
+                        broken
+                    )))
+                }
+            };
+
+            if node.hi < *bound {
+                // our right sibling may contain keys below
+                // the bound, due to a split or because we're
+                // looking for the end of the tree.
+                cursor = node.next.expect(
+                    "if our hi bound is not Inf (inity), \
+                    we should have a right sibling",
+                );
+                continue;
+            }
+
+            match node.data {
+                Data::Index(ref ptrs, _) => {
+                    let prefix = node.lo.inner();
+                    let old_cursor = cursor;
+                    for &(ref sep_k, ref ptr) in ptrs {
+                        let decoded_sep_k = prefix_decode(prefix, sep_k);
+                        if Bound::Inclusive(decoded_sep_k) < *bound {
+                            cursor = *ptr;
+                        } else {
+                            break; // we've found our next cursor
+                        }
+                    }
+                    if cursor == old_cursor {
+                        panic!("stuck in page traversal loop");
+                    }
+                }
+                Data::Leaf(_) => return Ok((node, cas_key)),
+            }
+        }
+    }
+
+    // returns true if none of the pages have changed since they
+    // were read. first and last use this to make sure the leaves
+    // they skipped over were still empty when they found an entry.
+    fn unchanged<'g>(
+        &self,
+        pages: &[(PageID, TreePtr<'g>)],
+        guard: &'g Guard,
+    ) -> DbResult<bool, ()> {
+        for &(pid, cas_key) in pages {
+            let get_cursor =
+                self.pages.get(pid, guard).map_err(|e| e.danger_cast())?;
+            match get_cursor {
+                PageGet::Materialized(_, current) if current == cas_key => {}
+                _ => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    // fails once the tree has been dropped from its Db, so that
+    // nothing traverses pages that may have been freed.
+    pub(crate) fn read_lock(&self) -> DbResult<RwLockReadGuard<()>, ()> {
+        let cc = self.concurrency_control.read().expect(
+            "a thread panicked and poisoned the Tree's concurrency control",
+        );
+        self.check_dropped()?;
+        Ok(cc)
+    }
+
+    pub(crate) fn write_lock(&self) -> RwLockWriteGuard<()> {
+        self.concurrency_control.write().expect(
+            "a thread panicked and poisoned the Tree's concurrency control",
+        )
+    }
+
+    // takes the write locks of several trees, always in the same
+    // order, so that callers locking overlapping trees can't deadlock.
+    pub(crate) fn write_locks<'t>(
+        trees: &[&'t Tree],
+    ) -> Vec<RwLockWriteGuard<'t, ()>> {
+        let mut trees = trees.to_vec();
+        trees.sort_by_key(|t| t.lock_id());
+        trees.dedup_by_key(|t| t.lock_id());
+        trees.into_iter().map(|t| t.write_lock()).collect()
+    }
+
+    // refuses keys and values that are over the configured limits.
+    pub(crate) fn check_sizes(
+        &self,
+        key: &[u8],
+        value: &[u8],
+    ) -> DbResult<(), ()> {
+        let max_key_size = self.config.get_max_key_size();
+        if key.len() > max_key_size {
+            return Err(Error::TooLarge {
+                limit: "max_key_size",
+                max: max_key_size,
+                size: key.len(),
+            });
+        }
+        let max_value_size = self.config.get_max_value_size();
+        if value.len() > max_value_size {
+            return Err(Error::TooLarge {
+                limit: "max_value_size",
+                max: max_value_size,
+                size: value.len(),
+            });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_dropped(&self) -> DbResult<(), ()> {
+        if self.dropped.load(SeqCst) {
+            Err(Error::Unsupported("this tree has been dropped".to_owned()))
+        } else {
+            Ok(())
+        }
+    }
+
+    // identifies the lock that the tree shares with its hidden trees.
+    // transactions acquire locks in the order of this id.
+    pub(crate) fn lock_id(&self) -> usize {
+        &*self.concurrency_control as *const RwLock<()> as usize
+    }
+
+    // identifies the Db that the tree belongs to
+    pub(crate) fn db_id(&self) -> usize {
+        &*self.pages as *const _ as usize
+    }
+
+    // identifies this tree within its Db.
+    pub(crate) fn tree_id(&self) -> usize {
+        &*self.root as *const AtomicUsize as usize
+    }
+
+    pub(crate) fn is_read_only(&self) -> bool {
+        self.config.read_only
+    }
+
     #[doc(hidden)]
     pub fn key_debug_str(&self, key: &[u8]) -> String {
         let guard = pin();
@@ -693,7 +2957,7 @@ impl Tree {
 
     /// returns the traversal path, completing any observed
     /// partially complete splits or merges along the way.
-    fn path_for_key<'g>(
+    pub(super) fn path_for_key<'g>(
         &self,
         key: &[u8],
         guard: &'g Guard,
@@ -718,6 +2982,9 @@ impl Tree {
                     "cannot find pid {} in path_for_key",
                     cursor
                 );
+                // point reads don't take the lock, so the pages
+                // may have been freed by dropping the tree.
+                self.check_dropped()?;
                 cursor = self.root.load(SeqCst);
                 continue;
             }
@@ -780,7 +3047,7 @@ impl Tree {
                 .expect("we just pushed to path, so it's not empty")
                 .0
                 .data {
-                Data::Index(ref ptrs) => {
+                Data::Index(ref ptrs, _) => {
                     let old_cursor = cursor;
                     for &(ref sep_k, ref ptr) in ptrs {
                         let decoded_sep_k = prefix_decode(&*prefix, sep_k);
@@ -804,6 +3071,107 @@ impl Tree {
     }
 }
 
+// the change in how many entries a leaf holds, given whether
+// a key had a value before and after it was written.
+fn entry_delta(old: bool, new: bool) -> i64 {
+    new as i64 - old as i64
+}
+
+// the entries that a split moved from a node to its new right sibling,
+// as changes to the counts that their parent keeps of them.
+fn split_counts(
+    node: &Node,
+    parent_split: &ParentSplit,
+    moved: i64,
+) -> Vec<(Key, PageID, i64)> {
+    vec![
+        (node.lo.inner().to_vec(), node.id, -moved),
+        (parent_split.at.inner().to_vec(), parent_split.to, moved),
+    ]
+}
+
+// folds the length and then the contents of some bytes into a
+// checksum, so that bytes can't move between a key and its value
+// without changing the result.
+fn checksum_field(crc: u64, bytes: &[u8]) -> u64 {
+    let len = bytes.len() as u64;
+    let mut len_bytes = [0u8; 8];
+    for (i, b) in len_bytes.iter_mut().enumerate() {
+        *b = (len >> (i * 8)) as u8;
+    }
+    crc64_update(crc64_update(crc, &len_bytes), bytes)
+}
+
+// whether the value that a cas expects is the one that it found
+fn cas_matches(old: &Option<Value>, cur: &Option<IVec>) -> bool {
+    match (old, cur) {
+        (&Some(ref old), &Some(ref cur)) => *old == *cur,
+        (&None, &None) => true,
+        _ => false,
+    }
+}
+
+// converts any range of keys into an inclusive lower key and an
+// upper bound. the smallest key that sorts after any given key is
+// that key followed by a zero byte, so every bound can be expressed
+// as an inclusive start and an exclusive end.
+pub(super) fn range_bounds<K, R>(range: &R) -> (Key, Bound)
+    where K: AsRef<[u8]> + ?Sized,
+          R: RangeBounds<K>
+{
+    let lo = match range.start_bound() {
+        ops::Bound::Included(start) => start.as_ref().to_vec(),
+        ops::Bound::Excluded(start) => successor(start.as_ref()),
+        ops::Bound::Unbounded => vec![],
+    };
+    let hi = match range.end_bound() {
+        ops::Bound::Included(end) => Bound::Exclusive(successor(end.as_ref())),
+        ops::Bound::Excluded(end) => Bound::Exclusive(end.as_ref().to_vec()),
+        ops::Bound::Unbounded => Bound::Inf,
+    };
+    (lo, hi)
+}
+
+// the smallest key that sorts after the provided one
+pub(super) fn successor(key: &[u8]) -> Key {
+    let mut next = key.to_vec();
+    next.push(0);
+    next
+}
+
+// the upper bound of the keys starting with a prefix, which is the
+// smallest key that sorts after every one of them. it's found by
+// dropping trailing 0xFF bytes and incrementing the last remaining
+// byte. prefixes made up of only 0xFF bytes extend to the end.
+pub(super) fn prefix_hi(prefix: &[u8]) -> Bound {
+    let mut hi = prefix.to_vec();
+    while hi.last() == Some(&std::u8::MAX) {
+        hi.pop();
+    }
+
+    match hi.pop() {
+        Some(last) => {
+            hi.push(last + 1);
+            Bound::Exclusive(hi)
+        }
+        None => Bound::Inf,
+    }
+}
+
+impl Drop for Tree {
+    fn drop(&mut self) {
+        // the last handle to the Db persists the entry counts, so that
+        // they don't need to be recounted on the next start. if this
+        // doesn't happen, because of a crash, they will be.
+        if Arc::strong_count(&self.lens) != 1 || self.config.read_only {
+            return;
+        }
+        if let Err(e) = self.persist_lens() {
+            error!("failed to persist entry counts while dropping: {:?}", e);
+        }
+    }
+}
+
 impl Debug for Tree {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         let mut pid = self.root.load(SeqCst);
@@ -841,7 +3209,7 @@ impl Debug for Tree {
                 };
 
                 match left_node.data {
-                    Data::Index(ptrs) => {
+                    Data::Index(ptrs, _) => {
                         if let Some(&(ref _sep, ref next_pid)) = ptrs.first() {
                             pid = *next_pid;
                             left_most = *next_pid;
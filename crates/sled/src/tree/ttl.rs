@@ -0,0 +1,273 @@
+use std::sync::{Mutex, MutexGuard, RwLock};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::*;
+use super::hidden::*;
+
+/// Returns the current time, which entries written with
+/// `Tree::set_with_ttl` are considered expired after.
+pub type Clock = fn() -> SystemTime;
+
+// the prefix of the name of the hidden tree that holds the deadlines
+// of another tree's entries. each entry's key is stamped with its
+// deadline, so that expired entries are found in the order they expired.
+pub(super) const DEADLINES_PREFIX: &[u8] = b"__sled__ttl__";
+
+// the most expired entries that one sweep
+// removes from a tree, to keep flushes timely.
+const SWEEP_LIMIT: usize = 64;
+
+// the deadlines of a tree's entries, shared between its handles
+pub(super) struct Deadlines {
+    // created by the tree's first set_with_ttl
+    tree: RwLock<Option<Tree>>,
+    // set once the tree exists, so that trees that never
+    // expire anything don't pay for looking deadlines up.
+    active: AtomicBool,
+    clock: RwLock<Clock>,
+}
+
+impl Default for Deadlines {
+    fn default() -> Deadlines {
+        Deadlines {
+            tree: RwLock::new(None),
+            active: AtomicBool::new(false),
+            clock: RwLock::new(SystemTime::now),
+        }
+    }
+}
+
+impl Deadlines {
+    pub(super) fn tree(&self) -> Option<Tree> {
+        if !self.active.load(SeqCst) {
+            return None;
+        }
+        self.tree
+            .read()
+            .expect("a thread panicked and poisoned a Tree's deadlines lock")
+            .clone()
+    }
+
+    pub(super) fn set_tree(&self, tree: Tree) {
+        *self.tree.write().expect(
+            "a thread panicked and poisoned a Tree's deadlines lock",
+        ) = Some(tree);
+        self.active.store(true, SeqCst);
+    }
+
+    pub(super) fn set_clock(&self, clock: Clock) {
+        *self.clock.write().expect(
+            "a thread panicked and poisoned a Tree's clock lock",
+        ) = clock;
+    }
+
+    // the current time, in milliseconds since the unix epoch
+    pub(super) fn now(&self) -> u64 {
+        let clock = *self.clock.read().expect(
+            "a thread panicked and poisoned a Tree's clock lock",
+        );
+        match clock().duration_since(UNIX_EPOCH) {
+            Ok(since_epoch) => millis(since_epoch),
+            Err(_) => 0,
+        }
+    }
+}
+
+// removes the expired entries of a Db's trees, a few at a time, on
+// the log's flusher thread. only the handles given out to users hold
+// this, so the flusher doesn't keep the Db alive after they're gone.
+#[derive(Default)]
+pub(super) struct Expirer {
+    trees: Mutex<Vec<Tree>>,
+}
+
+impl Expirer {
+    pub(super) fn watch(&self, tree: &Tree) {
+        let mut tree = tree.clone();
+        tree.expirer = None;
+        let mut trees = self.lock();
+        if !trees.iter().any(|t| t.tree_id() == tree.tree_id()) {
+            trees.push(tree);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<Vec<Tree>> {
+        self.trees.lock().expect(
+            "a thread panicked and poisoned the Db's expirer mutex",
+        )
+    }
+}
+
+impl Maintenance for Expirer {
+    fn maintain(&self) {
+        let trees = {
+            let mut trees = self.lock();
+            trees.retain(|tree| tree.check_dropped().is_ok());
+            trees.clone()
+        };
+        for tree in trees {
+            if let Err(e) = tree.sweep_expired(SWEEP_LIMIT) {
+                error!("failed to remove expired entries: {:?}", e);
+            }
+        }
+    }
+}
+
+impl Tree {
+    // returns true if the key has a deadline that has passed. point
+    // reads call this without the lock, and see the deadlines a batch
+    // gives its keys as soon as they see the batch's values.
+    pub(super) fn is_expired(&self, key: &[u8]) -> DbResult<bool, ()> {
+        let deadlines = match self.deadlines.tree() {
+            Some(deadlines) => deadlines,
+            None => return Ok(false),
+        };
+        match deadlines.get_inner(&*by_key(key))? {
+            Some(deadline) => {
+                Ok(decode_stamp(&*deadline) <= self.deadlines.now())
+            }
+            None => Ok(false),
+        }
+    }
+
+    // returns true if the key has a deadline, whether or not it has
+    // passed. callers must hold the read or write lock. set_with_ttl
+    // takes the write lock, so if this is false under the read lock,
+    // the key can be written without worrying about deadlines.
+    pub(super) fn has_deadline(&self, key: &[u8]) -> DbResult<bool, ()> {
+        match self.deadlines.tree() {
+            Some(deadlines) => {
+                deadlines.get_inner(&*by_key(key)).map(|d| d.is_some())
+            }
+            None => Ok(false),
+        }
+    }
+
+    // the writes to a tree's deadlines that remove the deadlines of
+    // the provided keys. callers must hold the write lock.
+    pub(super) fn deadline_removals<'k, I>(
+        deadlines: &Tree,
+        keys: I,
+    ) -> DbResult<Batch, ()>
+        where I: IntoIterator<Item = &'k Key>
+    {
+        let mut batch = Batch::default();
+        for key in keys {
+            let by_key = by_key(key);
+            if let Some(deadline) = deadlines.get_inner(&*by_key)? {
+                batch.remove(by_stamp(decode_stamp(&*deadline), key));
+                batch.remove(by_key);
+            }
+        }
+        Ok(batch)
+    }
+
+    // the writes to a tree's deadlines that give
+    // a key a new deadline, `ttl` from now.
+    pub(super) fn deadline_insertion(
+        &self,
+        deadlines: &Tree,
+        key: &Key,
+        ttl: Duration,
+    ) -> DbResult<Batch, ()> {
+        let deadline = self.deadlines.now().saturating_add(millis(ttl));
+        let mut batch = Tree::deadline_removals(deadlines, Some(key))?;
+        batch.insert(by_key(key), encode_stamp(deadline).to_vec());
+        batch.insert(by_stamp(deadline, key), vec![]);
+        Ok(batch)
+    }
+
+    // the writes to a tree's deadlines that give `to` the deadline
+    // that `from` has, if any, in place of both of their deadlines.
+    pub(super) fn deadline_move(
+        deadlines: &Tree,
+        from: &Key,
+        to: &Key,
+    ) -> DbResult<Batch, ()> {
+        let deadline = deadlines.get_inner(&*by_key(from))?;
+        let mut batch = Tree::deadline_removals(deadlines, vec![from, to])?;
+        if let Some(deadline) = deadline {
+            let deadline = decode_stamp(&*deadline);
+            batch.insert(by_key(to), encode_stamp(deadline).to_vec());
+            batch.insert(by_stamp(deadline, to), vec![]);
+        }
+        Ok(batch)
+    }
+
+    // returns the tree holding our deadlines, creating it
+    // if this is our first. callers must hold the write lock.
+    pub(super) fn deadlines_tree(&self) -> DbResult<Tree, ()> {
+        if let Some(deadlines) = self.deadlines.tree() {
+            return Ok(deadlines);
+        }
+
+        let mut name = DEADLINES_PREFIX.to_vec();
+        name.extend_from_slice(&*self.name);
+        let mut deadlines = self.create_tenant(name)?;
+        deadlines.expirer = None;
+        deadlines.concurrency_control = self.concurrency_control.clone();
+        self.deadlines.set_tree(deadlines.clone());
+
+        if let Some(ref expirer) = self.expirer {
+            expirer.watch(self);
+        }
+        Ok(deadlines)
+    }
+
+    // removes up to `limit` entries whose deadlines have
+    // passed, in the order they expired.
+    fn sweep_expired(&self, limit: usize) -> DbResult<(), ()> {
+        let deadlines = match self.deadlines.tree() {
+            Some(deadlines) => deadlines,
+            None => return Ok(()),
+        };
+
+        // find candidates without blocking writers, and then
+        // make sure they're still expired under the write lock.
+        let now = self.deadlines.now();
+        let mut expired = vec![];
+        for res in deadlines.scan(&[BY_STAMP]).keys().take(limit) {
+            let by_deadline = res?;
+            if by_deadline.len() < 9 || by_deadline[0] != BY_STAMP {
+                break;
+            }
+            let deadline = decode_stamp(&by_deadline[1..9]);
+            if deadline > now {
+                break;
+            }
+            expired.push((deadline, by_deadline[9..].to_vec()));
+        }
+        if expired.is_empty() {
+            return Ok(());
+        }
+
+        let _cc = self.write_lock();
+        if self.check_dropped().is_err() {
+            return Ok(());
+        }
+
+        let mut batch = Batch::default();
+        let mut deadline_batch = Batch::default();
+        for (deadline, key) in expired {
+            // the key may have been given a different deadline, or
+            // none at all, since we looked.
+            let by_key = by_key(&*key);
+            let current = deadlines.get_inner(&*by_key)?;
+            if current.map(|d| decode_stamp(&*d)) == Some(deadline) {
+                deadline_batch.remove(by_key);
+                batch.remove(key.clone());
+            }
+            deadline_batch.remove(by_stamp(deadline, &*key));
+        }
+        Tree::write_batches(vec![(self, batch), (&deadlines, deadline_batch)])
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration
+        .as_secs()
+        .saturating_mul(1000)
+        .saturating_add(u64::from(duration.subsec_nanos() / 1_000_000))
+}
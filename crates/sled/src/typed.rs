@@ -0,0 +1,318 @@
+use std::marker::PhantomData;
+use std::ops::{self, RangeBounds};
+
+use bincode::{Infinite, deserialize, serialize};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use super::*;
+
+/// Types that can be used as the keys of a `TypedTree`. A key's
+/// encoding must sort the same way as the key itself, so that ranges
+/// of keys can be read in order, and must be self-delimiting, so that
+/// keys can be combined in tuples.
+///
+/// Unsigned integers are encoded in big-endian order, and signed
+/// integers the same way after flipping their sign bit, so that
+/// negative numbers come first. Strings and byte vectors have their
+/// 0 bytes escaped as `[0, 255]` and are ended with `[0, 0]`. Tuples
+/// encode each of their elements in turn.
+pub trait KeyEncode: Sized {
+    /// Append the encoding of this key to `buf`.
+    fn encode_key(&self, buf: &mut Vec<u8>);
+
+    /// Decode a key from the start of `bytes`, and advance
+    /// `bytes` past it.
+    fn decode_key(bytes: &mut &[u8]) -> DbResult<Self, ()>;
+}
+
+macro_rules! impl_key_encode_for_int {
+    ($t:ty, $u:ty, $flip:expr) => {
+        impl KeyEncode for $t {
+            fn encode_key(&self, buf: &mut Vec<u8>) {
+                let bits = u64::from((*self as $u) ^ $flip);
+                let len = std::mem::size_of::<$t>();
+                for i in 0..len {
+                    buf.push((bits >> ((len - 1 - i) * 8)) as u8);
+                }
+            }
+
+            fn decode_key(bytes: &mut &[u8]) -> DbResult<$t, ()> {
+                let len = std::mem::size_of::<$t>();
+                if bytes.len() < len {
+                    return Err(Error::Serialization(format!(
+                        "expected {} bytes for a key of type {}, found {}",
+                        len,
+                        stringify!($t),
+                        bytes.len()
+                    )));
+                }
+                let bits = bytes[..len]
+                    .iter()
+                    .fold(0, |bits: u64, &b| (bits << 8) | u64::from(b));
+                *bytes = &bytes[len..];
+                Ok((bits as $u ^ $flip) as $t)
+            }
+        }
+    }
+}
+
+impl_key_encode_for_int!(u8, u8, 0);
+impl_key_encode_for_int!(u16, u16, 0);
+impl_key_encode_for_int!(u32, u32, 0);
+impl_key_encode_for_int!(u64, u64, 0);
+impl_key_encode_for_int!(usize, u64, 0);
+impl_key_encode_for_int!(i8, u8, 1 << 7);
+impl_key_encode_for_int!(i16, u16, 1 << 15);
+impl_key_encode_for_int!(i32, u32, 1 << 31);
+impl_key_encode_for_int!(i64, u64, 1 << 63);
+impl_key_encode_for_int!(isize, u64, 1 << 63);
+
+impl KeyEncode for Vec<u8> {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        for &b in self {
+            buf.push(b);
+            if b == 0 {
+                buf.push(255);
+            }
+        }
+        buf.extend_from_slice(&[0, 0]);
+    }
+
+    fn decode_key(bytes: &mut &[u8]) -> DbResult<Vec<u8>, ()> {
+        let mut ret = vec![];
+        let mut i = 0;
+        loop {
+            match (bytes.get(i), bytes.get(i + 1)) {
+                (Some(&0), Some(&0)) => break,
+                (Some(&0), Some(&255)) => {
+                    ret.push(0);
+                    i += 2;
+                }
+                (Some(&0), _) | (None, _) => {
+                    return Err(Error::Serialization(
+                        "found a badly escaped byte string in a key"
+                            .to_owned(),
+                    ))
+                }
+                (Some(&b), _) => {
+                    ret.push(b);
+                    i += 1;
+                }
+            }
+        }
+        *bytes = &bytes[i + 2..];
+        Ok(ret)
+    }
+}
+
+impl KeyEncode for String {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        // the escaping is the same as for bytes, without the copy
+        for &b in self.as_bytes() {
+            buf.push(b);
+            if b == 0 {
+                buf.push(255);
+            }
+        }
+        buf.extend_from_slice(&[0, 0]);
+    }
+
+    fn decode_key(bytes: &mut &[u8]) -> DbResult<String, ()> {
+        let raw = Vec::<u8>::decode_key(bytes)?;
+        String::from_utf8(raw).map_err(|e| {
+            Error::Serialization(format!("found a key that isn't utf8: {}", e))
+        })
+    }
+}
+
+macro_rules! impl_key_encode_for_tuple {
+    ($($name:ident),+) => {
+        impl<$($name: KeyEncode),+> KeyEncode for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn encode_key(&self, buf: &mut Vec<u8>) {
+                let ($(ref $name,)+) = *self;
+                $($name.encode_key(buf);)+
+            }
+
+            fn decode_key(bytes: &mut &[u8]) -> DbResult<Self, ()> {
+                Ok(($($name::decode_key(bytes)?,)+))
+            }
+        }
+    }
+}
+
+impl_key_encode_for_tuple!(A, B);
+impl_key_encode_for_tuple!(A, B, C);
+impl_key_encode_for_tuple!(A, B, C, D);
+
+/// A `Tree` whose keys and values are typed. Keys are encoded with
+/// `KeyEncode`, so that ranges of them are read in the order of the
+/// keys themselves, and values are encoded with bincode. Bytes that
+/// can't be decoded as the expected type are reported with
+/// `Error::Serialization`. Requires the `serde` build feature.
+///
+/// # Examples
+///
+/// ```
+/// let config = sled::ConfigBuilder::new().temporary(true).build();
+/// let t = sled::Tree::start(config).unwrap();
+/// let typed: sled::TypedTree<u64, String> = sled::TypedTree::new(t);
+///
+/// typed.set(&256, &"two hundred and fifty six".to_owned()).unwrap();
+/// typed.set(&2, &"two".to_owned()).unwrap();
+/// typed.set(&16, &"sixteen".to_owned()).unwrap();
+///
+/// let keys: Vec<u64> = typed.range(2..).map(|res| res.unwrap().0).collect();
+/// assert_eq!(keys, vec![2, 16, 256]);
+/// assert_eq!(typed.get(&16), Ok(Some("sixteen".to_owned())));
+/// ```
+pub struct TypedTree<K, V> {
+    tree: Tree,
+    marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> TypedTree<K, V>
+    where K: KeyEncode,
+          V: Serialize + DeserializeOwned
+{
+    /// Wrap a `Tree`, which should only hold keys and values written
+    /// by a `TypedTree` of the same types.
+    pub fn new(tree: Tree) -> TypedTree<K, V> {
+        TypedTree {
+            tree: tree,
+            marker: PhantomData,
+        }
+    }
+
+    /// The underlying `Tree`.
+    pub fn tree(&self) -> &Tree {
+        &self.tree
+    }
+
+    /// Retrieve a value if it exists.
+    pub fn get(&self, key: &K) -> DbResult<Option<V>, ()> {
+        match self.tree.get(&*encode_key(key))? {
+            Some(bytes) => decode_value(&*bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Set a key to a new value.
+    pub fn set(&self, key: &K, value: &V) -> DbResult<(), ()> {
+        self.tree.set(encode_key(key), encode_value(value)?)
+    }
+
+    /// Delete a value, returning the last value if it existed.
+    pub fn del(&self, key: &K) -> DbResult<Option<V>, ()> {
+        match self.tree.del(&*encode_key(key))? {
+            Some(bytes) => decode_value(&*bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Compare and swap, like `Tree::cas`. Values are compared by
+    /// their encodings, so `old` must encode to the same bytes as
+    /// the current value, which it does if the two are equal.
+    pub fn cas(
+        &self,
+        key: &K,
+        old: Option<&V>,
+        new: Option<&V>,
+    ) -> DbResult<(), Option<V>> {
+        let old = match old {
+            Some(old) => Some(encode_value(old).map_err(|e| e.danger_cast())?),
+            None => None,
+        };
+        let new = match new {
+            Some(new) => Some(encode_value(new).map_err(|e| e.danger_cast())?),
+            None => None,
+        };
+
+        match self.tree.cas(encode_key(key), old, new) {
+            Ok(()) => Ok(()),
+            Err(Error::CasFailed(Some(actual))) => {
+                match decode_value(&*actual) {
+                    Ok(actual) => Err(Error::CasFailed(Some(actual))),
+                    Err(e) => Err(e.danger_cast()),
+                }
+            }
+            Err(Error::CasFailed(None)) => Err(Error::CasFailed(None)),
+            Err(other) => Err(other.danger_cast()),
+        }
+    }
+
+    /// Iterate over every key and value, in key order.
+    pub fn iter(&self) -> TypedIter<K, V> {
+        TypedIter {
+            inner: self.tree.iter(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Iterate over the keys and values in the provided range,
+    /// in key order.
+    pub fn range<R>(&self, range: R) -> TypedIter<K, V>
+        where R: RangeBounds<K>
+    {
+        let bound = |bound: ops::Bound<&K>| match bound {
+            ops::Bound::Included(k) => ops::Bound::Included(encode_key(k)),
+            ops::Bound::Excluded(k) => ops::Bound::Excluded(encode_key(k)),
+            ops::Bound::Unbounded => ops::Bound::Unbounded,
+        };
+        let range = (bound(range.start_bound()), bound(range.end_bound()));
+        TypedIter {
+            inner: self.tree.range::<Vec<u8>, _>(range),
+            marker: PhantomData,
+        }
+    }
+}
+
+/// An iterator over the keys and values of a `TypedTree`, in order.
+pub struct TypedIter<'a, K, V> {
+    inner: Iter<'a>,
+    marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<'a, K, V> Iterator for TypedIter<'a, K, V>
+    where K: KeyEncode,
+          V: DeserializeOwned
+{
+    type Item = DbResult<(K, V), ()>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|res| {
+            let (k, v) = res?;
+            Ok((decode_key(&*k)?, decode_value(&*v)?))
+        })
+    }
+}
+
+fn encode_key<K: KeyEncode>(key: &K) -> Key {
+    let mut buf = vec![];
+    key.encode_key(&mut buf);
+    buf
+}
+
+fn decode_key<K: KeyEncode>(mut bytes: &[u8]) -> DbResult<K, ()> {
+    let key = K::decode_key(&mut bytes)?;
+    if !bytes.is_empty() {
+        return Err(Error::Serialization(format!(
+            "found {} bytes left over after decoding a key",
+            bytes.len()
+        )));
+    }
+    Ok(key)
+}
+
+fn encode_value<V: Serialize>(value: &V) -> DbResult<Value, ()> {
+    serialize(value, Infinite).map_err(|e| {
+        Error::Serialization(format!("failed to encode a value: {}", e))
+    })
+}
+
+fn decode_value<V: DeserializeOwned>(bytes: &[u8]) -> DbResult<V, ()> {
+    deserialize(bytes).map_err(|e| {
+        Error::Serialization(format!("failed to decode a value: {}", e))
+    })
+}
@@ -203,6 +203,78 @@ fn test_crash_recovery_no_runtime_snapshot() {
     cleanup();
 }
 
+fn u64_to_vec(u: u64) -> Vec<u8> {
+    (0..8).rev().map(|i| (u >> (i * 8)) as u8).collect()
+}
+
+fn vec_to_u64(b: &[u8]) -> u64 {
+    b.iter().fold(0, |acc, b| (acc << 8) + *b as u64)
+}
+
+fn run_generate_id() {
+    let config = ConfigBuilder::new()
+        .io_bufs(2)
+        .flush_every_ms(Some(10))
+        .io_buf_size(100_000)
+        .path("test_crashes_ids".to_string())
+        .build();
+
+    let tree = sled::Tree::start(config).unwrap();
+
+    // ids are recorded as keys, so the last key recovered
+    // is at most the last id handed out before the crash.
+    let last = tree.iter().next_back().map(|res| {
+        vec_to_u64(&*res.unwrap().0)
+    });
+
+    let first = tree.generate_id().unwrap();
+    if let Some(last) = last {
+        assert!(
+            first > last,
+            "generated id {} after recovering id {}",
+            first,
+            last
+        );
+    }
+
+    thread::spawn(|| {
+        let runtime = rand::thread_rng().gen_range(0, 100);
+        thread::sleep(Duration::from_millis(runtime));
+        unsafe {
+            libc::raise(9);
+        }
+    });
+
+    let mut last = first;
+    loop {
+        let id = tree.generate_id().unwrap();
+        assert!(id > last);
+        last = id;
+        tree.set(u64_to_vec(id), vec![]).unwrap();
+    }
+}
+
+#[test]
+fn test_crash_generate_id() {
+    let _res = fs::remove_dir_all("test_crashes_ids");
+    for _ in 0..20 {
+        let child = unsafe { libc::fork() };
+        if child == 0 {
+            run_generate_id()
+        } else {
+            let mut status = 0;
+            unsafe {
+                libc::waitpid(child, &mut status as *mut libc::c_int, 0);
+            }
+            if status != 9 {
+                let _res = fs::remove_dir_all("test_crashes_ids");
+                panic!("child exited abnormally");
+            }
+        }
+    }
+    let _res = fs::remove_dir_all("test_crashes_ids");
+}
+
 fn cleanup_with_snapshots() {
     let dir = Path::new("test_crashes_with_snapshot");
     if dir.exists() {
@@ -225,11 +225,13 @@ fn log_chunky_iterator() {
     let mut threads = vec![];
     for _ in 0..100 {
         let thread = thread::spawn(|| {
+            // the sizes checked below are before compression
             let config = ConfigBuilder::new()
                 .temporary(true)
                 .segment_mode(SegmentMode::Linear)
                 .io_buf_size(100)
                 .min_items_per_segment(1)
+                .use_compression(false)
                 .build();
 
             let log = Log::start_raw_log(config.clone()).unwrap();
@@ -27,14 +27,17 @@ impl Materializer for TestMaterializer {
         TestMaterializer
     }
 
-    fn merge(&self, frags: &[&Vec<usize>]) -> Vec<usize> {
+    fn merge(
+        &self,
+        frags: &[&Vec<usize>],
+    ) -> pagecache::CacheResult<Vec<usize>, ()> {
         let mut consolidated = vec![];
         for &frag in frags.iter() {
             let mut frag = frag.clone();
             consolidated.append(&mut frag);
         }
 
-        consolidated
+        Ok(consolidated)
     }
 
     fn recover(&self, _: &Vec<usize>) -> Option<()> {
@@ -3,11 +3,20 @@ extern crate rand;
 extern crate sled;
 extern crate pagecache;
 
-use std::collections::BTreeMap;
+use std::cmp;
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::fs;
+use std::ops;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::thread;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use quickcheck::{Arbitrary, Gen, QuickCheck, StdGen};
+use rand::Rng;
 
 use sled::*;
 use pagecache::ConfigBuilder;
@@ -55,12 +64,12 @@ fn parallel_tree_ops() {
     par!{t, |tree: &Tree, k: Vec<u8>| {
         assert_eq!(tree.get(&*k), Ok(None));
         tree.set(k.clone(), k.clone()).unwrap();
-        assert_eq!(tree.get(&*k), Ok(Some(k)));
+        assert_eq!(tree.get(&*k), Ok(Some(k.into())));
     }};
 
     println!("========== reading sets ==========");
     par!{t, |tree: &Tree, k: Vec<u8>| {
-        if tree.get(&*k.clone()) != Ok(Some(k.clone())) {
+        if tree.get(&*k.clone()) != Ok(Some(k.clone().into())) {
             println!("{}", tree.key_debug_str(&*k.clone()));
             panic!("expected key {:?} not found", k);
         }
@@ -78,7 +87,7 @@ fn parallel_tree_ops() {
         let k1 = k.clone();
         let mut k2 = k.clone();
         k2.reverse();
-        assert_eq!(tree.get(&*k1), Ok(Some(k2)));
+        assert_eq!(tree.get(&*k1), Ok(Some(k2.into())));
     }};
 
     println!("========== deleting ==========");
@@ -113,7 +122,7 @@ fn tree_subdir() {
 
     std::fs::remove_dir_all("test_tree_subdir").unwrap();
 
-    assert_eq!(res, Ok(Some(vec![1])));
+    assert_eq!(res, Ok(Some(vec![1].into())));
 }
 
 #[test]
@@ -145,48 +154,3389 @@ fn tree_iterator() {
     let half_way = N_PER_THREAD / 2;
     let half_key = kv(half_way);
     let mut tree_scan = t.scan(&*half_key);
-    assert_eq!(tree_scan.next(), Some(Ok((half_key.clone(), half_key))));
+    assert_eq!(tree_scan.next(), Some(Ok((half_key.clone(), half_key.into()))));
 
     let first_key = kv(0);
     let mut tree_scan = t.scan(&*first_key);
-    assert_eq!(tree_scan.next(), Some(Ok((first_key.clone(), first_key))));
+    assert_eq!(
+        tree_scan.next(),
+        Some(Ok((first_key.clone(), first_key.into())))
+    );
+
+    let last_key = kv(N_PER_THREAD - 1);
+    let mut tree_scan = t.scan(&*last_key);
+    assert_eq!(tree_scan.next(), Some(Ok((last_key.clone(), last_key.into()))));
+    assert_eq!(tree_scan.next(), None);
+}
+
+#[test]
+fn recover_tree() {
+    println!("========== recovery ==========");
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(2)
+        .io_buf_size(5000)
+        .flush_every_ms(None)
+        .snapshot_after_ops(100)
+        .build();
+    let t = sled::Tree::start(config.clone()).unwrap();
+    for i in 0..N_PER_THREAD {
+        let k = kv(i);
+        t.set(k.clone(), k).unwrap();
+    }
+    drop(t);
+
+    let t = sled::Tree::start(config.clone()).unwrap();
+    for i in 0..config.blink_fanout << 1 {
+        let k = kv(i as usize);
+        assert_eq!(t.get(&*k), Ok(Some(k.clone().into())));
+        t.del(&*k).unwrap();
+    }
+    drop(t);
+
+    let t = sled::Tree::start(config.clone()).unwrap();
+    for i in 0..config.blink_fanout << 1 {
+        let k = kv(i as usize);
+        assert_eq!(t.get(&*k), Ok(None));
+    }
+}
+
+#[test]
+fn tree_concurrent_pops() {
+    const N_PRODUCERS: usize = 4;
+    const N_CONSUMERS: usize = 4;
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .build();
+    let t = Arc::new(sled::Tree::start(config).unwrap());
+
+    let mut producers = vec![];
+    for tn in 0..N_PRODUCERS {
+        let tree = t.clone();
+        producers.push(thread::spawn(move || for i in 0..N_PER_THREAD {
+            let k = kv(tn * N_PER_THREAD + i);
+            tree.set(k.clone(), k).unwrap();
+        }));
+    }
+
+    let mut consumers = vec![];
+    for cn in 0..N_CONSUMERS {
+        let tree = t.clone();
+        consumers.push(thread::spawn(move || {
+            let mut popped = vec![];
+            let mut misses = 0;
+            while misses < 1000 {
+                let res = if cn % 2 == 0 {
+                    tree.pop_min()
+                } else {
+                    tree.pop_max()
+                };
+                match res.unwrap() {
+                    Some((k, v)) => {
+                        assert_eq!(k, v);
+                        popped.push(k);
+                        misses = 0;
+                    }
+                    None => {
+                        misses += 1;
+                        thread::yield_now();
+                    }
+                }
+            }
+            popped
+        }));
+    }
+
+    for producer in producers {
+        producer.join().unwrap();
+    }
+
+    let mut seen = std::collections::HashSet::new();
+    for consumer in consumers {
+        for k in consumer.join().unwrap() {
+            assert!(seen.insert(k.clone()), "popped {:?} twice", k);
+        }
+    }
+
+    // anything the consumers gave up on is still in the tree
+    while let Some((k, _)) = t.pop_min().unwrap() {
+        assert!(seen.insert(k.clone()), "popped {:?} twice", k);
+    }
+
+    assert_eq!(seen.len(), N_PRODUCERS * N_PER_THREAD);
+    assert_eq!(t.pop_max(), Ok(None));
+}
+
+#[test]
+fn tree_transactional_pops() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(2)
+        .flush_every_ms(None)
+        .build();
+    let t = Arc::new(sled::Tree::start(config).unwrap());
+    for i in 1..5 {
+        t.set(kv(i), kv(i)).unwrap();
+    }
+
+    // pops see the transaction's own writes and deletions
+    t.transaction::<_, _, ()>(|tx| {
+        tx.set(kv(0), vec![0]);
+        tx.del(&*kv(4))?;
+        assert_eq!(tx.pop_min(), Ok(Some((kv(0).into(), vec![0].into()))));
+        assert_eq!(tx.pop_min(), Ok(Some((kv(1).into(), kv(1).into()))));
+        assert_eq!(tx.pop_max(), Ok(Some((kv(3).into(), kv(3).into()))));
+        tx.set(kv(5), vec![5]);
+        assert_eq!(tx.pop_max(), Ok(Some((kv(5).into(), vec![5].into()))));
+        assert_eq!(tx.pop_max(), Ok(Some((kv(2).into(), kv(2).into()))));
+        assert_eq!(tx.pop_min(), Ok(None));
+        Ok(())
+    }).unwrap();
+    assert_eq!(t.iter().next(), None);
+
+    // a popped key that changes before commit re-runs the transaction
+    t.set(kv(1), kv(1)).unwrap();
+    let attempts = AtomicUsize::new(0);
+    let popped = t.transaction::<_, _, ()>(|tx| {
+        let popped = tx.pop_min()?;
+        if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+            t.set(kv(1), vec![1]).unwrap();
+        }
+        Ok(popped)
+    }).unwrap();
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    assert_eq!(popped, Some((kv(1).into(), vec![1].into())));
+    assert_eq!(t.get(&*kv(1)), Ok(None));
+
+    // concurrent transactions never pop the same entry
+    const N_POPS: usize = 100;
+    for i in 0..N_POPS {
+        t.set(kv(i), kv(i)).unwrap();
+    }
+    let mut threads = vec![];
+    for n in 0..4 {
+        let t = t.clone();
+        threads.push(thread::spawn(move || {
+            let mut popped = vec![];
+            loop {
+                let res = t.transaction::<_, _, ()>(|tx| if n % 2 == 0 {
+                    Ok(tx.pop_min()?)
+                } else {
+                    Ok(tx.pop_max()?)
+                });
+                match res.unwrap() {
+                    Some((k, _)) => popped.push(k),
+                    None => return popped,
+                }
+            }
+        }));
+    }
+    let mut seen = HashSet::new();
+    for thread in threads {
+        for k in thread.join().unwrap() {
+            assert!(seen.insert(k.clone()), "popped {:?} twice", k);
+        }
+    }
+    assert_eq!(seen.len(), N_POPS);
+}
+
+#[test]
+fn tree_get_lt_gt() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(2)
+        .flush_every_ms(None)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    let mut reference = BTreeMap::new();
+
+    assert_eq!(t.get_lt(&*kv(0)), Ok(None));
+    assert_eq!(t.get_gt(&*kv(0)), Ok(None));
+
+    // only even keys, so that every odd key falls between entries
+    for i in 0..N_PER_THREAD {
+        let k = kv(i * 2);
+        t.set(k.clone(), k.clone()).unwrap();
+        reference.insert(k.clone(), k);
+    }
+
+    // remove a run of keys to leave some leaves empty
+    for i in 40..80 {
+        let k = kv(i * 2);
+        t.del(&*k).unwrap();
+        reference.remove(&k);
+    }
+
+    for i in 0..N_PER_THREAD * 2 + 1 {
+        let k = kv(i);
+        let lt = reference
+            .range::<Vec<u8>, _>(..k.clone())
+            .next_back()
+            .map(|(k, v)| (k.clone(), v.clone().into()));
+        assert_eq!(t.get_lt(&*k), Ok(lt), "get_lt({:?})", k);
+
+        let gt = reference
+            .range::<Vec<u8>, _>((
+                std::collections::Bound::Excluded(k.clone()),
+                std::collections::Bound::Unbounded,
+            ))
+            .next()
+            .map(|(k, v)| (k.clone(), v.clone().into()));
+        assert_eq!(t.get_gt(&*k), Ok(gt), "get_gt({:?})", k);
+    }
+
+    assert_eq!(t.get_lt(b""), Ok(None));
+    assert_eq!(t.get_gt(b""), Ok(Some((kv(0), kv(0).into()))));
+}
+
+#[test]
+fn tree_double_ended_iter_during_splits() {
+    const N: usize = SPACE / 2;
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(2)
+        .build();
+    let t = Arc::new(sled::Tree::start(config).unwrap());
+
+    // even keys exist before iteration begins, odd keys are
+    // inserted concurrently and cause leaves to split under us
+    for i in 0..N {
+        t.set(kv(i * 2), vec![]).unwrap();
+    }
+
+    let t2 = t.clone();
+    let writer = thread::spawn(move || for i in 0..N {
+        t2.set(kv(i * 2 + 1), vec![]).unwrap();
+    });
+
+    let mut front = vec![];
+    let mut back = vec![];
+    let mut iter = t.iter();
+    for step in 0.. {
+        let next = if step % 3 == 0 {
+            iter.next().map(|res| front.push(res.unwrap().0))
+        } else {
+            iter.next_back().map(|res| back.push(res.unwrap().0))
+        };
+        if next.is_none() {
+            break;
+        }
+    }
+
+    writer.join().unwrap();
+
+    back.reverse();
+    front.extend(back);
+
+    for pair in front.windows(2) {
+        assert!(
+            pair[0] < pair[1],
+            "iterator yielded a key twice or out of order"
+        );
+    }
+    for i in 0..N {
+        assert!(
+            front.binary_search(&kv(i * 2)).is_ok(),
+            "iterator skipped a key present for the whole iteration"
+        );
+    }
+}
+
+#[test]
+fn tree_batch_atomic_for_readers() {
+    const N_KEYS: u8 = 16;
+    const N_BATCHES: u16 = 500;
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(2)
+        .build();
+    let t = Arc::new(sled::Tree::start(config).unwrap());
+
+    for k in 0..N_KEYS {
+        t.set(vec![k], u16_to_bytes(0)).unwrap();
+    }
+
+    let t2 = t.clone();
+    let writer = thread::spawn(move || for i in 1..N_BATCHES {
+        let mut batch = Batch::default();
+        for k in 0..N_KEYS {
+            batch.insert(vec![k], u16_to_bytes(i));
+        }
+        t2.apply_batch(batch).unwrap();
+    });
+
+    // batches write keys in ascending order, so if we ever
+    // read a partially applied batch in ascending order, a
+    // later key will appear older than an earlier one.
+    let mut readers = vec![];
+    for _ in 0..4 {
+        let t = t.clone();
+        readers.push(thread::spawn(move || loop {
+            let mut last = 0;
+            for k in 0..N_KEYS {
+                let v = bytes_to_u16(&*t.get(&[k]).unwrap().unwrap());
+                assert!(v >= last, "observed a partially applied batch");
+                last = v;
+            }
+            if last == N_BATCHES - 1 {
+                return;
+            }
+        }));
+    }
+
+    writer.join().unwrap();
+    for reader in readers.into_iter() {
+        reader.join().unwrap();
+    }
+}
+
+#[test]
+fn db_batches_to_different_trees_atomic() {
+    const N_TREES: u8 = 4;
+    const N_KEYS: u8 = 16;
+    const N_BATCHES: u16 = 200;
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(2)
+        .build();
+    let db = sled::Db::start(config).unwrap();
+
+    // every tree has its own writer, and the batches they log
+    // share the batch page while they're being applied.
+    let mut threads = vec![];
+    for n in 0..N_TREES {
+        let t = db.open_tree(vec![n]).unwrap();
+        for k in 0..N_KEYS {
+            t.set(vec![k], u16_to_bytes(0)).unwrap();
+        }
+
+        let writer = t.clone();
+        threads.push(thread::spawn(move || for i in 1..N_BATCHES {
+            let mut batch = Batch::default();
+            for k in 0..N_KEYS {
+                batch.insert(vec![k], u16_to_bytes(i));
+            }
+            writer.apply_batch(batch).unwrap();
+        }));
+
+        // as in tree_batch_atomic_for_readers
+        threads.push(thread::spawn(move || loop {
+            let mut last = 0;
+            for k in 0..N_KEYS {
+                let v = bytes_to_u16(&*t.get(&[k]).unwrap().unwrap());
+                assert!(v >= last, "observed a partially applied batch");
+                last = v;
+            }
+            if last == N_BATCHES - 1 {
+                return;
+            }
+        }));
+    }
+
+    for thread in threads.into_iter() {
+        thread.join().unwrap();
+    }
+}
+
+static INDEXING_STALLED: AtomicBool = AtomicBool::new(false);
+static INDEXING_RELEASED: AtomicBool = AtomicBool::new(false);
+
+// indexes records by their value, holding up the
+// indexing of [255] until INDEXING_RELEASED is set.
+fn stalling_index(_k: &[u8], v: &[u8]) -> Vec<Vec<u8>> {
+    if *v == [255] {
+        INDEXING_STALLED.store(true, Ordering::SeqCst);
+        while !INDEXING_RELEASED.load(Ordering::SeqCst) {
+            thread::yield_now();
+        }
+    }
+    vec![v.to_vec()]
+}
+
+#[test]
+fn db_batches_only_block_their_own_trees() {
+    let config = ConfigBuilder::new().temporary(true).build();
+    let db = sled::Db::start(config).unwrap();
+    let records = db.open_tree(b"records".to_vec()).unwrap();
+    let index = db.open_tree(b"by_value".to_vec()).unwrap();
+    let other = db.open_tree(b"other".to_vec()).unwrap();
+    let indexed = IndexedTree::new(records.clone())
+        .with_index(b"by_value".to_vec(), index, stalling_index)
+        .unwrap();
+    indexed.set(vec![1], vec![10]).unwrap();
+
+    // holds the write locks of the records and their index
+    let writer = thread::spawn(move || indexed.set(vec![2], vec![255]));
+    while !INDEXING_STALLED.load(Ordering::SeqCst) {
+        thread::yield_now();
+    }
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let reader = {
+        let records = records.clone();
+        thread::spawn(move || {
+            assert_eq!(records.get(&[1]), Ok(Some(vec![10].into())));
+            assert_eq!(records.get(&[2]), Ok(None));
+            assert_eq!(records.contains_key(&[1]), Ok(true));
+
+            other.set(vec![1], vec![1]).unwrap();
+            let mut batch = Batch::default();
+            batch.insert(vec![2], vec![2]);
+            other.apply_batch(batch).unwrap();
+            assert_eq!(other.iter().count(), 2);
+            tx.send(()).unwrap();
+        })
+    };
+
+    let unblocked = rx.recv_timeout(Duration::from_secs(10)).is_ok();
+    INDEXING_RELEASED.store(true, Ordering::SeqCst);
+    writer.join().unwrap().unwrap();
+    reader.join().unwrap();
+    assert!(unblocked, "a batch blocked point reads or other trees");
+    assert_eq!(records.get(&[2]), Ok(Some(vec![255].into())));
+}
+
+#[test]
+fn tree_transactions_in_opposite_orders() {
+    const N_TXS: u16 = 500;
+
+    let config = ConfigBuilder::new().temporary(true).build();
+    let a = Arc::new(sled::Tree::start(config).unwrap());
+    let config = ConfigBuilder::new().temporary(true).build();
+    let b = Arc::new(sled::Tree::start(config).unwrap());
+
+    // each thread increments a counter in both trees, but
+    // names the trees in the opposite order from the other.
+    fn increment(first: &Tree, second: &Tree) {
+        (first, second)
+            .transaction::<_, _, ()>(|&(ref first, ref second)| {
+                for tree in &[first, second] {
+                    let cur = tree.get(b"counter")?.map_or(0, |v| {
+                        bytes_to_u16(&*v)
+                    });
+                    tree.set(b"counter".to_vec(), u16_to_bytes(cur + 1));
+                }
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    let mut threads = vec![];
+    for i in 0..2 {
+        let (a, b) = (a.clone(), b.clone());
+        threads.push(thread::spawn(move || for _ in 0..N_TXS {
+            if i == 0 {
+                increment(&a, &b);
+            } else {
+                increment(&b, &a);
+            }
+        }));
+    }
+
+    for thread in threads.into_iter() {
+        thread.join().unwrap();
+    }
+
+    let expected = Some(u16_to_bytes(N_TXS * 2).into());
+    assert_eq!(a.get(b"counter").unwrap(), expected);
+    assert_eq!(b.get(b"counter").unwrap(), expected);
+}
+
+#[test]
+fn tree_transaction_abort() {
+    let config = ConfigBuilder::new().temporary(true).build();
+    let a = sled::Tree::start(config).unwrap();
+    let config = ConfigBuilder::new().temporary(true).build();
+    let b = sled::Tree::start(config).unwrap();
+
+    a.set(b"k".to_vec(), b"a".to_vec()).unwrap();
+
+    let res = (&a, &b).transaction::<_, (), _>(|&(ref ta, ref tb)| {
+        ta.set(b"k".to_vec(), b"aborted".to_vec());
+        tb.set(b"k".to_vec(), b"aborted".to_vec());
+        // writes are visible within the transaction
+        assert_eq!(ta.get(b"k")?, Some(b"aborted".to_vec().into()));
+        Err(TransactionError::Abort(42))
+    });
+
+    assert_eq!(res, Err(TransactionError::Abort(42)));
+    assert_eq!(a.get(b"k"), Ok(Some(b"a".to_vec().into())));
+    assert_eq!(b.get(b"k"), Ok(None));
+}
+
+#[test]
+fn tree_generate_id() {
+    let config = ConfigBuilder::new().temporary(true).build();
+    let t = Arc::new(sled::Tree::start(config.clone()).unwrap());
+
+    let mut threads = vec![];
+    for _ in 0..N_THREADS {
+        let t = t.clone();
+        threads.push(thread::spawn(move || {
+            (0..N_PER_THREAD)
+                .map(|_| t.generate_id().unwrap())
+                .collect::<Vec<_>>()
+        }));
+    }
+
+    let mut ids = vec![];
+    for thread in threads.into_iter() {
+        ids.extend(thread.join().unwrap());
+    }
+
+    ids.sort();
+    ids.dedup();
+    assert_eq!(ids.len(), N, "generate_id handed out duplicate ids");
+
+    let max = *ids.last().unwrap();
+    drop(t);
+
+    let t = sled::Tree::start(config).unwrap();
+    assert!(t.generate_id().unwrap() > max);
+}
+
+#[test]
+fn db_named_trees_are_isolated() {
+    let config = ConfigBuilder::new().temporary(true).build();
+    let db = sled::Db::start(config).unwrap();
+    let a = db.open_tree(b"a".to_vec()).unwrap();
+    let b = db.open_tree(b"b".to_vec()).unwrap();
+
+    for i in 0..N_PER_THREAD {
+        let k = kv(i);
+        a.set(k.clone(), vec![1]).unwrap();
+        if i % 2 == 0 {
+            b.set(k, vec![2]).unwrap();
+        }
+    }
+
+    assert_eq!(a.iter().count(), N_PER_THREAD);
+    assert_eq!(b.iter().count(), N_PER_THREAD / 2);
+    assert_eq!(db.iter().count(), 0);
+    assert!(b.iter().all(|r| r.unwrap().1 == vec![2]));
+
+    // reopening returns a handle to the same tree
+    let a2 = db.open_tree(b"a".to_vec()).unwrap();
+    assert_eq!(a2.get(&*kv(1)), Ok(Some(vec![1].into())));
+
+    assert_eq!(db.tree_names().len(), 3);
+    assert_eq!(db.drop_tree(b"a"), Ok(true));
+    assert_eq!(db.tree_names().len(), 2);
+    assert!(a2.get(&*kv(1)).is_err());
+    assert_eq!(b.get(&*kv(0)), Ok(Some(vec![2].into())));
+
+    // a tree with the same name starts out empty
+    let a = db.open_tree(b"a".to_vec()).unwrap();
+    assert_eq!(a.iter().count(), 0);
+}
+
+#[test]
+fn db_drop_tree_during_iteration() {
+    let config = ConfigBuilder::new().temporary(true).blink_fanout(2).build();
+    let db = sled::Db::start(config).unwrap();
+    let t = db.open_tree(b"doomed".to_vec()).unwrap();
+    for i in 0..N_PER_THREAD {
+        t.set(kv(i), vec![]).unwrap();
+    }
+
+    let mut iter = t.iter();
+    assert!(iter.next().unwrap().is_ok());
+    db.drop_tree(b"doomed").unwrap();
+
+    // reuse the freed pages for something else
+    let other = db.open_tree(b"other".to_vec()).unwrap();
+    for i in 0..N_PER_THREAD {
+        other.set(kv(i), vec![1]).unwrap();
+    }
+
+    assert!(iter.next().unwrap().is_err());
+    assert!(iter.next().is_none());
+    assert!(t.scan(&*kv(0)).next().unwrap().is_err());
+    assert!(t.set(kv(0), vec![]).is_err());
+    assert!(t.apply_batch(Batch::default()).is_err());
+}
+
+#[test]
+fn db_named_trees_recover() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(2)
+        .io_buf_size(5000)
+        .flush_every_ms(None)
+        .snapshot_after_ops(100)
+        .build();
+    let db = sled::Db::start(config.clone()).unwrap();
+    let dropped = db.open_tree(b"dropped".to_vec()).unwrap();
+    for i in 0..N_PER_THREAD {
+        dropped.set(kv(i), vec![0]).unwrap();
+    }
+    let kept = db.open_tree(b"kept".to_vec()).unwrap();
+    for i in 0..N_PER_THREAD {
+        kept.set(kv(i), vec![1]).unwrap();
+        db.set(kv(i), vec![2]).unwrap();
+    }
+    db.drop_tree(b"dropped").unwrap();
+
+    // reuses pages freed by the dropped tree
+    let reused = db.open_tree(b"reused".to_vec()).unwrap();
+    for i in 0..N_PER_THREAD {
+        reused.set(kv(i), vec![3]).unwrap();
+    }
+    db.flush().unwrap();
+    drop((dropped, kept, reused, db));
+
+    let db = sled::Db::start(config).unwrap();
+    let mut names = db.tree_names();
+    names.retain(|name| !name.starts_with(b"__sled__"));
+    assert_eq!(names, vec![b"kept".to_vec(), b"reused".to_vec()]);
+
+    for &(name, value) in &[(&b"kept"[..], 1), (&b"reused"[..], 3)] {
+        let t = db.open_tree(name.to_vec()).unwrap();
+        assert_eq!(t.iter().count(), N_PER_THREAD);
+        for i in 0..N_PER_THREAD {
+            assert_eq!(t.get(&*kv(i)), Ok(Some(vec![value].into())));
+        }
+    }
+    for i in 0..N_PER_THREAD {
+        assert_eq!(db.get(&*kv(i)), Ok(Some(vec![2].into())));
+    }
+}
+
+#[test]
+fn db_transaction_across_named_trees() {
+    let config = ConfigBuilder::new().temporary(true).build();
+    let db = sled::Db::start(config).unwrap();
+    let from = db.open_tree(b"from".to_vec()).unwrap();
+    let to = db.open_tree(b"to".to_vec()).unwrap();
+    from.set(b"k".to_vec(), vec![1]).unwrap();
+
+    (&from, &to)
+        .transaction::<_, _, ()>(|&(ref f, ref t)| {
+            let v = f.del(b"k")?.unwrap();
+            t.set(b"k".to_vec(), v.to_vec());
+            Ok(())
+        })
+        .unwrap();
+
+    assert_eq!(from.get(b"k"), Ok(None));
+    assert_eq!(to.get(b"k"), Ok(Some(vec![1].into())));
+}
+
+#[test]
+fn tree_len() {
+    fn delete_on_zero(
+        _k: &[u8],
+        _old: Option<&[u8]>,
+        merged: &[u8],
+    ) -> Option<Vec<u8>> {
+        if merged == [0] { None } else { Some(merged.to_vec()) }
+    }
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(2)
+        .merge_operator(delete_on_zero)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    assert!(t.is_empty());
+
+    for i in 0..N_PER_THREAD {
+        t.set(kv(i), vec![1]).unwrap();
+        t.set(kv(i), vec![2]).unwrap();
+    }
+    assert_eq!(t.len(), N_PER_THREAD);
+
+    // replacing an existing value doesn't change the count
+    t.cas(kv(0), Some(vec![2]), Some(vec![3])).unwrap();
+    assert_eq!(t.len(), N_PER_THREAD);
+    t.cas(kv(0), Some(vec![3]), None).unwrap();
+    t.cas(kv(0), None, Some(vec![1])).unwrap();
+    assert_eq!(t.len(), N_PER_THREAD);
+
+    t.del(&*kv(0)).unwrap();
+    t.del(&*kv(0)).unwrap();
+    assert_eq!(t.len(), N_PER_THREAD - 1);
+
+    t.merge(kv(0), vec![1]).unwrap();
+    assert_eq!(t.len(), N_PER_THREAD);
+    t.merge(kv(0), vec![0]).unwrap();
+    assert_eq!(t.len(), N_PER_THREAD - 1);
+
+    let mut batch = Batch::default();
+    batch.insert(kv(0), vec![]);
+    batch.insert(kv(1), vec![]);
+    batch.remove(kv(2));
+    t.apply_batch(batch).unwrap();
+    assert_eq!(t.len(), N_PER_THREAD - 1);
+
+    assert_eq!(t.len(), t.iter().count());
+}
+
+#[test]
+fn tree_len_concurrent() {
+    let config = ConfigBuilder::new().temporary(true).blink_fanout(2).build();
+    let t = Arc::new(sled::Tree::start(config).unwrap());
+
+    let mut threads = vec![];
+    for tn in 0..N_THREADS {
+        let t = t.clone();
+        threads.push(thread::spawn(move || for i in 0..N_PER_THREAD {
+            let k = kv(i * N_THREADS + tn);
+            t.set(k.clone(), vec![]).unwrap();
+            if i % 3 == 0 {
+                t.del(&*k).unwrap();
+            }
+            // everyone fights over a few shared keys
+            t.set(kv(i % 7), vec![]).unwrap();
+        }));
+    }
+    for thread in threads.into_iter() {
+        thread.join().unwrap();
+    }
+
+    assert_eq!(t.len(), t.iter().count());
+}
+
+#[test]
+fn tree_len_recovers() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(2)
+        .io_buf_size(5000)
+        .flush_every_ms(None)
+        .snapshot_after_ops(100)
+        .build();
+    let db = sled::Db::start(config.clone()).unwrap();
+    let other = db.open_tree(b"other".to_vec()).unwrap();
+    for i in 0..N_PER_THREAD {
+        db.set(kv(i), vec![]).unwrap();
+        if i % 2 == 0 {
+            other.set(kv(i), vec![]).unwrap();
+        }
+    }
+    drop((db, other));
+
+    // recovered from the counts persisted while shutting down
+    let db = sled::Db::start(config.clone()).unwrap();
+    let other = db.open_tree(b"other".to_vec()).unwrap();
+    assert_eq!(db.len(), N_PER_THREAD);
+    assert_eq!(other.len(), N_PER_THREAD / 2);
+
+    // persisted again after further writes
+    for i in 0..N_PER_THREAD / 2 {
+        db.del(&*kv(i)).unwrap();
+    }
+    other.set(kv(N_PER_THREAD), vec![]).unwrap();
+    drop((db, other));
+
+    let db = sled::Db::start(config).unwrap();
+    let other = db.open_tree(b"other".to_vec()).unwrap();
+    assert_eq!(db.len(), N_PER_THREAD / 2);
+    assert_eq!(other.len(), N_PER_THREAD / 2 + 1);
+}
+
+#[test]
+fn tree_watch_prefix() {
+    let config = ConfigBuilder::new().temporary(true).blink_fanout(2).build();
+    let db = sled::Db::start(config).unwrap();
+    let mut subscriber = db.watch_prefix(vec![1]);
+
+    db.set(vec![1, 1], vec![1]).unwrap();
+    db.set(vec![2, 1], vec![1]).unwrap();
+    db.cas(vec![1, 1], Some(vec![1]), Some(vec![2])).unwrap();
+    db.cas(vec![1, 2], None, None).unwrap();
+    db.del(&[1, 2]).unwrap();
+
+    let mut batch = Batch::default();
+    batch.insert(vec![1, 2], vec![3]);
+    batch.insert(vec![2, 2], vec![3]);
+    batch.remove(vec![1, 1]);
+    db.apply_batch(batch).unwrap();
+
+    let expected = vec![
+        Event::Insert {
+            key: vec![1, 1],
+            value: vec![1].into(),
+        },
+        Event::Insert {
+            key: vec![1, 1],
+            value: vec![2].into(),
+        },
+        Event::Remove { key: vec![1, 1] },
+        Event::Insert {
+            key: vec![1, 2],
+            value: vec![3].into(),
+        },
+    ];
+    for event in expected {
+        assert_eq!(subscriber.next(), Some(event));
+    }
+    assert!(subscriber.next_timeout(Duration::from_secs(0)).is_err());
+
+    // subscribers of a dropped tree end
+    let tmp = db.open_tree(b"tmp".to_vec()).unwrap();
+    let mut subscriber = tmp.watch_prefix(vec![]);
+    tmp.set(vec![1], vec![1]).unwrap();
+    db.drop_tree(b"tmp").unwrap();
+    assert!(subscriber.next().is_some());
+    assert_eq!(subscriber.next(), None);
+}
+
+#[test]
+fn tree_watch_prefix_concurrent() {
+    let config = ConfigBuilder::new().temporary(true).blink_fanout(2).build();
+    let t = Arc::new(sled::Tree::start(config).unwrap());
+    let mut subscriber = t.watch_prefix(vec![]);
+
+    // stay within the subscriber's buffer
+    let mut threads = vec![];
+    for tn in 0..N_THREADS {
+        let t = t.clone();
+        threads.push(thread::spawn(move || for i in 0..N_PER_THREAD / 2 {
+            t.set(vec![(i % 3) as u8], vec![tn as u8]).unwrap();
+        }));
+    }
+    for thread in threads.into_iter() {
+        thread.join().unwrap();
+    }
+
+    // the last event for each key is the value it ended up with
+    let mut last = BTreeMap::new();
+    let mut events = 0;
+    while let Ok(event) = subscriber.next_timeout(Duration::from_secs(0)) {
+        events += 1;
+        match event {
+            Event::Insert { key, value } => last.insert(key, value),
+            Event::Remove { .. } | Event::Clear => {
+                panic!("nothing was removed")
+            }
+        };
+    }
+    assert_eq!(events, N / 2);
+    for (k, v) in last {
+        assert_eq!(t.get(&*k), Ok(Some(v)));
+    }
+}
+
+#[test]
+fn tree_watch_prefix_overflow() {
+    let config = ConfigBuilder::new().temporary(true).build();
+    let t = sled::Tree::start(config).unwrap();
+    let mut slow = t.watch_prefix(vec![]);
+
+    for i in 0..N {
+        t.set(kv(i), vec![]).unwrap();
+    }
+
+    // the slow subscriber was disconnected once its buffer
+    // filled up, but still receives everything buffered so far.
+    let received = (&mut slow).count();
+    assert!(received > 0 && received < N);
+    assert_eq!(slow.next(), None);
+}
+
+#[test]
+fn tree_first_last() {
+    let config = ConfigBuilder::new().temporary(true).blink_fanout(2).build();
+    let t = sled::Tree::start(config).unwrap();
+    assert_eq!(t.first(), Ok(None));
+    assert_eq!(t.last(), Ok(None));
+
+    for i in 0..N_PER_THREAD {
+        t.set(kv(i), vec![]).unwrap();
+    }
+    assert_eq!(t.first(), Ok(Some((kv(0), vec![].into()))));
+    assert_eq!(t.last(), Ok(Some((kv(N_PER_THREAD - 1), vec![].into()))));
+
+    // leave empty leaves at both ends of the tree
+    for i in 0..N_PER_THREAD / 3 {
+        t.del(&*kv(i)).unwrap();
+        t.del(&*kv(N_PER_THREAD - 1 - i)).unwrap();
+    }
+    assert_eq!(t.first(), Ok(Some((kv(N_PER_THREAD / 3), vec![].into()))));
+    assert_eq!(
+        t.last(),
+        Ok(Some((kv(N_PER_THREAD - 1 - N_PER_THREAD / 3), vec![].into())))
+    );
+
+    for i in N_PER_THREAD / 3..N_PER_THREAD - N_PER_THREAD / 3 {
+        t.del(&*kv(i)).unwrap();
+    }
+    assert_eq!(t.first(), Ok(None));
+    assert_eq!(t.last(), Ok(None));
+}
+
+#[test]
+fn tree_first_last_during_inserts() {
+    let config = ConfigBuilder::new().temporary(true).blink_fanout(2).build();
+    let t = Arc::new(sled::Tree::start(config).unwrap());
+    let middle = N_PER_THREAD / 2;
+    t.set(kv(middle), vec![]).unwrap();
+
+    // keys only ever get inserted below and above the middle,
+    // so the extremes we observe must only ever move outwards.
+    let t2 = t.clone();
+    let writer = thread::spawn(move || for i in 1..middle {
+        t2.set(kv(middle - i), vec![]).unwrap();
+        t2.set(kv(middle + i), vec![]).unwrap();
+    });
+
+    let mut first = kv(middle);
+    let mut last = kv(middle);
+    for _ in 0..N_PER_THREAD / 2 {
+        let (k, _v) = t.first().unwrap().unwrap();
+        assert!(k <= first);
+        first = k;
+        let (k, _v) = t.last().unwrap().unwrap();
+        assert!(k >= last);
+        last = k;
+    }
+    writer.join().unwrap();
+
+    assert_eq!(t.first(), Ok(Some((kv(1), vec![].into()))));
+    assert_eq!(t.last(), Ok(Some((kv(2 * middle - 1), vec![].into()))));
+}
+
+#[test]
+fn tree_clear() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(2)
+        .flush_every_ms(None)
+        .build();
+    let db = sled::Db::start(config.clone()).unwrap();
+    let other = db.open_tree(b"other".to_vec()).unwrap();
+    for i in 0..N_PER_THREAD {
+        db.set(kv(i), vec![]).unwrap();
+        other.set(kv(i), vec![]).unwrap();
+    }
+
+    let mut subscriber = db.watch_prefix(vec![]);
+    {
+        let mut iter = db.iter();
+        assert_eq!(iter.next(), Some(Ok((kv(0), vec![].into()))));
+
+        db.clear().unwrap();
+
+        // iterators over the old tree end
+        assert_eq!(iter.next(), None);
+    }
+    assert_eq!(subscriber.next(), Some(Event::Clear));
+    assert_eq!(db.len(), 0);
+    assert_eq!(db.iter().next(), None);
+    assert_eq!(other.len(), N_PER_THREAD);
+
+    // the cleared tree is usable
+    for i in 0..N_PER_THREAD / 2 {
+        db.set(kv(i), vec![1]).unwrap();
+    }
+    drop((db, other, subscriber));
+
+    let db = sled::Db::start(config).unwrap();
+    let other = db.open_tree(b"other".to_vec()).unwrap();
+    assert_eq!(db.iter().count(), N_PER_THREAD / 2);
+    assert_eq!(db.get(&*kv(0)), Ok(Some(vec![1].into())));
+    assert_eq!(other.iter().count(), N_PER_THREAD);
+}
+
+#[test]
+fn tree_keys_values() {
+    let config = ConfigBuilder::new().temporary(true).blink_fanout(2).build();
+    let db = sled::Db::start(config).unwrap();
+    let t = db.open_tree(b"t".to_vec()).unwrap();
+    for i in 0..N_PER_THREAD {
+        t.set(kv(i), vec![i as u8]).unwrap();
+    }
+
+    let keys: Vec<_> = t.iter().keys().map(|r| r.unwrap()).collect();
+    let expected: Vec<_> = (0..N_PER_THREAD).map(kv).collect();
+    assert_eq!(keys, expected);
+
+    let values: Vec<_> =
+        t.iter().values().rev().map(|r| r.unwrap()).collect();
+    let expected: Vec<_> =
+        (0..N_PER_THREAD).rev().map(|i| vec![i as u8]).collect();
+    assert_eq!(values, expected);
+
+    let keys: Vec<_> = t
+        .range(kv(10)..kv(20))
+        .keys()
+        .rev()
+        .map(|r| r.unwrap())
+        .collect();
+    let expected: Vec<_> = (10..20).rev().map(kv).collect();
+    assert_eq!(keys, expected);
+
+    let values: Vec<_> =
+        t.scan_prefix(&[0, 0]).values().map(|r| r.unwrap()).collect();
+    let expected: Vec<_> = (0..256).map(|i| vec![i as u8]).collect();
+    assert_eq!(values, expected);
+
+    // both ends of one adapter meet in the middle
+    let mut keys = t.range(kv(0)..kv(3)).keys();
+    assert_eq!(keys.next(), Some(Ok(kv(0))));
+    assert_eq!(keys.next_back(), Some(Ok(kv(2))));
+    assert_eq!(keys.next(), Some(Ok(kv(1))));
+    assert_eq!(keys.next_back(), None);
+
+    // errors are passed through
+    let mut keys = t.iter().keys();
+    let mut values = t.iter().values();
+    db.drop_tree(b"t").unwrap();
+    assert!(keys.next().unwrap().is_err());
+    assert!(keys.next().is_none());
+    assert!(values.next_back().unwrap().is_err());
+    assert!(values.next_back().is_none());
+}
+
+#[test]
+fn tree_checksum() {
+    let tree = || {
+        let config =
+            ConfigBuilder::new().temporary(true).blink_fanout(2).build();
+        sled::Tree::start(config).unwrap()
+    };
+    let forward = tree();
+    let reverse = tree();
+
+    // same entries, but written in opposite orders, and with
+    // extra keys that split the second tree before being removed
+    for i in 0..N_PER_THREAD / 3 {
+        forward.set(kv(i), kv(i)).unwrap();
+    }
+    for i in (0..N_PER_THREAD / 3 * 2).rev() {
+        reverse.set(kv(i), kv(i)).unwrap();
+    }
+    for i in N_PER_THREAD / 3..N_PER_THREAD / 3 * 2 {
+        reverse.del(&*kv(i)).unwrap();
+    }
+    assert_eq!(forward.checksum().unwrap(), reverse.checksum().unwrap());
+
+    reverse.set(kv(0), vec![]).unwrap();
+    assert_ne!(forward.checksum().unwrap(), reverse.checksum().unwrap());
+
+    // moving bytes from a value to its key changes the checksum
+    let a = tree();
+    let b = tree();
+    a.set(vec![1], vec![2, 3]).unwrap();
+    b.set(vec![1, 2], vec![3]).unwrap();
+    assert_ne!(a.checksum().unwrap(), b.checksum().unwrap());
+}
+
+#[test]
+fn tree_checksum_concurrent() {
+    const KEYS: usize = 64;
+    const GENERATIONS: usize = 20;
+
+    // each generation overwrites every key in order, so the
+    // tree is only ever in one of the states in between.
+    fn write_generations<F: FnMut()>(t: &sled::Tree, mut f: F) {
+        for g in 1..GENERATIONS + 1 {
+            for i in 0..KEYS {
+                t.set(kv(i), vec![g as u8]).unwrap();
+                f();
+            }
+        }
+    }
+    let tree = || {
+        let config = ConfigBuilder::new()
+            .temporary(true)
+            .blink_fanout(4)
+            .flush_every_ms(None)
+            .build();
+        let t = sled::Tree::start(config).unwrap();
+        for i in 0..KEYS {
+            t.set(kv(i), vec![0]).unwrap();
+        }
+        t
+    };
+
+    let reference = tree();
+    let mut states = HashSet::new();
+    states.insert(reference.checksum().unwrap());
+    write_generations(&reference, || {
+        states.insert(reference.checksum().unwrap());
+    });
+
+    // checksums taken while the generations are being
+    // written never see a mix of two states.
+    let t = Arc::new(tree());
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let t = t.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            write_generations(&t, || {});
+            done.store(true, Ordering::SeqCst);
+        })
+    };
+    while !done.load(Ordering::SeqCst) {
+        assert!(states.contains(&t.checksum().unwrap()));
+    }
+    writer.join().unwrap();
+    assert_eq!(t.checksum(), reference.checksum());
+}
+
+#[test]
+fn tree_contains_key() {
+    // removes the key when merging a zero
+    fn remove_on_zero(
+        _k: &[u8],
+        old: Option<&[u8]>,
+        to_merge: &[u8],
+    ) -> Option<Vec<u8>> {
+        if to_merge == [0] {
+            return None;
+        }
+        let mut ret = old.map(|o| o.to_vec()).unwrap_or_else(Vec::new);
+        ret.extend_from_slice(to_merge);
+        Some(ret)
+    }
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(2)
+        .flush_every_ms(None)
+        .merge_operator(remove_on_zero)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    for i in 0..N_PER_THREAD {
+        t.set(kv(i), vec![i as u8; 4096]).unwrap();
+    }
+    for i in 0..N_PER_THREAD {
+        assert_eq!(t.contains_key(&*kv(i)), Ok(true));
+    }
+    t.del(&*kv(0)).unwrap();
+    assert_eq!(t.contains_key(&*kv(0)), Ok(false));
+    assert_eq!(t.contains_key(&*kv(N_PER_THREAD)), Ok(false));
+
+    // unresolved merges count if they resolve to a value
+    t.merge(kv(0), vec![1]).unwrap();
+    t.merge(kv(0), vec![2]).unwrap();
+    assert_eq!(t.contains_key(&*kv(0)), Ok(true));
+    t.merge(kv(0), vec![0]).unwrap();
+    assert_eq!(t.contains_key(&*kv(0)), Ok(false));
+
+    // transactions see their own writes, and conflict on presence
+    let res = t.transaction::<_, _, ()>(|tx| {
+        assert_eq!(tx.contains_key(&*kv(1)), Ok(true));
+        tx.del(&*kv(1))?;
+        assert_eq!(tx.contains_key(&*kv(1)), Ok(false));
+        tx.set(kv(0), vec![]);
+        assert_eq!(tx.contains_key(&*kv(0)), Ok(true));
+        Ok(())
+    });
+    assert_eq!(res, Ok(()));
+    assert_eq!(t.contains_key(&*kv(0)), Ok(true));
+    assert_eq!(t.contains_key(&*kv(1)), Ok(false));
+
+    let attempts = AtomicUsize::new(0);
+    t.transaction::<_, _, ()>(|tx| {
+        if !tx.contains_key(&*kv(2))? {
+            tx.set(kv(3), vec![]);
+        }
+        if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+            t.del(&*kv(2)).unwrap();
+        }
+        Ok(())
+    }).unwrap();
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    assert_eq!(t.get(&*kv(3)), Ok(Some(vec![].into())));
+
+    // expired entries are absent
+    t.set_with_ttl(kv(4), vec![], Duration::from_secs(0)).unwrap();
+    assert_eq!(t.contains_key(&*kv(4)), Ok(false));
+}
+
+#[test]
+fn tree_range_resumed() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(2)
+        .flush_every_ms(None)
+        .build();
+    let t = sled::Tree::start(config.clone()).unwrap();
+    for i in 0..100 {
+        t.set(kv(i), kv(i)).unwrap();
+    }
+
+    // with such small leaves, pages of each of these sizes
+    // end right at the edge of a leaf at some point.
+    let expected: Vec<Vec<u8>> = (10..90).map(kv).collect();
+    for page_size in 1..8 {
+        let mut seen = vec![];
+        let mut token = None;
+        loop {
+            let mut iter = match token.take() {
+                Some(token) => t.range_resumed(kv(10)..kv(90), token),
+                None => t.range(kv(10)..kv(90)),
+            };
+            let before = seen.len();
+            for res in iter.by_ref().take(page_size) {
+                seen.push(res.unwrap().0);
+            }
+            if seen.len() == before {
+                break;
+            }
+            token = Some(iter.resume_token());
+        }
+        assert_eq!(seen, expected);
+    }
+
+    // the last returned key and the one after it are removed
+    let token = {
+        let mut iter = t.range(kv(10)..kv(90));
+        assert_eq!(iter.next(), Some(Ok((kv(10), kv(10).into()))));
+        iter.resume_token()
+    };
+    t.del(&*kv(10)).unwrap();
+    t.del(&*kv(11)).unwrap();
+    let mut iter = t.range_resumed(kv(10)..kv(90), token);
+    assert_eq!(iter.next(), Some(Ok((kv(12), kv(12).into()))));
+
+    // both ends are remembered
+    assert_eq!(iter.next_back(), Some(Ok((kv(89), kv(89).into()))));
+    let token = iter.resume_token();
+    drop(iter);
+    t.del(&*kv(88)).unwrap();
+    t.set(kv(50), vec![]).unwrap();
+    {
+        let mut iter = t.range_resumed(kv(10)..kv(90), token.clone());
+        assert_eq!(iter.next_back(), Some(Ok((kv(87), kv(87).into()))));
+        assert_eq!(iter.next(), Some(Ok((kv(13), kv(13).into()))));
+    }
+
+    // tokens only refer to keys, so they outlive the process
+    drop(t);
+    let t = sled::Tree::start(config).unwrap();
+    let resumed: Vec<_> = t.range_resumed(kv(10)..kv(90), token)
+        .map(|res| res.unwrap().0)
+        .collect();
+    let expected: Vec<_> = (13..88).map(kv).collect();
+    assert_eq!(resumed, expected);
+
+    // a token from a finished iterator resumes with nothing
+    let token = {
+        let mut iter = t.scan_prefix(&*kv(20));
+        assert_eq!(iter.next(), Some(Ok((kv(20), kv(20).into()))));
+        assert_eq!(iter.next(), None);
+        iter.resume_token()
+    };
+    assert_eq!(t.range_resumed(kv(20)..kv(30), token).next(), None);
+}
+
+#[test]
+fn tree_retain() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(4)
+        .flush_every_ms(None)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+
+    // more entries than retain reads at once
+    for i in 0..N {
+        t.set(kv(i), vec![(i % 3) as u8]).unwrap();
+    }
+    let mut subscriber = t.watch_prefix(vec![]);
+
+    let mut seen = 0;
+    let removed = t.retain(|_k, v| {
+        seen += 1;
+        v[0] != 0
+    }).unwrap();
+    assert_eq!(seen, N);
+    assert_eq!(removed, N / 3);
+    assert_eq!(t.len(), N - N / 3);
+    for i in 0..N {
+        let expected = if i % 3 == 0 {
+            None
+        } else {
+            Some(vec![(i % 3) as u8].into())
+        };
+        assert_eq!(t.get(&*kv(i)), Ok(expected));
+    }
+    for i in (0..N).filter(|i| i % 3 == 0) {
+        assert_eq!(
+            subscriber.next_timeout(Duration::from_secs(0)),
+            Ok(Event::Remove { key: kv(i) })
+        );
+    }
+    assert!(subscriber.next_timeout(Duration::from_secs(0)).is_err());
+
+    // a concurrent writer's values are never removed out from under
+    // it, whether it writes before or after the predicate sees a key.
+    for i in 0..N {
+        t.set(kv(i), vec![0]).unwrap();
+    }
+    let t = Arc::new(t);
+    let writer = {
+        let t = t.clone();
+        thread::spawn(move || for i in (0..N).rev() {
+            t.set(kv(i), vec![1]).unwrap();
+        })
+    };
+    t.retain(|_k, v| v[0] == 1).unwrap();
+    writer.join().unwrap();
+    assert_eq!(t.len(), N);
+    for i in 0..N {
+        assert_eq!(t.get(&*kv(i)), Ok(Some(vec![1].into())));
+    }
+}
+
+#[test]
+fn tree_bulk_load() {
+    let new_config = || {
+        ConfigBuilder::new()
+            .temporary(true)
+            .blink_fanout(4)
+            .io_buf_size(5000)
+            .flush_every_ms(None)
+            .build()
+    };
+
+    // sizes that fill the levels of the tree exactly, or not quite
+    for &n in &[0, 1, 3, 4, 5, 16, 17, 63, 64, 65, N] {
+        for &fill_factor in &[1., 0.5, 0.01] {
+            let t = sled::Tree::start(new_config()).unwrap();
+            let entries = (0..n).map(|i| (kv(i), kv(i)));
+            let loaded =
+                t.bulk_loader().fill_factor(fill_factor).load(entries);
+            assert_eq!(loaded, Ok(n));
+            assert_eq!(t.len(), n);
+
+            // reads see the same tree as if it had been inserted into
+            let inserted = sled::Tree::start(
+                ConfigBuilder::new().temporary(true).build(),
+            ).unwrap();
+            for i in 0..n {
+                inserted.set(kv(i), kv(i)).unwrap();
+            }
+            assert_eq!(t.checksum(), inserted.checksum());
+            let forward: Vec<_> = t.iter().map(|res| res.unwrap()).collect();
+            let expected: Vec<_> =
+                inserted.iter().map(|res| res.unwrap()).collect();
+            assert_eq!(forward, expected);
+            let mut reverse: Vec<_> =
+                t.iter().rev().map(|res| res.unwrap()).collect();
+            reverse.reverse();
+            assert_eq!(reverse, expected);
+            for i in 0..n {
+                assert_eq!(t.get(&*kv(i)), Ok(Some(kv(i).into())));
+            }
+            if n > 2 {
+                assert_eq!(t.get_lt(&*kv(n / 2)), Ok(Some((kv(n / 2 - 1), kv(n / 2 - 1).into()))));
+                assert_eq!(t.get_gt(&*kv(n / 2)), Ok(Some((kv(n / 2 + 1), kv(n / 2 + 1).into()))));
+            }
+        }
+    }
+
+    // the loaded tree splits and recovers like any other
+    let config = new_config();
+    let t = sled::Tree::start(config.clone()).unwrap();
+    t.bulk_load((0..N).filter(|i| i % 2 == 0).map(|i| (kv(i), kv(i))))
+        .unwrap();
+    for i in (0..N).filter(|i| i % 2 == 1) {
+        t.set(kv(i), kv(i)).unwrap();
+    }
+    for i in (0..N).filter(|i| i % 3 == 0) {
+        t.del(&*kv(i)).unwrap();
+    }
+    drop(t);
+    let t = sled::Tree::start(config).unwrap();
+    let expected: Vec<_> = (0..N).filter(|i| i % 3 != 0).map(kv).collect();
+    let keys: Vec<_> = t.iter().keys().map(|res| res.unwrap()).collect();
+    assert_eq!(keys, expected);
+    assert_eq!(t.len(), expected.len());
+}
+
+#[test]
+fn tree_bulk_load_errors() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(4)
+        .max_value_size(8)
+        .flush_every_ms(None)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+
+    // entries out of order, repeated or too large are refused,
+    // and leave nothing behind even after many were loaded.
+    let out_of_order = (0..100).map(kv).chain(vec![kv(50)]);
+    assert!(t.bulk_load(out_of_order.map(|k| (k, vec![]))).is_err());
+    let repeated = (0..100).map(kv).chain(vec![kv(99)]);
+    assert!(t.bulk_load(repeated.map(|k| (k, vec![]))).is_err());
+    assert_eq!(
+        t.bulk_load(vec![(vec![1], vec![]), (vec![2], vec![0; 9])]),
+        Err(Error::TooLarge {
+            limit: "max_value_size",
+            max: 8,
+            size: 9,
+        })
+    );
+    assert!(t.bulk_loader().fill_factor(0.).load(vec![]).is_err());
+    assert!(t.bulk_loader().fill_factor(1.5).load(vec![]).is_err());
+    assert!(t.is_empty());
+    assert_eq!(t.iter().next(), None);
+
+    // only empty trees can be loaded
+    t.set(vec![0], vec![]).unwrap();
+    assert!(t.bulk_load(vec![(vec![1], vec![])]).is_err());
+    assert_eq!(t.len(), 1);
+    t.del(&[0]).unwrap();
+    assert_eq!(t.bulk_load(vec![(vec![1], vec![])]), Ok(1));
+}
+
+#[test]
+fn tree_rename() {
+    static NOW: AtomicUsize = AtomicUsize::new(1_000);
+    fn clock() -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(NOW.load(Ordering::SeqCst) as u64)
+    }
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    t.set_expiration_clock(clock);
+
+    t.set(kv(1), vec![1]).unwrap();
+    t.set(kv(2), vec![2]).unwrap();
+    let mut subscriber = t.watch_prefix(vec![]);
+
+    // renaming onto an existing key returns the value it displaced,
+    // and subscribers see the old key go before the new one arrives.
+    assert_eq!(t.rename(&*kv(2), kv(1)), Ok(Some(vec![1].into())));
+    assert_eq!(t.get(&*kv(1)), Ok(Some(vec![2].into())));
+    assert_eq!(t.get(&*kv(2)), Ok(None));
+    assert_eq!(t.len(), 1);
+    assert_eq!(
+        subscriber.next_timeout(Duration::from_secs(0)),
+        Ok(Event::Remove { key: kv(2) })
+    );
+    assert_eq!(
+        subscriber.next_timeout(Duration::from_secs(0)),
+        Ok(Event::Insert {
+            key: kv(1),
+            value: vec![2].into(),
+        })
+    );
+
+    // missing keys, and renaming a key to itself, change nothing
+    assert_eq!(t.rename(&*kv(3), kv(1)), Ok(None));
+    assert_eq!(t.rename(&*kv(1), kv(1)), Ok(None));
+    assert_eq!(t.get(&*kv(1)), Ok(Some(vec![2].into())));
+    assert!(subscriber.next_timeout(Duration::from_secs(0)).is_err());
+
+    // a moved entry keeps its deadline, and the
+    // destination's own deadline is forgotten.
+    t.set_with_ttl(kv(4), vec![4], Duration::from_millis(10)).unwrap();
+    t.set_with_ttl(kv(5), vec![5], Duration::from_millis(100)).unwrap();
+    assert_eq!(t.rename(&*kv(5), kv(6)), Ok(None));
+    assert_eq!(t.rename(&*kv(4), kv(1)), Ok(Some(vec![2].into())));
+    assert_eq!(t.rename(&*kv(6), kv(4)), Ok(None));
+    NOW.fetch_add(50, Ordering::SeqCst);
+    assert_eq!(t.get(&*kv(1)), Ok(None));
+    assert_eq!(t.get(&*kv(4)), Ok(Some(vec![5].into())));
+    NOW.fetch_add(100, Ordering::SeqCst);
+    assert_eq!(t.get(&*kv(4)), Ok(None));
+    assert_eq!(t.rename(&*kv(4), kv(7)), Ok(None));
+    assert_eq!(t.get(&*kv(7)), Ok(None));
+}
+
+#[test]
+fn tree_rename_concurrent() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .build();
+    let t = Arc::new(sled::Tree::start(config).unwrap());
+    t.set(kv(0), vec![1]).unwrap();
+
+    let done = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let t = t.clone();
+        let done = done.clone();
+        thread::spawn(move || while !done.load(Ordering::SeqCst) {
+            // the value is always under exactly one of the keys
+            let values = t.multi_get(vec![kv(0), kv(1)]);
+            let present = values
+                .into_iter()
+                .filter(|res| res.as_ref().unwrap().is_some())
+                .count();
+            assert_eq!(present, 1);
+        })
+    };
+
+    for i in 0..N_PER_THREAD {
+        let (from, to) = if i % 2 == 0 { (0, 1) } else { (1, 0) };
+        assert_eq!(t.rename(&*kv(from), kv(to)), Ok(None));
+    }
+    done.store(true, Ordering::SeqCst);
+    reader.join().unwrap();
+    assert_eq!(t.len(), 1);
+}
+
+#[test]
+fn tree_count_range() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(4)
+        .flush_every_ms(None)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    for i in 0..N {
+        t.set(kv(i), vec![]).unwrap();
+    }
+
+    assert_eq!(t.count_range::<Vec<u8>, _>(..), Ok(N));
+    assert_eq!(t.count_range(vec![255]..), Ok(0));
+    for &(lo, hi) in &[(0, 10), (0, N / 2), (N / 3, N / 2), (100, N - 1)] {
+        assert_eq!(t.count_range(kv(lo)..kv(hi)), Ok(hi - lo));
+    }
+}
+
+#[test]
+fn tree_count_range_random_writes() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(4)
+        .flush_every_ms(None)
+        .build();
+    let t = Arc::new(sled::Tree::start(config).unwrap());
+
+    // inserting and removing random keys from several threads at once
+    // splits nodes while the counts of the entries under them change.
+    let threads: Vec<_> = (0..N_THREADS)
+        .map(|_| {
+            let t = t.clone();
+            thread::spawn(move || {
+                let mut rng = rand::thread_rng();
+                for _ in 0..N_PER_THREAD * 4 {
+                    let key = kv(rng.gen_range(0, N));
+                    if rng.gen_weighted_bool(3) {
+                        t.del(&*key).unwrap();
+                    } else {
+                        t.set(key, vec![]).unwrap();
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    // once none of them are in flight, the counts are exact
+    let mut rng = rand::thread_rng();
+    assert_eq!(t.count_range::<Vec<u8>, _>(..), Ok(t.iter().count()));
+    for _ in 0..200 {
+        let a = rng.gen_range(0, N);
+        let b = rng.gen_range(0, N);
+        let (lo, hi) = (cmp::min(a, b), cmp::max(a, b));
+        assert_eq!(
+            t.count_range(kv(lo)..kv(hi)),
+            Ok(t.range(kv(lo)..kv(hi)).count()),
+            "miscounted {}..{}",
+            lo,
+            hi
+        );
+    }
+}
+
+#[test]
+fn tree_count_range_skewed_removals() {
+    const FANOUT: usize = 4;
+    const KEYS: usize = FANOUT * FANOUT * FANOUT * FANOUT * FANOUT;
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(FANOUT as u8)
+        .flush_every_ms(None)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+
+    // every node is full, so the entries are spread evenly
+    // and the estimates are exact.
+    let ranges: Vec<(usize, usize)> = (0..KEYS)
+        .step_by(KEYS / 16)
+        .flat_map(|lo| {
+            (lo..KEYS + 1).step_by(KEYS / 16 - 3).map(move |hi| (lo, hi))
+        })
+        .collect();
+    t.bulk_load((0..KEYS).map(|i| (kv(i), vec![]))).unwrap();
+    for &(lo, hi) in &ranges {
+        assert_eq!(t.count_range(kv(lo)..kv(hi)), Ok(hi - lo));
+    }
+    assert_eq!(
+        t.split_points(4),
+        Ok(vec![kv(KEYS / 4), kv(KEYS / 2), kv(KEYS / 4 * 3)])
+    );
+
+    // removing the first quarter of the keys and every other key of
+    // the third leaves the nodes under them emptier than the rest,
+    // which the index keeps count of.
+    for i in (0..KEYS / 4).chain((KEYS / 2..KEYS / 4 * 3).step_by(2)) {
+        t.del(&*kv(i)).unwrap();
+    }
+    for &(lo, hi) in &ranges {
+        assert_eq!(
+            t.count_range(kv(lo)..kv(hi)),
+            Ok(t.range(kv(lo)..kv(hi)).count()),
+            "miscounted {}..{}",
+            lo,
+            hi
+        );
+    }
+
+    // and each chunk is off from an even split by at most
+    // half of what's under one of the nodes it starts at.
+    let len = t.len();
+    let splits = t.split_points(4).unwrap();
+    let mut bounds = vec![vec![]];
+    bounds.extend(splits);
+    for (i, lo) in bounds.iter().enumerate() {
+        let chunk = match bounds.get(i + 1) {
+            Some(hi) => t.range(lo.clone()..hi.clone()).count(),
+            None => t.range(lo.clone()..).count(),
+        };
+        let slack = len / (4 * 8) / 2 + 1;
+        assert!(
+            chunk + slack >= len / 4 && chunk <= len / 4 + slack,
+            "unbalanced chunk of {} out of {} entries",
+            chunk,
+            len
+        );
+    }
+}
+
+#[test]
+fn tree_split_points_skewed() {
+    const KEYS: usize = 4000;
+
+    // every key shares a long prefix, and they're written out of order
+    fn key(i: usize) -> Vec<u8> {
+        let i = (i * 7919) % KEYS;
+        let mut key = vec![b'x'; 100];
+        key.extend_from_slice(&[(i >> 8) as u8, i as u8]);
+        key
+    }
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(8)
+        .flush_every_ms(None)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    assert_eq!(t.split_points(4), Ok(vec![]));
+    for i in 0..KEYS {
+        t.set(key(i), vec![]).unwrap();
+    }
+
+    assert_eq!(t.split_points(0), Ok(vec![]));
+    assert_eq!(t.split_points(1), Ok(vec![]));
+
+    let splits = t.split_points(4).unwrap();
+    assert_eq!(splits.len(), 3);
+    let mut bounds = vec![vec![]];
+    bounds.extend(splits);
+    for (i, lo) in bounds.iter().enumerate() {
+        let chunk: Vec<_> = match bounds.get(i + 1) {
+            Some(hi) => t.range(lo.clone()..hi.clone()).collect(),
+            None => t.range(lo.clone()..).collect(),
+        };
+        assert!(
+            chunk.len() >= KEYS / 4 * 3 / 4 && chunk.len() <= KEYS / 4 * 5 / 4,
+            "unbalanced chunk of {} entries",
+            chunk.len()
+        );
+    }
+}
+
+// indexes each byte of a value, so that a value is found under
+// as many index keys as it has distinct bytes.
+fn by_byte(_k: &[u8], v: &[u8]) -> Vec<Vec<u8>> {
+    v.iter().map(|&b| vec![b]).collect()
+}
+
+fn by_len(_k: &[u8], v: &[u8]) -> Vec<Vec<u8>> {
+    vec![vec![v.len() as u8]]
+}
+
+#[test]
+fn tree_indexed() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .build();
+    let db = sled::Db::start(config).unwrap();
+    let records = db.open_tree(b"records".to_vec()).unwrap();
+    let bytes = db.open_tree(b"by_byte".to_vec()).unwrap();
+    let lens = db.open_tree(b"by_len".to_vec()).unwrap();
+
+    // indexes must be in their own trees of the same Db, with unique names
+    let other_config = ConfigBuilder::new().temporary(true).build();
+    let other = sled::Tree::start(other_config).unwrap();
+    let new = || IndexedTree::new(records.clone());
+    assert!(new().with_index(b"x".to_vec(), other, by_byte).is_err());
+    assert!(new().with_index(b"x".to_vec(), records.clone(), by_byte).is_err());
+    let twice = new()
+        .with_index(b"x".to_vec(), bytes.clone(), by_byte)
+        .unwrap()
+        .with_index(b"y".to_vec(), bytes.clone(), by_byte);
+    assert!(twice.is_err());
+    let renamed = new()
+        .with_index(b"x".to_vec(), bytes.clone(), by_byte)
+        .unwrap()
+        .with_index(b"x".to_vec(), lens.clone(), by_len);
+    assert!(renamed.is_err());
+
+    let t = new()
+        .with_index(b"by_byte".to_vec(), bytes.clone(), by_byte)
+        .unwrap()
+        .with_index(b"by_len".to_vec(), lens.clone(), by_len)
+        .unwrap();
+    assert!(t.get_by_index(b"by_nothing", &[1]).is_err());
+
+    t.set(vec![1], vec![0, 1]).unwrap();
+    t.set(vec![2], vec![1, 2, 2]).unwrap();
+    assert_eq!(
+        t.get_by_index(b"by_byte", &[1]),
+        Ok(vec![
+            (vec![1], vec![0, 1].into()),
+            (vec![2], vec![1, 2, 2].into()),
+        ])
+    );
+    assert_eq!(
+        t.get_by_index(b"by_byte", &[2]),
+        Ok(vec![(vec![2], vec![1, 2, 2].into())])
+    );
+    assert_eq!(
+        t.get_by_index(b"by_len", &[3]),
+        Ok(vec![(vec![2], vec![1, 2, 2].into())])
+    );
+    assert_eq!(bytes.len(), 4);
+
+    // changing a value moves it between index keys, and keeps
+    // the entries for index keys it still has.
+    t.set(vec![1], vec![0, 3, 3]).unwrap();
+    assert_eq!(t.get_by_index(b"by_byte", &[1]).unwrap().len(), 1);
+    assert_eq!(
+        t.get_by_index(b"by_byte", &[3]),
+        Ok(vec![(vec![1], vec![0, 3, 3].into())])
+    );
+    assert_eq!(t.get_by_index(b"by_len", &[3]).unwrap().len(), 2);
+    assert_eq!(t.get_by_index(b"by_len", &[2]), Ok(vec![]));
+    assert_eq!(bytes.len(), 4);
+
+    // index keys containing 0 bytes don't run into each other
+    t.set(vec![0], vec![]).unwrap();
+    assert_eq!(
+        t.get_by_index(b"by_len", &[0]),
+        Ok(vec![(vec![0], vec![].into())])
+    );
+    assert_eq!(t.get_by_index(b"by_byte", &[0]).unwrap().len(), 1);
+
+    assert_eq!(t.del(&[1]), Ok(Some(vec![0, 3, 3].into())));
+    assert_eq!(t.del(&[1]), Ok(None));
+    assert_eq!(t.get_by_index(b"by_byte", &[0]), Ok(vec![]));
+    assert_eq!(bytes.len(), 2);
+
+    let mut batch = Batch::default();
+    batch.remove(vec![2]);
+    batch.insert(vec![3], vec![4]);
+    t.apply_batch(batch).unwrap();
+    assert_eq!(t.get(&[2]), Ok(None));
+    assert_eq!(
+        t.get_by_index(b"by_byte", &[4]),
+        Ok(vec![(vec![3], vec![4].into())])
+    );
+    assert_eq!(bytes.len(), 1);
+    assert_eq!(lens.len(), 2);
+}
+
+#[test]
+fn tree_typed_key_order() {
+    fn encode<K: KeyEncode>(key: K) -> Vec<u8> {
+        let mut buf = vec![];
+        key.encode_key(&mut buf);
+        buf
+    }
+
+    fn decode<K: KeyEncode>(mut bytes: &[u8]) -> K {
+        let key = K::decode_key(&mut bytes).unwrap();
+        assert!(bytes.is_empty());
+        key
+    }
+
+    // each list is in order, and so must its encodings be
+    let u64s = vec![0u64, 1, 255, 256, 1 << 32, std::u64::MAX];
+    let i32s = vec![std::i32::MIN, -256, -1, 0, 1, 255, std::i32::MAX];
+    let strings: Vec<String> = vec!["", "\0", "\0\0", "a", "a\0", "ab"]
+        .into_iter()
+        .map(|s| s.to_owned())
+        .collect();
+    let tuples: Vec<(u8, String)> = vec![(0, "b"), (1, ""), (1, "a")]
+        .into_iter()
+        .map(|(n, s)| (n, s.to_owned()))
+        .collect();
+
+    fn check<K: KeyEncode + Clone + PartialEq + std::fmt::Debug>(keys: Vec<K>) {
+        let encoded: Vec<Vec<u8>> = keys.iter().cloned().map(encode).collect();
+        let mut sorted = encoded.clone();
+        sorted.sort();
+        assert_eq!(encoded, sorted);
+        for (key, bytes) in keys.into_iter().zip(encoded) {
+            assert_eq!(decode::<K>(&*bytes), key);
+        }
+    }
+    check(u64s);
+    check(i32s);
+    check(strings);
+    check(tuples);
+
+    // truncated keys and leftover bytes are errors, not panics
+    let mut short: &[u8] = &[0, 1];
+    assert!(u32::decode_key(&mut short).is_err());
+    let mut unterminated: &[u8] = b"abc";
+    assert!(String::decode_key(&mut unterminated).is_err());
+}
+
+#[test]
+fn tree_typed() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    let typed: TypedTree<u64, (String, Vec<u32>)> = TypedTree::new(t.clone());
+
+    // native-endian keys would put 256 before 1
+    for i in (0..1000u64).rev() {
+        typed.set(&i, &(i.to_string(), vec![i as u32; 2])).unwrap();
+    }
+    let keys: Vec<u64> = typed.iter().map(|res| res.unwrap().0).collect();
+    assert_eq!(keys, (0..1000).collect::<Vec<_>>());
+    let keys: Vec<u64> =
+        typed.range(250..260).map(|res| res.unwrap().0).collect();
+    assert_eq!(keys, (250..260).collect::<Vec<_>>());
+    let keys: Vec<u64> =
+        typed.range(..=2).map(|res| res.unwrap().0).collect();
+    assert_eq!(keys, vec![0, 1, 2]);
+
+    assert_eq!(typed.get(&256), Ok(Some(("256".to_owned(), vec![256, 256]))));
+    assert_eq!(typed.get(&1000), Ok(None));
+    assert_eq!(typed.del(&256), Ok(Some(("256".to_owned(), vec![256, 256]))));
+    assert_eq!(typed.get(&256), Ok(None));
+
+    let one = ("1".to_owned(), vec![1, 1]);
+    let uno = ("uno".to_owned(), vec![]);
+    assert_eq!(
+        typed.cas(&1, Some(&uno), None),
+        Err(Error::CasFailed(Some(one.clone())))
+    );
+    assert_eq!(typed.cas(&1, Some(&one), Some(&uno)), Ok(()));
+    assert_eq!(typed.get(&1), Ok(Some(uno)));
+    assert_eq!(typed.cas(&256, Some(&one), None), Err(Error::CasFailed(None)));
+    assert_eq!(typed.cas(&256, None, Some(&one)), Ok(()));
+
+    // bytes that aren't what they should be are reported, not panicked on
+    t.set(vec![0, 0, 0, 0, 0, 0, 0, 7], vec![255]).unwrap();
+    match typed.get(&7) {
+        Err(Error::Serialization(_)) => {}
+        other => panic!("expected a serialization error, got {:?}", other),
+    }
+    t.set(vec![1], vec![]).unwrap();
+    let last = typed.range(1000..).next();
+    match last {
+        Some(Err(Error::Serialization(_))) => {}
+        other => panic!("expected a serialization error, got {:?}", other),
+    }
+}
+
+#[test]
+fn tree_ivec_values() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .snapshot_after_ops(100)
+        .flush_every_ms(None)
+        .blink_fanout(4)
+        .build();
+    let t = sled::Tree::start(config.clone()).unwrap();
+
+    // lengths on both sides of what's stored inline
+    let value = |len: usize| (0..len).map(|i| i as u8).collect::<Vec<u8>>();
+    for len in 0..64 {
+        t.set(vec![len as u8], value(len)).unwrap();
+    }
+
+    let check = |t: &sled::Tree| {
+        for len in 0..64 {
+            let v = t.get(&[len as u8]).unwrap().unwrap();
+            assert_eq!(v, value(len));
+            assert_eq!(&*v, &*value(len));
+            assert_eq!(v.clone(), v);
+            assert_eq!(Vec::from(v.clone()), value(len));
+            assert_eq!(IVec::from(value(len)), v);
+            assert_eq!(IVec::from(&*value(len)), v);
+        }
+    };
+    check(&t);
+
+    // ordered by their contents, like byte slices
+    let mut values: Vec<IVec> =
+        t.iter().values().map(|res| res.unwrap()).collect();
+    values.reverse();
+    values.sort();
+    let mut expected: Vec<IVec> =
+        (0..64).map(|len| value(len).into()).collect();
+    expected.sort_by_key(|v| v.to_vec());
+    assert_eq!(values, expected);
+
+    // the values are read back the same from the log and snapshots
+    drop(t);
+    let t = sled::Tree::start(config.clone()).unwrap();
+    check(&t);
+    for len in 0..64 {
+        t.set(vec![len as u8], value(63 - len)).unwrap();
+    }
+    drop(t);
+    let t = sled::Tree::start(config).unwrap();
+    for len in 0..64 {
+        assert_eq!(t.get(&[len as u8]), Ok(Some(value(63 - len).into())));
+    }
+}
+
+#[test]
+fn tree_flush_async() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .snapshot_after_ops(1_000_000)
+        .build();
+    let t = Arc::new(sled::Tree::start(config.clone()).unwrap());
+    t.flush().unwrap();
+    assert_eq!(t.flush(), Ok(0));
+
+    // each writer starts a flush of its writes, and is gone by the
+    // time the flushes are waited on
+    let writers: Vec<_> = (0..N_THREADS)
+        .map(|i| {
+            let t = t.clone();
+            thread::spawn(move || {
+                for j in 0..N_PER_THREAD {
+                    let k = kv(i * N_PER_THREAD + j);
+                    t.set(k.clone(), k).unwrap();
+                }
+                t.flush_async()
+            })
+        })
+        .collect();
+    let flushes: Vec<FlushHandle> =
+        writers.into_iter().map(|w| w.join().unwrap()).collect();
+    for flush in flushes {
+        flush.wait().unwrap();
+    }
+
+    // everything is stable now, so there's nothing left to flush
+    assert!(t.flush_async().is_done());
+    assert_eq!(t.flush(), Ok(0));
+
+    // recover from a copy of the files as they are now, while the tree is
+    // still running, as if it had crashed right after the flushes.
+    let _ = std::fs::remove_dir_all("test_tree_flush_async");
+    std::fs::create_dir_all("test_tree_flush_async").unwrap();
+    for file in &["conf", "db"] {
+        std::fs::copy(
+            config.get_path().join(file),
+            Path::new("test_tree_flush_async").join(file),
+        ).unwrap();
+    }
+    // the copy is recovered with the same configuration, which
+    // removes its directory when it's dropped.
+    let mut recovered_builder = (*config).clone();
+    recovered_builder.tmp_path = PathBuf::from("test_tree_flush_async");
+    let recovered = sled::Tree::start(recovered_builder.build()).unwrap();
+    for i in 0..N {
+        assert_eq!(recovered.get(&*kv(i)), Ok(Some(kv(i).into())));
+    }
+}
+
+#[test]
+fn tree_changes_since() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .blink_fanout(4)
+        .build();
+    let changes = |t: &sled::Tree, epoch| -> Vec<(IVec, Option<IVec>)> {
+        t.changes_since(epoch).map(|res| res.unwrap()).collect()
+    };
+    let change = |i: usize, v: Option<u8>| {
+        (IVec::from(kv(i)), v.map(|v| IVec::from(vec![v])))
+    };
+
+    let db = sled::Db::start(config.clone()).unwrap();
+    let t = db.open_tree(b"t".to_vec()).unwrap();
+    let untracked = db.open_tree(b"untracked".to_vec()).unwrap();
+    for i in 0..1000 {
+        t.set(kv(i), vec![0]).unwrap();
+    }
+
+    // writes from before tracking started aren't known
+    t.track_changes().unwrap();
+    let start = db.current_epoch();
+    assert!(untracked.changes_since(0).next().unwrap().is_err());
+    assert!(t.changes_since(start - 1).next().unwrap().is_err());
+    assert_eq!(changes(&t, start), vec![]);
+
+    // only the keys that were modified are returned, once each
+    for i in (0..1000).step_by(100) {
+        t.set(kv(i), vec![1]).unwrap();
+        t.set(kv(i), vec![2]).unwrap();
+    }
+    t.del(&*kv(500)).unwrap();
+    t.del(&*kv(1200)).unwrap();
+    untracked.set(kv(1), vec![1]).unwrap();
+    let expected: Vec<_> = (0..1000)
+        .step_by(100)
+        .map(|i| change(i, if i == 500 { None } else { Some(2) }))
+        .collect();
+    assert_eq!(changes(&t, start), expected);
+
+    // flushing ends the epoch, and only if it had changes
+    assert_eq!(db.current_epoch(), start);
+    t.flush().unwrap();
+    let next = db.current_epoch();
+    assert_eq!(next, start + 1);
+    t.flush().unwrap();
+    assert_eq!(db.current_epoch(), next);
+    assert_eq!(changes(&t, next), vec![]);
+
+    // keys are returned in the order of the last epoch they changed in
+    t.set(kv(0), vec![3]).unwrap();
+    t.set(kv(1), vec![3]).unwrap();
+    assert_eq!(
+        changes(&t, next),
+        vec![change(0, Some(3)), change(1, Some(3))]
+    );
+    let mut expected: Vec<_> = expected[1..].to_vec();
+    expected.push(change(0, Some(3)));
+    expected.push(change(1, Some(3)));
+    assert_eq!(changes(&t, start), expected);
+
+    // the epoch and the changes survive a restart
+    drop(t);
+    drop(untracked);
+    drop(db);
+    let db = sled::Db::start(config.clone()).unwrap();
+    let t = db.open_tree(b"t".to_vec()).unwrap();
+    assert_eq!(db.current_epoch(), next);
+    assert_eq!(changes(&t, start), expected);
+
+    // clearing removes every entry, reading the changes a chunk at a time
+    t.clear().unwrap();
+    let cleared: Vec<_> =
+        (0..1000).filter(|&i| i != 500).map(|i| change(i, None)).collect();
+    assert_eq!(changes(&t, next), cleared);
+    assert!(t.bulk_load(vec![(kv(1), vec![1])]).is_err());
+
+    // the changes are hidden, and go away with their tree
+    assert_eq!(db.tree_names().len(), 3);
+    match db.open_tree(b"__sled__changes__t".to_vec()) {
+        Err(Error::Unsupported(_)) => {}
+        Err(other) => panic!("opened the hidden changes tree: {:?}", other),
+        Ok(_) => panic!("opened the hidden changes tree"),
+    }
+    db.drop_tree(b"t").unwrap();
+    let t = db.open_tree(b"t".to_vec()).unwrap();
+    assert!(t.changes_since(next).next().unwrap().is_err());
+    assert_eq!(db.tree_names().len(), 3);
+}
+
+#[test]
+fn tree_temporary() {
+    let builder = ConfigBuilder::new().temporary(true).flush_every_ms(None);
+
+    // configs built from the same builder get paths of their own,
+    // so they can be used at the same time without colliding
+    let config_1 = builder.clone().build();
+    let config_2 = builder.build();
+    let path_1 = config_1.get_path();
+    let path_2 = config_2.get_path();
+    assert_ne!(path_1, path_2);
+
+    let db_1 = Db::start(config_1.clone()).unwrap();
+    let db_2 = Db::start(config_2).unwrap();
+    db_1.set(b"k".to_vec(), b"1".to_vec()).unwrap();
+    db_2.set(b"k".to_vec(), b"2".to_vec()).unwrap();
+    assert_eq!(db_1.get(b"k"), Ok(Some(b"1".to_vec().into())));
+    assert_eq!(db_2.get(b"k"), Ok(Some(b"2".to_vec().into())));
+    assert!(path_1.exists());
+    assert!(path_2.exists());
+
+    // a temporary config can only be used by one system at a time
+    match Db::start(config_1.clone()) {
+        Err(Error::Unsupported(_)) => {}
+        Err(other) => panic!("expected the config to be in use: {:?}", other),
+        Ok(_) => panic!("started a temporary config that was in use"),
+    }
+
+    // but it can be restarted once it's dropped, keeping its data,
+    // since the config still refers to its files
+    let tree = db_1.open_tree(b"tree".to_vec()).unwrap();
+    drop(db_1);
+    drop(tree);
+    assert!(path_1.exists());
+    let db_1 = Db::start(config_1).unwrap();
+    assert_eq!(db_1.get(b"k"), Ok(Some(b"1".to_vec().into())));
+
+    // the files are removed with the last handle to them
+    drop(db_1);
+    assert!(!path_1.exists());
+    drop(db_2);
+    assert!(!path_2.exists());
+
+    // including while unwinding from a panic
+    let config = ConfigBuilder::new().temporary(true).build();
+    let path = config.get_path();
+    let thread_path = path.clone();
+    let res = thread::spawn(move || {
+        let db = Db::start(config).unwrap();
+        db.set(b"k".to_vec(), b"v".to_vec()).unwrap();
+        db.flush().unwrap();
+        assert!(thread_path.exists());
+        panic!("unwinding with a temporary database open");
+    }).join();
+    assert!(res.is_err());
+    assert!(!path.exists());
+}
+
+#[test]
+fn tree_cache_capacity() {
+    const CAPACITY: usize = 1 << 20;
+    const VALUE_LEN: usize = 1000;
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .cache_capacity(CAPACITY)
+        .cache_bits(2)
+        .flush_every_ms(None)
+        .build();
+    let db = Db::start(config).unwrap();
+
+    // the most recently used page of each shard is kept even if it
+    // takes the shard over its share, so allow a few pages of slack
+    let slack = CAPACITY / 4;
+    let n = 10 * CAPACITY / VALUE_LEN;
+    let key = |i: usize| vec![(i >> 8) as u8, i as u8];
+    for i in 0..n {
+        db.set(key(i), vec![i as u8; VALUE_LEN]).unwrap();
+        if i % 100 == 0 {
+            let stats = db.cache_stats();
+            assert!(
+                stats.resident_bytes <= CAPACITY + slack,
+                "{} bytes resident after {} writes",
+                stats.resident_bytes,
+                i
+            );
+        }
+    }
+
+    let stats = db.cache_stats();
+    assert!(stats.evictions > 0);
+    assert!(stats.resident_bytes > CAPACITY / 2);
+    assert!(stats.resident_bytes <= CAPACITY + slack);
+
+    // evicted pages are read back from disk
+    for i in 0..n {
+        assert_eq!(db.get(&*key(i)), Ok(Some(vec![i as u8; VALUE_LEN].into())));
+    }
+    let after = db.cache_stats();
+    assert!(after.misses > stats.misses);
+    assert!(after.hit_rate() < 1.);
+    assert!(after.resident_bytes <= CAPACITY + slack);
+}
+
+fn json_value(i: usize) -> Vec<u8> {
+    let mut value = format!("{{\"id\": {}, \"tags\": [", i);
+    for j in 0..64 {
+        value.push_str(&*format!("{{\"name\": \"tag\", \"rank\": {}}}, ", j));
+    }
+    value.push_str("]}");
+    value.into_bytes()
+}
+
+#[test]
+fn tree_compression() {
+    let size_on_disk = |use_compression: bool| {
+        let config = ConfigBuilder::new()
+            .temporary(true)
+            .io_buf_size(1 << 17)
+            .use_compression(use_compression)
+            .zstd_compression_factor(3)
+            .flush_every_ms(None)
+            .build();
+        let db = Db::start(config).unwrap();
+        let mut logical_bytes = 0;
+        for i in 0..1200 {
+            let (k, v) = (kv(i), json_value(i));
+            logical_bytes += k.len() + v.len();
+            db.set(k, v).unwrap();
+        }
+        db.flush().unwrap();
+        for i in 0..1200 {
+            assert_eq!(db.get(&*kv(i)), Ok(Some(json_value(i).into())));
+        }
+        (db.size_on_disk().unwrap(), logical_bytes as u64)
+    };
+
+    let (plain, logical_bytes) = size_on_disk(false);
+    assert!(plain > logical_bytes);
+    let (compressed, _) = size_on_disk(true);
+    assert!(
+        compressed < logical_bytes / 3,
+        "{} bytes on disk for {} bytes of values",
+        compressed,
+        logical_bytes
+    );
+}
+
+#[test]
+fn tree_compression_recovery() {
+    let plain = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(1 << 20)
+        .use_compression(false)
+        .snapshot_after_ops(100)
+        .flush_every_ms(None)
+        .build();
+    // copied from the plain config, so it uses the same files
+    let compressed = (*plain).clone().use_compression(true).build();
+
+    let check = |db: &Db, n: usize| for i in 0..n {
+        assert_eq!(db.get(&*kv(i)), Ok(Some(json_value(i).into())));
+    };
+
+    // each restart switches compression on or off, and can
+    // read what was written before it
+    let configs = vec![&plain, &compressed, &plain, &compressed];
+    for (round, config) in configs.into_iter().enumerate() {
+        let db = Db::start(config.clone()).unwrap();
+        check(&db, round * 250);
+        for i in round * 250..(round + 1) * 250 {
+            db.set(kv(i), json_value(i)).unwrap();
+        }
+        check(&db, (round + 1) * 250);
+    }
+
+    let db = Db::start(plain.clone()).unwrap();
+    check(&db, 1000);
+}
+
+#[test]
+fn tree_read_only() {
+    const KEYS: usize = 3000;
+
+    fn key(i: usize) -> Vec<u8> {
+        vec![(i >> 8) as u8, i as u8]
+    }
+
+    // returns how many of the writer's keys were recovered,
+    // which must be a prefix of the ones that it wrote
+    fn check_prefix(db: &Db) -> usize {
+        let mut recovered = 0;
+        for res in db.iter() {
+            let (k, v) = res.unwrap();
+            assert_eq!(k, key(recovered));
+            assert_eq!(v, key(recovered));
+            recovered += 1;
+        }
+        recovered
+    }
+
+    // a missing system is an error, rather than being created
+    let missing = ConfigBuilder::new().temporary(true).build();
+    let missing_path = missing.get_path();
+    match Db::start((*missing).clone().read_only(true).build()) {
+        Err(Error::Io(_)) => {}
+        Err(other) => panic!("expected a missing system: {:?}", other),
+        Ok(_) => panic!("started a read-only system that doesn't exist"),
+    }
+    assert!(!missing_path.exists());
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(Some(1))
+        .build();
+    let path = config.get_path();
+    let read_only = (*config).clone().read_only(true).build();
+    let db = Db::start(config).unwrap();
+    db.set(key(0), key(0)).unwrap();
+    db.flush().unwrap();
+
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let db = db.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            for i in 1..KEYS {
+                db.set(key(i), key(i)).unwrap();
+            }
+            db.flush().unwrap();
+            done.store(true, Ordering::SeqCst);
+        })
+    };
+
+    // systems opened while the writer runs see a prefix of its writes
+    loop {
+        let finished = done.load(Ordering::SeqCst);
+        let reader = Db::start(read_only.clone()).unwrap();
+        let recovered = check_prefix(&reader);
+        assert!(recovered >= 1);
+        if finished {
+            assert_eq!(recovered, KEYS);
+            break;
+        }
+    }
+    writer.join().unwrap();
+
+    // and nothing can be written to them
+    let reader = Db::start(read_only.clone()).unwrap();
+    assert_eq!(reader.set(key(0), vec![]), Err(Error::ReadOnly));
+    assert_eq!(reader.del(&*key(0)), Err(Error::ReadOnly));
+    let mut batch = Batch::default();
+    batch.insert(key(0), vec![]);
+    assert_eq!(reader.apply_batch(batch), Err(Error::ReadOnly));
+    let res = reader.transaction::<_, _, ()>(|tx| {
+        tx.set(key(0), vec![]);
+        Ok(())
+    });
+    assert_eq!(res, Err(TransactionError::Storage(Error::ReadOnly)));
+    assert_eq!(reader.flush(), Err(Error::ReadOnly));
+
+    // their state is the one they started with
+    db.set(key(KEYS), key(KEYS)).unwrap();
+    db.flush().unwrap();
+    assert_eq!(reader.get(&*key(KEYS)), Ok(None));
+    assert_eq!(check_prefix(&reader), KEYS);
+    let later = Db::start(read_only.clone()).unwrap();
+    assert_eq!(later.get(&*key(KEYS)), Ok(Some(key(KEYS).into())));
+
+    // read-only systems leave the writer's temporary files alone
+    drop((reader, later, read_only));
+    assert!(path.exists());
+    assert_eq!(db.get(&*key(0)), Ok(Some(key(0).into())));
+    drop(db);
+    assert!(!path.exists());
+}
+
+#[test]
+fn tree_flush_every_ms() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(Some(60 * 60 * 1000))
+        .build();
+    let db = Db::start(config.clone()).unwrap();
+    db.set(vec![1], vec![1]).unwrap();
+
+    // dropping the system stops its flush thread without
+    // waiting for the rest of the interval
+    let before = Instant::now();
+    drop(db);
+    assert!(before.elapsed() < Duration::from_secs(10));
+
+    // and the log is flushed on the way out
+    let db = Db::start(config).unwrap();
+    assert_eq!(db.get(&[1]), Ok(Some(vec![1].into())));
+}
+
+#[test]
+fn tree_snapshot_truncated() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(1 << 20)
+        .snapshot_after_ops(100)
+        .flush_every_ms(None)
+        .build();
+
+    let db = Db::start(config.clone()).unwrap();
+    for i in 0..1000 {
+        db.set(kv(i), kv(i)).unwrap();
+    }
+    drop(db);
+
+    // the previous snapshot is kept as a fallback
+    let mut snapshots = config.get_snapshot_files().unwrap();
+    assert!(snapshots.len() >= 2);
+
+    // chop the newest snapshot in half, as if it was torn
+    snapshots.sort();
+    let newest = snapshots.pop().unwrap();
+    let f = fs::OpenOptions::new().write(true).open(&newest).unwrap();
+    let len = f.metadata().unwrap().len();
+    f.set_len(len / 2).unwrap();
+    drop(f);
+
+    let db = Db::start(config.clone()).unwrap();
+    for i in 0..1000 {
+        assert_eq!(db.get(&*kv(i)), Ok(Some(kv(i).into())));
+    }
+    for i in 1000..1100 {
+        db.set(kv(i), kv(i)).unwrap();
+    }
+    drop(db);
+
+    // the snapshots written since then can be recovered from
+    let db = Db::start(config).unwrap();
+    for i in 0..1100 {
+        assert_eq!(db.get(&*kv(i)), Ok(Some(kv(i).into())));
+    }
+}
+
+#[test]
+fn tree_recovers_prefix_after_corruption() {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    const N_KEYS: usize = 1000;
+    const SEGMENT_LEN: usize = 1 << 16;
+    let value = |i: usize| vec![i as u8; 64];
+
+    // flip a byte at each of these fractions of the way through
+    // what was written to the last segment of the log
+    for &(num, den) in &[(0, 1), (1, 4), (1, 2), (3, 4), (1, 1)] {
+        let config = ConfigBuilder::new()
+            .temporary(true)
+            .io_buf_size(SEGMENT_LEN)
+            .segment_mode(pagecache::SegmentMode::Linear)
+            .snapshot_after_ops(1_000_000)
+            .flush_every_ms(None)
+            .build();
+
+        let db = Db::start(config.clone()).unwrap();
+        for i in 0..N_KEYS {
+            db.set(kv(i), value(i)).unwrap();
+        }
+        drop(db);
+
+        let mut f = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(config.get_path().join("db"))
+            .unwrap();
+        let len = f.metadata().unwrap().len() as usize;
+        let segment_start = (len - 1) / SEGMENT_LEN * SEGMENT_LEN;
+        let mut segment = vec![];
+        f.seek(SeekFrom::Start(segment_start as u64)).unwrap();
+        f.read_to_end(&mut segment).unwrap();
+
+        // the unwritten rest of the segment is filled with one byte
+        let filler = segment[segment.len() - 1];
+        let first = pagecache::SEG_HEADER_LEN;
+        let last = segment.iter().rposition(|&b| b != filler).unwrap();
+        assert!(last > first);
+        let at = segment_start + first + (last - first) * num / den;
+
+        f.seek(SeekFrom::Start(at as u64)).unwrap();
+        f.write_all(&[segment[at - segment_start] ^ 0xFF]).unwrap();
+        drop(f);
+
+        // the tail of the log is lost, but what's left is a prefix
+        // of the writes, and nothing is read back wrong
+        let db = Db::start(config.clone()).unwrap();
+        let info = db.recovery_info();
+        assert!(info.segments_scanned > 0);
+        assert!(info.bytes_discarded > 0);
+
+        let recovered = (0..N_KEYS)
+            .take_while(|&i| db.get(&*kv(i)).unwrap().is_some())
+            .count();
+        assert!(recovered > 0);
+        for i in 0..N_KEYS {
+            let expected = if i < recovered { Some(value(i)) } else { None };
+            assert_eq!(
+                db.get(&*kv(i)).unwrap().map(|v| v.to_vec()),
+                expected,
+                "key {} is wrong after flipping the byte at {}",
+                i,
+                at
+            );
+        }
+
+        // the log carries on from where recovery stopped
+        for i in recovered..N_KEYS {
+            db.set(kv(i), value(i)).unwrap();
+        }
+        drop(db);
+
+        let db = Db::start(config).unwrap();
+        assert_eq!(db.recovery_info().bytes_discarded, 0);
+        for i in 0..N_KEYS {
+            assert_eq!(db.get(&*kv(i)), Ok(Some(value(i).into())));
+        }
+    }
+}
+
+#[test]
+fn tree_refuses_unreadable_log() {
+    use std::io::{Seek, SeekFrom, Write};
+
+    let path = env::temp_dir()
+        .join(format!("sled_unreadable_log.{}", std::process::id()));
+    let _ = fs::remove_dir_all(&path);
+
+    // each start gets a Config of its own, like a new process would
+    let config = || {
+        ConfigBuilder::new()
+            .path(&path)
+            .io_buf_size(1 << 16)
+            .segment_mode(pagecache::SegmentMode::Linear)
+            .flush_every_ms(None)
+            .build()
+    };
+
+    let db = Db::start(config()).unwrap();
+    for i in 0..100 {
+        db.set(kv(i), kv(i)).unwrap();
+    }
+    drop(db);
+    let written = fs::read(path.join("db")).unwrap();
+
+    // without a conf file recording its format, the log is assumed
+    // to be in a format that can't be read, and is left alone
+    let conf = fs::read(path.join("conf")).unwrap();
+    fs::remove_file(path.join("conf")).unwrap();
+    match Db::start(config()).err() {
+        Some(Error::Unsupported(_)) => {}
+        other => panic!("expected the log to be refused: {:?}", other),
+    }
+    assert_eq!(fs::read(path.join("db")).unwrap(), written);
+
+    fs::write(path.join("conf"), conf).unwrap();
+    let db = Db::start(config()).unwrap();
+    assert_eq!(db.get(&*kv(99)), Ok(Some(kv(99).into())));
+    drop(db);
+
+    // a log without a single message that passes its checks is
+    // corrupt, and is left alone rather than started afresh
+    for snapshot in config().get_snapshot_files().unwrap() {
+        fs::remove_file(snapshot).unwrap();
+    }
+    let mut f = fs::OpenOptions::new()
+        .write(true)
+        .open(path.join("db"))
+        .unwrap();
+    let at = pagecache::SEG_HEADER_LEN as u64;
+    f.seek(SeekFrom::Start(at)).unwrap();
+    f.write_all(&[0xFF]).unwrap();
+    drop(f);
+    let corrupted = fs::read(path.join("db")).unwrap();
+
+    match Db::start(config()).err() {
+        Some(Error::Corruption { at: lid }) => assert_eq!(lid, at),
+        other => panic!("expected the log to be refused: {:?}", other),
+    }
+    assert_eq!(fs::read(path.join("db")).unwrap(), corrupted);
+
+    fs::remove_dir_all(&path).unwrap();
+}
+
+// exit codes of tree_single_writer_child
+const CHILD_BUSY: i32 = 2;
+const CHILD_CRASHED: i32 = 3;
+
+fn single_writer_config(path: &Path) -> pagecache::Config {
+    ConfigBuilder::new().path(path).flush_every_ms(None).build()
+}
+
+#[test]
+fn tree_single_writer() {
+    fn run_child(path: &Path, mode: &str) -> Option<i32> {
+        let output = Command::new(env::current_exe().unwrap())
+            .arg("tree_single_writer_child")
+            .arg("--exact")
+            .arg("--ignored")
+            .env("SLED_SINGLE_WRITER_PATH", path)
+            .env("SLED_SINGLE_WRITER_MODE", mode)
+            .output()
+            .unwrap();
+        output.status.code()
+    }
+
+    let path = env::temp_dir()
+        .join(format!("sled_single_writer.{}", std::process::id()));
+    let _ = fs::remove_dir_all(&path);
+
+    // a running system can't be started again by another process
+    let db = Db::start(single_writer_config(&path)).unwrap();
+    assert_eq!(run_child(&path, "open"), Some(CHILD_BUSY));
+
+    // or by this one
+    match Db::start(single_writer_config(&path)) {
+        Err(Error::Busy(ref busy)) if *busy == path => {}
+        Err(other) => panic!("expected the system to be busy: {:?}", other),
+        Ok(_) => panic!("started a system that was already running"),
+    }
+
+    // until it has been dropped
+    drop(db);
+    assert_eq!(run_child(&path, "open"), Some(0));
+
+    // a process that dies leaves its lock file behind,
+    // which doesn't stop the system from being started
+    assert_eq!(run_child(&path, "crash"), Some(CHILD_CRASHED));
+    assert!(path.join("lock").exists());
+    let db = Db::start(single_writer_config(&path)).unwrap();
+    assert_eq!(db.get(b"open"), Ok(Some(vec![1].into())));
+    assert_eq!(db.get(b"crash"), Ok(Some(vec![1].into())));
+
+    // read-only systems take no lock, so another process can read
+    // while this one writes, and write while this one reads.
+    assert_eq!(run_child(&path, "read"), Some(0));
+    drop(db);
+    let read_only = ConfigBuilder::new()
+        .path(&path)
+        .flush_every_ms(None)
+        .read_only(true)
+        .build();
+    let reader = Db::start(read_only).unwrap();
+    assert_eq!(run_child(&path, "open"), Some(0));
+    assert_eq!(reader.get(b"open"), Ok(Some(vec![1].into())));
+
+    drop(reader);
+    fs::remove_dir_all(&path).unwrap();
+}
+
+// run in another process by tree_single_writer
+#[test]
+#[ignore]
+fn tree_single_writer_child() {
+    let path = match env::var("SLED_SINGLE_WRITER_PATH") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    let mode = env::var("SLED_SINGLE_WRITER_MODE").unwrap();
+
+    if mode == "read" {
+        let read_only = ConfigBuilder::new()
+            .path(path)
+            .flush_every_ms(None)
+            .read_only(true)
+            .build();
+        let db = Db::start(read_only).unwrap();
+        assert_eq!(db.get(b"open"), Ok(Some(vec![1].into())));
+        return;
+    }
+
+    let db = match Db::start(single_writer_config(path.as_ref())) {
+        Ok(db) => db,
+        Err(Error::Busy(_)) => std::process::exit(CHILD_BUSY),
+        Err(other) => panic!("failed to start: {:?}", other),
+    };
+    db.set(mode.clone().into_bytes(), vec![1]).unwrap();
+    db.flush().unwrap();
+
+    if mode == "crash" {
+        // exits without dropping the system
+        std::process::exit(CHILD_CRASHED);
+    }
+}
+
+#[test]
+fn tree_size_limits() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .max_key_size(4)
+        .max_value_size(8)
+        .merge_operator(test_merge_operator)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+
+    let key_error = || {
+        Error::TooLarge {
+            limit: "max_key_size",
+            max: 4,
+            size: 5,
+        }
+    };
+    let value_error = || {
+        Error::TooLarge {
+            limit: "max_value_size",
+            max: 8,
+            size: 9,
+        }
+    };
+
+    // writes right at the limits, and empty keys, are fine
+    t.set(vec![1; 4], vec![1; 8]).unwrap();
+    t.set(vec![], vec![2]).unwrap();
+    assert_eq!(t.get(&[]), Ok(Some(vec![2].into())));
+    assert_eq!(t.iter().next(), Some(Ok((vec![], vec![2].into()))));
+
+    assert_eq!(t.set(vec![1; 5], vec![]), Err(key_error()));
+    assert_eq!(t.set(vec![1], vec![1; 9]), Err(value_error()));
+    assert_eq!(t.merge(vec![1; 5], vec![1]), Err(key_error()));
+    assert_eq!(t.merge(vec![1], vec![1; 9]), Err(value_error()));
+    assert_eq!(
+        t.cas(vec![1; 4], Some(vec![1; 8]), Some(vec![1; 9])),
+        Err(Error::TooLarge {
+            limit: "max_value_size",
+            max: 8,
+            size: 9,
+        })
+    );
+    assert_eq!(
+        t.set_with_ttl(vec![1; 5], vec![], Duration::from_secs(60)),
+        Err(key_error())
+    );
+
+    // none of a batch is applied if any of it is too large
+    let mut batch = Batch::default();
+    batch.insert(vec![2], vec![2]);
+    batch.insert(vec![3], vec![1; 9]);
+    assert_eq!(t.apply_batch(batch), Err(value_error()));
+    assert_eq!(t.get(&[2]), Ok(None));
+
+    let res = t.transaction::<_, _, ()>(|tx| {
+        tx.set(vec![2], vec![2]);
+        tx.set(vec![1; 5], vec![]);
+        Ok(())
+    });
+    assert_eq!(res, Err(TransactionError::Storage(key_error())));
+    assert_eq!(t.get(&[2]), Ok(None));
+
+    assert_eq!(t.get(&[1; 4]), Ok(Some(vec![1; 8].into())));
+    assert_eq!(t.del(&[]), Ok(Some(vec![2].into())));
+    assert_eq!(t.len(), 1);
+}
+
+#[test]
+fn tree_batch_too_large() {
+    // compression would shrink the batch to fit
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(10000)
+        .use_compression(false)
+        .build();
+    let max_value_size = config.get_max_value_size();
+    let max_message_size = config.max_message_size();
+    let t = sled::Tree::start(config).unwrap();
+
+    // every value is allowed, but together they're too large to log
+    let mut batch = Batch::default();
+    for i in 0..(max_message_size / max_value_size + 1) {
+        batch.insert(vec![i as u8], vec![0; max_value_size]);
+    }
+    match t.apply_batch(batch) {
+        Err(Error::TooLarge { limit: "max_message_size", .. }) => {}
+        other => panic!("expected the batch to be too large, got {:?}", other),
+    }
+    assert!(t.is_empty());
+
+    // values that are too large are refused up front
+    assert_eq!(
+        t.set(vec![], vec![0; max_value_size + 1]),
+        Err(Error::TooLarge {
+            limit: "max_value_size",
+            max: max_value_size,
+            size: max_value_size + 1,
+        })
+    );
+}
+
+#[test]
+fn tree_multi_get() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(2)
+        .flush_every_ms(None)
+        .build();
+    let db = sled::Db::start(config).unwrap();
+    let t = db.open_tree(b"t".to_vec()).unwrap();
+    for i in (0..N).filter(|i| i % 3 != 0) {
+        t.set(kv(i), vec![i as u8]).unwrap();
+    }
+
+    // scattered across every leaf, unsorted, with duplicates
+    // and missing keys, and compared against separate gets.
+    let keys: Vec<_> = (0..N)
+        .map(|i| kv((i * 7919) % N))
+        .chain((0..N).rev().map(kv))
+        .chain(vec![vec![], vec![255; 4]])
+        .collect();
+    let expected: Vec<_> = keys.iter().map(|k| t.get(k)).collect();
+    assert_eq!(t.multi_get(&keys), expected);
+
+    // clustered in a few leaves
+    let keys = vec![kv(11), kv(10), kv(12), kv(9), kv(11)];
+    let values = t.multi_get(keys);
+    assert_eq!(
+        values,
+        vec![
+            Ok(Some(vec![11].into())),
+            Ok(Some(vec![10].into())),
+            Ok(None),
+            Ok(None),
+            Ok(Some(vec![11].into())),
+        ]
+    );
+
+    assert_eq!(t.multi_get(Vec::<Vec<u8>>::new()), vec![]);
+
+    // expired entries are absent
+    t.set_with_ttl(kv(1), vec![], Duration::from_secs(0)).unwrap();
+    let values = t.multi_get(&[kv(1), kv(2)]);
+    assert_eq!(values, vec![Ok(None), Ok(Some(vec![2].into()))]);
+
+    // every lookup fails on a dropped tree
+    db.drop_tree(b"t").unwrap();
+    let values = t.multi_get(&[kv(1), kv(2)]);
+    assert_eq!(values.len(), 2);
+    assert!(values.iter().all(|res| res.is_err()));
+}
+
+#[test]
+fn tree_snapshot() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(2)
+        .flush_every_ms(None)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    for i in 0..N_PER_THREAD {
+        t.set(kv(i), vec![0]).unwrap();
+    }
+    let before: Vec<_> = t.iter().map(|res| res.unwrap()).collect();
+
+    let snapshot = t.snapshot().unwrap();
+    for i in 0..N_PER_THREAD {
+        t.set(kv(i), vec![1]).unwrap();
+    }
+    t.del(&*kv(0)).unwrap();
+    t.set(kv(N_PER_THREAD), vec![1]).unwrap();
+
+    for i in 0..N_PER_THREAD {
+        assert_eq!(snapshot.get(&*kv(i)), Ok(Some(vec![0].into())));
+    }
+    assert_eq!(snapshot.get(&*kv(N_PER_THREAD)), Ok(None));
+    assert_eq!(t.get(&*kv(0)), Ok(None));
+    assert_eq!(t.get(&*kv(1)), Ok(Some(vec![1].into())));
 
-    let last_key = kv(N_PER_THREAD - 1);
-    let mut tree_scan = t.scan(&*last_key);
-    assert_eq!(tree_scan.next(), Some(Ok((last_key.clone(), last_key))));
-    assert_eq!(tree_scan.next(), None);
+    let iterated: Vec<_> = snapshot.iter().map(|res| res.unwrap()).collect();
+    assert_eq!(iterated, before);
+    let ranged: Vec<_> = snapshot
+        .range(kv(10)..=kv(20))
+        .map(|res| res.unwrap())
+        .collect();
+    assert_eq!(&*ranged, &before[10..21]);
+    let prefixed: Vec<_> =
+        snapshot.scan_prefix(&[0, 1]).map(|res| res.unwrap()).collect();
+    assert_eq!(&*prefixed, &before[256..N_PER_THREAD]);
+
+    // clearing the tree keeps everything the snapshot can see
+    t.clear().unwrap();
+    t.set(kv(5), vec![2]).unwrap();
+    let iterated: Vec<_> = snapshot.iter().map(|res| res.unwrap()).collect();
+    assert_eq!(iterated, before);
+    assert_eq!(snapshot.get(&*kv(5)), Ok(Some(vec![0].into())));
+
+    // a new snapshot sees the tree as it is now
+    drop(snapshot);
+    let snapshot = t.snapshot().unwrap();
+    t.set(kv(5), vec![3]).unwrap();
+    let iterated: Vec<_> = snapshot.iter().map(|res| res.unwrap()).collect();
+    assert_eq!(iterated, vec![(kv(5), vec![2].into())]);
 }
 
 #[test]
-fn recover_tree() {
-    println!("========== recovery ==========");
+fn tree_snapshot_during_writes() {
     let config = ConfigBuilder::new()
         .temporary(true)
         .blink_fanout(2)
-        .io_buf_size(5000)
         .flush_every_ms(None)
-        .snapshot_after_ops(100)
         .build();
-    let t = sled::Tree::start(config.clone()).unwrap();
+    let t = Arc::new(sled::Tree::start(config).unwrap());
+    for i in (0..N).filter(|i| i % 2 == 0) {
+        t.set(kv(i), vec![0]).unwrap();
+    }
+    let before: Vec<_> = t.iter().map(|res| res.unwrap()).collect();
+    let snapshot = t.snapshot().unwrap();
+
+    // overwrite, remove, and fill in keys while the snapshot is read,
+    // splitting leaves along the way.
+    let writers: Vec<_> = (0..N_THREADS)
+        .map(|id| {
+            let t = t.clone();
+            thread::spawn(move || for i in 0..N {
+                if i % N_THREADS != id {
+                    continue;
+                }
+                match i % 3 {
+                    0 => t.set(kv(i), vec![1]).unwrap(),
+                    1 => {
+                        let _ = t.cas(kv(i), Some(vec![0]), Some(vec![1]));
+                    }
+                    _ => {
+                        t.del(&*kv(i)).unwrap();
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for _ in 0..5 {
+        let iterated: Vec<_> =
+            snapshot.iter().map(|res| res.unwrap()).collect();
+        assert_eq!(iterated, before);
+    }
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    let iterated: Vec<_> = snapshot.iter().map(|res| res.unwrap()).collect();
+    assert_eq!(iterated, before);
+    for i in 0..N {
+        let expected = if i % 2 == 0 { Some(vec![0].into()) } else { None };
+        assert_eq!(snapshot.get(&*kv(i)), Ok(expected));
+    }
+}
+
+#[test]
+fn tree_snapshot_ttl() {
+    static NOW: AtomicUsize = AtomicUsize::new(1_000);
+    fn clock() -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(NOW.load(Ordering::SeqCst) as u64)
+    }
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    t.set_expiration_clock(clock);
+
+    t.set_with_ttl(kv(1), vec![1], Duration::from_millis(10)).unwrap();
+    t.set_with_ttl(kv(2), vec![2], Duration::from_millis(100)).unwrap();
+    t.set_with_ttl(kv(3), vec![3], Duration::from_millis(10)).unwrap();
+    NOW.fetch_add(50, Ordering::SeqCst);
+
+    // entries are seen as they were when the snapshot was taken, even
+    // after they expire, or are given a new deadline.
+    let snapshot = t.snapshot().unwrap();
+    NOW.fetch_add(100, Ordering::SeqCst);
+    t.set_with_ttl(kv(3), vec![4], Duration::from_millis(10)).unwrap();
+    t.del(&*kv(2)).unwrap();
+
+    let expected = vec![(kv(2), vec![2].into())];
+    let iterated: Vec<_> = snapshot.iter().map(|res| res.unwrap()).collect();
+    assert_eq!(iterated, expected);
+    assert_eq!(snapshot.get(&*kv(1)), Ok(None));
+    assert_eq!(snapshot.get(&*kv(2)), Ok(Some(vec![2].into())));
+    assert_eq!(snapshot.get(&*kv(3)), Ok(None));
+    assert_eq!(t.get(&*kv(3)), Ok(Some(vec![4].into())));
+}
+
+#[test]
+fn tree_ttl_reads() {
+    static NOW: AtomicUsize = AtomicUsize::new(1_000);
+    fn clock() -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(NOW.load(Ordering::SeqCst) as u64)
+    }
+    let advance = |ms| NOW.fetch_add(ms, Ordering::SeqCst);
+    let ttl = Duration::from_millis;
+
+    // nothing is removed in the background without a flusher
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(2)
+        .flush_every_ms(None)
+        .build();
+    let t = sled::Db::start(config).unwrap();
+    t.set_expiration_clock(clock);
+
     for i in 0..N_PER_THREAD {
-        let k = kv(i);
-        t.set(k.clone(), k).unwrap();
+        t.set(kv(i), vec![1]).unwrap();
     }
-    drop(t);
+    // every other key expires, at different times
+    for i in (0..N_PER_THREAD).filter(|i| i % 2 == 0) {
+        t.set_with_ttl(kv(i), vec![2], ttl(10 + i as u64)).unwrap();
+    }
+    assert_eq!(t.get(&*kv(0)), Ok(Some(vec![2].into())));
+    assert_eq!(t.iter().count(), N_PER_THREAD);
 
-    let t = sled::Tree::start(config.clone()).unwrap();
-    for i in 0..config.blink_fanout << 1 {
-        let k = kv(i as usize);
-        assert_eq!(t.get(&*k), Ok(Some(k.clone())));
-        t.del(&*k).unwrap();
+    advance(10 + N_PER_THREAD);
+    let odd: Vec<_> =
+        (0..N_PER_THREAD).filter(|i| i % 2 == 1).map(kv).collect();
+    let keys: Vec<_> = t.iter().keys().map(|r| r.unwrap()).collect();
+    assert_eq!(keys, odd);
+    let keys: Vec<_> = t.iter().keys().rev().map(|r| r.unwrap()).collect();
+    assert_eq!(keys, odd.iter().rev().cloned().collect::<Vec<_>>());
+    assert_eq!(t.get(&*kv(0)), Ok(None));
+    assert_eq!(t.get_gt(&*kv(0)), Ok(Some((kv(1), vec![1].into()))));
+    assert_eq!(t.get_lt(&*kv(3)), Ok(Some((kv(1), vec![1].into()))));
+    assert_eq!(t.first(), Ok(Some((kv(1), vec![1].into()))));
+    assert_eq!(t.last(), Ok(Some((kv(N_PER_THREAD - 1), vec![1].into()))));
+
+    // expired entries are still counted until they're removed
+    assert_eq!(t.len(), N_PER_THREAD);
+
+    // cas treats expired entries as absent
+    assert_eq!(
+        t.cas(kv(0), Some(vec![2]), Some(vec![3])),
+        Err(Error::CasFailed(None))
+    );
+    assert_eq!(t.cas(kv(0), None, Some(vec![3])), Ok(()));
+    assert_eq!(t.del(&*kv(2)), Ok(None));
+
+    // writing a key removes its deadline
+    t.set_with_ttl(kv(4), vec![4], ttl(10)).unwrap();
+    t.set(kv(4), vec![5]).unwrap();
+    t.set_with_ttl(kv(6), vec![6], ttl(10)).unwrap();
+    let mut batch = Batch::default();
+    batch.insert(kv(6), vec![7]);
+    t.apply_batch(batch).unwrap();
+    t.set_with_ttl(kv(8), vec![8], ttl(10)).unwrap();
+    t.set_with_ttl(kv(8), vec![9], ttl(1_000)).unwrap();
+    advance(100);
+    assert_eq!(t.get(&*kv(0)), Ok(Some(vec![3].into())));
+    assert_eq!(t.get(&*kv(4)), Ok(Some(vec![5].into())));
+    assert_eq!(t.get(&*kv(6)), Ok(Some(vec![7].into())));
+    assert_eq!(t.get(&*kv(8)), Ok(Some(vec![9].into())));
+    assert_eq!(t.cas(kv(8), Some(vec![9]), None), Ok(()));
+    assert_eq!(t.get(&*kv(8)), Ok(None));
+
+    // merging keeps a deadline, unless it has already passed
+    t.set_merge_operator(test_merge_operator);
+    t.set_with_ttl(vec![1], vec![0, 1], ttl(10)).unwrap();
+    t.merge(vec![1], vec![1]).unwrap();
+    assert_eq!(t.get(&[1]), Ok(Some(vec![0, 2].into())));
+    advance(10);
+    assert_eq!(t.get(&[1]), Ok(None));
+    t.merge(vec![1], vec![1]).unwrap();
+    advance(100);
+    assert_eq!(t.get(&[1]), Ok(Some(vec![0, 1].into())));
+}
+
+#[test]
+fn tree_ttl_background_removal() {
+    static NOW: AtomicUsize = AtomicUsize::new(1_000);
+    fn clock() -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(NOW.load(Ordering::SeqCst) as u64)
     }
-    drop(t);
 
-    let t = sled::Tree::start(config.clone()).unwrap();
-    for i in 0..config.blink_fanout << 1 {
-        let k = kv(i as usize);
-        assert_eq!(t.get(&*k), Ok(None));
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(2)
+        .flush_every_ms(Some(1))
+        .build();
+    let db = sled::Db::start(config).unwrap();
+    let t = db.open_tree(b"t".to_vec()).unwrap();
+    t.set_expiration_clock(clock);
+
+    // more than are removed in a single pass
+    for i in 0..N_PER_THREAD {
+        t.set_with_ttl(kv(i), vec![], Duration::from_millis(10)).unwrap();
+    }
+    t.set_with_ttl(vec![255], vec![], Duration::from_secs(60)).unwrap();
+    let mut subscriber = t.watch_prefix(vec![]);
+
+    NOW.fetch_add(10, Ordering::SeqCst);
+    for i in 0..N_PER_THREAD {
+        let event = subscriber.next_timeout(Duration::from_secs(10));
+        assert_eq!(event, Ok(Event::Remove { key: kv(i) }));
+    }
+    assert_eq!(t.len(), 1);
+    assert_eq!(t.iter().keys().next(), Some(Ok(vec![255])));
+
+    // the deadlines are hidden, and go away with their tree
+    assert_eq!(db.tree_names().len(), 2);
+    assert!(db.open_tree(b"__sled__ttl__t".to_vec()).is_err());
+    db.drop_tree(b"t").unwrap();
+    let t = db.open_tree(b"t".to_vec()).unwrap();
+    t.set(kv(0), vec![]).unwrap();
+    NOW.fetch_add(100_000, Ordering::SeqCst);
+    assert_eq!(t.get(&*kv(0)), Ok(Some(vec![].into())));
+}
+
+#[test]
+fn tree_ttl_recovers() {
+    static NOW: AtomicUsize = AtomicUsize::new(1_000);
+    fn clock() -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(NOW.load(Ordering::SeqCst) as u64)
+    }
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .build();
+    let db = sled::Db::start(config.clone()).unwrap();
+    let t = db.open_tree(b"t".to_vec()).unwrap();
+    t.set_expiration_clock(clock);
+    t.set_with_ttl(vec![1], vec![1], Duration::from_millis(10)).unwrap();
+    t.set_with_ttl(vec![2], vec![2], Duration::from_millis(20)).unwrap();
+    t.set_with_ttl(vec![3], vec![3], Duration::from_millis(10)).unwrap();
+    t.set(vec![3], vec![3]).unwrap();
+    db.set_with_ttl(vec![4], vec![4], Duration::from_secs(60)).unwrap();
+    drop((db, t));
+
+    let db = sled::Db::start(config).unwrap();
+    let t = db.open_tree(b"t".to_vec()).unwrap();
+    t.set_expiration_clock(clock);
+    assert_eq!(t.iter().count(), 3);
+
+    NOW.fetch_add(10, Ordering::SeqCst);
+    assert_eq!(t.get(&[1]), Ok(None));
+    assert_eq!(t.get(&[2]), Ok(Some(vec![2].into())));
+    assert_eq!(t.get(&[3]), Ok(Some(vec![3].into())));
+    assert_eq!(db.get(&[4]), Ok(Some(vec![4].into())));
+}
+
+#[test]
+fn tree_space_usage() {
+    // leaves only hold a few of these values, so they're consolidated
+    // often enough for deletions to leave their old segments unused.
+    // disk_bytes is counted before compression, so it's left off to
+    // compare it with the size on disk.
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(100_000)
+        .page_consolidation_threshold(3)
+        .flush_every_ms(None)
+        .use_compression(false)
+        .build();
+    let db = sled::Db::start(config.clone()).unwrap();
+    let t = db.open_tree(b"t".to_vec()).unwrap();
+    let empty_size = db.size_on_disk().unwrap();
+
+    for i in 0..N {
+        t.set(kv(i), vec![0; 1000]).unwrap();
+    }
+    let full = t.space_usage().unwrap();
+    let full_size = db.size_on_disk().unwrap();
+    assert_eq!(full.key_bytes, N as u64 * kv(0).len() as u64);
+    assert_eq!(full.value_bytes, N as u64 * 1000);
+    assert!(full.disk_bytes > full.key_bytes + full.value_bytes);
+    assert!(full.fragmentation > 0. && full.fragmentation < 0.5);
+    assert!(full_size >= full.disk_bytes);
+    assert!(full_size > empty_size + N as u64 * 1000);
+    assert!(db.space_usage().unwrap().disk_bytes < 10_000);
+
+    drop((db, t));
+    let db = sled::Db::start(config).unwrap();
+    let t = db.open_tree(b"t".to_vec()).unwrap();
+    let recovered = t.space_usage().unwrap();
+    assert_eq!(recovered.key_bytes, full.key_bytes);
+    assert_eq!(recovered.value_bytes, full.value_bytes);
+    assert!(recovered.disk_bytes > full.key_bytes + full.value_bytes);
+    assert!(recovered.disk_bytes <= full.disk_bytes);
+
+    for i in 0..N {
+        t.del(&*kv(i)).unwrap();
+    }
+
+    // segments are cleaned as later writes come in
+    let mut i = 0;
+    while db.size_on_disk().unwrap() > full_size / 2 {
+        assert!(i < 100_000, "segments were never cleaned");
+        db.set(kv(i % 100), vec![]).unwrap();
+        i += 1;
+    }
+
+    let emptied = t.space_usage().unwrap();
+    assert_eq!(emptied.key_bytes, 0);
+    assert_eq!(emptied.value_bytes, 0);
+    assert!(emptied.disk_bytes < full.disk_bytes / 2);
+    assert_eq!(emptied.fragmentation, 1.);
+}
+
+#[test]
+fn tree_space_usage_counts() {
+    fn concatenate(
+        _k: &[u8],
+        old: Option<&[u8]>,
+        merged: &[u8],
+    ) -> Option<Vec<u8>> {
+        if merged.is_empty() {
+            return None;
+        }
+        let mut ret = old.map(|o| o.to_vec()).unwrap_or_else(Vec::new);
+        ret.extend_from_slice(merged);
+        Some(ret)
+    }
+
+    // the lengths of the keys and values, counted the slow way
+    fn counted(t: &sled::Tree) -> (u64, u64) {
+        t.iter().map(|res| res.unwrap()).fold((0, 0), |(k, v), (key, value)| {
+            (k + key.len() as u64, v + value.len() as u64)
+        })
+    }
+    fn assert_counts(t: &sled::Tree) {
+        let usage = t.space_usage().unwrap();
+        assert_eq!((usage.key_bytes, usage.value_bytes), counted(t));
+    }
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(4)
+        .flush_every_ms(None)
+        .merge_operator(concatenate)
+        .build();
+    let db = sled::Db::start(config.clone()).unwrap();
+    let t = db.open_tree(b"t".to_vec()).unwrap();
+
+    for i in 0..N_PER_THREAD {
+        t.set(kv(i), vec![0; i % 10]).unwrap();
+    }
+    assert_counts(&t);
+
+    // overwriting a value counts the difference in its length
+    t.set(kv(0), vec![0; 100]).unwrap();
+    t.cas(kv(1), Some(vec![0; 1]), Some(vec![0; 50])).unwrap();
+    t.cas(kv(2), Some(vec![0; 2]), None).unwrap();
+    t.merge(kv(3), vec![0; 7]).unwrap();
+    t.merge(kv(4), vec![]).unwrap();
+    t.merge(vec![1, 2, 3], vec![0; 3]).unwrap();
+    t.del(&*kv(5)).unwrap();
+    t.del(&*kv(5)).unwrap();
+    let mut batch = Batch::default();
+    batch.insert(kv(6), vec![0; 60]);
+    batch.remove(kv(7));
+    t.apply_batch(batch).unwrap();
+    assert_counts(&t);
+
+    // the counts are persisted on shutdown
+    let before = t.space_usage().unwrap();
+    drop((db, t));
+    let db = sled::Db::start(config.clone()).unwrap();
+    let t = db.open_tree(b"t".to_vec()).unwrap();
+    assert_eq!(t.space_usage().unwrap().key_bytes, before.key_bytes);
+    assert_eq!(t.space_usage().unwrap().value_bytes, before.value_bytes);
+    assert_counts(&t);
+
+    // and counted again after a crash, when they weren't
+    for i in 0..N_PER_THREAD {
+        t.set(kv(i), vec![1; i % 7]).unwrap();
+    }
+    t.flush().unwrap();
+    let _ = fs::remove_dir_all("test_tree_space_usage_counts");
+    fs::create_dir_all("test_tree_space_usage_counts").unwrap();
+    for file in &["conf", "db"] {
+        fs::copy(
+            config.get_path().join(file),
+            Path::new("test_tree_space_usage_counts").join(file),
+        ).unwrap();
+    }
+    let mut crashed_builder = (*config).clone();
+    crashed_builder.tmp_path = PathBuf::from("test_tree_space_usage_counts");
+    let crashed = sled::Db::start(crashed_builder.build()).unwrap();
+    let crashed_t = crashed.open_tree(b"t".to_vec()).unwrap();
+    assert_counts(&crashed_t);
+    assert_counts(&crashed);
+    drop((crashed, crashed_t));
+
+    // clearing and bulk loading replace every count
+    t.clear().unwrap();
+    assert_eq!(counted(&t), (0, 0));
+    assert_counts(&t);
+    let entries = (0..N_PER_THREAD).map(|i| (kv(i), vec![2; i % 5]));
+    t.bulk_load(entries).unwrap();
+    assert_counts(&t);
+}
+
+#[test]
+fn db_export_import() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(16 << 20)
+        .flush_every_ms(None)
+        .build();
+    let big_size = config.get_max_value_size();
+    let db = sled::Db::start(config.clone()).unwrap();
+    let small = db.open_tree(b"small".to_vec()).unwrap();
+    for i in 0..N {
+        small.set(kv(i), kv(i)).unwrap();
+    }
+    small.set(vec![255], vec![]).unwrap();
+    db.open_tree(b"empty".to_vec()).unwrap();
+    let big = db.open_tree(b"big".to_vec()).unwrap();
+    // values as large as are allowed
+    for i in 0..8 {
+        big.set(vec![i], vec![i; big_size]).unwrap();
+    }
+    db.set(vec![1], vec![]).unwrap();
+    drop((db, small, big));
+
+    let read_only = (*config).clone().read_only(true).build();
+    let old = sled::Db::start(read_only).unwrap();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(16 << 20)
+        .flush_every_ms(None)
+        .build();
+    let new = sled::Db::start(config).unwrap();
+    new.import(old.export()).unwrap();
+
+    let names = old.tree_names();
+    assert_eq!(names.len(), 4);
+    assert_eq!(new.tree_names(), names);
+    for name in names {
+        let expected = old.open_tree(name.clone()).unwrap();
+        let actual = new.open_tree(name).unwrap();
+        assert_eq!(actual.len(), expected.len());
+        assert_eq!(actual.checksum(), expected.checksum());
+    }
+    let big = new.open_tree(b"big".to_vec()).unwrap();
+    assert_eq!(big.get(&[2]).unwrap().map(|v| v.len()), Some(big_size));
+    assert_eq!(new.get(&[1]), Ok(Some(vec![].into())));
+    assert!(new.open_tree(b"empty".to_vec()).unwrap().is_empty());
+
+    // trees are only imported into when they're empty
+    assert!(new.import(old.export()).is_err());
+}
+
+#[test]
+fn tree_contended_counter() {
+    const N_COUNTERS: usize = 8;
+    const N_INCREMENTS: usize = 1000;
+
+    fn increment(old: Option<&[u8]>) -> Option<Vec<u8>> {
+        let number = match old {
+            Some(bytes) => bytes_to_u16(bytes) + 1,
+            None => 1,
+        };
+        Some(u16_to_bytes(number))
+    }
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .build();
+    let t = Arc::new(sled::Tree::start(config).unwrap());
+
+    let mut threads = vec![];
+    for _ in 0..N_COUNTERS {
+        let tree = t.clone();
+        threads.push(thread::spawn(move || for _ in 0..N_INCREMENTS {
+            tree.fetch_and_update(b"counter".to_vec(), increment).unwrap();
+        }));
     }
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let expected = u16_to_bytes((N_COUNTERS * N_INCREMENTS) as u16);
+    assert_eq!(t.get(b"counter"), Ok(Some(expected.into())));
+    assert_eq!(
+        t.update_and_fetch(b"counter".to_vec(), increment),
+        Ok(Some(u16_to_bytes((N_COUNTERS * N_INCREMENTS) as u16 + 1).into()))
+    );
 }
 
 #[derive(Debug, Clone)]
@@ -288,6 +3638,85 @@ fn test_merge_operator(
     Some(ret)
 }
 
+#[test]
+fn tree_set_merge_operator() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(2)
+        .page_consolidation_threshold(1000)
+        .flush_every_ms(None)
+        .build();
+    let db = sled::Db::start(config.clone()).unwrap();
+    let t = db.open_tree(b"merged".to_vec()).unwrap();
+
+    match t.merge(vec![1], vec![1]) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("merge without an operator returned {:?}", other),
+    }
+
+    db.set_merge_operator(test_merge_operator);
+    for i in 0..8 * 40 {
+        let k = vec![(i % 8) as u8];
+        t.merge(k, vec![1]).unwrap();
+    }
+    for k in 0..8u8 {
+        let expected = u16_to_bytes(40);
+        assert_eq!(t.get(&[k]), Ok(Some(expected.into())));
+    }
+    let merged: Vec<_> = t.iter().map(|res| res.unwrap()).collect();
+    assert_eq!(merged.len(), 8);
+
+    // every tree in the Db uses the same operator
+    db.merge(vec![1], vec![2]).unwrap();
+    assert_eq!(db.get(&[1]), Ok(Some(u16_to_bytes(2).into())));
+
+    // the unconsolidated merge fragments are replayed in order
+    db.flush().unwrap();
+    drop(t);
+    drop(db);
+    let db = sled::Db::start(config).unwrap();
+    db.set_merge_operator(test_merge_operator);
+    let t = db.open_tree(b"merged".to_vec()).unwrap();
+    let recovered: Vec<_> = t.iter().map(|res| res.unwrap()).collect();
+    assert_eq!(merged, recovered);
+}
+
+#[test]
+fn tree_reopen_without_merge_operator() {
+    let path = env::temp_dir().join(format!(
+        "sled_reopen_without_merge_operator.{}",
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&path);
+    let config = || {
+        ConfigBuilder::new()
+            .path(path.clone())
+            .page_consolidation_threshold(1000)
+            .flush_every_ms(None)
+    };
+
+    let db = sled::Db::start(config().build()).unwrap();
+    db.set_merge_operator(test_merge_operator);
+    db.set(vec![1], u16_to_bytes(1)).unwrap();
+    db.merge(vec![1], vec![2]).unwrap();
+    db.flush().unwrap();
+    drop(db);
+
+    // a new process that forgot to set the merge operator can't read
+    // the merged value back, but it isn't brought down either.
+    let db = sled::Db::start(config().build()).unwrap();
+    match db.get(&[1]) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("reading a merge without an operator: {:?}", other),
+    }
+
+    db.set_merge_operator(test_merge_operator);
+    assert_eq!(db.get(&[1]), Ok(Some(u16_to_bytes(3).into())));
+    drop(db);
+
+    fs::remove_dir_all(&path).unwrap();
+}
+
 fn prop_tree_matches_btreemap(
     ops: Vec<Op>,
     blink_fanout: u8,
@@ -333,7 +3762,7 @@ fn prop_tree_matches_btreemap(
             }
             Cas(k, old, new) => {
                 let tree_old = tree.get(&*vec![k]).unwrap();
-                if tree_old == Some(vec![0, old]) {
+                if tree_old == Some(vec![0, old].into()) {
                     tree.set(vec![k], vec![0, new]).unwrap();
                 }
 
@@ -354,7 +3783,7 @@ fn prop_tree_matches_btreemap(
                     .map(|(ref rk, ref rv)| (**rk, **rv));
                 for r in ref_iter {
                     assert_eq!(
-                        Some((r.0, u16_to_bytes(r.1))),
+                        Some((r.0, u16_to_bytes(r.1).into())),
                         tree_iter.next()
                     );
                 }
@@ -369,6 +3798,186 @@ fn prop_tree_matches_btreemap(
     true
 }
 
+fn prop_scan_prefix_matches_filtered_scan(
+    keys: Vec<Vec<u8>>,
+    prefix: Vec<u8>,
+) -> bool {
+    // squash bytes into a tiny alphabet that includes 0xFF so that
+    // prefixes actually collide and the overflow case is exercised.
+    let squash = |k: &Vec<u8>| -> Vec<u8> {
+        k.iter().take(4).map(|b| [0, 1, 255][*b as usize % 3]).collect()
+    };
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(2)
+        .build();
+    let tree = sled::Tree::start(config).unwrap();
+
+    for k in &keys {
+        tree.set(squash(k), vec![]).unwrap();
+    }
+
+    let prefix = squash(&prefix);
+
+    let scanned: Vec<Vec<u8>> = tree
+        .scan_prefix(&*prefix)
+        .map(|res| res.unwrap().0)
+        .collect();
+
+    let filtered: Vec<Vec<u8>> = tree
+        .iter()
+        .map(|res| res.unwrap().0)
+        .filter(|k| k.starts_with(&*prefix))
+        .collect();
+
+    scanned == filtered
+}
+
+#[test]
+fn quickcheck_scan_prefix_matches_filtered_scan() {
+    QuickCheck::new()
+        .gen(StdGen::new(rand::thread_rng(), 20))
+        .tests(50)
+        .quickcheck(
+            prop_scan_prefix_matches_filtered_scan
+                as fn(Vec<Vec<u8>>, Vec<u8>) -> bool,
+        );
+}
+
+fn prop_double_ended_range_matches_btreemap(
+    sets: Vec<u8>,
+    dels: Vec<u8>,
+    start: u8,
+    end: u8,
+    from_back: Vec<bool>,
+) -> bool {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(2)
+        .build();
+    let tree = sled::Tree::start(config).unwrap();
+    let mut reference = BTreeMap::new();
+
+    for k in sets {
+        tree.set(vec![k], vec![k]).unwrap();
+        reference.insert(vec![k], vec![k]);
+    }
+    for k in dels {
+        tree.del(&[k]).unwrap();
+        reference.remove(&vec![k]);
+    }
+
+    if start > end {
+        // BTreeMap::range panics on reversed ranges
+        return tree.range(vec![start]..vec![end]).next().is_none();
+    }
+
+    let mut tree_iter = tree.range(vec![start]..vec![end]);
+    let mut ref_iter = reference
+        .range(vec![start]..vec![end])
+        .map(|(k, v)| (k.clone(), v.clone().into()));
+
+    for back in from_back {
+        let (t, r) = if back {
+            (tree_iter.next_back(), ref_iter.next_back())
+        } else {
+            (tree_iter.next(), ref_iter.next())
+        };
+        if t.map(|res| res.unwrap()) != r {
+            return false;
+        }
+    }
+
+    let rest: Vec<_> = tree_iter.map(|res| res.unwrap()).collect();
+    rest == ref_iter.collect::<Vec<_>>()
+}
+
+#[test]
+fn quickcheck_double_ended_range_matches_btreemap() {
+    QuickCheck::new()
+        .gen(StdGen::new(rand::thread_rng(), 50))
+        .tests(50)
+        .quickcheck(
+            prop_double_ended_range_matches_btreemap
+                as fn(Vec<u8>, Vec<u8>, u8, u8, Vec<bool>) -> bool,
+        );
+}
+
+// maps each byte to a key of one or two bytes, so that half of the
+// keys are immediately followed by the next possible key: [k, 0].
+fn range_key(b: u8) -> Vec<u8> {
+    if b % 2 == 0 {
+        vec![b / 2]
+    } else {
+        vec![b / 2, 0]
+    }
+}
+
+fn range_bound(kind: u8, b: u8) -> ops::Bound<Vec<u8>> {
+    match kind % 3 {
+        0 => ops::Bound::Included(range_key(b)),
+        1 => ops::Bound::Excluded(range_key(b)),
+        _ => ops::Bound::Unbounded,
+    }
+}
+
+fn prop_bounded_range_matches_btreemap(
+    sets: Vec<u8>,
+    start: (u8, u8),
+    end: (u8, u8),
+) -> bool {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(2)
+        .build();
+    let tree = sled::Tree::start(config).unwrap();
+    let mut reference = BTreeMap::new();
+
+    for b in sets {
+        tree.set(range_key(b), vec![b]).unwrap();
+        reference.insert(range_key(b), vec![b]);
+    }
+
+    let range = (range_bound(start.0, start.1), range_bound(end.0, end.1));
+
+    let empty = match range {
+        (ops::Bound::Included(ref s), ops::Bound::Included(ref e)) => s > e,
+        (ops::Bound::Included(ref s), ops::Bound::Excluded(ref e)) |
+        (ops::Bound::Excluded(ref s), ops::Bound::Included(ref e)) |
+        (ops::Bound::Excluded(ref s), ops::Bound::Excluded(ref e)) => s >= e,
+        _ => false,
+    };
+    let expected: Vec<_> = if empty {
+        // BTreeMap::range panics on some of these
+        vec![]
+    } else {
+        reference
+            .range(range.clone())
+            .map(|(k, v)| (k.clone(), v.clone().into()))
+            .collect()
+    };
+
+    let forward: Vec<_> =
+        tree.range(range.clone()).map(|res| res.unwrap()).collect();
+    let mut backward: Vec<_> =
+        tree.range(range).rev().map(|res| res.unwrap()).collect();
+    backward.reverse();
+
+    forward == expected && backward == expected
+}
+
+#[test]
+fn quickcheck_bounded_range_matches_btreemap() {
+    QuickCheck::new()
+        .gen(StdGen::new(rand::thread_rng(), 100))
+        .tests(50)
+        .quickcheck(
+            prop_bounded_range_matches_btreemap
+                as fn(Vec<u8>, (u8, u8), (u8, u8)) -> bool,
+        );
+}
+
 #[test]
 fn quickcheck_tree_matches_btreemap() {
     // use fewer tests for travis OSX builds that stall out all the time
@@ -9,6 +9,8 @@ extern crate tests;
 
 use std::collections::{BTreeMap, HashSet};
 use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
 
 use quickcheck::{Arbitrary, Gen, QuickCheck, StdGen};
 
@@ -84,12 +86,12 @@ fn v(b: &Vec<u8>) -> u16 {
     ((b[0] as u16) << 8) + b[1] as u16
 }
 
-fn prop_tree_crashes_nicely(ops: Vec<Op>, flusher: bool) -> bool {
-    lazy_static! {
-        // forces quickcheck to run one thread at a time
-        static ref M: Mutex<()> = Mutex::new(());
-    }
+lazy_static! {
+    // forces failpoint tests to run one thread at a time
+    static ref M: Mutex<()> = Mutex::new(());
+}
 
+fn prop_tree_crashes_nicely(ops: Vec<Op>, flusher: bool) -> bool {
     let _lock = M.lock().expect("our test lock should not be poisoned");
 
     // clear all failpoints that may be left over from the last run
@@ -187,6 +189,24 @@ fn run_tree_crashes_nicely(ops: Vec<Op>, flusher: bool) -> bool {
                     }
                 }
             }
+
+            // a crash may have lost changes to the counts in the index,
+            // which are rebuilt on restart along with the tree's length
+            let halves = (
+                tree.count_range(..vec![0, 128]),
+                tree.count_range(vec![0, 128]..),
+            );
+            let counted = match halves {
+                (Ok(below), Ok(above)) => below + above,
+                other => {
+                    println!("could not count entries: {:?}", other);
+                    return false;
+                }
+            };
+            if counted != tree.len() {
+                println!("counted {} of {} entries", counted, tree.len());
+                return false;
+            }
         }
     }
 
@@ -242,7 +262,7 @@ fn run_tree_crashes_nicely(ops: Vec<Op>, flusher: bool) -> bool {
                 match res {
                     Some(_) => {
                         // we definitely caused a file write
-                        tree.flush().expect("should be able to flush after del")
+                        tree.flush().expect("should be able to flush after del");
                     }
                     None => {
                         // we might not have actually written anything
@@ -286,6 +306,512 @@ fn quickcheck_tree_with_failpoints() {
         .quickcheck(prop_tree_crashes_nicely as fn(Vec<Op>, bool) -> bool);
 }
 
+fn batch_crash_recovers(fail_point: &'static str, actions: &str) -> u8 {
+    let _lock = M.lock().expect("our test lock should not be poisoned");
+
+    fail::teardown();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(300)
+        .min_items_per_segment(1)
+        .blink_fanout(2)
+        .build();
+
+    let tree = sled::Tree::start(config.clone()).expect("tree should start");
+
+    for k in 0..3 {
+        tree.set(vec![k], vec![0]).unwrap();
+    }
+
+    let mut batch = Batch::default();
+    for k in 0..3 {
+        batch.insert(vec![k], vec![1]);
+    }
+
+    // crash partway through applying the batch
+    fail::cfg(fail_point, actions).expect(
+        "should be able to configure failpoint",
+    );
+    assert_eq!(tree.apply_batch(batch), Err(Error::FailPoint));
+    fail::teardown();
+
+    drop(tree);
+    let tree = sled::Tree::start(config).expect("tree should restart");
+
+    let values: Vec<sled::IVec> = (0..3)
+        .map(|k| tree.get(&[k]).unwrap().expect("key should be present"))
+        .collect();
+
+    assert!(
+        values.iter().all(|v| *v == values[0]),
+        "recovered a partially applied batch: {:?}",
+        values
+    );
+
+    values[0][0]
+}
+
+#[test]
+fn failpoints_batch_atomic_across_crashes() {
+    // crashing before the batch is logged loses all of it
+    assert_eq!(batch_crash_recovers("batch write", "return"), 0);
+
+    // crashing after the batch is logged, but between writes
+    // to individual keys, recovers all of it
+    assert_eq!(batch_crash_recovers("batch apply", "1*off->return"), 1);
+    assert_eq!(batch_crash_recovers("batch apply", "2*off->return"), 1);
+
+    // crashing before the logged batch is cleared recovers all of it
+    assert_eq!(batch_crash_recovers("batch clear", "return"), 1);
+}
+
+#[test]
+fn failpoints_failed_batch_not_recovered_over_later_ones() {
+    let _lock = M.lock().expect("our test lock should not be poisoned");
+
+    fail::teardown();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(300)
+        .min_items_per_segment(1)
+        .blink_fanout(2)
+        .build();
+
+    let tree = sled::Tree::start(config.clone()).expect("tree should start");
+
+    // a batch that fails partway through stays logged
+    let mut batch = Batch::default();
+    for k in 0..3 {
+        batch.insert(vec![k], vec![1]);
+    }
+    fail::cfg("batch apply", "1*off->return").expect(
+        "should be able to configure failpoint",
+    );
+    assert_eq!(tree.apply_batch(batch), Err(Error::FailPoint));
+    fail::teardown();
+
+    // until a later batch to the same tree takes its place
+    let mut batch = Batch::default();
+    for k in 0..3 {
+        batch.insert(vec![k], vec![2]);
+    }
+    tree.apply_batch(batch).unwrap();
+
+    drop(tree);
+    let tree = sled::Tree::start(config).expect("tree should restart");
+    for k in 0..3 {
+        assert_eq!(tree.get(&[k]), Ok(Some(vec![2].into())));
+    }
+}
+
+// returns the keys holding the moved value, and the length of the tree,
+// after a crash at the given fail point during a rename.
+fn rename_crash_recovers(
+    fail_point: &str,
+    actions: &str,
+) -> (Vec<u8>, usize) {
+    let _lock = M.lock().expect("our test lock should not be poisoned");
+
+    fail::teardown();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(300)
+        .min_items_per_segment(1)
+        .blink_fanout(2)
+        .build();
+
+    let tree = sled::Tree::start(config.clone()).expect("tree should start");
+    tree.set(vec![0], vec![10]).unwrap();
+    tree.set(vec![1], vec![20]).unwrap();
+
+    fail::cfg(fail_point, actions).expect(
+        "should be able to configure failpoint",
+    );
+    assert_eq!(tree.rename(&[1], vec![0]), Err(Error::FailPoint));
+    fail::teardown();
+
+    drop(tree);
+    let tree = sled::Tree::start(config).expect("tree should restart");
+
+    let holders: Vec<u8> = (0..2)
+        .filter(|&k| tree.get(&[k]).unwrap() == Some(vec![20].into()))
+        .collect();
+    (holders, tree.len())
+}
+
+#[test]
+fn failpoints_rename_atomic_across_crashes() {
+    // crashing before the rename is logged leaves the value where it was
+    assert_eq!(rename_crash_recovers("batch write", "return"), (vec![1], 2));
+
+    // crashing after the rename is logged moves it, even between
+    // removing the old key and inserting the new one.
+    assert_eq!(
+        rename_crash_recovers("batch apply", "1*off->return"),
+        (vec![0], 1)
+    );
+    assert_eq!(rename_crash_recovers("batch clear", "return"), (vec![0], 1));
+}
+
+fn by_byte(_k: &[u8], v: &[u8]) -> Vec<Vec<u8>> {
+    v.iter().map(|&b| vec![b]).collect()
+}
+
+// returns whether the records recovered after a crash at the given
+// fail point while updating an indexed tree were the updated ones,
+// after checking that the index matches the records exactly.
+fn indexed_crash_recovers(fail_point: &str, actions: &str) -> bool {
+    let _lock = M.lock().expect("our test lock should not be poisoned");
+
+    fail::teardown();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(1000)
+        .min_items_per_segment(1)
+        .blink_fanout(2)
+        .build();
+
+    let open = |db: &Db| {
+        let records = db.open_tree(b"records".to_vec()).unwrap();
+        let index = db.open_tree(b"by_byte".to_vec()).unwrap();
+        IndexedTree::new(records)
+            .with_index(b"by_byte".to_vec(), index, by_byte)
+            .unwrap()
+    };
+
+    let db = Db::start(config.clone()).expect("db should start");
+    let t = open(&db);
+    for k in 0..4 {
+        t.set(vec![k], vec![k, 10]).unwrap();
+    }
+
+    // every record moves to new index keys, and one goes away
+    let mut batch = Batch::default();
+    for k in 0..3 {
+        batch.insert(vec![k], vec![k, 20]);
+    }
+    batch.remove(vec![3]);
+
+    fail::cfg(fail_point, actions).expect(
+        "should be able to configure failpoint",
+    );
+    assert_eq!(t.apply_batch(batch), Err(Error::FailPoint));
+    fail::teardown();
+
+    drop(t);
+    drop(db);
+    let db = Db::start(config).expect("db should restart");
+    let t = open(&db);
+    let index = db.open_tree(b"by_byte".to_vec()).unwrap();
+
+    // every record can be found under each of its bytes, and there
+    // are no other index entries to find anything else under.
+    let mut expected_entries = 0;
+    for res in t.primary().iter() {
+        let (k, v) = res.unwrap();
+        for &b in v.iter() {
+            let found = t.get_by_index(b"by_byte", &[b]).unwrap();
+            assert!(found.contains(&(k.clone(), v.clone())));
+        }
+        expected_entries += v.len();
+    }
+    assert_eq!(index.iter().count(), expected_entries);
+
+    let updated = t.get(&[0]).unwrap() == Some(vec![0, 20].into());
+    for k in 0..3 {
+        let value = if updated { vec![k, 20] } else { vec![k, 10] };
+        assert_eq!(t.get(&[k]), Ok(Some(value.into())));
+    }
+    assert_eq!(t.get(&[3]).unwrap().is_some(), !updated);
+    updated
+}
+
+#[test]
+fn failpoints_indexed_consistent_across_crashes() {
+    // crashing before the update is logged loses all of it
+    assert!(!indexed_crash_recovers("batch write", "return"));
+
+    // crashing after the update is logged, whether partway through
+    // the records or their index entries, recovers all of it.
+    for n in &[1, 2, 4, 8, 11] {
+        let actions = format!("{}*off->return", n);
+        assert!(indexed_crash_recovers("batch apply", &actions));
+    }
+    assert!(indexed_crash_recovers("batch clear", "return"));
+}
+
+// returns the number of entries that survive a crash at
+// the given fail point while clearing a tree.
+fn clear_crash_recovers(fail_point: &str) -> usize {
+    let _lock = M.lock().expect("our test lock should not be poisoned");
+
+    fail::teardown();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(300)
+        .min_items_per_segment(1)
+        .blink_fanout(2)
+        .build();
+
+    let tree = sled::Tree::start(config.clone()).expect("tree should start");
+
+    for k in 0..10 {
+        tree.set(vec![k], vec![0]).unwrap();
+    }
+
+    fail::cfg(fail_point, "return").expect(
+        "should be able to configure failpoint",
+    );
+    assert_eq!(tree.clear(), Err(Error::FailPoint));
+    fail::teardown();
+
+    drop(tree);
+    let tree = sled::Tree::start(config).expect("tree should restart");
+
+    let count = tree.iter().count();
+    assert_eq!(tree.len(), count);
+    count
+}
+
+#[test]
+fn failpoints_clear_atomic_across_crashes() {
+    // crashing before the new root is recorded keeps everything
+    assert_eq!(clear_crash_recovers("clear meta"), 10);
+
+    // crashing while freeing the old pages keeps nothing
+    assert_eq!(clear_crash_recovers("clear free"), 0);
+}
+
+// returns the keys that survive a crash after removing the given
+// number of entries while retaining the even keys of a tree.
+fn retain_crash_recovers(removals: usize) -> Vec<u8> {
+    let _lock = M.lock().expect("our test lock should not be poisoned");
+
+    fail::teardown();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(300)
+        .min_items_per_segment(1)
+        .blink_fanout(2)
+        .build();
+
+    let tree = sled::Tree::start(config.clone()).expect("tree should start");
+
+    for k in 0..10 {
+        tree.set(vec![k], vec![k]).unwrap();
+    }
+
+    fail::cfg("retain remove", &*format!("{}*off->return", removals))
+        .expect("should be able to configure failpoint");
+    assert_eq!(tree.retain(|k, _v| k[0] % 2 == 0), Err(Error::FailPoint));
+    fail::teardown();
+
+    drop(tree);
+    let tree = sled::Tree::start(config).expect("tree should restart");
+
+    let mut keys = vec![];
+    for res in tree.iter() {
+        let (k, v) = res.unwrap();
+        assert_eq!(k, v, "recovered a damaged entry");
+        keys.push(k[0]);
+    }
+    assert_eq!(tree.len(), keys.len());
+    keys
+}
+
+#[test]
+fn failpoints_retain_partial_across_crashes() {
+    assert_eq!(retain_crash_recovers(0), (0..10).collect::<Vec<u8>>());
+    assert_eq!(retain_crash_recovers(2), vec![0, 2, 4, 5, 6, 7, 8, 9]);
+    assert_eq!(retain_crash_recovers(4), vec![0, 2, 4, 6, 8, 9]);
+}
+
+// returns the number of entries that survive a crash
+// at the given fail point while bulk loading a tree.
+fn bulk_load_crash_recovers(fail_point: &str) -> usize {
+    let _lock = M.lock().expect("our test lock should not be poisoned");
+
+    fail::teardown();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(300)
+        .min_items_per_segment(1)
+        .blink_fanout(2)
+        .build();
+
+    let tree = sled::Tree::start(config.clone()).expect("tree should start");
+
+    fail::cfg(fail_point, "return").expect(
+        "should be able to configure failpoint",
+    );
+    let entries = (0..10).map(|k| (vec![k], vec![k]));
+    assert_eq!(tree.bulk_load(entries), Err(Error::FailPoint));
+    fail::teardown();
+    drop(tree);
+
+    let tree = sled::Tree::start(config).expect("tree should restart");
+    let mut count = 0;
+    for res in tree.iter() {
+        let (k, v) = res.unwrap();
+        assert_eq!(k, vec![count as u8]);
+        assert_eq!(k, v);
+        count += 1;
+    }
+    assert_eq!(tree.len(), count);
+    count
+}
+
+#[test]
+fn failpoints_bulk_load_atomic_across_crashes() {
+    // crashing before the new root is recorded keeps nothing
+    assert_eq!(bulk_load_crash_recovers("bulk load meta"), 0);
+
+    // crashing while freeing the old pages keeps everything
+    assert_eq!(bulk_load_crash_recovers("bulk load free"), 10);
+}
+
+#[test]
+fn failpoints_len_recounted_after_crash() {
+    let _lock = M.lock().expect("our test lock should not be poisoned");
+
+    fail::teardown();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(300)
+        .min_items_per_segment(1)
+        .blink_fanout(2)
+        .build();
+
+    let tree = sled::Tree::start(config.clone()).expect("tree should start");
+    for k in 0..10 {
+        tree.set(vec![k], vec![0]).unwrap();
+    }
+    tree.del(&[0]).unwrap();
+
+    // crash before the counts are persisted during shutdown
+    fail::cfg("lens persist", "return").expect(
+        "should be able to configure failpoint",
+    );
+    drop(tree);
+    fail::teardown();
+
+    let tree = sled::Tree::start(config).expect("tree should restart");
+    assert_eq!(tree.len(), 9);
+}
+
+// returns how many writes survive a crash that happens
+// after the flush interval has passed.
+fn crash_after_interval(flush_every_ms: Option<u64>) -> usize {
+    let _lock = M.lock().expect("our test lock should not be poisoned");
+
+    fail::teardown();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(flush_every_ms)
+        .build();
+
+    let tree = sled::Tree::start(config.clone()).expect("tree should start");
+    for k in 0..10 {
+        tree.set(vec![k], vec![k]).unwrap();
+    }
+    thread::sleep(Duration::from_millis(200));
+
+    fail::cfg("buffer write", "return").expect(
+        "should be able to configure failpoint",
+    );
+    // the background flusher may hit the failpoint first, in which
+    // case this write is already refused.
+    let _ = tree.set(vec![10], vec![10]);
+    assert!(tree.flush().is_err());
+    fail::teardown();
+
+    drop(tree);
+    let tree = sled::Tree::start(config).expect("tree should restart");
+    (0..10).filter(|k| tree.get(&[*k]).unwrap().is_some()).count()
+}
+
+#[test]
+fn failpoints_flush_every_ms_bounds_loss() {
+    // writes are synced in the background once the interval passes
+    assert_eq!(crash_after_interval(Some(10)), 10);
+
+    // but without the background thread, unflushed writes are lost
+    assert_eq!(crash_after_interval(None), 0);
+}
+
+#[test]
+fn failpoints_background_write_error_reported() {
+    let _lock = M.lock().expect("our test lock should not be poisoned");
+
+    fail::teardown();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(Some(10))
+        .build();
+
+    let tree = sled::Tree::start(config.clone()).expect("tree should start");
+    tree.set(vec![0], vec![0]).unwrap();
+    tree.flush().unwrap();
+
+    // the background flush fails to write this
+    fail::cfg("buffer write io", "return").expect(
+        "should be able to configure failpoint",
+    );
+    tree.set(vec![1], vec![1]).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    fail::teardown();
+
+    // which is reported by later writes and flushes, since
+    // nothing written after it can be recovered
+    match tree.set(vec![2], vec![2]) {
+        Err(Error::Io(_)) => {}
+        other => panic!("expected the write error: {:?}", other),
+    }
+    match tree.flush() {
+        Err(Error::Io(_)) => {}
+        other => panic!("expected the write error: {:?}", other),
+    }
+    assert_eq!(tree.get(&[0]), Ok(Some(vec![0].into())));
+
+    drop(tree);
+    let tree = sled::Tree::start(config).expect("tree should restart");
+    assert_eq!(tree.get(&[0]), Ok(Some(vec![0].into())));
+    assert_eq!(tree.get(&[1]), Ok(None));
+}
+
+#[test]
+fn failpoints_failed_flush_keeps_epoch() {
+    let _lock = M.lock().expect("our test lock should not be poisoned");
+
+    fail::teardown();
+
+    let config = ConfigBuilder::new().temporary(true).build();
+
+    let tree = sled::Tree::start(config).expect("tree should start");
+    tree.track_changes().unwrap();
+    let epoch = tree.current_epoch();
+    tree.set(vec![0], vec![0]).unwrap();
+
+    fail::cfg("buffer write", "return").expect(
+        "should be able to configure failpoint",
+    );
+    assert!(tree.flush().is_err());
+    fail::teardown();
+
+    // the write wasn't made durable, so its epoch must not have ended
+    assert_eq!(tree.current_epoch(), epoch);
+}
+
 #[test]
 fn failpoints_bug_01() {
     // postmortem 1: model did not account for proper reasons to fail to start
@@ -803,3 +1329,109 @@ fn failpoints_bug_12() {
         false,
     ))
 }
+
+#[test]
+fn failpoints_bug_13() {
+    assert!(prop_tree_crashes_nicely(
+        vec![Set, Set, FailPoint("trailer write post"), Del(0)],
+        false,
+    ))
+}
+
+#[test]
+fn failpoints_bug_14() {
+    assert!(prop_tree_crashes_nicely(
+        vec![
+            Set,
+            Set,
+            Del(2),
+            Del(2),
+            FailPoint("trailer write"),
+            Set,
+            Del(0),
+            Restart,
+        ],
+        false,
+    ))
+}
+
+#[test]
+fn failpoints_bug_15() {
+    assert!(prop_tree_crashes_nicely(
+        vec![
+            Set,
+            FailPoint("snap write mv"),
+            Set,
+            FailPoint("trailer write"),
+            Set,
+            Set,
+            Restart,
+        ],
+        false,
+    ))
+}
+
+#[test]
+fn failpoints_bug_16() {
+    assert!(prop_tree_crashes_nicely(
+        vec![
+            Restart,
+            Set,
+            Restart,
+            Del(0),
+            Restart,
+            Set,
+            Restart,
+            FailPoint("trailer write"),
+            Del(1),
+            Set,
+        ],
+        false,
+    ))
+}
+
+#[test]
+fn failpoints_bug_17() {
+    // postmortem 1: messages that only fit in a segment once compressed
+    // were written compressed, and then failed to decompress into a
+    // segment-sized buffer when read back.
+    assert!(prop_tree_crashes_nicely(
+        vec![
+            Set,
+            Restart,
+            Set,
+            Set,
+            Set,
+            Set,
+            Set,
+            Set,
+            Set,
+            Set,
+            Del(4),
+            Set,
+            Restart,
+            Set,
+            Set,
+            Set,
+            Del(4),
+            Restart,
+            Del(13),
+            Restart,
+            Set,
+            Set,
+            Set,
+            Set,
+            Set,
+            Set,
+            Set,
+            Set,
+            Set,
+            Set,
+            Set,
+            Set,
+            Set,
+            Del(0),
+        ],
+        false,
+    ))
+}